@@ -0,0 +1,104 @@
+/*
+    Throughput of the VirtualTun data path: one thread pushes packets the
+    way smol_stack_virtual_tun_send does and another pops them the way
+    smol_stack_virtual_tun_receive_instantly_into does. Compares the old
+    Mutex<PacketQueue> + Condvar design with the PacketRing one, both
+    bounded to the same number of packets and notifying the poller's
+    condvar on every push and pop.
+
+        cargo bench --bench packet_queues
+*/
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use smoltcp_cpp_interface_rust::virtual_tun::queue::{PacketQueue, QueueLimits};
+use smoltcp_cpp_interface_rust::virtual_tun::ring::{PacketRing, RING_SLOTS};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+const PACKETS: usize = 10_000;
+const PACKET_SIZE: usize = 1400;
+const MTU: usize = 1500;
+
+type HasData = Arc<(Mutex<()>, Condvar)>;
+
+fn limits() -> QueueLimits {
+    QueueLimits {
+        max_packets: RING_SLOTS,
+        max_bytes: 0,
+    }
+}
+
+fn mutex_queue(queue: &Arc<Mutex<PacketQueue<Vec<u8>>>>, has_data: &HasData) {
+    let producer_queue = queue.clone();
+    let producer_has_data = has_data.clone();
+    let producer = thread::spawn(move || {
+        let packet = vec![7u8; PACKET_SIZE];
+        for _ in 0..PACKETS {
+            //The old send copied the packet into a new Vec
+            let mut data = packet.to_vec();
+            loop {
+                match producer_queue.lock().unwrap().push_back(data) {
+                    Ok(()) => break,
+                    Err(refused) => data = refused,
+                }
+                thread::yield_now();
+            }
+            producer_has_data.1.notify_all();
+        }
+    });
+    let mut buffer = vec![0u8; MTU];
+    let mut received = 0;
+    while received < PACKETS {
+        let packet = queue.lock().unwrap().pop_front();
+        match packet {
+            Some(packet) => {
+                buffer[..packet.len()].copy_from_slice(&packet);
+                has_data.1.notify_all();
+                received += 1;
+            }
+            None => thread::yield_now(),
+        }
+    }
+    producer.join().unwrap();
+}
+
+fn ring(ring: &Arc<PacketRing>, has_data: &HasData) {
+    let producer_ring = ring.clone();
+    let producer_has_data = has_data.clone();
+    let producer = thread::spawn(move || {
+        let packet = vec![7u8; PACKET_SIZE];
+        for _ in 0..PACKETS {
+            while producer_ring.push(&packet).is_err() {
+                thread::yield_now();
+            }
+            producer_has_data.1.notify_all();
+        }
+    });
+    let mut buffer = vec![0u8; MTU];
+    let mut received = 0;
+    while received < PACKETS {
+        match ring.pop_into(&mut buffer) {
+            Some(_) => {
+                has_data.1.notify_all();
+                received += 1;
+            }
+            None => thread::yield_now(),
+        }
+    }
+    producer.join().unwrap();
+}
+
+fn packet_queues(c: &mut Criterion) {
+    let mut group = c.benchmark_group("virtual_tun_packets");
+    group.throughput(Throughput::Bytes((PACKETS * PACKET_SIZE) as u64));
+    let has_data: HasData = Arc::new((Mutex::new(()), Condvar::new()));
+
+    let queue = Arc::new(Mutex::new(PacketQueue::new(limits())));
+    group.bench_function("mutex_packet_queue", |b| b.iter(|| mutex_queue(&queue, &has_data)));
+
+    let packet_ring = Arc::new(PacketRing::new(RING_SLOTS, MTU, QueueLimits::unlimited()));
+    group.bench_function("packet_ring", |b| b.iter(|| ring(&packet_ring, &has_data)));
+    group.finish();
+}
+
+criterion_group!(benches, packet_queues);
+criterion_main!(benches);
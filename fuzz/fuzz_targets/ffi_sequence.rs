@@ -0,0 +1,232 @@
+/*
+    Drives a VirtualTun stack through random sequences of C ABI calls,
+    with arbitrary socket keys (including stale ones), enums, pointers
+    and lengths. Packets the stack sends can be fed back to it, so
+    it may end up talking to itself. Nothing here blocks: only the
+    non-waiting receives are used and the clock is manual.
+    Every owner handed over with smol_stack_smol_socket_send must be
+    released by the time the stack is destroyed
+*/
+#![no_main]
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use smoltcp_cpp_interface_rust::virtual_tun::interface::*;
+use std::ffi::{c_void, CString};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static OWNERS_ALIVE: AtomicUsize = AtomicUsize::new(0);
+
+//Stands for SmolOwner's destructor on the C++ side
+unsafe extern "C" fn release_owner(_owner: *const c_void) -> u8 {
+    OWNERS_ALIVE.fetch_sub(1, Ordering::SeqCst);
+    0
+}
+
+extern "C" fn allocate(size: usize) -> *mut u8 {
+    Box::into_raw(vec![0u8; size].into_boxed_slice()) as *mut u8
+}
+
+fn free(cbuffer: &CBuffer) {
+    unsafe {
+        Box::from_raw(ptr::slice_from_raw_parts_mut(cbuffer.data, cbuffer.len));
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum Call {
+    AddSocket { socket_type: u8 },
+    RemoveSocket { socket: u8 },
+    Connect { socket: u8, address: [u8; 4], src_port: u16, dst_port: u16 },
+    Listen { socket: u8, port: u16 },
+    Bind { socket: u8, port: u16 },
+    Send { socket: u8, data: Vec<u8>, endpoint_type: u8, address: [u8; 4], port: u16, owned: bool },
+    SendNull { socket: u8, len: u16 },
+    Receive { socket: u8, capacity: u16 },
+    ReceiveAllocating { socket: u8 },
+    ShutdownWrite { socket: u8 },
+    SetOption { socket: u8, option: u8, value: u64 },
+    GetOption { socket: u8, option: u8 },
+    SetQueueLimits { queue: u8, max_packets: u8, max_bytes: u16 },
+    Inject { data: Vec<u8> },
+    Reflect,
+    Poll,
+    Spin { socket: u8 },
+    AdvanceClock { millis: u16 },
+}
+
+fuzz_target!(|calls: Vec<Call>| {
+    OWNERS_ALIVE.store(0, Ordering::SeqCst);
+    let name = CString::new("fuzz0").unwrap();
+    let mut smol_stack = smol_stack_smol_stack_new_virtual_tun(name.as_ptr()).unwrap();
+    smol_stack_set_manual_clock(&mut smol_stack, 0);
+    smol_stack_add_ipv4_address(
+        &mut smol_stack,
+        CIpv4Cidr {
+            address: CIpv4Address {
+                address: [192, 168, 69, 1],
+            },
+            prefix: 24,
+        },
+    );
+    smol_stack_finalize(&mut smol_stack);
+
+    //Every key ever returned, removed ones stay to exercise stale keys
+    let mut keys: Vec<usize> = Vec::new();
+    let key = |keys: &Vec<usize>, socket: u8| -> usize {
+        if keys.is_empty() {
+            socket as usize
+        } else {
+            keys[socket as usize % keys.len()]
+        }
+    };
+    let mut buffer = vec![0u8; 65536];
+
+    for call in calls.iter() {
+        match call {
+            Call::AddSocket { socket_type } => {
+                let mut socket = 0;
+                if smol_stack_add_socket(&mut smol_stack, *socket_type, &mut socket) == 0 {
+                    keys.push(socket);
+                }
+            }
+            Call::RemoveSocket { socket } => {
+                smol_stack_remove_socket(&mut smol_stack, key(&keys, *socket));
+            }
+            Call::Connect { socket, address, src_port, dst_port } => {
+                let address = CIpAddress {
+                    is_ipv4: 1,
+                    ipv4_address: CIpv4Address { address: *address },
+                    ipv6_address: CIpv6Address { address: [0; 8] },
+                };
+                smol_stack_tcp_connect(&mut smol_stack, key(&keys, *socket), address, *src_port, *dst_port);
+            }
+            Call::Listen { socket, port } => {
+                smol_stack_tcp_listen(&mut smol_stack, key(&keys, *socket), *port);
+            }
+            Call::Bind { socket, port } => {
+                smol_stack_udp_bind(&mut smol_stack, key(&keys, *socket), *port);
+            }
+            Call::Send { socket, data, endpoint_type, address, port, owned } => {
+                let endpoint = CIpEndpoint {
+                    endpoint_type: *endpoint_type % 3,
+                    ipv4: CIpv4Address { address: *address },
+                    ipv6: CIpv6Address { address: [0xfdaa, 0, 0, 0, 0, 0, 0, 2] },
+                    port: *port,
+                };
+                if *owned {
+                    OWNERS_ALIVE.fetch_add(1, Ordering::SeqCst);
+                    smol_stack_smol_socket_send(
+                        &mut smol_stack,
+                        key(&keys, *socket),
+                        data.as_ptr() as *mut u8,
+                        data.len(),
+                        endpoint,
+                        1 as *const c_void,
+                        release_owner,
+                    );
+                } else {
+                    smol_stack_smol_socket_send_copy(
+                        &mut smol_stack,
+                        key(&keys, *socket),
+                        data.as_ptr() as *mut u8,
+                        data.len(),
+                        endpoint,
+                    );
+                }
+            }
+            Call::SendNull { socket, len } => {
+                let endpoint = CIpEndpoint {
+                    endpoint_type: 0,
+                    ipv4: CIpv4Address { address: [0; 4] },
+                    ipv6: CIpv6Address { address: [0; 8] },
+                    port: 0,
+                };
+                smol_stack_smol_socket_send_copy(
+                    &mut smol_stack,
+                    key(&keys, *socket),
+                    ptr::null_mut(),
+                    *len as usize,
+                    endpoint,
+                );
+            }
+            Call::Receive { socket, capacity } => {
+                let capacity = std::cmp::min(*capacity as usize, buffer.len());
+                let mut len = 0;
+                let mut address = CIpAddress {
+                    is_ipv4: 0,
+                    ipv4_address: CIpv4Address { address: [0; 4] },
+                    ipv6_address: CIpv6Address { address: [0; 8] },
+                };
+                smol_stack_smol_socket_receive_into(
+                    &mut smol_stack,
+                    key(&keys, *socket),
+                    buffer.as_mut_ptr(),
+                    capacity,
+                    &mut len,
+                    &mut address,
+                );
+            }
+            Call::ReceiveAllocating { socket } => {
+                let mut cbuffer = CBuffer {
+                    data: ptr::null_mut(),
+                    len: 0,
+                };
+                if smol_stack_smol_socket_receive(
+                    &mut smol_stack,
+                    key(&keys, *socket),
+                    &mut cbuffer,
+                    allocate,
+                    ptr::null_mut(),
+                ) == 0
+                {
+                    free(&cbuffer);
+                }
+            }
+            Call::ShutdownWrite { socket } => {
+                smol_stack_smol_socket_shutdown_write(&mut smol_stack, key(&keys, *socket));
+            }
+            Call::SetOption { socket, option, value } => {
+                smol_stack_smol_socket_set_option(&mut smol_stack, key(&keys, *socket), *option, *value);
+            }
+            Call::GetOption { socket, option } => {
+                let mut value = 0;
+                smol_stack_smol_socket_get_option(&mut smol_stack, key(&keys, *socket), *option, &mut value);
+            }
+            Call::SetQueueLimits { queue, max_packets, max_bytes } => {
+                smol_stack_set_queue_limits(&mut smol_stack, *queue, *max_packets as usize, *max_bytes as usize);
+            }
+            Call::Inject { data } => {
+                smol_stack_virtual_tun_send(&mut smol_stack, data.as_ptr() as *mut u8, data.len());
+            }
+            Call::Reflect => {
+                let mut len = 0;
+                while smol_stack_virtual_tun_receive_instantly_into(
+                    &mut smol_stack,
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    &mut len,
+                ) == 0
+                {
+                    let packet = buffer[..len].to_vec();
+                    //Stop if the stack refuses it, the queue might be limited
+                    if smol_stack_virtual_tun_send(&mut smol_stack, packet.as_ptr() as *mut u8, packet.len()) != 0 {
+                        break;
+                    }
+                }
+            }
+            Call::Poll => {
+                smol_stack_poll(&mut smol_stack);
+                smol_stack_spin_all(&mut smol_stack);
+            }
+            Call::Spin { socket } => {
+                smol_stack_spin(&mut smol_stack, key(&keys, *socket));
+            }
+            Call::AdvanceClock { millis } => {
+                smol_stack_advance_clock(&mut smol_stack, *millis as u64);
+            }
+        }
+    }
+    smol_stack_destroy(Some(smol_stack));
+    assert_eq!(OWNERS_ALIVE.load(Ordering::SeqCst), 0);
+});
@@ -0,0 +1,76 @@
+/*
+    Feeds arbitrary packets, as if they came from the OpenVPN side, to a
+    finalized VirtualTun stack with a listening TCP socket and a bound
+    UDP socket, running poll/spin after each one. Whatever the stack
+    answers is received and thrown away
+*/
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use smoltcp_cpp_interface_rust::virtual_tun::interface::*;
+use std::ffi::CString;
+use std::ptr;
+
+//Same numbering as smol_stack_add_socket
+const SOCKET_TYPE_TCP: u8 = 0;
+const SOCKET_TYPE_UDP: u8 = 1;
+
+fuzz_target!(|packets: Vec<Vec<u8>>| {
+    let name = CString::new("fuzz0").unwrap();
+    let mut smol_stack = smol_stack_smol_stack_new_virtual_tun(name.as_ptr()).unwrap();
+    smol_stack_set_manual_clock(&mut smol_stack, 0);
+    smol_stack_add_ipv4_address(
+        &mut smol_stack,
+        CIpv4Cidr {
+            address: CIpv4Address {
+                address: [192, 168, 69, 1],
+            },
+            prefix: 24,
+        },
+    );
+    smol_stack_add_ipv6_address(
+        &mut smol_stack,
+        CIpv6Cidr {
+            address: CIpv6Address {
+                address: [0xfdaa, 0, 0, 0, 0, 0, 0, 1],
+            },
+            prefix: 64,
+        },
+    );
+    assert_eq!(smol_stack_finalize(&mut smol_stack), 0);
+
+    let mut tcp_socket = 0;
+    assert_eq!(smol_stack_add_socket(&mut smol_stack, SOCKET_TYPE_TCP, &mut tcp_socket), 0);
+    assert_eq!(smol_stack_tcp_listen(&mut smol_stack, tcp_socket, 80), 0);
+    let mut udp_socket = 0;
+    assert_eq!(smol_stack_add_socket(&mut smol_stack, SOCKET_TYPE_UDP, &mut udp_socket), 0);
+    assert_eq!(smol_stack_udp_bind(&mut smol_stack, udp_socket, 53), 0);
+
+    let mut buffer = vec![0; 65536];
+    for packet in packets.iter() {
+        smol_stack_virtual_tun_send(&mut smol_stack, packet.as_ptr() as *mut u8, packet.len());
+        smol_stack_spin_all(&mut smol_stack);
+        smol_stack_poll(&mut smol_stack);
+        smol_stack_spin_all(&mut smol_stack);
+        smol_stack_advance_clock(&mut smol_stack, 100);
+        let mut len = 0;
+        while smol_stack_virtual_tun_receive_instantly_into(
+            &mut smol_stack,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut len,
+        ) == 0
+        {}
+        for socket in [tcp_socket, udp_socket].iter() {
+            while smol_stack_smol_socket_receive_into(
+                &mut smol_stack,
+                *socket,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut len,
+                ptr::null_mut(),
+            ) == 0
+            {}
+        }
+    }
+    smol_stack_destroy(Some(smol_stack));
+});
@@ -0,0 +1,720 @@
+/*
+    Runs a stack described by a configuration file (see config.rs) on a
+    TUN/TAP device, an fd inherited from the parent process (like one
+    end of a socketpair) or a UDP tunnel, with a few tools to exercise
+    it from the outside, without the C++ product:
+
+        smol_daemon stack.toml ping 192.168.69.100
+        smol_daemon stack.toml get http://192.168.69.100:8080/index.html
+        smol_daemon --stats 10 stack.toml echo 7
+
+    SIGUSR1 prints the statistics and the connection table, SIGINT and
+    SIGTERM close the connections and stop
+*/
+extern crate smoltcp;
+extern crate smoltcp_cpp_interface_rust;
+
+use smoltcp::phy::{ChecksumCapabilities, Device};
+use smoltcp::socket::{IcmpEndpoint, IcmpPacketMetadata, IcmpSocket, IcmpSocketBuffer};
+use smoltcp::socket::{Socket, SocketHandle, TcpSocket, TcpState};
+use smoltcp::wire::{Icmpv4Packet, Icmpv4Repr, Icmpv6Packet, Icmpv6Repr, IpAddress};
+use smoltcp_cpp_interface_rust::virtual_tun::config::StackConfig;
+use smoltcp_cpp_interface_rust::virtual_tun::interface::SmolStackType;
+use smoltcp_cpp_interface_rust::virtual_tun::logging::set_log_function;
+use smoltcp_cpp_interface_rust::virtual_tun::smol_stack::{Packet, SmolStack, SocketType};
+use smoltcp_cpp_interface_rust::virtual_tun::smol_stack::{SMOL_RESULT_CLOSED, SMOL_RESULT_END_OF_STREAM};
+use smoltcp_cpp_interface_rust::virtual_tun::smol_stack::{SMOL_RESULT_NOT_AVAILABLE, SMOL_RESULT_OK};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CStr;
+use std::io::Write;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage: smol_daemon [--stats SECONDS] CONFIG COMMAND [ARGS]
+
+CONFIG is a TOML or JSON stack configuration (tun, tap, fd, unix_socket
+or udp_tunnel device). Commands:
+    serve                   keep the stack up, it answers pings by itself
+    ping ADDRESS [COUNT]    send COUNT (default 4) ICMP echo requests
+    connect ADDRESS PORT    TCP connection between stdin/stdout and ADDRESS:PORT
+    echo PORT               TCP echo server
+    get URL                 HTTP GET of http://ADDRESS[:PORT]/PATH to stdout
+
+--stats prints the statistics and the connection table to stderr every
+SECONDS, SIGUSR1 prints them once";
+
+//Clients give up if the connection isn't up by then
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+//How long TCP connections get to close in order when the daemon stops
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+//Longest wait between two polls, so signals are never missed for long
+const MAX_WAIT: Duration = Duration::from_secs(1);
+//Listening sockets kept by echo, the most connections it serves at once
+const ECHO_BACKLOG: usize = 4;
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+const PING_PAYLOAD: [u8; 56] = [0xa5; 56];
+
+static STOP: AtomicBool = AtomicBool::new(false);
+static DUMP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    if signal == libc::SIGUSR1 {
+        DUMP.store(true, Ordering::SeqCst);
+    } else {
+        STOP.store(true, Ordering::SeqCst);
+    }
+}
+
+extern "C" fn log_to_stderr(level: u8, target: *const c_char, message: *const c_char) {
+    let target = unsafe { CStr::from_ptr(target) }.to_string_lossy();
+    let message = unsafe { CStr::from_ptr(message) }.to_string_lossy();
+    eprintln!("[{}] {}: {}", level, target, message);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("smol_daemon: {}", message);
+    process::exit(2);
+}
+
+fn parse_address(address: &str) -> Result<IpAddress, String> {
+    address
+        .parse::<IpAddress>()
+        .or_else(|_| Err(format!("\"{}\" is not an IP address, names aren't resolved", address)))
+}
+
+fn parse_port(port: &str) -> Result<u16, String> {
+    match port.parse::<u16>() {
+        Ok(port) if port != 0 => Ok(port),
+        _ => Err(format!("\"{}\" is not a port", port)),
+    }
+}
+
+//Random port in the dynamic range, for outgoing connections
+fn local_port() -> u16 {
+    49152 + rand::random::<u16>() % 16384
+}
+
+/*
+    Splits http://ADDRESS[:PORT]/PATH, IPv6 addresses between brackets.
+    Returns the address, the port, the Host header and the path
+*/
+fn parse_url(url: &str) -> Result<(IpAddress, u16, String, String), String> {
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None => return Err(format!("\"{}\" is not an http:// URL", url)),
+    };
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (address, port) = if host.starts_with('[') {
+        match host.find(']') {
+            Some(i) => (&host[1..i], host[i + 1..].strip_prefix(':')),
+            None => return Err(format!("\"{}\" has no closing bracket", host)),
+        }
+    } else {
+        match host.rfind(':') {
+            Some(i) => (&host[..i], Some(&host[i + 1..])),
+            None => (host, None),
+        }
+    };
+    let port = match port {
+        Some(port) => parse_port(port)?,
+        None => 80,
+    };
+    Ok((parse_address(address)?, port, host.to_string(), path.to_string()))
+}
+
+//State and endpoints of a TCP socket added with add_socket
+fn tcp_state<'a, 'b: 'a, 'c: 'a + 'b, D>(stack: &mut SmolStack<'a, 'b, 'c, D>, key: usize) -> Option<(TcpState, String)>
+where
+    D: for<'d> Device<'d>,
+{
+    let socket_handle = stack.get_smol_socket(key)?.socket_handle;
+    let socket = stack.sockets.get::<TcpSocket>(socket_handle);
+    Some((socket.state(), socket.remote_endpoint().to_string()))
+}
+
+fn tcp_connect<'a, 'b: 'a, 'c: 'a + 'b, D>(
+    stack: &mut SmolStack<'a, 'b, 'c, D>,
+    address: IpAddress,
+    port: u16,
+) -> Result<usize, String>
+where
+    D: for<'d> Device<'d>,
+{
+    let mut key = 0;
+    if stack.add_socket(SocketType::TCP, &mut key) != SMOL_RESULT_OK {
+        return Err("could not add a TCP socket".to_string());
+    }
+    let result = match address {
+        IpAddress::Ipv4(address) => stack.tcp_connect_ipv4(key, address.into(), local_port(), port),
+        IpAddress::Ipv6(address) => stack.tcp_connect_ipv6(key, address.into(), local_port(), port),
+        _ => return Err(format!("can't connect to {}", address)),
+    };
+    if result != SMOL_RESULT_OK {
+        return Err(format!("could not connect to {}:{} ({})", address, port, result));
+    }
+    Ok(key)
+}
+
+/*
+    The tools don't wait for room: with sockets.to_send limits in the
+    configuration, what doesn't fit is an error
+*/
+fn send<'a, 'b: 'a, 'c: 'a + 'b, D>(stack: &mut SmolStack<'a, 'b, 'c, D>, key: usize, data: Vec<u8>) -> Result<(), String>
+where
+    D: for<'d> Device<'d>,
+{
+    let smol_socket = stack.get_smol_socket(key).ok_or("the socket is gone")?;
+    match smol_socket.send(Packet::from_vec(data, None)) {
+        SMOL_RESULT_OK => Ok(()),
+        result => Err(format!("send failed ({}), is sockets.to_send too small?", result)),
+    }
+}
+
+//Copies what the connection received to stdout. True once the peer closed it
+fn receive_to_stdout<'a, 'b: 'a, 'c: 'a + 'b, D>(stack: &mut SmolStack<'a, 'b, 'c, D>, key: usize) -> Result<bool, String>
+where
+    D: for<'d> Device<'d>,
+{
+    let smol_socket = match stack.get_smol_socket(key) {
+        Some(smol_socket) => smol_socket,
+        None => return Err("the socket is gone".to_string()),
+    };
+    let mut buffer = [0; 4096];
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    loop {
+        let mut len = 0;
+        let mut endpoint = None;
+        match smol_socket.receive_into(&mut buffer, &mut len, &mut endpoint) {
+            SMOL_RESULT_OK => {
+                stdout.write_all(&buffer[..len]).map_err(|err| err.to_string())?;
+            }
+            SMOL_RESULT_NOT_AVAILABLE => break,
+            SMOL_RESULT_END_OF_STREAM => {
+                stdout.flush().map_err(|err| err.to_string())?;
+                return Ok(true);
+            }
+            SMOL_RESULT_CLOSED => return Err("connection refused, reset or timed out".to_string()),
+            result => return Err(format!("receive failed ({})", result)),
+        }
+    }
+    stdout.flush().map_err(|err| err.to_string())?;
+    Ok(false)
+}
+
+struct Ping {
+    address: IpAddress,
+    count: u16,
+    ident: u16,
+    handle: Option<SocketHandle>,
+    sent: u16,
+    received: u16,
+    send_times: HashMap<u16, Instant>,
+    next_send: Instant,
+}
+
+impl Ping {
+    fn step<'a, 'b: 'a, 'c: 'a + 'b, D>(&mut self, stack: &mut SmolStack<'a, 'b, 'c, D>) -> Result<bool, String>
+    where
+        D: for<'d> Device<'d>,
+    {
+        //ICMPv6 checksums cover the source address, the first one we have of that version
+        let source = match stack.interface.as_ref() {
+            Some(interface) => interface
+                .ip_addrs()
+                .iter()
+                .map(|cidr| cidr.address())
+                .find(|address| same_version(address, &self.address)),
+            None => None,
+        };
+        let source = source.ok_or_else(|| format!("the stack has no address to ping {} from", self.address))?;
+        let handle = match self.handle {
+            Some(handle) => handle,
+            None => {
+                let rx_buffer = IcmpSocketBuffer::new(vec![IcmpPacketMetadata::EMPTY; 16], vec![0; 4096]);
+                let tx_buffer = IcmpSocketBuffer::new(vec![IcmpPacketMetadata::EMPTY; 16], vec![0; 4096]);
+                let mut socket = IcmpSocket::new(rx_buffer, tx_buffer);
+                socket.bind(IcmpEndpoint::Ident(self.ident)).map_err(|err| err.to_string())?;
+                let handle = stack.sockets.add(socket);
+                self.handle = Some(handle);
+                handle
+            }
+        };
+        let checksum = ChecksumCapabilities::default();
+        let mut socket = stack.sockets.get::<IcmpSocket>(handle);
+        let now = Instant::now();
+        if self.sent < self.count && now >= self.next_send && socket.can_send() {
+            let seq_no = self.sent;
+            match self.address {
+                IpAddress::Ipv4(_) => {
+                    let repr = Icmpv4Repr::EchoRequest {
+                        ident: self.ident,
+                        seq_no: seq_no,
+                        data: &PING_PAYLOAD,
+                    };
+                    let buffer = socket.send(repr.buffer_len(), self.address).map_err(|err| err.to_string())?;
+                    repr.emit(&mut Icmpv4Packet::new_unchecked(buffer), &checksum);
+                }
+                _ => {
+                    let repr = Icmpv6Repr::EchoRequest {
+                        ident: self.ident,
+                        seq_no: seq_no,
+                        data: &PING_PAYLOAD,
+                    };
+                    let buffer = socket.send(repr.buffer_len(), self.address).map_err(|err| err.to_string())?;
+                    repr.emit(&source, &self.address, &mut Icmpv6Packet::new_unchecked(buffer), &checksum);
+                }
+            }
+            self.send_times.insert(seq_no, now);
+            self.sent += 1;
+            self.next_send = now + PING_INTERVAL;
+        }
+        while socket.can_recv() {
+            let (payload, from) = socket.recv().map_err(|err| err.to_string())?;
+            let reply = match from {
+                IpAddress::Ipv4(_) => Icmpv4Packet::new_checked(payload)
+                    .and_then(|packet| Icmpv4Repr::parse(&packet, &checksum))
+                    .ok()
+                    .and_then(|repr| match repr {
+                        Icmpv4Repr::EchoReply { ident, seq_no, data } => Some((ident, seq_no, data.len())),
+                        _ => None,
+                    }),
+                _ => Icmpv6Packet::new_checked(payload)
+                    .and_then(|packet| Icmpv6Repr::parse(&from, &source, &packet, &checksum))
+                    .ok()
+                    .and_then(|repr| match repr {
+                        Icmpv6Repr::EchoReply { ident, seq_no, data } => Some((ident, seq_no, data.len())),
+                        _ => None,
+                    }),
+            };
+            if let Some((ident, seq_no, len)) = reply {
+                if let (true, Some(sent_at)) = (ident == self.ident, self.send_times.remove(&seq_no)) {
+                    self.received += 1;
+                    println!(
+                        "{} bytes from {}: icmp_seq={} time={:.1} ms",
+                        len,
+                        from,
+                        seq_no,
+                        sent_at.elapsed().as_secs_f64() * 1000.0
+                    );
+                }
+            }
+        }
+        //The last request gets one interval to be answered
+        let done = self.sent == self.count && (self.received == self.count || now >= self.next_send);
+        if done {
+            println!(
+                "{} packets transmitted, {} received, {}% packet loss",
+                self.sent,
+                self.received,
+                100 * (self.sent - self.received) as u32 / self.sent.max(1) as u32
+            );
+            if self.received == 0 {
+                return Err(format!("no replies from {}", self.address));
+            }
+        }
+        Ok(done)
+    }
+}
+
+fn same_version(a: &IpAddress, b: &IpAddress) -> bool {
+    match (a, b) {
+        (IpAddress::Ipv4(_), IpAddress::Ipv4(_)) | (IpAddress::Ipv6(_), IpAddress::Ipv6(_)) => true,
+        _ => false,
+    }
+}
+
+struct Client {
+    address: IpAddress,
+    port: u16,
+    key: usize,
+    started: Instant,
+    connected: bool,
+    //HTTP GET request, sent as soon as the connection is up. None for connect
+    request: Option<Vec<u8>>,
+    stdin_open: bool,
+}
+
+impl Client {
+    fn step<'a, 'b: 'a, 'c: 'a + 'b, D>(&mut self, stack: &mut SmolStack<'a, 'b, 'c, D>) -> Result<bool, String>
+    where
+        D: for<'d> Device<'d>,
+    {
+        if self.key == 0 {
+            self.key = tcp_connect(stack, self.address, self.port)?;
+            self.started = Instant::now();
+        }
+        if !self.connected {
+            if stack.may_send(self.key) == SMOL_RESULT_OK {
+                self.connected = true;
+                eprintln!("connected to {}:{}", self.address, self.port);
+                if let Some(request) = self.request.take() {
+                    send(stack, self.key, request)?;
+                }
+            } else if self.started.elapsed() > CONNECT_TIMEOUT {
+                return Err(format!("no connection to {}:{} after {:?}", self.address, self.port, CONNECT_TIMEOUT));
+            }
+        }
+        receive_to_stdout(stack, self.key)
+    }
+
+    //connect copies stdin to the connection once it's up
+    fn wants_stdin(&self) -> bool {
+        self.connected && self.stdin_open
+    }
+
+    fn stdin_readable<'a, 'b: 'a, 'c: 'a + 'b, D>(&mut self, stack: &mut SmolStack<'a, 'b, 'c, D>) -> Result<(), String>
+    where
+        D: for<'d> Device<'d>,
+    {
+        let mut buffer = vec![0; 4096];
+        let n = unsafe { libc::read(0, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+        if n > 0 {
+            buffer.truncate(n as usize);
+            send(stack, self.key, buffer)?;
+        } else {
+            //End of input (or an error): the peer gets our FIN, we keep reading until its own
+            self.stdin_open = false;
+            if let Some(smol_socket) = stack.get_smol_socket(self.key) {
+                smol_socket.shutdown_write();
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Listener {
+    key: usize,
+    peer: Option<String>,
+    finished: bool,
+}
+
+struct Echo {
+    port: u16,
+    listeners: Vec<Listener>,
+}
+
+impl Echo {
+    fn listen<'a, 'b: 'a, 'c: 'a + 'b, D>(&self, stack: &mut SmolStack<'a, 'b, 'c, D>) -> Result<Listener, String>
+    where
+        D: for<'d> Device<'d>,
+    {
+        let mut key = 0;
+        if stack.add_socket(SocketType::TCP, &mut key) != SMOL_RESULT_OK {
+            return Err("could not add a TCP socket".to_string());
+        }
+        if stack.tcp_listen(key, self.port) != SMOL_RESULT_OK {
+            return Err(format!("could not listen on port {}", self.port));
+        }
+        Ok(Listener {
+            key: key,
+            peer: None,
+            finished: false,
+        })
+    }
+
+    fn step<'a, 'b: 'a, 'c: 'a + 'b, D>(&mut self, stack: &mut SmolStack<'a, 'b, 'c, D>) -> Result<bool, String>
+    where
+        D: for<'d> Device<'d>,
+    {
+        while self.listeners.len() < ECHO_BACKLOG {
+            let listener = self.listen(stack)?;
+            self.listeners.push(listener);
+        }
+        let mut buffer = [0; 4096];
+        for i in 0..self.listeners.len() {
+            let listener = &mut self.listeners[i];
+            let (state, remote) = tcp_state(stack, listener.key).ok_or("a listening socket is gone")?;
+            if listener.peer.is_none() && state != TcpState::Listen && state != TcpState::Closed {
+                eprintln!("echo: connection from {}", remote);
+                listener.peer = Some(remote);
+            }
+            let smol_socket = stack.get_smol_socket(listener.key).ok_or("a listening socket is gone")?;
+            loop {
+                let mut len = 0;
+                let mut endpoint = None;
+                match smol_socket.receive_into(&mut buffer, &mut len, &mut endpoint) {
+                    SMOL_RESULT_OK => {
+                        if smol_socket.send(Packet::from_vec(buffer[..len].to_vec(), None)) != SMOL_RESULT_OK {
+                            return Err("echo failed, is sockets.to_send too small?".to_string());
+                        }
+                    }
+                    SMOL_RESULT_END_OF_STREAM => {
+                        //Everything was echoed, close our side too
+                        if !listener.finished {
+                            smol_socket.shutdown_write();
+                            listener.finished = true;
+                        }
+                        break;
+                    }
+                    SMOL_RESULT_CLOSED => {
+                        listener.finished = true;
+                        break;
+                    }
+                    _ => break,
+                }
+            }
+            let closed = match state {
+                TcpState::Closed | TcpState::TimeWait => true,
+                _ => false,
+            };
+            if listener.finished && closed {
+                if let Some(ref peer) = listener.peer {
+                    eprintln!("echo: {} closed", peer);
+                }
+                stack.remove_socket(listener.key);
+                self.listeners[i] = self.listen(stack)?;
+            }
+        }
+        Ok(false)
+    }
+}
+
+enum Command {
+    Serve,
+    Ping(Ping),
+    Client(Client),
+    Echo(Echo),
+}
+
+impl Command {
+    fn parse(args: &[String]) -> Result<Command, String> {
+        let client = |address: IpAddress, port: u16, request: Option<Vec<u8>>, stdin_open: bool| Client {
+            address: address,
+            port: port,
+            key: 0,
+            started: Instant::now(),
+            connected: false,
+            request: request,
+            stdin_open: stdin_open,
+        };
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        match args.as_slice() {
+            ["serve"] => Ok(Command::Serve),
+            ["ping", address] | ["ping", address, _] => {
+                let count = match args.get(2) {
+                    Some(count) => match count.parse::<u16>() {
+                        Ok(count) if count > 0 => count,
+                        _ => return Err(format!("\"{}\" is not a count", count)),
+                    },
+                    None => 4,
+                };
+                Ok(Command::Ping(Ping {
+                    address: parse_address(address)?,
+                    count: count,
+                    ident: rand::random::<u16>(),
+                    handle: None,
+                    sent: 0,
+                    received: 0,
+                    send_times: HashMap::new(),
+                    next_send: Instant::now(),
+                }))
+            }
+            ["connect", address, port] => Ok(Command::Client(client(
+                parse_address(address)?,
+                parse_port(port)?,
+                None,
+                true,
+            ))),
+            ["get", url] => {
+                let (address, port, host, path) = parse_url(url)?;
+                let request = format!(
+                    "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: smol_daemon\r\nConnection: close\r\n\r\n",
+                    path, host
+                );
+                Ok(Command::Client(client(address, port, Some(request.into_bytes()), false)))
+            }
+            ["echo", port] => Ok(Command::Echo(Echo {
+                port: parse_port(port)?,
+                listeners: Vec::new(),
+            })),
+            _ => Err(USAGE.to_string()),
+        }
+    }
+
+    //True once the command is done
+    fn step<'a, 'b: 'a, 'c: 'a + 'b, D>(&mut self, stack: &mut SmolStack<'a, 'b, 'c, D>) -> Result<bool, String>
+    where
+        D: for<'d> Device<'d>,
+    {
+        match *self {
+            Command::Serve => Ok(false),
+            Command::Ping(ref mut ping) => ping.step(stack),
+            Command::Client(ref mut client) => client.step(stack),
+            Command::Echo(ref mut echo) => echo.step(stack),
+        }
+    }
+
+    //When the command has something to do even if no packet comes
+    fn deadline(&self) -> Option<Instant> {
+        match *self {
+            Command::Ping(ref ping) => Some(ping.next_send),
+            Command::Client(ref client) if !client.connected => Some(client.started + CONNECT_TIMEOUT),
+            _ => None,
+        }
+    }
+}
+
+fn print_stats<'a, 'b: 'a, 'c: 'a + 'b, D>(stack: &mut SmolStack<'a, 'b, 'c, D>, started: Instant)
+where
+    D: for<'d> Device<'d>,
+{
+    let addresses: Vec<String> = match stack.interface.as_ref() {
+        Some(interface) => interface.ip_addrs().iter().map(|cidr| cidr.to_string()).collect(),
+        None => Vec::new(),
+    };
+    let mut tcp_states = BTreeMap::new();
+    let mut connections = Vec::new();
+    let (mut udp, mut icmp) = (0, 0);
+    for socket in stack.sockets.iter() {
+        match *socket {
+            Socket::Tcp(ref socket) => {
+                *tcp_states.entry(socket.state().to_string()).or_insert(0) += 1;
+                if socket.state() != TcpState::Listen && socket.state() != TcpState::Closed {
+                    connections.push(format!(
+                        "tcp {} -> {} {} send_queue={} recv_queue={}",
+                        socket.local_endpoint(),
+                        socket.remote_endpoint(),
+                        socket.state(),
+                        socket.send_queue(),
+                        socket.recv_queue()
+                    ));
+                }
+            }
+            Socket::Udp(ref socket) => {
+                udp += 1;
+                connections.push(format!("udp {}", socket.endpoint()));
+            }
+            Socket::Icmp(_) => icmp += 1,
+            _ => {}
+        }
+    }
+    let tcp_states: Vec<String> = tcp_states
+        .iter()
+        .map(|(state, count)| format!("{} {}", count, state))
+        .collect();
+    eprintln!(
+        "uptime {}s, addresses [{}], tcp [{}], udp {}, icmp {}",
+        started.elapsed().as_secs(),
+        addresses.join(", "),
+        tcp_states.join(", "),
+        udp,
+        icmp
+    );
+    for connection in connections {
+        eprintln!("    {}", connection);
+    }
+}
+
+/*
+    Polls until the command is done or a signal stops us, waiting on the
+    device's fd (and stdin, when the command reads it) in between
+*/
+fn run<'a, 'b: 'a, 'c: 'a + 'b, D>(
+    stack: &mut SmolStack<'a, 'b, 'c, D>,
+    command: &mut Command,
+    stats_every: Option<Duration>,
+) -> Result<(), String>
+where
+    D: for<'d> Device<'d>,
+{
+    let started = Instant::now();
+    let mut next_stats = stats_every.map(|every| started + every);
+    let fd = match stack.fd {
+        Some(fd) => fd,
+        None => return Err("the device has no fd to wait on".to_string()),
+    };
+    loop {
+        stack.poll();
+        stack.spin_all();
+        let done = command.step(stack)?;
+        //Sends what the command queued right away
+        stack.spin_all();
+        stack.poll();
+        let now = Instant::now();
+        let stats_due = next_stats.map_or(false, |next_stats| now >= next_stats);
+        if DUMP.swap(false, Ordering::SeqCst) || stats_due || (done && stats_every.is_some()) {
+            print_stats(stack, started);
+            next_stats = stats_every.map(|every| now + every);
+        }
+        if done || STOP.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mut timeout = MAX_WAIT;
+        if let Some(delay) = stack.poll_delay() {
+            timeout = timeout.min(Duration::from_millis(delay.total_millis()));
+        }
+        for deadline in [command.deadline(), next_stats].iter().filter_map(|deadline| *deadline) {
+            timeout = timeout.min(deadline.saturating_duration_since(now));
+        }
+        let wants_stdin = match *command {
+            Command::Client(ref client) => client.wants_stdin(),
+            _ => false,
+        };
+        let mut pollfds = [
+            libc::pollfd {
+                fd: fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: 0,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        let count = if wants_stdin { 2 } else { 1 };
+        //Interrupted by a signal is fine, the loop checks the flags
+        unsafe { libc::poll(pollfds.as_mut_ptr(), count, timeout.as_millis() as libc::c_int) };
+        if wants_stdin && pollfds[1].revents != 0 {
+            if let Command::Client(ref mut client) = *command {
+                client.stdin_readable(stack)?;
+            }
+        }
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut stats_every = None;
+    if args.first().map(|arg| arg.as_str()) == Some("--stats") {
+        let seconds = match args.get(1).and_then(|seconds| seconds.parse::<u64>().ok()) {
+            Some(seconds) if seconds > 0 => seconds,
+            _ => fail(USAGE),
+        };
+        stats_every = Some(Duration::from_secs(seconds));
+        args.drain(..2);
+    }
+    if args.len() < 2 {
+        fail(USAGE);
+    }
+    let mut command = Command::parse(&args[1..]).unwrap_or_else(|err| fail(&err));
+    //Warnings and errors by default, the configuration's logging.level overrides it
+    set_log_function(Some(log_to_stderr), 2);
+    let config = StackConfig::from_file(Path::new(&args[0])).unwrap_or_else(|err| fail(&err.message));
+    let mut smol_stack: Box<SmolStackType> = config.build().unwrap_or_else(|err| fail(&err.message));
+    unsafe {
+        libc::signal(libc::SIGINT, on_signal as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, on_signal as libc::sighandler_t);
+        libc::signal(libc::SIGUSR1, on_signal as libc::sighandler_t);
+    }
+    let result = match *smol_stack {
+        SmolStackType::Tun(ref mut smol_stack) => run(smol_stack, &mut command, stats_every),
+        SmolStackType::Tap(ref mut smol_stack) => run(smol_stack, &mut command, stats_every),
+        SmolStackType::Fd(ref mut smol_stack) => run(smol_stack, &mut command, stats_every),
+        SmolStackType::UnixSocket(ref mut smol_stack) => run(smol_stack, &mut command, stats_every),
+        SmolStackType::UdpTunnel(ref mut smol_stack) => run(smol_stack, &mut command, stats_every),
+        _ => Err("virtual_tun stacks need an application moving their packets, use another device".to_string()),
+    };
+    smol_stack.shutdown(Some(CLOSE_TIMEOUT));
+    if let Err(err) = result {
+        eprintln!("smol_daemon: {}", err);
+        process::exit(1);
+    }
+}
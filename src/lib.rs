@@ -1,3 +1,5 @@
+#[macro_use]
+extern crate log;
 
 pub mod virtual_tun;
 pub use virtual_tun::VirtualTunInterface;
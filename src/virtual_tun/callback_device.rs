@@ -0,0 +1,214 @@
+use super::smol_stack::{SMOL_RESULT_NOT_AVAILABLE, SMOL_RESULT_OK, SMOL_RESULT_WOULD_BLOCK};
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use smoltcp::{Error, Result};
+use std::ffi::c_void;
+
+//Warning: keep these synced with the MEDIUM_* constants on interface.h
+pub const MEDIUM_IP: u8 = 0;
+pub const MEDIUM_ETHERNET: u8 = 1;
+
+pub fn medium_from_u8(medium: u8) -> Option<Medium> {
+    match medium {
+        MEDIUM_IP => Some(Medium::Ip),
+        MEDIUM_ETHERNET => Some(Medium::Ethernet),
+        _ => None,
+    }
+}
+
+/*
+    Called by poll to get the next packet. Copies it to `buffer` (which
+    holds `capacity` bytes, the MTU), writes its size on `len` and returns
+    SMOL_RESULT_OK, or returns SMOL_RESULT_NOT_AVAILABLE if there's none
+*/
+pub type CReceiveFunction =
+    extern "C" fn(context: *const c_void, buffer: *mut u8, capacity: usize, len: *mut usize) -> u8;
+/*
+    Called by poll with a packet to send. `data` is only valid during the
+    call. Returns SMOL_RESULT_OK, or SMOL_RESULT_WOULD_BLOCK if the packet
+    can't be taken now (TCP retransmits it later)
+*/
+pub type CTransmitFunction = extern "C" fn(context: *const c_void, data: *const u8, len: usize) -> u8;
+
+/*
+    Device whose packets come from and go to C functions, for platforms
+    that have their own packet I/O. There are no queues in between: the
+    callbacks run on the thread calling poll, with the context given
+    at creation
+*/
+#[derive(Clone, Copy)]
+pub struct CallbackDevice {
+    medium: Medium,
+    mtu: usize,
+    receive_function: CReceiveFunction,
+    transmit_function: CTransmitFunction,
+    context: *const c_void,
+}
+
+impl CallbackDevice {
+    pub fn new(
+        medium: Medium,
+        mtu: usize,
+        receive_function: CReceiveFunction,
+        transmit_function: CTransmitFunction,
+        context: *const c_void,
+    ) -> CallbackDevice {
+        CallbackDevice {
+            medium: medium,
+            mtu: mtu,
+            receive_function: receive_function,
+            transmit_function: transmit_function,
+            context: context,
+        }
+    }
+}
+
+impl<'d> Device<'d> for CallbackDevice {
+    type RxToken = RxToken;
+    type TxToken = TxToken;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut d = DeviceCapabilities::default();
+        d.max_transmission_unit = self.mtu;
+        d
+    }
+
+    fn receive(&'d mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let mut buffer = vec![0; self.mtu];
+        let mut len = 0;
+        match (self.receive_function)(self.context, buffer.as_mut_ptr(), buffer.len(), &mut len) {
+            SMOL_RESULT_OK if len <= buffer.len() => {
+                buffer.truncate(len);
+                Some((RxToken { buffer: buffer }, TxToken { device: *self }))
+            }
+            SMOL_RESULT_OK => {
+                error!("receive callback wrote {} bytes on a {} bytes buffer", len, buffer.len());
+                None
+            }
+            SMOL_RESULT_NOT_AVAILABLE => None,
+            r => {
+                debug!("receive callback returned {}", r);
+                None
+            }
+        }
+    }
+
+    fn transmit(&'d mut self) -> Option<Self::TxToken> {
+        Some(TxToken { device: *self })
+    }
+
+    fn medium(&self) -> Medium {
+        self.medium
+    }
+}
+
+#[doc(hidden)]
+pub struct RxToken {
+    buffer: Vec<u8>,
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        f(&mut self.buffer[..])
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken {
+    device: CallbackDevice,
+}
+
+impl phy::TxToken for TxToken {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let mut buffer = vec![0; len];
+        let result = f(&mut buffer)?;
+        match (self.device.transmit_function)(self.device.context, buffer.as_ptr(), buffer.len()) {
+            SMOL_RESULT_OK => Ok(result),
+            SMOL_RESULT_WOULD_BLOCK => Err(Error::Exhausted),
+            r => {
+                debug!("transmit callback returned {}", r);
+                Err(Error::Exhausted)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::interface::{CIpv4Address, CIpv4Cidr, SmolStackType};
+    use super::super::smol_stack::{Packet, SocketType};
+    use super::*;
+    use smoltcp::wire::{IpAddress, IpEndpoint};
+    use std::sync::Mutex;
+
+    extern "C" fn receive_nothing(
+        _context: *const c_void,
+        _buffer: *mut u8,
+        _capacity: usize,
+        _len: *mut usize,
+    ) -> u8 {
+        SMOL_RESULT_NOT_AVAILABLE
+    }
+
+    extern "C" fn transmit(context: *const c_void, data: *const u8, len: usize) -> u8 {
+        let transmitted = unsafe { &*(context as *const Mutex<Vec<Vec<u8>>>) };
+        let packet = unsafe { std::slice::from_raw_parts(data, len) }.to_vec();
+        transmitted.lock().unwrap().push(packet);
+        SMOL_RESULT_OK
+    }
+
+    #[test]
+    fn packets_go_through_the_transmit_callback() {
+        let transmitted: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+        let mut smol_stack = SmolStackType::new_callback(
+            Medium::Ip,
+            1500,
+            receive_nothing,
+            transmit,
+            &transmitted as *const Mutex<Vec<Vec<u8>>> as *const c_void,
+        );
+        let cidr = CIpv4Cidr {
+            address: CIpv4Address {
+                address: [192, 168, 69, 1],
+            },
+            prefix: 24,
+        };
+        assert_eq!(smol_stack.add_ipv4_address(cidr), SMOL_RESULT_OK);
+        assert_eq!(smol_stack.finalize(), SMOL_RESULT_OK);
+        let mut socket_handle_key = 0;
+        assert_eq!(
+            smol_stack.add_socket(SocketType::UDP, &mut socket_handle_key),
+            SMOL_RESULT_OK
+        );
+        assert_eq!(smol_stack.udp_bind(socket_handle_key, 5000), SMOL_RESULT_OK);
+        let endpoint = IpEndpoint::new(IpAddress::v4(192, 168, 69, 2), 6000);
+        let packet = Packet::from_vec(b"datagram".to_vec(), Some(endpoint));
+        let smol_socket = smol_stack.get_smol_socket(socket_handle_key).unwrap();
+        assert_eq!(smol_socket.send(packet), SMOL_RESULT_OK);
+        smol_stack.spin_all();
+        smol_stack.poll();
+        let transmitted = transmitted.lock().unwrap();
+        assert_eq!(transmitted.len(), 1);
+        //IPv4 and UDP headers, then the payload
+        assert_eq!(transmitted[0].len(), 20 + 8 + 8);
+        assert!(transmitted[0].ends_with(b"datagram"));
+    }
+
+    #[test]
+    fn ethernet_stacks_need_an_ethernet_address() {
+        let mut smol_stack = SmolStackType::new_callback(
+            Medium::Ethernet,
+            1514,
+            receive_nothing,
+            transmit,
+            std::ptr::null(),
+        );
+        assert_ne!(smol_stack.finalize(), SMOL_RESULT_OK);
+    }
+}
@@ -0,0 +1,40 @@
+use smoltcp::time::{Duration, Instant};
+
+/*
+    Where SmolStack gets the time from when polling. System is the wall
+    clock. Manual only moves when advance() is called, so tests (Rust or
+    C++) can run TCP retransmissions, delayed ACKs and timeouts in
+    milliseconds of real time and get the same result every run
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clock {
+    System,
+    Manual(Instant),
+}
+
+impl Clock {
+    pub fn now(&self) -> Instant {
+        match self {
+            Clock::System => Instant::now(),
+            Clock::Manual(instant) => *instant,
+        }
+    }
+
+    pub fn is_manual(&self) -> bool {
+        match self {
+            Clock::System => false,
+            Clock::Manual(_) => true,
+        }
+    }
+
+    //Returns false (and does nothing) for the system clock
+    pub fn advance(&mut self, duration: Duration) -> bool {
+        match self {
+            Clock::System => false,
+            Clock::Manual(instant) => {
+                *instant = *instant + duration;
+                true
+            }
+        }
+    }
+}
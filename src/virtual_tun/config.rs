@@ -0,0 +1,603 @@
+use super::interface::{CEthernetAddress, CIpv4Cidr, CIpv6Cidr, SmolStackType};
+use super::pcap::PacketCapture;
+use super::queue::{QueueLimits, QUEUE_SOCKET_RECEIVED, QUEUE_SOCKET_TO_SEND};
+use super::smol_stack::{SocketBuffers, SMOL_RESULT_OK};
+use super::virtual_tun::VIRTUAL_TUN_MTU;
+use log::LevelFilter;
+use serde::Deserialize;
+use smoltcp::phy::Medium;
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr};
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/*
+    Declarative description of a stack, so deployments ship a file
+    instead of a series of FFI calls. TOML example (top level keys go
+    before the first table):
+
+        addresses = ["192.168.69.1/24", "fdaa::1/64"]
+        dns = ["192.168.69.100"]
+
+        [device]
+        type = "udp_tunnel"      #virtual_tun, tun, tap, fd, unix_socket or udp_tunnel
+        local = "127.0.0.1:5000"
+        peer = "127.0.0.1:5001"
+        medium = "ip"            #ip (default) or ethernet
+        mtu = 1500
+
+        [[routes]]
+        cidr = "0.0.0.0/0"
+        via = "192.168.69.100"
+
+        [sockets]
+        tcp_buffer_bytes = 65000
+        to_send = { max_packets = 64, max_bytes = 262144 }
+
+        [pcap]
+        path = "/tmp/stack.pcap"
+
+        [logging]
+        level = "debug"
+
+    The same document works as JSON. Unknown keys are errors, so a typo
+    doesn't silently fall back to a default
+*/
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StackConfig {
+    pub device: DeviceConfig,
+    //Required by tap devices and devices with medium "ethernet", like "02:00:00:00:00:01"
+    pub ethernet_address: Option<String>,
+    //CIDRs, IPv4 or IPv6
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    //Not used by the stack itself, see smol_stack_get_dns_servers
+    #[serde(default)]
+    pub dns: Vec<String>,
+    #[serde(default)]
+    pub sockets: SocketConfig,
+    pub pcap: Option<PcapConfig>,
+    pub logging: Option<LoggingConfig>,
+}
+
+//Each device type only accepts the keys its constructor takes
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum DeviceConfig {
+    VirtualTun {
+        #[serde(default)]
+        name: String,
+        mtu: Option<usize>,
+    },
+    Tun {
+        name: String,
+    },
+    Tap {
+        name: String,
+    },
+    //Either `fd` or both `read_fd` and `write_fd`
+    Fd {
+        fd: Option<i32>,
+        read_fd: Option<i32>,
+        write_fd: Option<i32>,
+        medium: Option<MediumConfig>,
+        mtu: Option<usize>,
+    },
+    UnixSocket {
+        path: PathBuf,
+        peer_path: PathBuf,
+        medium: Option<MediumConfig>,
+        mtu: Option<usize>,
+    },
+    UdpTunnel {
+        local: String,
+        peer: String,
+        medium: Option<MediumConfig>,
+        mtu: Option<usize>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MediumConfig {
+    Ip,
+    Ethernet,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub cidr: String,
+    pub via: String,
+}
+
+//Defaults for the sockets added later, anything left out keeps the built in default
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SocketConfig {
+    pub tcp_buffer_bytes: Option<usize>,
+    pub udp_buffer_packets: Option<usize>,
+    pub udp_buffer_bytes: Option<usize>,
+    pub to_send: Option<LimitsConfig>,
+    pub received: Option<LimitsConfig>,
+}
+
+//Same as QueueLimits, 0 (or leaving it out) means no limit
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    #[serde(default)]
+    pub max_packets: usize,
+    #[serde(default)]
+    pub max_bytes: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PcapConfig {
+    pub path: PathBuf,
+}
+
+/*
+    Only the level: messages go to the callback given to
+    smol_stack_set_log_callback, or to the Rust application's logger
+*/
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: LevelConfig,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LevelConfig {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Into<LevelFilter> for LevelConfig {
+    fn into(self) -> LevelFilter {
+        match self {
+            LevelConfig::Off => LevelFilter::Off,
+            LevelConfig::Error => LevelFilter::Error,
+            LevelConfig::Warn => LevelFilter::Warn,
+            LevelConfig::Info => LevelFilter::Info,
+            LevelConfig::Debug => LevelFilter::Debug,
+            LevelConfig::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+/*
+    Why a document was rejected or its stack couldn't be built. Syntax
+    errors carry the line and column given by the parser, the others
+    name the offending key, like "routes[1].via"
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigError {
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn invalid<T>(message: String) -> Result<T, ConfigError> {
+    Err(ConfigError { message: message })
+}
+
+//Used when the device takes an MTU and the document doesn't give one
+const DEFAULT_MTU: usize = 1500;
+
+//Everything in the document, checked and parsed, before any device is opened
+struct Plan {
+    ethernet_address: Option<EthernetAddress>,
+    addresses: Vec<IpCidr>,
+    routes: Vec<(IpCidr, IpAddress)>,
+    dns_servers: Vec<IpAddress>,
+    socket_buffers: SocketBuffers,
+}
+
+impl StackConfig {
+    //TOML, unless the document starts with '{', then JSON
+    pub fn parse(document: &str) -> Result<StackConfig, ConfigError> {
+        if document.trim_start().starts_with('{') {
+            serde_json::from_str(document).or_else(|err| invalid(format!("invalid JSON: {}", err)))
+        } else {
+            toml::from_str(document).or_else(|err| invalid(format!("invalid TOML: {}", err)))
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<StackConfig, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(document) => StackConfig::parse(&document).or_else(|err| {
+                invalid(format!("{}: {}", path.display(), err.message))
+            }),
+            Err(err) => invalid(format!("can't read {}: {}", path.display(), err)),
+        }
+    }
+
+    fn medium(&self) -> Medium {
+        let medium = match self.device {
+            DeviceConfig::VirtualTun { .. } | DeviceConfig::Tun { .. } => None,
+            DeviceConfig::Tap { .. } => Some(MediumConfig::Ethernet),
+            DeviceConfig::Fd { medium, .. } => medium,
+            DeviceConfig::UnixSocket { medium, .. } => medium,
+            DeviceConfig::UdpTunnel { medium, .. } => medium,
+        };
+        match medium {
+            Some(MediumConfig::Ethernet) => Medium::Ethernet,
+            _ => Medium::Ip,
+        }
+    }
+
+    fn plan(&self) -> Result<Plan, ConfigError> {
+        let mtu = match self.device {
+            DeviceConfig::VirtualTun { mtu, .. } => mtu,
+            DeviceConfig::Fd { mtu, .. } => mtu,
+            DeviceConfig::UnixSocket { mtu, .. } => mtu,
+            DeviceConfig::UdpTunnel { mtu, .. } => mtu,
+            _ => None,
+        };
+        if mtu == Some(0) {
+            return invalid("device.mtu: must be greater than 0".to_string());
+        }
+        if let DeviceConfig::Fd {
+            fd,
+            read_fd,
+            write_fd,
+            ..
+        } = self.device
+        {
+            match (fd, read_fd, write_fd) {
+                (Some(fd), None, None) if fd >= 0 => {}
+                (None, Some(read_fd), Some(write_fd)) if read_fd >= 0 && write_fd >= 0 => {}
+                (Some(_), None, None) | (None, Some(_), Some(_)) => {
+                    return invalid("device: fds can't be negative".to_string())
+                }
+                _ => return invalid("device: give either fd or both read_fd and write_fd".to_string()),
+            }
+        }
+        if let DeviceConfig::UdpTunnel { ref local, ref peer, .. } = self.device {
+            parse_socket_address("device.local", local)?;
+            parse_socket_address("device.peer", peer)?;
+        }
+        let ethernet_address = match (self.medium(), self.ethernet_address.as_ref()) {
+            (Medium::Ethernet, Some(address)) => match address.parse::<EthernetAddress>() {
+                Ok(ethernet_address) if ethernet_address.is_unicast() => Some(ethernet_address),
+                _ => {
+                    return invalid(format!(
+                        "ethernet_address: \"{}\" is not a unicast ethernet address",
+                        address
+                    ))
+                }
+            },
+            (Medium::Ethernet, None) => {
+                return invalid("ethernet_address: required by ethernet devices".to_string())
+            }
+            (_, Some(_)) => return invalid("ethernet_address: only ethernet devices take one".to_string()),
+            (_, None) => None,
+        };
+        let mut addresses = Vec::new();
+        for (i, address) in self.addresses.iter().enumerate() {
+            match address.parse::<IpCidr>() {
+                Ok(cidr) => addresses.push(cidr),
+                Err(_) => {
+                    return invalid(format!(
+                        "addresses[{}]: \"{}\" is not a CIDR like 192.168.69.1/24 or fdaa::1/64",
+                        i, address
+                    ))
+                }
+            }
+        }
+        let mut routes = Vec::new();
+        for (i, route) in self.routes.iter().enumerate() {
+            let cidr = match route.cidr.parse::<IpCidr>() {
+                Ok(cidr) => cidr,
+                Err(_) => return invalid(format!("routes[{}].cidr: \"{}\" is not a CIDR", i, route.cidr)),
+            };
+            let via = match route.via.parse::<IpAddress>() {
+                Ok(via) => via,
+                Err(_) => return invalid(format!("routes[{}].via: \"{}\" is not an IP address", i, route.via)),
+            };
+            match (cidr, via) {
+                (IpCidr::Ipv4(_), IpAddress::Ipv4(_)) | (IpCidr::Ipv6(_), IpAddress::Ipv6(_)) => {}
+                _ => {
+                    return invalid(format!(
+                        "routes[{}]: {} and its gateway {} are not of the same IP version",
+                        i, route.cidr, route.via
+                    ))
+                }
+            }
+            routes.push((cidr, via));
+        }
+        let mut dns_servers = Vec::new();
+        for (i, dns_server) in self.dns.iter().enumerate() {
+            match dns_server.parse::<IpAddress>() {
+                Ok(dns_server) => dns_servers.push(dns_server),
+                Err(_) => return invalid(format!("dns[{}]: \"{}\" is not an IP address", i, dns_server)),
+            }
+        }
+        let defaults = SocketBuffers::default();
+        let socket_buffers = SocketBuffers {
+            tcp_bytes: self.sockets.tcp_buffer_bytes.unwrap_or(defaults.tcp_bytes),
+            udp_packets: self.sockets.udp_buffer_packets.unwrap_or(defaults.udp_packets),
+            udp_bytes: self.sockets.udp_buffer_bytes.unwrap_or(defaults.udp_bytes),
+        };
+        for &(key, value) in [
+            ("tcp_buffer_bytes", socket_buffers.tcp_bytes),
+            ("udp_buffer_packets", socket_buffers.udp_packets),
+            ("udp_buffer_bytes", socket_buffers.udp_bytes),
+        ]
+        .iter()
+        {
+            if value == 0 {
+                return invalid(format!("sockets.{}: must be greater than 0", key));
+            }
+        }
+        match self.device {
+            DeviceConfig::VirtualTun { .. } => {}
+            _ if self.pcap.is_some() => {
+                return invalid("pcap: only virtual_tun devices can be captured".to_string())
+            }
+            _ => {}
+        }
+        Ok(Plan {
+            ethernet_address: ethernet_address,
+            addresses: addresses,
+            routes: routes,
+            dns_servers: dns_servers,
+            socket_buffers: socket_buffers,
+        })
+    }
+
+    fn open<'a, 'b: 'a, 'c: 'a + 'b>(&self) -> std::io::Result<Box<SmolStackType<'a, 'b, 'c>>> {
+        let medium = self.medium();
+        match self.device {
+            DeviceConfig::VirtualTun { ref name, mtu } => Ok(SmolStackType::new_virtual_tun_with_mtu(
+                name.clone(),
+                mtu.unwrap_or(VIRTUAL_TUN_MTU),
+            )),
+            DeviceConfig::Tun { ref name } => SmolStackType::open_tun(name),
+            DeviceConfig::Tap { ref name } => SmolStackType::open_tap(name),
+            DeviceConfig::Fd {
+                fd,
+                read_fd,
+                write_fd,
+                mtu,
+                ..
+            } => {
+                //plan() checked that one of the two forms is there
+                let read_fd = fd.or(read_fd).unwrap();
+                let write_fd = fd.or(write_fd).unwrap();
+                SmolStackType::new_from_fd(read_fd, write_fd, medium, mtu.unwrap_or(DEFAULT_MTU))
+            }
+            DeviceConfig::UnixSocket {
+                ref path,
+                ref peer_path,
+                mtu,
+                ..
+            } => SmolStackType::new_unix_socket(path, peer_path, medium, mtu.unwrap_or(DEFAULT_MTU)),
+            DeviceConfig::UdpTunnel {
+                ref local,
+                ref peer,
+                mtu,
+                ..
+            } => {
+                //Both were parsed by plan() already
+                let local = local.parse::<SocketAddr>().unwrap();
+                let peer = peer.parse::<SocketAddr>().unwrap();
+                SmolStackType::new_udp_tunnel(local, peer, medium, mtu.unwrap_or(DEFAULT_MTU))
+            }
+        }
+    }
+
+    /*
+        Opens the device and builds the finalized stack. The whole document
+        is checked before the device is opened, so an invalid one never
+        leaves a tun interface or a bound socket behind
+    */
+    pub fn build<'a, 'b: 'a, 'c: 'a + 'b>(&self) -> Result<Box<SmolStackType<'a, 'b, 'c>>, ConfigError> {
+        let plan = self.plan()?;
+        let mut smol_stack = match self.open() {
+            Ok(smol_stack) => smol_stack,
+            Err(err) => return invalid(format!("device: can't open it: {}", err)),
+        };
+        if let Some(ethernet_address) = plan.ethernet_address {
+            smol_stack.set_ethernet_address(CEthernetAddress {
+                address: ethernet_address.0,
+            });
+        }
+        for (i, cidr) in plan.addresses.into_iter().enumerate() {
+            let result = match cidr {
+                IpCidr::Ipv4(cidr) => smol_stack.add_ipv4_address(CIpv4Cidr {
+                    address: cidr.address().into(),
+                    prefix: cidr.prefix_len(),
+                }),
+                IpCidr::Ipv6(cidr) => smol_stack.add_ipv6_address(CIpv6Cidr {
+                    address: cidr.address().into(),
+                    prefix: cidr.prefix_len(),
+                }),
+                _ => return invalid(format!("addresses[{}]: unsupported address family", i)),
+            };
+            if result != SMOL_RESULT_OK {
+                return invalid(format!("addresses[{}]: rejected by the stack ({})", i, result));
+            }
+        }
+        for (i, (cidr, via)) in plan.routes.into_iter().enumerate() {
+            let result = smol_stack.add_route(cidr, via);
+            if result != SMOL_RESULT_OK {
+                return invalid(format!("routes[{}]: rejected by the stack ({})", i, result));
+            }
+        }
+        smol_stack.set_dns_servers(plan.dns_servers);
+        smol_stack.set_socket_buffers(plan.socket_buffers);
+        if let Some(ref limits) = self.sockets.to_send {
+            smol_stack.set_queue_limits(QUEUE_SOCKET_TO_SEND, limits.into());
+        }
+        if let Some(ref limits) = self.sockets.received {
+            smol_stack.set_queue_limits(QUEUE_SOCKET_RECEIVED, limits.into());
+        }
+        if let Some(ref pcap) = self.pcap {
+            let capture = match PacketCapture::create(&pcap.path, self.medium()) {
+                Ok(capture) => capture,
+                Err(err) => return invalid(format!("pcap.path: can't create {}: {}", pcap.path.display(), err)),
+            };
+            smol_stack.set_capture(Some(Arc::new(capture)));
+        }
+        if let Some(ref logging) = self.logging {
+            log::set_max_level(logging.level.into());
+        }
+        let result = smol_stack.finalize();
+        if result != SMOL_RESULT_OK {
+            return invalid(format!("the stack could not be finalized ({})", result));
+        }
+        Ok(smol_stack)
+    }
+}
+
+impl<'l> Into<QueueLimits> for &'l LimitsConfig {
+    fn into(self) -> QueueLimits {
+        QueueLimits {
+            max_packets: self.max_packets,
+            max_bytes: self.max_bytes,
+        }
+    }
+}
+
+fn parse_socket_address(key: &str, address: &str) -> Result<SocketAddr, ConfigError> {
+    address.parse::<SocketAddr>().or_else(|_| {
+        invalid(format!(
+            "{}: \"{}\" is not an address with a port, like 127.0.0.1:5000 or [::1]:5000",
+            key, address
+        ))
+    })
+}
+
+impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
+    //Parses `document` (see StackConfig) and builds the finalized stack it describes
+    pub fn from_config(document: &str) -> Result<Box<SmolStackType<'a, 'b, 'c>>, ConfigError> {
+        StackConfig::parse(document)?.build()
+    }
+
+    pub fn from_config_file(path: &Path) -> Result<Box<SmolStackType<'a, 'b, 'c>>, ConfigError> {
+        StackConfig::from_file(path)?.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    fn error_of(document: &str) -> String {
+        match SmolStackType::from_config(document) {
+            Ok(_) => panic!("the document was accepted"),
+            Err(err) => err.message,
+        }
+    }
+
+    #[test]
+    fn toml_and_json_build_the_same_stack() {
+        let toml = r#"
+            addresses = ["192.168.69.1/24", "fdaa::1/64"]
+            dns = ["192.168.69.100", "fdaa::100"]
+
+            [device]
+            type = "virtual_tun"
+            mtu = 1400
+
+            [[routes]]
+            cidr = "10.0.0.0/8"
+            via = "192.168.69.100"
+
+            [sockets]
+            tcp_buffer_bytes = 4096
+            to_send = { max_packets = 8 }
+        "#;
+        let json = r#"{
+            "device": {"type": "virtual_tun", "mtu": 1400},
+            "addresses": ["192.168.69.1/24", "fdaa::1/64"],
+            "dns": ["192.168.69.100", "fdaa::100"],
+            "routes": [{"cidr": "10.0.0.0/8", "via": "192.168.69.100"}],
+            "sockets": {"tcp_buffer_bytes": 4096, "to_send": {"max_packets": 8}}
+        }"#;
+        for document in [toml, json].iter() {
+            let mut smol_stack = SmolStackType::from_config(document).unwrap();
+            assert_eq!(
+                smol_stack.dns_servers(),
+                vec![
+                    "192.168.69.100".parse::<IpAddress>().unwrap(),
+                    "fdaa::100".parse::<IpAddress>().unwrap()
+                ]
+            );
+            //Already finalized
+            assert_ne!(smol_stack.finalize(), SMOL_RESULT_OK);
+        }
+    }
+
+    #[test]
+    fn errors_name_the_offending_key() {
+        let device = "[device]\ntype = \"virtual_tun\"\n";
+        assert!(error_of("[device]\ntype = \"virtual_tun\"\nmtuu = 1400\n").contains("unknown field `mtuu`"));
+        assert!(error_of("[device]\ntype = \"tun\"\nname = \"tun0\"\nmtu = 1400\n").contains("unknown field `mtu`"));
+        assert!(error_of("[device]\ntype = \"serial\"\n").contains("unknown variant `serial`"));
+        assert!(error_of("addresses = [\"10.0.0.1\"]\n").contains("missing field `device`"));
+        assert_eq!(
+            error_of(&format!("addresses = [\"10.0.0.1/24\", \"10.0.0.300/24\"]\n{}", device)),
+            "addresses[1]: \"10.0.0.300/24\" is not a CIDR like 192.168.69.1/24 or fdaa::1/64"
+        );
+        assert_eq!(
+            error_of(&format!("{}[[routes]]\ncidr = \"::/0\"\nvia = \"10.0.0.1\"\n", device)),
+            "routes[0]: ::/0 and its gateway 10.0.0.1 are not of the same IP version"
+        );
+        assert_eq!(
+            error_of(&format!("{}[sockets]\nudp_buffer_packets = 0\n", device)),
+            "sockets.udp_buffer_packets: must be greater than 0"
+        );
+        assert_eq!(
+            error_of("[device]\ntype = \"fd\"\nread_fd = 3\n"),
+            "device: give either fd or both read_fd and write_fd"
+        );
+        assert_eq!(
+            error_of("[device]\ntype = \"fd\"\nfd = 3\nmedium = \"ethernet\"\n"),
+            "ethernet_address: required by ethernet devices"
+        );
+        assert!(error_of("{\"device\": {\"type\": \"virtual_tun\"},}").starts_with("invalid JSON: "));
+    }
+
+    #[test]
+    fn udp_tunnel_device_from_config() {
+        //Two free ports, released right before the stack binds the first one
+        let local = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let document = format!(
+            r#"{{"device": {{"type": "udp_tunnel", "local": "{}", "peer": "{}", "medium": "ethernet"}},
+                "ethernet_address": "02:00:00:00:00:01",
+                "addresses": ["10.0.0.1/24"],
+                "logging": {{"level": "warn"}}}}"#,
+            local.local_addr().unwrap(),
+            peer.local_addr().unwrap()
+        );
+        drop(local);
+        let mut smol_stack = SmolStackType::from_config(&document).unwrap();
+        assert_eq!(smol_stack.poll(), SMOL_RESULT_OK);
+        assert_eq!(
+            error_of(&document.replace("02:00:00:00:00:01", "01:00:00:00:00:01")),
+            "ethernet_address: \"01:00:00:00:00:01\" is not a unicast ethernet address"
+        );
+    }
+}
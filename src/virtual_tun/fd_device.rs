@@ -0,0 +1,260 @@
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use smoltcp::{Error, Result};
+use std::fmt;
+use std::io;
+use std::os::unix::io::RawFd;
+
+/*
+    Device that reads and writes whole packets on file descriptors that
+    were opened by someone else: a TUN/TAP fd given by the platform
+    (Android's VpnService, iOS' packet flow fd...), one end of a
+    datagram socketpair or a pair of pipes. Each read or write must
+    carry exactly one packet, so pipes must be in packet mode (opened
+    with O_DIRECT) with an MTU of at most PIPE_BUF, and byte stream
+    pipes and stream sockets are refused on creation.
+    The fds are put in non blocking mode and are NOT closed on drop,
+    they belong to whoever gave them to us
+*/
+#[derive(Clone, Copy)]
+pub struct FdDevice {
+    read_fd: RawFd,
+    write_fd: RawFd,
+    medium: Medium,
+    mtu: usize,
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/*
+    Refuses fds that don't keep packet boundaries: stream sockets, and
+    pipes and FIFOs not in packet mode or whose packets can't hold `mtu`
+    bytes (bigger writes are split)
+*/
+fn check_keeps_packets(fd: RawFd, mtu: usize) -> io::Result<()> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let file_type = stat.st_mode & libc::S_IFMT;
+    if file_type == libc::S_IFIFO {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if flags & libc::O_DIRECT == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pipes are byte streams unless opened with O_DIRECT, they can't carry packets",
+            ));
+        }
+        if mtu > libc::PIPE_BUF {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "packets on pipes can't be bigger than PIPE_BUF",
+            ));
+        }
+    }
+    if file_type == libc::S_IFSOCK {
+        let mut socket_type: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let r = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_TYPE,
+                &mut socket_type as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if socket_type == libc::SOCK_STREAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "stream sockets are byte streams, they can't carry packets",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/*
+    The packet read by a non blocking device on `buffer`, given what the
+    read returned, or None if there was nothing to read. Shared by the
+    devices reading from fds: errors are only logged, since phy_wait
+    wakes up again while the fd stays readable
+*/
+pub fn received_packet(mut buffer: Vec<u8>, result: io::Result<usize>, source: &dyn fmt::Display) -> Option<Vec<u8>> {
+    match result {
+        //0 is an empty datagram or end of file, nothing to read
+        Ok(0) => None,
+        Ok(size) => {
+            buffer.truncate(size);
+            Some(buffer)
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => None,
+        Err(err) => {
+            debug!("receive error on {}: {}", source, err);
+            None
+        }
+    }
+}
+
+impl FdDevice {
+    pub fn new(read_fd: RawFd, write_fd: RawFd, medium: Medium, mtu: usize) -> io::Result<FdDevice> {
+        check_keeps_packets(read_fd, mtu)?;
+        if write_fd != read_fd {
+            check_keeps_packets(write_fd, mtu)?;
+        }
+        set_nonblocking(read_fd)?;
+        if write_fd != read_fd {
+            set_nonblocking(write_fd)?;
+        }
+        Ok(FdDevice {
+            read_fd: read_fd,
+            write_fd: write_fd,
+            medium: medium,
+            mtu: mtu,
+        })
+    }
+
+    //The fd phy_wait waits on
+    pub fn read_fd(&self) -> RawFd {
+        self.read_fd
+    }
+
+    fn recv(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let len = unsafe {
+            libc::read(
+                self.read_fd,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+
+    fn send(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let len = unsafe {
+            libc::write(
+                self.write_fd,
+                buffer.as_ptr() as *const libc::c_void,
+                buffer.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+}
+
+impl<'d> Device<'d> for FdDevice {
+    type RxToken = RxToken;
+    type TxToken = TxToken;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut d = DeviceCapabilities::default();
+        d.max_transmission_unit = self.mtu;
+        d
+    }
+
+    fn receive(&'d mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let mut buffer = vec![0; self.mtu];
+        let result = self.recv(&mut buffer[..]);
+        let buffer = received_packet(buffer, result, &format_args!("fd {}", self.read_fd))?;
+        Some((RxToken { buffer: buffer }, TxToken { device: *self }))
+    }
+
+    fn transmit(&'d mut self) -> Option<Self::TxToken> {
+        Some(TxToken { device: *self })
+    }
+
+    fn medium(&self) -> Medium {
+        self.medium
+    }
+}
+
+#[doc(hidden)]
+pub struct RxToken {
+    buffer: Vec<u8>,
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        f(&mut self.buffer[..])
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken {
+    device: FdDevice,
+}
+
+impl phy::TxToken for TxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let mut buffer = vec![0; len];
+        let result = f(&mut buffer)?;
+        match self.device.send(&buffer[..]) {
+            Ok(_) => Ok(result),
+            //The fd is full, TCP retransmits what we drop here
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Err(Error::Exhausted),
+            Err(err) => {
+                debug!("write error on fd {}: {}", self.device.write_fd, err);
+                Err(Error::Exhausted)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_streams_are_refused() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        assert!(FdDevice::new(fds[0], fds[1], Medium::Ip, 1500).is_err());
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+        //Packet mode, but packets would be split
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_DIRECT) }, 0);
+        assert!(FdDevice::new(fds[0], fds[1], Medium::Ip, libc::PIPE_BUF + 1).is_err());
+        assert!(FdDevice::new(fds[0], fds[1], Medium::Ip, libc::PIPE_BUF).is_ok());
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+        assert_eq!(
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) },
+            0
+        );
+        assert!(FdDevice::new(fds[0], fds[0], Medium::Ip, 1500).is_err());
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+}
@@ -0,0 +1,617 @@
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{Icmpv4DstUnreachable, Icmpv4Packet, Icmpv4Repr, Icmpv6DstUnreachable, Icmpv6Packet};
+use smoltcp::wire::{Icmpv6Repr, IpAddress, IpCidr, IpProtocol, Ipv4Packet, Ipv4Repr, Ipv6Packet, Ipv6Repr};
+use smoltcp::wire::{TcpControl, TcpPacket, TcpRepr, TcpSeqNumber, UdpPacket};
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+//Warning: keep these synced with the FILTER_* constants on interface.h
+//Packets C++ sends to the stack
+pub const FILTER_INGRESS: u8 = 0;
+//Packets the stack sends to C++
+pub const FILTER_EGRESS: u8 = 1;
+
+pub const FILTER_ACTION_ACCEPT: u8 = 0;
+pub const FILTER_ACTION_DROP: u8 = 1;
+//Drops and answers with a TCP RST, or ICMP unreachable for anything but TCP and ICMP
+pub const FILTER_ACTION_REJECT: u8 = 2;
+
+//Hop limit of the RSTs and ICMP errors sent by REJECT
+const REPLY_HOP_LIMIT: u8 = 64;
+//How much of the refused packet goes back inside an ICMP error, after its IP header
+const ICMP_ERROR_DATA: usize = 8;
+
+/*
+    Asked about packets no rule matched, returns one of the FILTER_ACTION_*.
+    Called on the poller thread, for every such packet, without holding
+    the filter's lock: it may add and remove rules
+*/
+pub type CFilterFunction = extern "C" fn(context: *const c_void, direction: u8, data: *const u8, len: usize) -> u8;
+
+/*
+    What a rule looks at: fields left as None match anything. Ports only
+    match TCP and UDP packets. Those whose ports can't be read (later
+    fragments, truncated headers) match any port range of DROP and REJECT
+    rules, never of ACCEPT ones, so tiny fragments can't sneak past a
+    refusing default (RFC 1858)
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterRule {
+    pub direction: u8,
+    pub protocol: Option<IpProtocol>,
+    pub source: Option<IpCidr>,
+    pub destination: Option<IpCidr>,
+    //Inclusive ranges
+    pub source_ports: Option<(u16, u16)>,
+    pub destination_ports: Option<(u16, u16)>,
+    pub action: u8,
+}
+
+//Ports of a packet, as far as the filter can tell
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Ports {
+    Known(u16, u16),
+    //TCP or UDP without a readable header: later fragments and truncated packets
+    Unknown,
+    //Protocols without ports
+    NotApplicable,
+}
+
+//The parts of a packet the rules match against
+struct PacketInfo {
+    protocol: IpProtocol,
+    source: IpAddress,
+    destination: IpAddress,
+    ports: Ports,
+}
+
+//Extension headers skipped to find the transport header of an IPv6 packet
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_AUTHENTICATION: u8 = 51;
+const IPV6_DESTINATION_OPTIONS: u8 = 60;
+
+/*
+    Follows the extension headers of an IPv6 payload, returning the upper
+    layer protocol and its header, empty after a later fragment. None if
+    the chain runs past the packet, which then matches no rule
+*/
+fn ipv6_upper_layer(mut next_header: IpProtocol, mut payload: &[u8]) -> Option<(IpProtocol, &[u8])> {
+    loop {
+        let header = u8::from(next_header);
+        let len = match header {
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DESTINATION_OPTIONS => (*payload.get(1)? as usize + 1) * 8,
+            IPV6_AUTHENTICATION => (*payload.get(1)? as usize + 2) * 4,
+            IPV6_FRAGMENT => 8,
+            _ => return Some((next_header, payload)),
+        };
+        if payload.len() < len {
+            return None;
+        }
+        next_header = IpProtocol::from(payload[0]);
+        if header == IPV6_FRAGMENT && u16::from_be_bytes([payload[2], payload[3]]) >> 3 != 0 {
+            return Some((next_header, &[]));
+        }
+        payload = &payload[len..];
+    }
+}
+
+impl PacketInfo {
+    fn parse(packet: &[u8]) -> Option<PacketInfo> {
+        let (protocol, source, destination, payload) = match packet.first()? >> 4 {
+            4 => {
+                let ipv4 = Ipv4Packet::new_checked(packet).ok()?;
+                //Later fragments have no transport header
+                let payload = if ipv4.frag_offset() == 0 { ipv4.payload() } else { &[] };
+                (ipv4.protocol(), ipv4.src_addr().into(), ipv4.dst_addr().into(), payload)
+            }
+            6 => {
+                let ipv6 = Ipv6Packet::new_checked(packet).ok()?;
+                let (protocol, payload) = ipv6_upper_layer(ipv6.next_header(), ipv6.payload())?;
+                (protocol, ipv6.src_addr().into(), ipv6.dst_addr().into(), payload)
+            }
+            _ => return None,
+        };
+        let ports = match protocol {
+            IpProtocol::Tcp => TcpPacket::new_checked(payload)
+                .ok()
+                .map_or(Ports::Unknown, |tcp| Ports::Known(tcp.src_port(), tcp.dst_port())),
+            IpProtocol::Udp => UdpPacket::new_checked(payload)
+                .ok()
+                .map_or(Ports::Unknown, |udp| Ports::Known(udp.src_port(), udp.dst_port())),
+            _ => Ports::NotApplicable,
+        };
+        Some(PacketInfo {
+            protocol: protocol,
+            source: source,
+            destination: destination,
+            ports: ports,
+        })
+    }
+}
+
+fn in_range(port: u16, range: Option<(u16, u16)>) -> bool {
+    match range {
+        Some((first, last)) => first <= port && port <= last,
+        None => true,
+    }
+}
+
+impl FilterRule {
+    fn matches(&self, direction: u8, info: &PacketInfo) -> bool {
+        if self.direction != direction {
+            return false;
+        }
+        if self.protocol.map_or(false, |protocol| protocol != info.protocol) {
+            return false;
+        }
+        if self.source.map_or(false, |cidr| !cidr.contains_addr(&info.source)) {
+            return false;
+        }
+        if self.destination.map_or(false, |cidr| !cidr.contains_addr(&info.destination)) {
+            return false;
+        }
+        if self.source_ports.is_none() && self.destination_ports.is_none() {
+            return true;
+        }
+        match info.ports {
+            Ports::Known(source_port, destination_port) => {
+                in_range(source_port, self.source_ports) && in_range(destination_port, self.destination_ports)
+            }
+            Ports::Unknown => self.action != FILTER_ACTION_ACCEPT,
+            Ports::NotApplicable => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct FilterCallback {
+    function: CFilterFunction,
+    context: *const c_void,
+}
+
+struct FilterState {
+    //In the order they are checked, with the id add_rule gave them
+    rules: Vec<(u32, FilterRule)>,
+    next_id: u32,
+    //For packets no rule (nor the callback) decided on, indexed by direction
+    default_actions: [u8; 2],
+    callback: Option<FilterCallback>,
+}
+
+/*
+    Firewall of a VirtualTun stack, checked by the device on every packet
+    it receives from C++ (ingress) and sends to it (egress). The first
+    rule that matches decides, then the callback, then the default action
+    of the direction (accept unless changed). Rules change at any time
+    from any thread, the poller only takes a read lock
+*/
+pub struct PacketFilter {
+    state: RwLock<FilterState>,
+    //Packets dropped or rejected, indexed by direction
+    refused: [AtomicU64; 2],
+}
+
+//The context pointer belongs to C++, which must make it usable from the poller thread
+unsafe impl Send for PacketFilter {}
+unsafe impl Sync for PacketFilter {}
+
+fn valid_action(action: u8) -> bool {
+    action == FILTER_ACTION_ACCEPT || action == FILTER_ACTION_DROP || action == FILTER_ACTION_REJECT
+}
+
+impl PacketFilter {
+    pub fn new() -> PacketFilter {
+        PacketFilter {
+            state: RwLock::new(FilterState {
+                rules: Vec::new(),
+                next_id: 1,
+                default_actions: [FILTER_ACTION_ACCEPT; 2],
+                callback: None,
+            }),
+            refused: [AtomicU64::new(0), AtomicU64::new(0)],
+        }
+    }
+
+    //Appends the rule, checked after the ones already there. Ids start at 1
+    pub fn add_rule(&self, rule: FilterRule) -> Option<u32> {
+        if rule.direction > FILTER_EGRESS || !valid_action(rule.action) {
+            return None;
+        }
+        let mut state = self.state.write().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.rules.push((id, rule));
+        Some(id)
+    }
+
+    pub fn remove_rule(&self, id: u32) -> bool {
+        let mut state = self.state.write().unwrap();
+        let len = state.rules.len();
+        state.rules.retain(|&(rule_id, _)| rule_id != id);
+        state.rules.len() != len
+    }
+
+    pub fn clear_rules(&self) {
+        self.state.write().unwrap().rules.clear();
+    }
+
+    pub fn rules(&self) -> Vec<(u32, FilterRule)> {
+        self.state.read().unwrap().rules.clone()
+    }
+
+    pub fn set_default_action(&self, direction: u8, action: u8) -> bool {
+        if direction > FILTER_EGRESS || !valid_action(action) {
+            return false;
+        }
+        self.state.write().unwrap().default_actions[direction as usize] = action;
+        true
+    }
+
+    pub fn set_callback(&self, function: Option<CFilterFunction>, context: *const c_void) {
+        self.state.write().unwrap().callback = function.map(|function| FilterCallback {
+            function: function,
+            context: context,
+        });
+    }
+
+    pub fn refused(&self, direction: u8) -> u64 {
+        self.refused[direction as usize].load(Ordering::Relaxed)
+    }
+
+    //One of the FILTER_ACTION_*, for `packet` going in `direction`
+    pub fn check(&self, direction: u8, packet: &[u8]) -> u8 {
+        let action = self.decide(direction, packet);
+        if action != FILTER_ACTION_ACCEPT {
+            self.refused[direction as usize].fetch_add(1, Ordering::Relaxed);
+        }
+        action
+    }
+
+    //Like check, for a look ahead at a packet the device will check later: not counted
+    pub fn decide(&self, direction: u8, packet: &[u8]) -> u8 {
+        let callback = {
+            let state = self.state.read().unwrap();
+            let default_action = state.default_actions[direction as usize];
+            if state.rules.is_empty() && state.callback.is_none() && default_action == FILTER_ACTION_ACCEPT {
+                return FILTER_ACTION_ACCEPT;
+            }
+            //What doesn't parse as IP matches no rule
+            let info = PacketInfo::parse(packet);
+            let rule = info.as_ref().and_then(|info| {
+                state
+                    .rules
+                    .iter()
+                    .find(|&&(_, ref rule)| rule.matches(direction, info))
+            });
+            match (rule, state.callback) {
+                (Some(&(_, ref rule)), _) => return rule.action,
+                (None, Some(callback)) => callback,
+                (None, None) => return default_action,
+            }
+        };
+        //Called without the lock, so it can change the rules
+        match (callback.function)(callback.context, direction, packet.as_ptr(), packet.len()) {
+            action if valid_action(action) => action,
+            _ => FILTER_ACTION_DROP,
+        }
+    }
+}
+
+/*
+    Answer to a packet refused with FILTER_ACTION_REJECT, from its
+    destination to its source: RST for TCP (none for a RST) and
+    administratively prohibited ICMP unreachable for the rest. None for
+    ICMP, multicast/broadcast and what doesn't parse, those are just dropped
+*/
+pub fn reject_reply(packet: &[u8]) -> Option<Vec<u8>> {
+    let checksums = ChecksumCapabilities::default();
+    let (source, destination, protocol, header_len, payload) = match packet.first()? >> 4 {
+        4 => {
+            let ipv4 = Ipv4Packet::new_checked(packet).ok()?;
+            let header_len = ipv4.header_len() as usize;
+            (ipv4.src_addr().into(), ipv4.dst_addr().into(), ipv4.protocol(), header_len, ipv4.payload())
+        }
+        6 => {
+            let ipv6 = Ipv6Packet::new_checked(packet).ok()?;
+            (ipv6.src_addr().into(), ipv6.dst_addr().into(), ipv6.next_header(), 40, ipv6.payload())
+        }
+        _ => return None,
+    };
+    let (source, destination): (IpAddress, IpAddress) = (source, destination);
+    if destination.is_multicast() || destination.is_broadcast() || !source.is_unicast() {
+        return None;
+    }
+    match protocol {
+        IpProtocol::Tcp => {
+            let tcp = TcpRepr::parse(&TcpPacket::new_checked(payload).ok()?, &source, &destination, &checksums).ok()?;
+            if tcp.control == TcpControl::Rst {
+                return None;
+            }
+            //RFC 793: take the sequence number from its ACK, or acknowledge all of it
+            let (seq_number, ack_number) = match tcp.ack_number {
+                Some(ack_number) => (ack_number, None),
+                None => (TcpSeqNumber(0), Some(tcp.seq_number + tcp.segment_len())),
+            };
+            let rst = TcpRepr {
+                src_port: tcp.dst_port,
+                dst_port: tcp.src_port,
+                control: TcpControl::Rst,
+                seq_number: seq_number,
+                ack_number: ack_number,
+                window_len: 0,
+                window_scale: None,
+                max_seg_size: None,
+                sack_permitted: false,
+                sack_ranges: [None, None, None],
+                payload: &[],
+            };
+            let mut reply = ip_reply(&destination, &source, IpProtocol::Tcp, rst.buffer_len())?;
+            let offset = reply.len() - rst.buffer_len();
+            rst.emit(
+                &mut TcpPacket::new_unchecked(&mut reply[offset..]),
+                &destination,
+                &source,
+                &checksums,
+            );
+            Some(reply)
+        }
+        IpProtocol::Icmp | IpProtocol::Icmpv6 => None,
+        _ => {
+            let data = &payload[..payload.len().min(ICMP_ERROR_DATA)];
+            match (source, destination) {
+                (IpAddress::Ipv4(_), IpAddress::Ipv4(_)) => {
+                    let header = Ipv4Repr::parse(&Ipv4Packet::new_checked(packet).ok()?, &checksums).ok()?;
+                    let icmp = Icmpv4Repr::DstUnreachable {
+                        reason: Icmpv4DstUnreachable::CommProhibited,
+                        header: header,
+                        data: data,
+                    };
+                    let mut reply = ip_reply(&destination, &source, IpProtocol::Icmp, icmp.buffer_len())?;
+                    let offset = reply.len() - icmp.buffer_len();
+                    icmp.emit(&mut Icmpv4Packet::new_unchecked(&mut reply[offset..]), &checksums);
+                    Some(reply)
+                }
+                _ => {
+                    let header = Ipv6Repr::parse(&Ipv6Packet::new_checked(&packet[..header_len + payload.len()]).ok()?).ok()?;
+                    let icmp = Icmpv6Repr::DstUnreachable {
+                        reason: Icmpv6DstUnreachable::AdminProhibit,
+                        header: header,
+                        data: data,
+                    };
+                    let mut reply = ip_reply(&destination, &source, IpProtocol::Icmpv6, icmp.buffer_len())?;
+                    let offset = reply.len() - icmp.buffer_len();
+                    icmp.emit(
+                        &destination,
+                        &source,
+                        &mut Icmpv6Packet::new_unchecked(&mut reply[offset..]),
+                        &checksums,
+                    );
+                    Some(reply)
+                }
+            }
+        }
+    }
+}
+
+//IP packet from `source` to `destination` with its header written and room for `payload_len` bytes
+fn ip_reply(source: &IpAddress, destination: &IpAddress, protocol: IpProtocol, payload_len: usize) -> Option<Vec<u8>> {
+    let checksums = ChecksumCapabilities::default();
+    match (*source, *destination) {
+        (IpAddress::Ipv4(source), IpAddress::Ipv4(destination)) => {
+            let header = Ipv4Repr {
+                src_addr: source,
+                dst_addr: destination,
+                protocol: protocol,
+                payload_len: payload_len,
+                hop_limit: REPLY_HOP_LIMIT,
+            };
+            let mut reply = vec![0; header.buffer_len() + payload_len];
+            header.emit(&mut Ipv4Packet::new_unchecked(&mut reply), &checksums);
+            Some(reply)
+        }
+        (IpAddress::Ipv6(source), IpAddress::Ipv6(destination)) => {
+            let header = Ipv6Repr {
+                src_addr: source,
+                dst_addr: destination,
+                next_header: protocol,
+                payload_len: payload_len,
+                hop_limit: REPLY_HOP_LIMIT,
+            };
+            let mut reply = vec![0; header.buffer_len() + payload_len];
+            header.emit(&mut Ipv6Packet::new_unchecked(&mut reply));
+            Some(reply)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::wire::{Ipv4Address, Ipv4Cidr, Ipv6Address};
+
+    const CLIENT: Ipv4Address = Ipv4Address([10, 0, 0, 2]);
+    const SERVER: Ipv4Address = Ipv4Address([10, 0, 0, 1]);
+    const CLIENT6: Ipv6Address = Ipv6Address([0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+    const SERVER6: Ipv6Address = Ipv6Address([0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+    //SYN from CLIENT:40000 to SERVER:`port`
+    fn syn(port: u16) -> Vec<u8> {
+        let tcp = TcpRepr {
+            src_port: 40000,
+            dst_port: port,
+            control: TcpControl::Syn,
+            seq_number: TcpSeqNumber(1000),
+            ack_number: None,
+            window_len: 1024,
+            window_scale: None,
+            max_seg_size: None,
+            sack_permitted: false,
+            sack_ranges: [None, None, None],
+            payload: &[],
+        };
+        let mut packet = ip_reply(&CLIENT.into(), &SERVER.into(), IpProtocol::Tcp, tcp.buffer_len()).unwrap();
+        let offset = packet.len() - tcp.buffer_len();
+        tcp.emit(
+            &mut TcpPacket::new_unchecked(&mut packet[offset..]),
+            &CLIENT.into(),
+            &SERVER.into(),
+            &ChecksumCapabilities::default(),
+        );
+        packet
+    }
+
+    //SYN from CLIENT6:40000 to SERVER6:`port`, behind an extension header
+    fn ipv6_syn(extension: u8, extension_header: [u8; 8], port: u16) -> Vec<u8> {
+        let ipv4 = syn(port);
+        let tcp = Ipv4Packet::new_checked(&ipv4[..]).unwrap().payload().to_vec();
+        let payload_len = extension_header.len() + tcp.len();
+        let mut packet = ip_reply(&CLIENT6.into(), &SERVER6.into(), IpProtocol::from(extension), payload_len).unwrap();
+        let offset = packet.len() - payload_len;
+        packet[offset..offset + 8].copy_from_slice(&extension_header);
+        packet[offset + 8..].copy_from_slice(&tcp);
+        packet
+    }
+
+    fn rule(destination_port: u16, action: u8) -> FilterRule {
+        FilterRule {
+            direction: FILTER_INGRESS,
+            protocol: Some(IpProtocol::Tcp),
+            source: Some(IpCidr::Ipv4(Ipv4Cidr::new(CLIENT, 24))),
+            destination: None,
+            source_ports: None,
+            destination_ports: Some((destination_port, destination_port)),
+            action: action,
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let filter = PacketFilter::new();
+        assert_eq!(filter.check(FILTER_INGRESS, &syn(80)), FILTER_ACTION_ACCEPT);
+        let allow_ssh = filter.add_rule(rule(22, FILTER_ACTION_ACCEPT)).unwrap();
+        filter.add_rule(rule(22, FILTER_ACTION_DROP)).unwrap();
+        assert!(filter.set_default_action(FILTER_INGRESS, FILTER_ACTION_REJECT));
+        assert_eq!(filter.check(FILTER_INGRESS, &syn(22)), FILTER_ACTION_ACCEPT);
+        assert_eq!(filter.check(FILTER_INGRESS, &syn(80)), FILTER_ACTION_REJECT);
+        //Egress has its own default
+        assert_eq!(filter.check(FILTER_EGRESS, &syn(80)), FILTER_ACTION_ACCEPT);
+        assert!(filter.remove_rule(allow_ssh));
+        assert!(!filter.remove_rule(allow_ssh));
+        assert_eq!(filter.check(FILTER_INGRESS, &syn(22)), FILTER_ACTION_DROP);
+        assert_eq!(filter.refused(FILTER_INGRESS), 2);
+        assert!(filter.add_rule(rule(22, 7)).is_none());
+    }
+
+    #[test]
+    fn ipv6_extension_headers_are_skipped_to_find_the_ports() {
+        let filter = PacketFilter::new();
+        let mut drop_ssh = rule(22, FILTER_ACTION_DROP);
+        drop_ssh.source = None;
+        filter.add_rule(drop_ssh).unwrap();
+        //PadN filling the rest of a destination options header
+        let options = [6, 0, 1, 4, 0, 0, 0, 0];
+        assert_eq!(filter.check(FILTER_INGRESS, &ipv6_syn(IPV6_DESTINATION_OPTIONS, options, 22)), FILTER_ACTION_DROP);
+        assert_eq!(filter.check(FILTER_INGRESS, &ipv6_syn(IPV6_DESTINATION_OPTIONS, options, 80)), FILTER_ACTION_ACCEPT);
+        //First fragment, more to come
+        let first_fragment = [6, 0, 0, 1, 0, 0, 0, 7];
+        assert_eq!(filter.check(FILTER_INGRESS, &ipv6_syn(IPV6_FRAGMENT, first_fragment, 22)), FILTER_ACTION_DROP);
+        assert_eq!(filter.check(FILTER_INGRESS, &ipv6_syn(IPV6_FRAGMENT, first_fragment, 80)), FILTER_ACTION_ACCEPT);
+    }
+
+    #[test]
+    fn packets_without_readable_ports_get_refused_by_port_rules() {
+        let mut later_fragment = syn(80);
+        {
+            let mut ipv4 = Ipv4Packet::new_unchecked(&mut later_fragment[..]);
+            ipv4.set_frag_offset(8);
+            ipv4.fill_checksum();
+        }
+        let filter = PacketFilter::new();
+        filter.add_rule(rule(22, FILTER_ACTION_DROP)).unwrap();
+        assert_eq!(filter.check(FILTER_INGRESS, &syn(80)), FILTER_ACTION_ACCEPT);
+        assert_eq!(filter.check(FILTER_INGRESS, &later_fragment), FILTER_ACTION_DROP);
+        //Same for IPv6, offset 8 of a fragmented packet
+        let mut drop_ssh = rule(22, FILTER_ACTION_DROP);
+        drop_ssh.source = None;
+        filter.add_rule(drop_ssh).unwrap();
+        let fragment = [6, 0, 0, 8, 0, 0, 0, 7];
+        assert_eq!(filter.check(FILTER_INGRESS, &ipv6_syn(IPV6_FRAGMENT, fragment, 80)), FILTER_ACTION_DROP);
+        //Rules without ports match them as usual
+        let filter = PacketFilter::new();
+        let mut drop_tcp = rule(22, FILTER_ACTION_DROP);
+        drop_tcp.destination_ports = None;
+        filter.add_rule(drop_tcp).unwrap();
+        assert_eq!(filter.check(FILTER_INGRESS, &later_fragment), FILTER_ACTION_DROP);
+    }
+
+    #[test]
+    fn packets_without_readable_ports_are_not_accepted_by_port_rules() {
+        let mut later_fragment = syn(22);
+        {
+            let mut ipv4 = Ipv4Packet::new_unchecked(&mut later_fragment[..]);
+            ipv4.set_frag_offset(8);
+            ipv4.fill_checksum();
+        }
+        //First fragment with only 8 bytes of TCP header, too short for the flags
+        let mut tiny_fragment = syn(22);
+        tiny_fragment.truncate(28);
+        {
+            let mut ipv4 = Ipv4Packet::new_unchecked(&mut tiny_fragment[..]);
+            ipv4.set_total_len(28);
+            ipv4.set_more_frags(true);
+            ipv4.fill_checksum();
+        }
+        let filter = PacketFilter::new();
+        filter.add_rule(rule(22, FILTER_ACTION_ACCEPT)).unwrap();
+        assert!(filter.set_default_action(FILTER_INGRESS, FILTER_ACTION_DROP));
+        assert_eq!(filter.check(FILTER_INGRESS, &syn(22)), FILTER_ACTION_ACCEPT);
+        assert_eq!(filter.check(FILTER_INGRESS, &later_fragment), FILTER_ACTION_DROP);
+        assert_eq!(filter.check(FILTER_INGRESS, &tiny_fragment), FILTER_ACTION_DROP);
+    }
+
+    extern "C" fn refuse_everything(_: *const c_void, _: u8, _: *const u8, _: usize) -> u8 {
+        FILTER_ACTION_DROP
+    }
+
+    #[test]
+    fn callback_decides_when_no_rule_matches() {
+        let filter = PacketFilter::new();
+        filter.add_rule(rule(22, FILTER_ACTION_ACCEPT)).unwrap();
+        filter.set_callback(Some(refuse_everything), std::ptr::null());
+        assert_eq!(filter.check(FILTER_INGRESS, &syn(22)), FILTER_ACTION_ACCEPT);
+        assert_eq!(filter.check(FILTER_INGRESS, &syn(80)), FILTER_ACTION_DROP);
+    }
+
+    extern "C" fn allow_ssh_from_now_on(context: *const c_void, _: u8, _: *const u8, _: usize) -> u8 {
+        let filter = unsafe { &*(context as *const PacketFilter) };
+        filter.add_rule(rule(22, FILTER_ACTION_ACCEPT)).unwrap();
+        FILTER_ACTION_DROP
+    }
+
+    #[test]
+    fn callback_can_change_the_rules() {
+        let filter = PacketFilter::new();
+        filter.set_callback(Some(allow_ssh_from_now_on), &filter as *const PacketFilter as *const c_void);
+        assert_eq!(filter.check(FILTER_INGRESS, &syn(22)), FILTER_ACTION_DROP);
+        assert_eq!(filter.check(FILTER_INGRESS, &syn(22)), FILTER_ACTION_ACCEPT);
+    }
+
+    #[test]
+    fn rejected_syn_gets_a_rst() {
+        let reply = reject_reply(&syn(80)).unwrap();
+        let ipv4 = Ipv4Packet::new_checked(&reply[..]).unwrap();
+        assert_eq!(ipv4.src_addr(), SERVER);
+        assert_eq!(ipv4.dst_addr(), CLIENT);
+        let rst = TcpPacket::new_checked(ipv4.payload()).unwrap();
+        assert!(rst.verify_checksum(&SERVER.into(), &CLIENT.into()));
+        assert!(rst.rst() && rst.ack());
+        assert_eq!((rst.src_port(), rst.dst_port()), (80, 40000));
+        //The SYN takes one sequence number
+        assert_eq!(rst.ack_number(), TcpSeqNumber(1001));
+        assert!(reject_reply(&reply).is_none());
+    }
+}
@@ -0,0 +1,256 @@
+use super::smol_stack::{SMOL_RESULT_INVALID_CHECKSUM, SMOL_RESULT_INVALID_HEADER_LENGTH};
+use super::smol_stack::{SMOL_RESULT_INVALID_IP_VERSION, SMOL_RESULT_INVALID_TOTAL_LENGTH};
+use super::smol_stack::SMOL_RESULT_NOT_OUR_ADDRESS;
+use smoltcp::wire::{IpAddress, IpCidr, IpProtocol, Ipv4Packet, Ipv6Packet};
+use smoltcp::wire::{Icmpv4Packet, Icmpv6Packet, TcpPacket, UdpPacket};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+//Warning: keep these synced with the INGRESS_CHECK_* constants on interface.h
+//IP version, header length and total length against the packet's size
+pub const INGRESS_CHECK_HEADER: u8 = 1;
+//IPv4 header checksum and TCP/UDP/ICMP checksums of unfragmented packets
+pub const INGRESS_CHECK_CHECKSUM: u8 = 2;
+//The destination is one of the stack's addresses, multicast or broadcast
+pub const INGRESS_CHECK_DESTINATION: u8 = 4;
+pub const INGRESS_CHECK_ALL: u8 = INGRESS_CHECK_HEADER | INGRESS_CHECK_CHECKSUM | INGRESS_CHECK_DESTINATION;
+
+/*
+    Packets refused by IngressChecks::validate, one counter per result
+    code. Read from any thread while C++ sends
+*/
+#[derive(Default)]
+pub struct IngressCounters {
+    pub invalid_ip_version: AtomicU64,
+    pub invalid_header_length: AtomicU64,
+    pub invalid_total_length: AtomicU64,
+    pub invalid_checksum: AtomicU64,
+    pub not_our_address: AtomicU64,
+}
+
+impl IngressCounters {
+    fn count(&self, result: u8) {
+        let counter = match result {
+            SMOL_RESULT_INVALID_IP_VERSION => &self.invalid_ip_version,
+            SMOL_RESULT_INVALID_HEADER_LENGTH => &self.invalid_header_length,
+            SMOL_RESULT_INVALID_TOTAL_LENGTH => &self.invalid_total_length,
+            SMOL_RESULT_INVALID_CHECKSUM => &self.invalid_checksum,
+            _ => &self.not_our_address,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/*
+    Optional validation of the packets C++ sends to a VirtualTun stack,
+    done on the sending thread before they are queued. smoltcp would drop
+    these anyway, but only later on the poller, where all we get is a
+    poll error. Nothing is checked unless enabled, with the INGRESS_CHECK_*
+    bits. The other checks read the header, so enabling any of them
+    checks it too
+*/
+#[derive(Default)]
+pub struct IngressChecks {
+    checks: u8,
+    pub counters: IngressCounters,
+}
+
+impl IngressChecks {
+    pub fn checks(&self) -> u8 {
+        self.checks
+    }
+
+    pub fn set_checks(&mut self, checks: u8) -> bool {
+        if checks & !INGRESS_CHECK_ALL != 0 {
+            return false;
+        }
+        self.checks = checks;
+        true
+    }
+
+    /*
+        Err with the SMOL_RESULT_INVALID_* or SMOL_RESULT_NOT_OUR_ADDRESS
+        code of the first check that failed, which is also counted.
+        `addresses` are the stack's own
+    */
+    pub fn validate(&self, packet: &[u8], addresses: &[IpCidr]) -> Result<(), u8> {
+        if self.checks == 0 {
+            return Ok(());
+        }
+        let r = match packet.first().map(|byte| byte >> 4) {
+            Some(4) => self.validate_ipv4(packet, addresses),
+            Some(6) => self.validate_ipv6(packet, addresses),
+            _ => Err(SMOL_RESULT_INVALID_IP_VERSION),
+        };
+        if let Err(result) = r {
+            self.counters.count(result);
+        }
+        r
+    }
+
+    fn validate_ipv4(&self, packet: &[u8], addresses: &[IpCidr]) -> Result<(), u8> {
+        //The rest of the checks read the header, so they need it whole
+        if packet.len() < 20 {
+            return Err(SMOL_RESULT_INVALID_HEADER_LENGTH);
+        }
+        let ipv4 = Ipv4Packet::new_unchecked(packet);
+        let header_len = ipv4.header_len() as usize;
+        if header_len < 20 || header_len > packet.len() {
+            return Err(SMOL_RESULT_INVALID_HEADER_LENGTH);
+        }
+        //A tunnel has no link layer padding, the sizes must match exactly
+        let total_len = ipv4.total_len() as usize;
+        if total_len < header_len || total_len != packet.len() {
+            return Err(SMOL_RESULT_INVALID_TOTAL_LENGTH);
+        }
+        let src_addr = IpAddress::Ipv4(ipv4.src_addr());
+        let dst_addr = IpAddress::Ipv4(ipv4.dst_addr());
+        if self.checks & INGRESS_CHECK_CHECKSUM != 0 {
+            if !ipv4.verify_checksum() {
+                return Err(SMOL_RESULT_INVALID_CHECKSUM);
+            }
+            //Fragments can't be checked until reassembled
+            if !ipv4.more_frags() && ipv4.frag_offset() == 0 {
+                validate_transport(ipv4.protocol(), ipv4.payload(), &src_addr, &dst_addr)?;
+            }
+        }
+        if self.checks & INGRESS_CHECK_DESTINATION != 0 && !is_ours(&dst_addr, addresses) {
+            return Err(SMOL_RESULT_NOT_OUR_ADDRESS);
+        }
+        Ok(())
+    }
+
+    fn validate_ipv6(&self, packet: &[u8], addresses: &[IpCidr]) -> Result<(), u8> {
+        if packet.len() < 40 {
+            return Err(SMOL_RESULT_INVALID_HEADER_LENGTH);
+        }
+        let ipv6 = Ipv6Packet::new_unchecked(packet);
+        if 40 + ipv6.payload_len() as usize != packet.len() {
+            return Err(SMOL_RESULT_INVALID_TOTAL_LENGTH);
+        }
+        let src_addr = IpAddress::Ipv6(ipv6.src_addr());
+        let dst_addr = IpAddress::Ipv6(ipv6.dst_addr());
+        //Only when nothing comes between the IPv6 header and the transport one
+        if self.checks & INGRESS_CHECK_CHECKSUM != 0 {
+            validate_transport(ipv6.next_header(), ipv6.payload(), &src_addr, &dst_addr)?;
+        }
+        if self.checks & INGRESS_CHECK_DESTINATION != 0 && !is_ours(&dst_addr, addresses) {
+            return Err(SMOL_RESULT_NOT_OUR_ADDRESS);
+        }
+        Ok(())
+    }
+}
+
+//Checksum of the TCP, UDP or ICMP packet in `payload`, other protocols pass
+fn validate_transport(protocol: IpProtocol, payload: &[u8], src_addr: &IpAddress, dst_addr: &IpAddress) -> Result<(), u8> {
+    let valid = match protocol {
+        IpProtocol::Tcp => TcpPacket::new_checked(payload)
+            .map_err(|_| SMOL_RESULT_INVALID_HEADER_LENGTH)?
+            .verify_checksum(src_addr, dst_addr),
+        IpProtocol::Udp => UdpPacket::new_checked(payload)
+            .map_err(|_| SMOL_RESULT_INVALID_HEADER_LENGTH)?
+            .verify_checksum(src_addr, dst_addr),
+        IpProtocol::Icmp => Icmpv4Packet::new_checked(payload)
+            .map_err(|_| SMOL_RESULT_INVALID_HEADER_LENGTH)?
+            .verify_checksum(),
+        IpProtocol::Icmpv6 => Icmpv6Packet::new_checked(payload)
+            .map_err(|_| SMOL_RESULT_INVALID_HEADER_LENGTH)?
+            .verify_checksum(src_addr, dst_addr),
+        _ => true,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(SMOL_RESULT_INVALID_CHECKSUM)
+    }
+}
+
+//One of `addresses`, the broadcast address of one of their subnets or multicast
+fn is_ours(address: &IpAddress, addresses: &[IpCidr]) -> bool {
+    if address.is_multicast() || address.is_broadcast() {
+        return true;
+    }
+    addresses.iter().any(|cidr| match (cidr, address) {
+        (IpCidr::Ipv4(cidr), IpAddress::Ipv4(address)) => {
+            let prefix_len = cidr.prefix_len() as u32;
+            let host_mask = u32::max_value().checked_shr(prefix_len).unwrap_or(0);
+            let broadcast = u32::from_be_bytes(cidr.address().0) | host_mask;
+            //Point to point /31 and /32 subnets have no broadcast address
+            cidr.address() == *address || (prefix_len < 31 && u32::from_be_bytes(address.0) == broadcast)
+        }
+        _ => cidr.address() == *address,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::phy::ChecksumCapabilities;
+    use smoltcp::wire::{Ipv4Address, Ipv4Cidr, Ipv4Repr, UdpRepr};
+
+    //UDP datagram from 10.0.0.2:1000 to `dst`:53 with a 4 byte payload
+    fn udp_packet(dst: Ipv4Address) -> Vec<u8> {
+        let src = Ipv4Address::new(10, 0, 0, 2);
+        let udp = UdpRepr {
+            src_port: 1000,
+            dst_port: 53,
+            payload: &[1, 2, 3, 4],
+        };
+        let ip = Ipv4Repr {
+            src_addr: src,
+            dst_addr: dst,
+            protocol: IpProtocol::Udp,
+            payload_len: udp.buffer_len(),
+            hop_limit: 64,
+        };
+        let mut buffer = vec![0; ip.buffer_len() + udp.buffer_len()];
+        let checksums = ChecksumCapabilities::default();
+        let mut packet = Ipv4Packet::new_unchecked(&mut buffer);
+        ip.emit(&mut packet, &checksums);
+        udp.emit(
+            &mut UdpPacket::new_unchecked(packet.payload_mut()),
+            &src.into(),
+            &dst.into(),
+            &checksums,
+        );
+        buffer
+    }
+
+    #[test]
+    fn rejects_and_counts_invalid_packets() {
+        let ours = Ipv4Address::new(10, 0, 0, 1);
+        let addresses = [IpCidr::Ipv4(Ipv4Cidr::new(ours, 24))];
+        let mut checks = IngressChecks::default();
+        assert!(checks.set_checks(INGRESS_CHECK_ALL));
+        let packet = udp_packet(ours);
+        assert_eq!(checks.validate(&packet, &addresses), Ok(()));
+        //Subnet broadcast
+        assert_eq!(checks.validate(&udp_packet(Ipv4Address::new(10, 0, 0, 255)), &addresses), Ok(()));
+        assert_eq!(checks.validate(&[0x55; 30], &addresses), Err(SMOL_RESULT_INVALID_IP_VERSION));
+        assert_eq!(checks.validate(&packet[..12], &addresses), Err(SMOL_RESULT_INVALID_HEADER_LENGTH));
+        assert_eq!(
+            checks.validate(&packet[..packet.len() - 1], &addresses),
+            Err(SMOL_RESULT_INVALID_TOTAL_LENGTH)
+        );
+        let mut corrupted = packet.clone();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        assert_eq!(checks.validate(&corrupted, &addresses), Err(SMOL_RESULT_INVALID_CHECKSUM));
+        assert_eq!(
+            checks.validate(&udp_packet(Ipv4Address::new(10, 0, 1, 1)), &addresses),
+            Err(SMOL_RESULT_NOT_OUR_ADDRESS)
+        );
+        let counters = &checks.counters;
+        for counter in &[
+            &counters.invalid_ip_version,
+            &counters.invalid_header_length,
+            &counters.invalid_total_length,
+            &counters.invalid_checksum,
+            &counters.not_our_address,
+        ] {
+            assert_eq!(counter.load(Ordering::Relaxed), 1);
+        }
+        //Disabled, anything goes
+        assert!(checks.set_checks(0));
+        assert_eq!(checks.validate(&corrupted, &addresses), Ok(()));
+        assert!(!checks.set_checks(0x80));
+    }
+}
@@ -9,6 +9,7 @@
 #include <memory>
 #include <optional>
 #include <utility>
+#include <vector>
 #include "utils.h"
 
 typedef void *SmolStackPtr;
@@ -17,6 +18,98 @@ typedef size_t SocketHandle;
 static const int SOCKET_TCP = 0;
 static const int SOCKET_UDP = 1;
 
+//Warning: keep these synced with the SMOL_RESULT_* constants on smol_stack.rs
+static const uint8_t SMOL_RESULT_OK = 0;
+static const uint8_t SMOL_RESULT_NOT_AVAILABLE = 1;
+static const uint8_t SMOL_RESULT_ERROR = 2;
+static const uint8_t SMOL_RESULT_UNSUPPORTED = 3;
+static const uint8_t SMOL_RESULT_INVALID_VALUE = 4;
+static const uint8_t SMOL_RESULT_END_OF_STREAM = 5;
+static const uint8_t SMOL_RESULT_WOULD_BLOCK = 6;
+static const uint8_t SMOL_RESULT_BUFFER_TOO_SMALL = 7;
+static const uint8_t SMOL_RESULT_TIMED_OUT = 8;
+static const uint8_t SMOL_RESULT_CANCELLED = 9;
+static const uint8_t SMOL_RESULT_CLOSED = 10;
+static const uint8_t SMOL_RESULT_SHUT_DOWN = 11;
+//Refused by the ingress checks, see virtualTunSetIngressChecks
+static const uint8_t SMOL_RESULT_INVALID_IP_VERSION = 12;
+static const uint8_t SMOL_RESULT_INVALID_HEADER_LENGTH = 13;
+static const uint8_t SMOL_RESULT_INVALID_TOTAL_LENGTH = 14;
+static const uint8_t SMOL_RESULT_INVALID_CHECKSUM = 15;
+static const uint8_t SMOL_RESULT_NOT_OUR_ADDRESS = 16;
+
+//Warning: keep these synced with ingress.rs
+//Bits of what virtualTunSend checks before taking a packet
+static const uint8_t INGRESS_CHECK_HEADER = 1;
+static const uint8_t INGRESS_CHECK_CHECKSUM = 2;
+static const uint8_t INGRESS_CHECK_DESTINATION = 4;
+static const uint8_t INGRESS_CHECK_ALL = 7;
+
+//Warning: keep these synced with filter.rs
+//Packets sent to the stack (virtualTunSend) / by it (virtualTunReceive*)
+static const uint8_t FILTER_INGRESS = 0;
+static const uint8_t FILTER_EGRESS = 1;
+
+static const uint8_t FILTER_ACTION_ACCEPT = 0;
+static const uint8_t FILTER_ACTION_DROP = 1;
+//Drops and answers with a TCP RST, or ICMP unreachable for anything but TCP and ICMP
+static const uint8_t FILTER_ACTION_REJECT = 2;
+
+//Warning: keep these synced with queue.rs
+static const uint8_t QUEUE_SOCKET_TO_SEND = 0;
+static const uint8_t QUEUE_SOCKET_RECEIVED = 1;
+static const uint8_t QUEUE_PACKETS_FROM_INSIDE = 2;
+static const uint8_t QUEUE_PACKETS_FROM_OUTSIDE = 3;
+
+static const uint8_t FLOW_EVENT_SOCKET_WRITABLE = 0;
+static const uint8_t FLOW_EVENT_SOCKET_READABLE = 1;
+static const uint8_t FLOW_EVENT_STACK_WRITABLE = 2;
+static const uint8_t FLOW_EVENT_STACK_READABLE = 3;
+
+//Warning: keep these synced with signal.rs
+//Bits returned by phy_wait(), several can be set at once
+static const uint8_t WAKE_REASON_TIMEOUT = 1;
+static const uint8_t WAKE_REASON_PACKETS = 2;
+static const uint8_t WAKE_REASON_SOCKETS = 4;
+static const uint8_t WAKE_REASON_ROOM = 8;
+static const uint8_t WAKE_REASON_WAKE = 16;
+static const uint8_t WAKE_REASON_SHUT_DOWN = 32;
+
+//Warning: keep these synced with SocketOption on smol_stack.rs
+//Time based options are in milliseconds, 0 disables them
+static const uint8_t SOCKET_OPTION_KEEP_ALIVE = 0;
+static const uint8_t SOCKET_OPTION_TIMEOUT = 1;
+static const uint8_t SOCKET_OPTION_NAGLE = 2;
+static const uint8_t SOCKET_OPTION_ACK_DELAY = 3;
+static const uint8_t SOCKET_OPTION_HOP_LIMIT = 4;
+
+//Warning: keep these synced with the TCP_STATE_* constants on interface.rs
+static const uint8_t TCP_STATE_CLOSED = 0;
+static const uint8_t TCP_STATE_LISTEN = 1;
+static const uint8_t TCP_STATE_SYN_SENT = 2;
+static const uint8_t TCP_STATE_SYN_RECEIVED = 3;
+static const uint8_t TCP_STATE_ESTABLISHED = 4;
+static const uint8_t TCP_STATE_FIN_WAIT_1 = 5;
+static const uint8_t TCP_STATE_FIN_WAIT_2 = 6;
+static const uint8_t TCP_STATE_CLOSE_WAIT = 7;
+static const uint8_t TCP_STATE_CLOSING = 8;
+static const uint8_t TCP_STATE_LAST_ACK = 9;
+static const uint8_t TCP_STATE_TIME_WAIT = 10;
+//Not a TCP socket
+static const uint8_t TCP_STATE_NONE = 255;
+
+//Warning: keep these synced with the MEDIUM_* constants on callback_device.rs
+static const uint8_t MEDIUM_IP = 0;
+static const uint8_t MEDIUM_ETHERNET = 1;
+
+//Warning: keep these synced with log::Level numbering used in logging.rs
+static const uint8_t LOG_LEVEL_OFF = 0;
+static const uint8_t LOG_LEVEL_ERROR = 1;
+static const uint8_t LOG_LEVEL_WARN = 2;
+static const uint8_t LOG_LEVEL_INFO = 3;
+static const uint8_t LOG_LEVEL_DEBUG = 4;
+static const uint8_t LOG_LEVEL_TRACE = 5;
+
 namespace smoltcp
 {
     using namespace std::chrono;
@@ -87,6 +180,11 @@ namespace smoltcp
         CIpv6Address ipv6Address;
     };
 
+    struct CEthernetAddress
+    {
+        uint8_t address[6];
+    };
+
     struct CIpv4Cidr
     {
         CIpv4Address address;
@@ -99,7 +197,8 @@ namespace smoltcp
         uint64_t prefix;
     };
 
-    enum CIpEndpointType
+    //Warning: keep this synced with the CIPENDPOINT_* constants on interface.rs
+    enum CIpEndpointType : uint8_t
     {
         None = 0,
         Ipv4 = 1,
@@ -114,6 +213,59 @@ namespace smoltcp
         uint16_t port;
     };
 
+    //Packets refused by the ingress checks, one counter per SMOL_RESULT_* code
+    struct CIngressCounters
+    {
+        uint64_t invalidIpVersion;
+        uint64_t invalidHeaderLength;
+        uint64_t invalidTotalLength;
+        uint64_t invalidChecksum;
+        uint64_t notOurAddress;
+    };
+
+    /*
+        Packet filter rule, see addFilterRule. protocol is an IP protocol
+        number, 0 for any. Addresses only count with hasSource/hasDestination
+        and port ranges (inclusive) with a max of 0 match any port.
+        Warning: keep this synced with CFilterRule on interface.rs
+    */
+    struct CFilterRule
+    {
+        uint8_t direction = FILTER_INGRESS;
+        uint8_t action = FILTER_ACTION_ACCEPT;
+        uint8_t protocol = 0;
+        uint8_t hasSource = 0;
+        CIpAddress source;
+        uint8_t sourcePrefix = 0;
+        uint8_t hasDestination = 0;
+        CIpAddress destination;
+        uint8_t destinationPrefix = 0;
+        uint16_t sourcePortMin = 0;
+        uint16_t sourcePortMax = 0;
+        uint16_t destinationPortMin = 0;
+        uint16_t destinationPortMax = 0;
+    };
+
+    /*
+        One socket of the connection table, see getConnections.
+        Warning: keep this synced with CConnection on interface.rs
+    */
+    struct CConnection
+    {
+        SocketHandle key;
+        //SOCKET_TCP or SOCKET_UDP
+        uint8_t socketType;
+        //TCP_STATE_*
+        uint8_t tcpState;
+        //None type when unbound or on any address, the port is still set
+        CIpEndpoint local;
+        CIpEndpoint remote;
+        //Bytes not acknowledged by the peer yet / not received by us yet
+        size_t sendQueue;
+        size_t recvQueue;
+        uint64_t ageMillis;
+    };
+
     extern "C" void cppDeleteArray(uint8_t *data);
     extern "C" void cppDeletePointer(uint8_t *data);
     extern "C" uint8_t *cpp_allocate_buffer(size_t size);
@@ -122,28 +274,79 @@ namespace smoltcp
     extern "C" SmolStackPtr smol_stack_smol_stack_new_virtual_tun(const char *interfaceName);
     extern "C" SmolStackPtr smol_stack_smol_stack_new_tun(const char *interfaceName);
     extern "C" SmolStackPtr smol_stack_smol_stack_new_tap(const char *interfaceName);
-    extern "C" uint8_t smol_stack_add_socket(SmolStackPtr, uint8_t socketType, SocketHandle socketHandle);
+    extern "C" SmolStackPtr smol_stack_smol_stack_new_callback(uint8_t medium, size_t mtu,
+                                                               uint8_t (*receive)(void *context, uint8_t *buffer, size_t capacity, size_t *len),
+                                                               uint8_t (*transmit)(void *context, const uint8_t *data, size_t len),
+                                                               void *context);
+    extern "C" SmolStackPtr smol_stack_new_from_fd(int fd, uint8_t medium, size_t mtu);
+    extern "C" SmolStackPtr smol_stack_new_from_fd_pair(int readFd, int writeFd, uint8_t medium, size_t mtu);
+    extern "C" SmolStackPtr smol_stack_smol_stack_new_unix_socket(const char *path, const char *peerPath, uint8_t medium, size_t mtu);
+    extern "C" SmolStackPtr smol_stack_smol_stack_new_udp_tunnel(CIpEndpoint local, CIpEndpoint peer, uint8_t medium, size_t mtu);
+    extern "C" SmolStackPtr smol_stack_new_from_config(const char *document, char *error, size_t errorCapacity);
+    extern "C" uint8_t smol_stack_set_ethernet_address(SmolStackPtr, CEthernetAddress);
+    extern "C" uint8_t smol_stack_get_dns_servers(SmolStackPtr, CIpAddress *addresses, size_t capacity, size_t *count);
+    extern "C" uint8_t smol_stack_get_connections(SmolStackPtr, CConnection *connections, size_t capacity, size_t *count);
+    extern "C" uint8_t smol_stack_get_connections_json(SmolStackPtr, CBuffer *cbuffer, uint8_t *(*)(size_t));
+#ifdef SMOL_METRICS
+    //Only exist when the Rust library is built with the metrics feature
+    extern "C" uint8_t smol_stack_get_metrics(SmolStackPtr, CBuffer *cbuffer, uint8_t *(*)(size_t));
+    extern "C" uint8_t smol_stack_serve_metrics(SmolStackPtr, CIpEndpoint address);
+#endif
+    extern "C" void smol_stack_wake(SmolStackPtr);
+    extern "C" uint8_t smol_stack_add_socket(SmolStackPtr, uint8_t socketType, SocketHandle *socketHandle);
+    extern "C" uint8_t smol_stack_remove_socket(SmolStackPtr, SocketHandle socketHandle);
     extern "C" void smol_stack_poll(SmolStackPtr);
-    extern "C" void smol_stack_phy_wait(SmolStackPtr, int64_t timestamp);
+    extern "C" uint8_t smol_stack_phy_wait(SmolStackPtr, int64_t timestamp);
+    extern "C" void smol_stack_set_manual_clock(SmolStackPtr, int64_t startMillis);
+    extern "C" void smol_stack_set_system_clock(SmolStackPtr);
+    extern "C" uint8_t smol_stack_advance_clock(SmolStackPtr, uint64_t millis);
+    extern "C" int64_t smol_stack_now(SmolStackPtr);
+    extern "C" int64_t smol_stack_poll_delay(SmolStackPtr);
     extern "C" void smol_stack_spin(SmolStackPtr, SocketHandle socketHandle);
     extern "C" void smol_stack_spin_all(SmolStackPtr);
     extern "C" uint8_t smol_stack_tcp_connect(SmolStackPtr, SocketHandle socketHandle, CIpAddress, uint16_t src_port, uint16_t dst_port);
     extern "C" uint8_t smol_stack_tcp_connect_ipv4(SmolStackPtr, SocketHandle socketHandle, CIpv4Address, uint16_t src_port, uint16_t dst_port);
+    extern "C" uint8_t smol_stack_tcp_listen(SmolStackPtr, SocketHandle socketHandle, uint16_t port);
+    extern "C" uint8_t smol_stack_udp_bind(SmolStackPtr, SocketHandle socketHandle, uint16_t port);
     extern "C" uint8_t smol_stack_tcp_connect_ipv6(SmolStackPtr, SocketHandle socketHandle, CIpv6Address, uint16_t src_port, uint16_t dst_port);
     extern "C" uint8_t smol_stack_smol_socket_send(SmolStackPtr, SocketHandle socketHandle, const uint8_t *data, size_t len, CIpEndpoint endpoint, void *, uint8_t (*)(void *));
     extern "C" uint8_t smol_stack_smol_socket_send_copy(SmolStackPtr, SocketHandle socketHandle, const uint8_t *data, size_t len, CIpEndpoint endpoint);
     extern "C" uint8_t smol_stack_smol_socket_receive(SmolStackPtr, SocketHandle socketHandle, CBuffer *cbuffer, uint8_t *(*)(size_t));
-    extern "C" uint8_t smol_stack_smol_socket_receive_wait(SmolStackPtr, SocketHandle socketHandle, CBuffer *cbuffer, uint8_t *(*)(size_t), CIpAddress *address);
+    extern "C" uint8_t smol_stack_smol_socket_receive_wait(SmolStackPtr, SocketHandle socketHandle, CBuffer *cbuffer, uint8_t *(*)(size_t), CIpAddress *address, int64_t timeoutMillis);
+    extern "C" uint8_t smol_stack_smol_socket_receive_into(SmolStackPtr, SocketHandle socketHandle, uint8_t *buffer, size_t capacity, size_t *len, CIpAddress *address);
+    extern "C" uint8_t smol_stack_smol_socket_receive_wait_into(SmolStackPtr, SocketHandle socketHandle, uint8_t *buffer, size_t capacity, size_t *len, CIpAddress *address, int64_t timeoutMillis);
+    extern "C" uint8_t smol_stack_smol_socket_cancel_receive(SmolStackPtr, SocketHandle socketHandle);
     extern "C" uint8_t smol_stack_smol_socket_may_send(SmolStackPtr, SocketHandle socketHandle);
-    extern "C" void smol_stack_add_ipv4_address(SmolStackPtr, CIpv4Cidr);
-    extern "C" void smol_stack_add_ipv6_address(SmolStackPtr, CIpv6Cidr);
+    extern "C" uint8_t smol_stack_smol_socket_shutdown_write(SmolStackPtr, SocketHandle socketHandle);
+    extern "C" uint8_t smol_stack_smol_socket_set_option(SmolStackPtr, SocketHandle socketHandle, uint8_t option, uint64_t value);
+    extern "C" uint8_t smol_stack_smol_socket_get_option(SmolStackPtr, SocketHandle socketHandle, uint8_t option, uint64_t *value);
+    extern "C" uint8_t smol_stack_add_ipv4_address(SmolStackPtr, CIpv4Cidr);
+    extern "C" uint8_t smol_stack_add_ipv6_address(SmolStackPtr, CIpv6Cidr);
     extern "C" void smol_stack_add_default_v4_gateway(SmolStackPtr, CIpv4Address);
     extern "C" void smol_stack_add_default_v6_gateway(SmolStackPtr, CIpv6Address);
     extern "C" uint8_t smol_stack_finalize(SmolStackPtr);
     extern "C" uint8_t smol_stack_virtual_tun_send(SmolStackPtr, const uint8_t *data, size_t len);
     extern "C" uint8_t smol_stack_virtual_tun_receive_wait(SmolStackPtr, CBuffer *cbuffer, uint8_t *(*)(size_t));
     extern "C" uint8_t smol_stack_virtual_tun_receive_instantly(SmolStackPtr, CBuffer *cbuffer, uint8_t *(*)(size_t));
+    extern "C" uint8_t smol_stack_virtual_tun_receive_instantly_into(SmolStackPtr, uint8_t *buffer, size_t capacity, size_t *len);
+    extern "C" uint8_t smol_stack_virtual_tun_receive_wait_into(SmolStackPtr, uint8_t *buffer, size_t capacity, size_t *len);
+    extern "C" uint8_t smol_stack_virtual_tun_send_batch(SmolStackPtr, const CBuffer *cbuffers, size_t count, size_t *sent);
+    extern "C" uint8_t smol_stack_virtual_tun_set_ingress_checks(SmolStackPtr, uint8_t checks);
+    extern "C" uint8_t smol_stack_virtual_tun_get_ingress_counters(SmolStackPtr, CIngressCounters *counters);
+    extern "C" uint8_t smol_stack_nat_enable(SmolStackPtr);
+    extern "C" uint8_t smol_stack_nat_set_host_alias(SmolStackPtr, CIpAddress address);
+    extern "C" uint8_t smol_stack_nat_get_flows(SmolStackPtr, size_t *tcp, size_t *udp);
+    extern "C" uint8_t smol_stack_filter_add_rule(SmolStackPtr, CFilterRule rule, uint32_t *id);
+    extern "C" uint8_t smol_stack_filter_remove_rule(SmolStackPtr, uint32_t id);
+    extern "C" uint8_t smol_stack_filter_clear(SmolStackPtr);
+    extern "C" uint8_t smol_stack_filter_set_default(SmolStackPtr, uint8_t direction, uint8_t action);
+    extern "C" uint8_t smol_stack_filter_set_callback(SmolStackPtr, uint8_t (*)(void *context, uint8_t direction, const uint8_t *data, size_t len), void *context);
+    extern "C" uint8_t smol_stack_virtual_tun_receive_batch_into(SmolStackPtr, CBuffer *cbuffers, size_t count, size_t *received);
+    extern "C" uint8_t smol_stack_shutdown(SmolStackPtr, int64_t gracefulTimeoutMillis);
     extern "C" void smol_stack_destroy(void *);
+    extern "C" uint8_t smol_stack_set_queue_limits(SmolStackPtr, uint8_t queue, size_t maxPackets, size_t maxBytes);
+    extern "C" void smol_stack_set_flow_callback(SmolStackPtr, void (*)(void *context, SocketHandle socketHandle, uint8_t event), void *context);
+    extern "C" uint8_t smol_stack_set_log_callback(void (*)(uint8_t level, const char *target, const char *message), uint8_t level);
 
     class RustSlice
     {
@@ -203,11 +406,10 @@ namespace smoltcp
         public:
         using Ptr = std::shared_ptr<TunSmolStack>;
     private:
-        SmolStackPtr smolStackPtr;
+        SmolStackPtr smolStackPtr = nullptr;
         std::random_device rd;
         std::mt19937 mt{rd()};
         std::uniform_int_distribution<int> random{49152, 49152 + 16383};
-        size_t currentHandle = 0;
         std::unordered_map<size_t, SmolSocket> smolSocketHandles;
 
     public:
@@ -232,31 +434,201 @@ namespace smoltcp
             {
                 smolStackPtr = smol_stack_smol_stack_new_tap(interfaceName.c_str());
             }
+            if (smolStackPtr == nullptr)
+            {
+                throw std::runtime_error("can't open interface " + interfaceName + "\n");
+            }
         }
 
-        size_t getNewHandle()
+        /*
+            Stack whose packets are read and written by `receive` and `transmit`,
+            called from poll() with `context`. `receive` copies the next packet
+            (at most `capacity` bytes) to `buffer`, sets `len` and returns
+            SMOL_RESULT_OK, or SMOL_RESULT_NOT_AVAILABLE if there's none.
+            `transmit` returns SMOL_RESULT_OK or SMOL_RESULT_WOULD_BLOCK.
+            Call wake() when the platform has new packets
+        */
+        TunSmolStack(uint8_t medium, size_t mtu,
+                     uint8_t (*receive)(void *context, uint8_t *buffer, size_t capacity, size_t *len),
+                     uint8_t (*transmit)(void *context, const uint8_t *data, size_t len),
+                     void *context)
         {
-            if (currentHandle < std::numeric_limits<size_t>::max())
+            smolStackPtr = smol_stack_smol_stack_new_callback(medium, mtu, receive, transmit, context);
+            if (smolStackPtr == nullptr)
             {
-                currentHandle += 1;
-                return currentHandle;
+                throw std::runtime_error("invalid medium or mtu\n");
             }
-            else
+        }
+
+        /*
+            Stack reading and writing one packet per read/write on `fd`: a TUN/TAP
+            fd opened by the platform or one end of a datagram socketpair. The fd
+            becomes non blocking and is still yours to close, after this is destroyed
+        */
+        TunSmolStack(int fd, uint8_t medium, size_t mtu)
+        {
+            smolStackPtr = smol_stack_new_from_fd(fd, medium, mtu);
+            if (smolStackPtr == nullptr)
+            {
+                throw std::runtime_error("invalid fd, medium or mtu\n");
+            }
+        }
+
+        //Same, but reads from `readFd` and writes to `writeFd`. Pipes must be opened with O_DIRECT, with mtu <= PIPE_BUF
+        TunSmolStack(int readFd, int writeFd, uint8_t medium, size_t mtu)
+        {
+            smolStackPtr = smol_stack_new_from_fd_pair(readFd, writeFd, medium, mtu);
+            if (smolStackPtr == nullptr)
+            {
+                throw std::runtime_error("invalid fd, medium or mtu\n");
+            }
+        }
+
+        /*
+            Stack linked to a stack on another process by a SOCK_DGRAM Unix socket.
+            Binds to `path` and sends to `peerPath`; the other process does the same
+            with both paths swapped. Until the peer is up, sends fail and smoltcp keeps
+            the packets to retry. Throws if `path` is bound by a running process
+        */
+        TunSmolStack(const std::string &path, const std::string &peerPath, uint8_t medium, size_t mtu)
+        {
+            smolStackPtr = smol_stack_smol_stack_new_unix_socket(path.c_str(), peerPath.c_str(), medium, mtu);
+            if (smolStackPtr == nullptr)
+            {
+                throw std::runtime_error("can't bind " + path + "\n");
+            }
+        }
+
+        /*
+            Stack whose packets go inside UDP datagrams from the host address `local`
+            to `peer`, a point to point tunnel for staging and tests (no encryption).
+            The stack on the other side uses the same addresses swapped
+        */
+        TunSmolStack(CIpEndpoint local, CIpEndpoint peer, uint8_t medium, size_t mtu)
+        {
+            smolStackPtr = smol_stack_smol_stack_new_udp_tunnel(local, peer, medium, mtu);
+            if (smolStackPtr == nullptr)
+            {
+                throw std::runtime_error("invalid endpoints, medium or mtu, or can't bind\n");
+            }
+        }
+
+        /*
+            Stack built and finalized from a TOML or JSON configuration document
+            (see config.rs): device, addresses, routes, DNS, socket defaults,
+            pcap and logging. Throws with the reason if the document is invalid
+            or the device can't be opened
+        */
+        explicit TunSmolStack(const std::string &configDocument)
+        {
+            char error[512] = {0};
+            smolStackPtr = smol_stack_new_from_config(configDocument.c_str(), error, sizeof(error));
+            if (smolStackPtr == nullptr)
+            {
+                throw std::runtime_error(std::string(error) + "\n");
+            }
+        }
+
+        //The DNS servers given by the configuration document, if any
+        std::vector<CIpAddress> getDnsServers()
+        {
+            size_t count = 0;
+            smol_stack_get_dns_servers(smolStackPtr, nullptr, 0, &count);
+            std::vector<CIpAddress> addresses(count);
+            if (smol_stack_get_dns_servers(smolStackPtr, addresses.data(), addresses.size(), &count) != SMOL_RESULT_OK)
+            {
+                throw std::runtime_error("could not get the DNS servers\n");
+            }
+            return addresses;
+        }
+
+        /*
+            Every socket with its endpoints, TCP state, queued bytes and
+            age, ordered by key. The stack's equivalent of `ss -tan`
+        */
+        std::vector<CConnection> getConnections()
+        {
+            std::vector<CConnection> connections;
+            size_t count = 0;
+            uint8_t r;
+            //Retries if sockets were added meanwhile
+            do
+            {
+                smol_stack_get_connections(smolStackPtr, nullptr, 0, &count);
+                connections.resize(count);
+                r = smol_stack_get_connections(smolStackPtr, connections.data(), connections.size(), &count);
+            } while (r == SMOL_RESULT_BUFFER_TOO_SMALL);
+            if (r != SMOL_RESULT_OK)
+            {
+                throw std::runtime_error("could not get the connections\n");
+            }
+            connections.resize(count);
+            return connections;
+        }
+
+        //Same as getConnections, as a JSON array
+        std::string getConnectionsJson()
+        {
+            CBuffer cbuffer;
+            if (smol_stack_get_connections_json(smolStackPtr, &cbuffer, &cpp_allocate_buffer) != SMOL_RESULT_OK)
             {
-                throw std::runtime_error("Reached handle too big, you're using too much sockets\n");
+                throw std::runtime_error("could not get the connections\n");
             }
+            Buffer buffer(cbuffer);
+            return std::string(reinterpret_cast<const char *>(buffer.getData()), buffer.len);
+        }
+
+#ifdef SMOL_METRICS
+        //Packet counters, sockets by state, queue depths and poll latency, in the Prometheus text format
+        std::string getMetrics()
+        {
+            CBuffer cbuffer;
+            if (smol_stack_get_metrics(smolStackPtr, &cbuffer, &cpp_allocate_buffer) != SMOL_RESULT_OK)
+            {
+                throw std::runtime_error("could not get the metrics\n");
+            }
+            Buffer buffer(cbuffer);
+            return std::string(reinterpret_cast<const char *>(buffer.getData()), buffer.len);
+        }
+
+        //Serves getMetrics() at http://address/metrics until the stack is destroyed
+        bool serveMetrics(CIpEndpoint address)
+        {
+            return smol_stack_serve_metrics(smolStackPtr, address) == SMOL_RESULT_OK;
+        }
+#endif
+
+        /*
+            Sends every log message from Rust (including smoltcp's) to
+            `logCallback` instead of stdout. It's process wide, not per stack.
+            Pass nullptr to silence logging
+        */
+        static bool setLogCallback(void (*logCallback)(uint8_t level, const char *target, const char *message), uint8_t level)
+        {
+            return smol_stack_set_log_callback(logCallback, level) == 0;
         }
 
+        //The handle is chosen by Rust, keys of removed sockets are never valid again
         SmolSocket addSocket(uint8_t socketType)
         {
-            size_t handle = getNewHandle();
-            uint8_t result = smol_stack_add_socket(smolStackPtr, socketType, handle);
+            SocketHandle handle = 0;
+            uint8_t result = smol_stack_add_socket(smolStackPtr, socketType, &handle);
+            if (result != SMOL_RESULT_OK)
+            {
+                throw std::runtime_error("could not add socket\n");
+            }
             SmolSocket smolSocket;
             smolSocket.handle = handle;
             smolSocketHandles[handle] = smolSocket;
             return smolSocket;
         }
 
+        bool removeSocket(SmolSocket smolSocket)
+        {
+            smolSocketHandles.erase(smolSocket.handle);
+            return smol_stack_remove_socket(smolStackPtr, smolSocket.handle) == SMOL_RESULT_OK;
+        }
+
         void poll()
         {
             smol_stack_poll(smolStackPtr);
@@ -280,11 +652,14 @@ namespace smoltcp
             owns `uint8_t* data`. We also pass the destructor function, which is the function 
             that accepts the `SmolOwner` pointer and deletes it. This function is supposed to
             be called from Rust when it does not need the data `uint8_t* data` anymore.
+            Returns a SMOL_RESULT_*. On SMOL_RESULT_WOULD_BLOCK the socket's queue is full,
+            the destructor is not called and the caller keeps `pointerToSmolOwner`, to send
+            it again after a FLOW_EVENT_SOCKET_WRITABLE or delete it.
         */
         template <typename T>
-        void send(SmolSocket smolSocket, const uint8_t *data, size_t len, CIpEndpoint endpoint, SmolOwner<T> *pointerToSmolOwner, uint8_t (*smolOwnerDestructor)(void *))
+        uint8_t send(SmolSocket smolSocket, const uint8_t *data, size_t len, CIpEndpoint endpoint, SmolOwner<T> *pointerToSmolOwner, uint8_t (*smolOwnerDestructor)(void *))
         {
-            smol_stack_smol_socket_send(smolStackPtr, smolSocket.handle, data, len, endpoint, static_cast<void *>(pointerToSmolOwner), smolOwnerDestructor);
+            return smol_stack_smol_socket_send(smolStackPtr, smolSocket.handle, data, len, endpoint, static_cast<void *>(pointerToSmolOwner), smolOwnerDestructor);
         }
 
         bool send_copy(SmolSocket smolSocket, const uint8_t *data, size_t len, CIpEndpoint endpoint)
@@ -332,19 +707,30 @@ namespace smoltcp
                 auto pair = std::make_pair(buffer, address);
                 return std::optional<decltype(pair)>(pair);
             }
+            else if (r == SMOL_RESULT_END_OF_STREAM)
+            {
+                //Empty buffer means the peer won't send anything else
+                auto pair = std::make_pair(std::make_shared<Buffer>(true), address);
+                return std::optional<decltype(pair)>(pair);
+            }
             else
             {
                 return std::nullopt;
             }
         }
 
-        std::optional<std::pair<std::shared_ptr<Buffer>, CIpAddress>> receiveWait(SmolSocket smolSocket)
+        /*
+            Blocks for at most timeoutMillis (forever if negative). nullopt
+            when it times out, is cancelled (cancelReceive) or the socket is
+            reset or removed; receiveWaitInto tells these apart
+        */
+        std::optional<std::pair<std::shared_ptr<Buffer>, CIpAddress>> receiveWait(SmolSocket smolSocket, int64_t timeoutMillis = -1)
         {
             //std::cout << "receiveWait" << std::endl;    
             CBuffer cbuffer;
             CIpAddress address;
 
-            uint8_t r = smol_stack_smol_socket_receive_wait(smolStackPtr, smolSocket.handle, &cbuffer, &cpp_allocate_buffer, &address);
+            uint8_t r = smol_stack_smol_socket_receive_wait(smolStackPtr, smolSocket.handle, &cbuffer, &cpp_allocate_buffer, &address, timeoutMillis);
             if (r == 0)
             {
                 //printBufferBeggining(cbuffer.data, cbuffer.len, 5);
@@ -355,6 +741,12 @@ namespace smoltcp
                 auto pair = std::make_pair(buffer, address);
                 return std::optional<decltype(pair)>(pair);
             }
+            else if (r == SMOL_RESULT_END_OF_STREAM)
+            {
+                //Empty buffer means the peer won't send anything else
+                auto pair = std::make_pair(std::make_shared<Buffer>(true), address);
+                return std::optional<decltype(pair)>(pair);
+            }
             else
             {
                 return std::nullopt;
@@ -364,12 +756,12 @@ namespace smoltcp
          /*
             Use your own custom allocator. Might be useful specially for ZLMediaKit which requires a buffer terminated with a \0
         */
-        std::optional<std::pair<std::shared_ptr<Buffer>, CIpAddress>> receiveWait(SmolSocket smolSocket, uint8_t *(*custom_allocator)(size_t))
+        std::optional<std::pair<std::shared_ptr<Buffer>, CIpAddress>> receiveWait(SmolSocket smolSocket, uint8_t *(*custom_allocator)(size_t), int64_t timeoutMillis = -1)
         {
             CBuffer cbuffer;
             CIpAddress address;
 
-            uint8_t r = smol_stack_smol_socket_receive_wait(smolStackPtr, smolSocket.handle, &cbuffer, custom_allocator, &address);
+            uint8_t r = smol_stack_smol_socket_receive_wait(smolStackPtr, smolSocket.handle, &cbuffer, custom_allocator, &address, timeoutMillis);
             if (r == 0)
             {
                 //std::cout << "#(" << cbuffer.len << ") - ";
@@ -378,12 +770,59 @@ namespace smoltcp
                 auto pair = std::make_pair(buffer, address);
                 return std::optional<decltype(pair)>(pair);
             }
+            else if (r == SMOL_RESULT_END_OF_STREAM)
+            {
+                //Empty buffer means the peer won't send anything else
+                auto pair = std::make_pair(std::make_shared<Buffer>(true), address);
+                return std::optional<decltype(pair)>(pair);
+            }
             else
             {
                 return std::nullopt;
             }
         }
 
+        /*
+            Receives into `buffer` without allocating. `len` gets the number
+            of bytes written. For TCP, bytes that don't fit are kept for the
+            next call. Returns SMOL_RESULT_END_OF_STREAM when the peer closed,
+            and SMOL_RESULT_BUFFER_TOO_SMALL (with the needed size in `len`)
+            for datagrams larger than `capacity`
+        */
+        uint8_t receiveInto(SmolSocket smolSocket, uint8_t *buffer, size_t capacity, size_t &len)
+        {
+            CIpAddress address;
+            return smol_stack_smol_socket_receive_into(smolStackPtr, smolSocket.handle, buffer, capacity, &len, &address);
+        }
+
+        /*
+            Same as receiveInto but blocks for at most timeoutMillis (forever
+            if negative). Can also return SMOL_RESULT_TIMED_OUT,
+            SMOL_RESULT_CANCELLED or SMOL_RESULT_CLOSED (reset or removed,
+            after what was received is read)
+        */
+        uint8_t receiveWaitInto(SmolSocket smolSocket, uint8_t *buffer, size_t capacity, size_t &len, int64_t timeoutMillis = -1)
+        {
+            CIpAddress address;
+            return smol_stack_smol_socket_receive_wait_into(smolStackPtr, smolSocket.handle, buffer, capacity, &len, &address, timeoutMillis);
+        }
+
+        //Makes every receiveWait/receiveWaitInto blocked on this socket return SMOL_RESULT_CANCELLED
+        bool cancelReceive(SmolSocket smolSocket)
+        {
+            return smol_stack_smol_socket_cancel_receive(smolStackPtr, smolSocket.handle) == SMOL_RESULT_OK;
+        }
+
+        /*
+            Sends FIN after everything already queued with send/send_copy
+            goes out. The socket can still receive; when the peer closes too,
+            receive and receiveWait return an empty Buffer (buffer->empty)
+        */
+        bool shutdownWrite(SmolSocket smolSocket)
+        {
+            return smol_stack_smol_socket_shutdown_write(smolStackPtr, smolSocket.handle) == SMOL_RESULT_OK;
+        }
+
         bool maySend(SmolSocket smolSocket)
         {
             uint8_t r = smol_stack_smol_socket_may_send(smolStackPtr, smolSocket.handle);
@@ -393,6 +832,30 @@ namespace smoltcp
                 return false;
         }
 
+        /*
+            Sets one of the SOCKET_OPTION_* options. Returns one of
+            SMOL_RESULT_*, SMOL_RESULT_UNSUPPORTED when the socket type
+            doesn't have the option (only hop limit applies to UDP)
+        */
+        uint8_t setSocketOption(SmolSocket smolSocket, uint8_t option, uint64_t value)
+        {
+            return smol_stack_smol_socket_set_option(smolStackPtr, smolSocket.handle, option, value);
+        }
+
+        std::optional<uint64_t> getSocketOption(SmolSocket smolSocket, uint8_t option)
+        {
+            uint64_t value = 0;
+            uint8_t r = smol_stack_smol_socket_get_option(smolStackPtr, smolSocket.handle, option, &value);
+            if (r == SMOL_RESULT_OK)
+            {
+                return value;
+            }
+            else
+            {
+                return std::nullopt;
+            }
+        }
+
         bool connect(SmolSocket smolSocket, CIpAddress address, uint16_t src_port, uint16_t dst_port)
         {
             uint8_t r = smol_stack_tcp_connect(smolStackPtr, smolSocket.handle, address, src_port, dst_port);
@@ -437,14 +900,30 @@ namespace smoltcp
             }
         }
 
-        void addIpv4Address(CIpv4Cidr cidr)
+        /*
+            Each socket accepts a single connection, add one listening
+            socket per connection to accept
+        */
+        bool listen(SmolSocket smolSocket, uint16_t port)
+        {
+            return smol_stack_tcp_listen(smolStackPtr, smolSocket.handle, port) == SMOL_RESULT_OK;
+        }
+
+        bool udpBind(SmolSocket smolSocket, uint16_t port)
+        {
+            return smol_stack_udp_bind(smolStackPtr, smolSocket.handle, port) == SMOL_RESULT_OK;
+        }
+
+        //Only before finalize()
+        bool addIpv4Address(CIpv4Cidr cidr)
         {
-            smol_stack_add_ipv4_address(smolStackPtr, cidr);
+            return smol_stack_add_ipv4_address(smolStackPtr, cidr) == SMOL_RESULT_OK;
         }
 
-        void addIpv6Address(CIpv6Cidr cidr)
+        //Only before finalize()
+        bool addIpv6Address(CIpv6Cidr cidr)
         {
-            smol_stack_add_ipv6_address(smolStackPtr, cidr);
+            return smol_stack_add_ipv6_address(smolStackPtr, cidr) == SMOL_RESULT_OK;
         }
 
         void addDefaultV4Gateway(CIpv4Address address)
@@ -457,9 +936,50 @@ namespace smoltcp
             smol_stack_add_default_v6_gateway(smolStackPtr, address);
         }
 
-        void phy_wait(int64_t timestamp)
+        /*
+            Limits one of the QUEUE_* queues, 0 means unlimited. Sends that
+            would go over the limit fail with SMOL_RESULT_WOULD_BLOCK
+            (send_copy returns false) until the flow callback gets the
+            corresponding FLOW_EVENT_*_WRITABLE event
+        */
+        uint8_t setQueueLimits(uint8_t queue, size_t maxPackets, size_t maxBytes)
+        {
+            return smol_stack_set_queue_limits(smolStackPtr, queue, maxPackets, maxBytes);
+        }
+
+        /*
+            The callback runs on Rust's threads (poller or whoever frees space).
+            socketHandle is 0 for FLOW_EVENT_STACK_* events
+        */
+        void setFlowCallback(void (*flowCallback)(void *context, SocketHandle socketHandle, uint8_t event), void *context)
+        {
+            smol_stack_set_flow_callback(smolStackPtr, flowCallback, context);
+        }
+
+        /*
+            Sleeps until the next smoltcp timer is due or something
+            happens: packets arrived (WAKE_REASON_PACKETS), sockets got
+            work (WAKE_REASON_SOCKETS), room was made (WAKE_REASON_ROOM)
+            or wake() was called. Returns the WAKE_REASON_* bits, poll
+            after it whatever they are. timestamp (milliseconds) is only
+            used by stacks that wait on an fd, VirtualTun and callback
+            stacks use the stack's clock
+        */
+        uint8_t phy_wait(int64_t timestamp)
+        {
+            return smol_stack_phy_wait(smolStackPtr, timestamp);
+        }
+
+        //Makes phy_wait() return so the stack is polled
+        void wake()
+        {
+            smol_stack_wake(smolStackPtr);
+        }
+
+        //Needed by MEDIUM_ETHERNET stacks (Tap included), before finalize()
+        bool setEthernetAddress(CEthernetAddress address)
         {
-            smol_stack_phy_wait(smolStackPtr, timestamp);
+            return smol_stack_set_ethernet_address(smolStackPtr, address) == SMOL_RESULT_OK;
         }
 
         int64_t currentTimeMillis()
@@ -467,14 +987,148 @@ namespace smoltcp
             return Instant::now().count();
         }
 
+        /*
+            From now on poll() only sees time move through advanceClock(),
+            and phy_wait() doesn't sleep. Used to replay connections
+            (retransmissions, timeouts) deterministically in tests
+        */
+        void setManualClock(int64_t startMillis)
+        {
+            smol_stack_set_manual_clock(smolStackPtr, startMillis);
+        }
+
+        void setSystemClock()
+        {
+            smol_stack_set_system_clock(smolStackPtr);
+        }
+
+        //Returns SMOL_RESULT_UNSUPPORTED if the clock isn't manual
+        uint8_t advanceClock(uint64_t millis)
+        {
+            return smol_stack_advance_clock(smolStackPtr, millis);
+        }
+
+        //Time as seen by the stack, in milliseconds
+        int64_t now()
+        {
+            return smol_stack_now(smolStackPtr);
+        }
+
+        //Milliseconds until the next timer of the stack, nullopt if there's none
+        std::optional<int64_t> pollDelay()
+        {
+            int64_t delay = smol_stack_poll_delay(smolStackPtr);
+            if (delay < 0)
+                return std::nullopt;
+            return delay;
+        }
+
         uint8_t finalize()
         {
             return smol_stack_finalize(smolStackPtr);
         }
 
-        void virtualTunSend(const uint8_t *data, size_t len)
+        /*
+            Returns SMOL_RESULT_WOULD_BLOCK if QUEUE_PACKETS_FROM_OUTSIDE is full and
+            SMOL_RESULT_INVALID_VALUE if the packet is bigger than the MTU (1500).
+            With ingress checks on, invalid packets get one of SMOL_RESULT_INVALID_IP_VERSION
+            to SMOL_RESULT_NOT_OUR_ADDRESS.
+            Sends and receives of packets must each come from one thread at a time.
+            Breaking change: a send (or receive) made while another thread is in
+            one returns SMOL_RESULT_WOULD_BLOCK, without touching the queue, where
+            it used to corrupt it. Receives that returned only SMOL_RESULT_OK or
+            SMOL_RESULT_NOT_AVAILABLE can now return it too
+        */
+        uint8_t virtualTunSend(const uint8_t *data, size_t len)
+        {
+            return smol_stack_virtual_tun_send(smolStackPtr, data, len);
+        }
+
+        /*
+            INGRESS_CHECK_* bits, 0 (the default) takes any packet. Checks run
+            on the sending thread, so corrupted tunnel traffic is seen at once
+            instead of as poll errors
+        */
+        bool virtualTunSetIngressChecks(uint8_t checks)
+        {
+            return smol_stack_virtual_tun_set_ingress_checks(smolStackPtr, checks) == SMOL_RESULT_OK;
+        }
+
+        //nullopt if the stack isn't VirtualTun or was shut down
+        std::optional<CIngressCounters> virtualTunGetIngressCounters()
+        {
+            CIngressCounters counters = {};
+            if (smol_stack_virtual_tun_get_ingress_counters(smolStackPtr, &counters) != SMOL_RESULT_OK)
+                return std::nullopt;
+            return counters;
+        }
+
+        /*
+            Makes a VirtualTun stack a userspace NAT gateway: TCP and UDP
+            flows sent to addresses that aren't the stack's are relayed
+            through host sockets. Loopback and link-local destinations are
+            refused, the host's loopback is only reachable through
+            setNatHostAlias. Call it before finalize
+        */
+        bool enableNat()
+        {
+            return smol_stack_nat_enable(smolStackPtr) == SMOL_RESULT_OK;
+        }
+
+        //Flows to address reach the host's loopback, like slirp's 10.0.2.2
+        bool setNatHostAlias(CIpAddress address)
+        {
+            return smol_stack_nat_set_host_alias(smolStackPtr, address) == SMOL_RESULT_OK;
+        }
+
+        //Open TCP connections and UDP flows, as a pair
+        std::optional<std::pair<size_t, size_t>> getNatFlows()
+        {
+            size_t tcp, udp;
+            if (smol_stack_nat_get_flows(smolStackPtr, &tcp, &udp) != SMOL_RESULT_OK)
+                return std::nullopt;
+            return std::make_pair(tcp, udp);
+        }
+
+        /*
+            Packet filter of VirtualTun stacks: the first rule that matches a
+            packet decides, then the filter callback if set, then the default
+            action of its direction (FILTER_ACTION_ACCEPT unless changed).
+            Rules can be changed from any thread at any time.
+            Returns the id of the rule, for removeFilterRule
+        */
+        std::optional<uint32_t> addFilterRule(const CFilterRule &rule)
+        {
+            uint32_t id;
+            if (smol_stack_filter_add_rule(smolStackPtr, rule, &id) != SMOL_RESULT_OK)
+                return std::nullopt;
+            return id;
+        }
+
+        bool removeFilterRule(uint32_t id)
+        {
+            return smol_stack_filter_remove_rule(smolStackPtr, id) == SMOL_RESULT_OK;
+        }
+
+        bool clearFilterRules()
+        {
+            return smol_stack_filter_clear(smolStackPtr) == SMOL_RESULT_OK;
+        }
+
+        bool setFilterDefault(uint8_t direction, uint8_t action)
         {
-            smol_stack_virtual_tun_send(smolStackPtr, data, len);
+            return smol_stack_filter_set_default(smolStackPtr, direction, action) == SMOL_RESULT_OK;
+        }
+
+        /*
+            Asked about the packets no rule matched, returns a FILTER_ACTION_*.
+            Runs on the poller thread and must not call back into the stack,
+            except to change the filter (addFilterRule, removeFilterRule...).
+            With NAT enabled, packets that open a flow are asked about twice
+        */
+        bool setFilterCallback(uint8_t (*filterCallback)(void *context, uint8_t direction, const uint8_t *data, size_t len), void *context)
+        {
+            return smol_stack_filter_set_callback(smolStackPtr, filterCallback, context) == SMOL_RESULT_OK;
         }
 
         std::optional<std::shared_ptr<Buffer>> virtualTunReceiveWait()
@@ -509,6 +1163,38 @@ namespace smoltcp
             }
         }
 
+        //Packets are never split, see receiveInto
+        uint8_t virtualTunReceiveInstantlyInto(uint8_t *buffer, size_t capacity, size_t &len)
+        {
+            return smol_stack_virtual_tun_receive_instantly_into(smolStackPtr, buffer, capacity, &len);
+        }
+
+        uint8_t virtualTunReceiveWaitInto(uint8_t *buffer, size_t capacity, size_t &len)
+        {
+            return smol_stack_virtual_tun_receive_wait_into(smolStackPtr, buffer, capacity, &len);
+        }
+
+        /*
+            Many packets per call, waking the poller once. The data is copied, so
+            `cbuffers` stays yours. `sent` tells how many were taken, the
+            rest were refused by a full queue (SMOL_RESULT_WOULD_BLOCK) or the
+            ingress checks (their code, for the first refused packet)
+        */
+        uint8_t virtualTunSendBatch(const CBuffer *cbuffers, size_t count, size_t &sent)
+        {
+            return smol_stack_virtual_tun_send_batch(smolStackPtr, cbuffers, count, &sent);
+        }
+
+        /*
+            Fills up to `count` of your buffers, one packet each. Set each
+            len to the buffer's capacity; it comes back as the packet's size
+            for the first `received` ones
+        */
+        uint8_t virtualTunReceiveBatchInto(CBuffer *cbuffers, size_t count, size_t &received)
+        {
+            return smol_stack_virtual_tun_receive_batch_into(smolStackPtr, cbuffers, count, &received);
+        }
+
         /*
             Smoltcp's thread is responsible for calling the callback
             back with the data once it's ready
@@ -533,6 +1219,20 @@ namespace smoltcp
         }
         */
 
+        /*
+            Closes TCP connections in order for at most gracefulTimeoutMillis
+            (negative aborts them), then drops every socket: queued buffers
+            are released, blocked receives return SMOL_RESULT_CLOSED and
+            phy_wait() returns WAKE_REASON_SHUT_DOWN. Later calls fail with
+            SMOL_RESULT_SHUT_DOWN. Call it from the polling thread or while
+            it's blocked in phy_wait(). The destructor aborts if this wasn't
+            called
+        */
+        uint8_t shutdown(int64_t gracefulTimeoutMillis = -1)
+        {
+            return smol_stack_shutdown(smolStackPtr, gracefulTimeoutMillis);
+        }
+
         ~TunSmolStack()
         {
             std::cout << "TunSmolStack destruction" << std::endl;
@@ -1,22 +1,45 @@
 extern crate rand;
 
+use super::callback_device::{medium_from_u8, CallbackDevice, CReceiveFunction, CTransmitFunction};
+use super::clock::Clock;
+use super::config::StackConfig;
+use super::fd_device::FdDevice;
+use super::filter::{CFilterFunction, FilterRule, PacketFilter};
+use super::ingress::IngressCounters;
+use super::udp_tunnel_device::{socket_address, UdpTunnelDevice};
+use super::unix_socket_device::UnixSocketDevice;
+use super::logging::{self, CLogFunction};
+#[cfg(feature = "metrics")]
+use super::metrics::StackMetrics;
+use super::queue::{CFlowFunction, FlowNotifier, QueueLimits};
+use super::ring::{PacketRing, RING_SLOTS};
+use super::signal::{Signal, WAKE_REASON_SHUT_DOWN};
 use super::smol_stack::SmolSocket;
-use super::smol_stack::{Blob, Packet, SmolStack, SocketType};
+use super::pcap::PacketCapture;
+use super::smol_stack::{Blob, Connection, Packet, SmolStack, SocketBuffers, SocketOption, SocketType};
+use super::smol_stack::{write_address, SMOL_RESULT_BUFFER_TOO_SMALL, SMOL_RESULT_INVALID_VALUE};
+use super::smol_stack::{SMOL_RESULT_ERROR, SMOL_RESULT_NOT_AVAILABLE, SMOL_RESULT_OK};
+use super::smol_stack::{SMOL_RESULT_SHUT_DOWN, SMOL_RESULT_UNSUPPORTED};
 use super::virtual_tun::VirtualTunInterface as VirtualTunDevice;
-use smoltcp::phy::wait as phy_wait;
+use super::virtual_tun::VIRTUAL_TUN_MTU;
+use smoltcp::phy::Medium;
 use smoltcp::phy::TapInterface as TapDevice;
 use smoltcp::phy::TunInterface as TunDevice;
 use smoltcp::phy::TunInterface;
-use smoltcp::socket::{SocketHandle, TcpSocket};
+use smoltcp::socket::{SocketHandle, TcpSocket, TcpState};
+use smoltcp::time::Duration as SmolDuration;
 use smoltcp::time::Instant;
-use smoltcp::wire::{IpAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv6Address};
+use smoltcp::wire::{IpAddress, IpCidr, IpEndpoint, IpProtocol, Ipv4Address, Ipv6Address};
 use std::collections::VecDeque;
 use std::ffi::{c_void, CStr};
+use std::net::{SocketAddr, UdpSocket};
 use std::os::raw::{c_char, c_int};
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
 use std::slice;
 use std::str::{self};
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
 use std::time::Duration;
 
 pub enum SmolSocketType {
@@ -41,76 +64,199 @@ pub enum SmolStackType<'a, 'b: 'a, 'c: 'a + 'b> {
     VirtualTun(SmolStack<'a, 'b, 'c, VirtualTunDevice>),
     Tun(SmolStack<'a, 'b, 'c, TunDevice>),
     Tap(SmolStack<'a, 'b, 'c, TapDevice>),
+    Callback(SmolStack<'a, 'b, 'c, CallbackDevice>),
+    Fd(SmolStack<'a, 'b, 'c, FdDevice>),
+    UnixSocket(SmolStack<'a, 'b, 'c, UnixSocketDevice>),
+    UdpTunnel(SmolStack<'a, 'b, 'c, UdpTunnelDevice>),
+    //What's left after shutdown: every call fails with SMOL_RESULT_SHUT_DOWN
+    ShutDown,
 }
 
-//TODO: erase when confirmed its working
+//Destroying a stack that wasn't shut down aborts everything in order
 impl<'a, 'b: 'a, 'c: 'a + 'b> Drop for SmolStackType<'a, 'b, 'c> {
     fn drop(&mut self) {
-        println!("dropped SmolStackType");
+        self.shutdown(None);
+        debug!("dropped SmolStackType");
     }
 }
 
 impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
     pub fn new_virtual_tun(interface_name: String) -> Box<SmolStackType<'a, 'b, 'c>> {
-        let packets_from_inside = Arc::new(Mutex::new(VecDeque::new()));
-        let packets_from_outside = Arc::new(Mutex::new(VecDeque::new()));
-        let has_data = Arc::new((Mutex::new(()), Condvar::new()));
+        SmolStackType::new_virtual_tun_with_mtu(interface_name, VIRTUAL_TUN_MTU)
+    }
+
+    //The MTU is also the size of each ring slot, so it's allocated RING_SLOTS times on each ring
+    pub fn new_virtual_tun_with_mtu(interface_name: String, mtu: usize) -> Box<SmolStackType<'a, 'b, 'c>> {
+        let packets_from_inside = Arc::new(PacketRing::new(RING_SLOTS, mtu, QueueLimits::unlimited()));
+        let packets_from_outside = Arc::new(PacketRing::new(RING_SLOTS, mtu, QueueLimits::unlimited()));
+        let has_data = Arc::new(Signal::new());
+        let flow_notifier = Arc::new(FlowNotifier::new());
+        let packet_filter = Arc::new(PacketFilter::new());
         let device = VirtualTunDevice::new(
             interface_name.as_str(),
             packets_from_inside.clone(),
             packets_from_outside.clone(),
             has_data.clone(),
+            flow_notifier.clone(),
+            packet_filter.clone(),
         )
         .unwrap();
-        let smol_stack = SmolStack::new(
+        let mut smol_stack = SmolStack::new(
             device,
             None,
             Some(packets_from_inside.clone()),
             Some(packets_from_outside.clone()),
             Some(has_data.clone()),
+            Some(packet_filter),
+            flow_notifier,
         );
+        #[cfg(feature = "metrics")]
+        {
+            let metrics = smol_stack.metrics();
+            smol_stack.device_mut().unwrap().set_metrics(Some(metrics));
+        }
         Box::new(SmolStackType::VirtualTun(smol_stack))
     }
 
-    pub fn new_tun(interface_name: String) -> Box<SmolStackType<'a, 'b, 'c>> {
-        let device = TunDevice::new(interface_name.as_str()).unwrap();
-        let has_data = Arc::new((Mutex::new(()), Condvar::new()));
+    pub fn open_tun(interface_name: &str) -> std::io::Result<Box<SmolStackType<'a, 'b, 'c>>> {
+        let device = TunDevice::new(interface_name)?;
+        let has_data = Arc::new(Signal::with_wake_fd()?);
         let fd = Some(device.as_raw_fd());
         let smol_stack = SmolStack::new(
             device,
-            None,
+            fd,
             None,
             None,
             Some(has_data.clone()),
+            None,
+            Arc::new(FlowNotifier::new()),
         );
-        Box::new(SmolStackType::Tun(smol_stack))
+        Ok(Box::new(SmolStackType::Tun(smol_stack)))
     }
 
-    pub fn new_tap(interface_name: String) -> Box<SmolStackType<'a, 'b, 'c>> {
-        let device = TapDevice::new(interface_name.as_str()).unwrap();
-        let has_data = Arc::new((Mutex::new(()), Condvar::new()));
+    pub fn open_tap(interface_name: &str) -> std::io::Result<Box<SmolStackType<'a, 'b, 'c>>> {
+        let device = TapDevice::new(interface_name)?;
+        let has_data = Arc::new(Signal::with_wake_fd()?);
         let fd = Some(device.as_raw_fd());
         let smol_stack = SmolStack::new(
             device,
+            fd,
+            None,
+            None,
+            Some(has_data.clone()),
+            None,
+            Arc::new(FlowNotifier::new()),
+        );
+        Ok(Box::new(SmolStackType::Tap(smol_stack)))
+    }
+
+    pub fn new_callback(
+        medium: Medium,
+        mtu: usize,
+        receive_function: CReceiveFunction,
+        transmit_function: CTransmitFunction,
+        context: *const c_void,
+    ) -> Box<SmolStackType<'a, 'b, 'c>> {
+        let device = CallbackDevice::new(medium, mtu, receive_function, transmit_function, context);
+        let has_data = Arc::new(Signal::new());
+        let smol_stack = SmolStack::new(
+            device,
+            None,
+            None,
+            None,
+            Some(has_data.clone()),
+            None,
+            Arc::new(FlowNotifier::new()),
+        );
+        Box::new(SmolStackType::Callback(smol_stack))
+    }
+
+    pub fn new_from_fd(
+        read_fd: RawFd,
+        write_fd: RawFd,
+        medium: Medium,
+        mtu: usize,
+    ) -> std::io::Result<Box<SmolStackType<'a, 'b, 'c>>> {
+        let device = FdDevice::new(read_fd, write_fd, medium, mtu)?;
+        let has_data = Arc::new(Signal::with_wake_fd()?);
+        let fd = Some(device.read_fd());
+        let smol_stack = SmolStack::new(
+            device,
+            fd,
+            None,
+            None,
+            Some(has_data.clone()),
+            None,
+            Arc::new(FlowNotifier::new()),
+        );
+        Ok(Box::new(SmolStackType::Fd(smol_stack)))
+    }
+
+    pub fn new_unix_socket(
+        path: &Path,
+        peer_path: &Path,
+        medium: Medium,
+        mtu: usize,
+    ) -> std::io::Result<Box<SmolStackType<'a, 'b, 'c>>> {
+        let device = UnixSocketDevice::new(path, peer_path, medium, mtu)?;
+        let has_data = Arc::new(Signal::with_wake_fd()?);
+        let fd = Some(device.fd());
+        let smol_stack = SmolStack::new(
+            device,
+            fd,
+            None,
+            None,
+            Some(has_data.clone()),
             None,
+            Arc::new(FlowNotifier::new()),
+        );
+        Ok(Box::new(SmolStackType::UnixSocket(smol_stack)))
+    }
+
+    pub fn new_udp_tunnel(
+        local: SocketAddr,
+        peer: SocketAddr,
+        medium: Medium,
+        mtu: usize,
+    ) -> std::io::Result<Box<SmolStackType<'a, 'b, 'c>>> {
+        SmolStackType::new_udp_tunnel_from_socket(UdpSocket::bind(local)?, peer, medium, mtu)
+    }
+
+    pub fn new_udp_tunnel_from_socket(
+        socket: UdpSocket,
+        peer: SocketAddr,
+        medium: Medium,
+        mtu: usize,
+    ) -> std::io::Result<Box<SmolStackType<'a, 'b, 'c>>> {
+        let device = UdpTunnelDevice::from_socket(socket, peer, medium, mtu)?;
+        let has_data = Arc::new(Signal::with_wake_fd()?);
+        let fd = Some(device.fd());
+        let smol_stack = SmolStack::new(
+            device,
+            fd,
             None,
             None,
             Some(has_data.clone()),
+            None,
+            Arc::new(FlowNotifier::new()),
         );
-        Box::new(SmolStackType::Tap(smol_stack))
+        Ok(Box::new(SmolStackType::UdpTunnel(smol_stack)))
     }
 
-    pub fn new_socket_handle_key(&mut self) -> usize {
+    pub fn set_ethernet_address(&mut self, address: CEthernetAddress) -> u8 {
         match self {
-            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
-                smol_stack.new_socket_handle_key()
-            }
-            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.new_socket_handle_key(),
-            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.new_socket_handle_key(),
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.set_ethernet_address(address),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.set_ethernet_address(address),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.set_ethernet_address(address),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.set_ethernet_address(address),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.set_ethernet_address(address),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.set_ethernet_address(address),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.set_ethernet_address(address),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
         }
     }
 
-    pub fn add_socket(&mut self, socket_type: SocketType, socket_handle: usize) -> u8 {
+    pub fn add_socket(&mut self, socket_type: SocketType, socket_handle: &mut usize) -> u8 {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
                 smol_stack.add_socket(socket_type, socket_handle)
@@ -121,6 +267,34 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
             &mut SmolStackType::Tap(ref mut smol_stack) => {
                 smol_stack.add_socket(socket_type, socket_handle)
             }
+            &mut SmolStackType::Callback(ref mut smol_stack) => {
+                smol_stack.add_socket(socket_type, socket_handle)
+            }
+            &mut SmolStackType::Fd(ref mut smol_stack) => {
+                smol_stack.add_socket(socket_type, socket_handle)
+            }
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => {
+                smol_stack.add_socket(socket_type, socket_handle)
+            }
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => {
+                smol_stack.add_socket(socket_type, socket_handle)
+            }
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+        }
+    }
+
+    pub fn remove_socket(&mut self, socket_handle_key: usize) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.remove_socket(socket_handle_key)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.remove_socket(socket_handle_key),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.remove_socket(socket_handle_key),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.remove_socket(socket_handle_key),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.remove_socket(socket_handle_key),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.remove_socket(socket_handle_key),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.remove_socket(socket_handle_key),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
         }
     }
 
@@ -141,6 +315,19 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
             &mut SmolStackType::Tap(ref mut smol_stack) => {
                 smol_stack.tcp_connect_ipv4(socket_handle_key, address, src_port, dst_port)
             }
+            &mut SmolStackType::Callback(ref mut smol_stack) => {
+                smol_stack.tcp_connect_ipv4(socket_handle_key, address, src_port, dst_port)
+            }
+            &mut SmolStackType::Fd(ref mut smol_stack) => {
+                smol_stack.tcp_connect_ipv4(socket_handle_key, address, src_port, dst_port)
+            }
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => {
+                smol_stack.tcp_connect_ipv4(socket_handle_key, address, src_port, dst_port)
+            }
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => {
+                smol_stack.tcp_connect_ipv4(socket_handle_key, address, src_port, dst_port)
+            }
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
         }
     }
 
@@ -161,9 +348,52 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
             &mut SmolStackType::Tap(ref mut smol_stack) => {
                 smol_stack.tcp_connect(socket_handle_key, address, src_port, dst_port)
             }
+            &mut SmolStackType::Callback(ref mut smol_stack) => {
+                smol_stack.tcp_connect(socket_handle_key, address, src_port, dst_port)
+            }
+            &mut SmolStackType::Fd(ref mut smol_stack) => {
+                smol_stack.tcp_connect(socket_handle_key, address, src_port, dst_port)
+            }
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => {
+                smol_stack.tcp_connect(socket_handle_key, address, src_port, dst_port)
+            }
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => {
+                smol_stack.tcp_connect(socket_handle_key, address, src_port, dst_port)
+            }
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
         }
     }
     
+    pub fn tcp_listen(&mut self, socket_handle_key: usize, port: u16) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.tcp_listen(socket_handle_key, port)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.tcp_listen(socket_handle_key, port),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.tcp_listen(socket_handle_key, port),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.tcp_listen(socket_handle_key, port),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.tcp_listen(socket_handle_key, port),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.tcp_listen(socket_handle_key, port),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.tcp_listen(socket_handle_key, port),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+        }
+    }
+
+    pub fn udp_bind(&mut self, socket_handle_key: usize, port: u16) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.udp_bind(socket_handle_key, port)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.udp_bind(socket_handle_key, port),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.udp_bind(socket_handle_key, port),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.udp_bind(socket_handle_key, port),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.udp_bind(socket_handle_key, port),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.udp_bind(socket_handle_key, port),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.udp_bind(socket_handle_key, port),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+        }
+    }
+
     pub fn may_send(
         &mut self,
         socket_handle_key: usize
@@ -178,10 +408,167 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
             &mut SmolStackType::Tap(ref mut smol_stack) => {
                 smol_stack.may_send(socket_handle_key)
             }
+            &mut SmolStackType::Callback(ref mut smol_stack) => {
+                smol_stack.may_send(socket_handle_key)
+            }
+            &mut SmolStackType::Fd(ref mut smol_stack) => {
+                smol_stack.may_send(socket_handle_key)
+            }
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => {
+                smol_stack.may_send(socket_handle_key)
+            }
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => {
+                smol_stack.may_send(socket_handle_key)
+            }
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
         }
     }
     
 
+    pub fn set_socket_option(
+        &mut self,
+        socket_handle_key: usize,
+        option: SocketOption,
+        value: u64,
+    ) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.set_socket_option(socket_handle_key, option, value)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => {
+                smol_stack.set_socket_option(socket_handle_key, option, value)
+            }
+            &mut SmolStackType::Tap(ref mut smol_stack) => {
+                smol_stack.set_socket_option(socket_handle_key, option, value)
+            }
+            &mut SmolStackType::Callback(ref mut smol_stack) => {
+                smol_stack.set_socket_option(socket_handle_key, option, value)
+            }
+            &mut SmolStackType::Fd(ref mut smol_stack) => {
+                smol_stack.set_socket_option(socket_handle_key, option, value)
+            }
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => {
+                smol_stack.set_socket_option(socket_handle_key, option, value)
+            }
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => {
+                smol_stack.set_socket_option(socket_handle_key, option, value)
+            }
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+        }
+    }
+
+    pub fn get_socket_option(
+        &mut self,
+        socket_handle_key: usize,
+        option: SocketOption,
+        value: &mut u64,
+    ) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.get_socket_option(socket_handle_key, option, value)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => {
+                smol_stack.get_socket_option(socket_handle_key, option, value)
+            }
+            &mut SmolStackType::Tap(ref mut smol_stack) => {
+                smol_stack.get_socket_option(socket_handle_key, option, value)
+            }
+            &mut SmolStackType::Callback(ref mut smol_stack) => {
+                smol_stack.get_socket_option(socket_handle_key, option, value)
+            }
+            &mut SmolStackType::Fd(ref mut smol_stack) => {
+                smol_stack.get_socket_option(socket_handle_key, option, value)
+            }
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => {
+                smol_stack.get_socket_option(socket_handle_key, option, value)
+            }
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => {
+                smol_stack.get_socket_option(socket_handle_key, option, value)
+            }
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+        }
+    }
+
+    pub fn set_queue_limits(&mut self, queue: u8, limits: QueueLimits) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.set_queue_limits(queue, limits)
+            }
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.set_queue_limits(queue, limits),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.set_queue_limits(queue, limits),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.set_queue_limits(queue, limits),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.set_queue_limits(queue, limits),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.set_queue_limits(queue, limits),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.set_queue_limits(queue, limits),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+        }
+    }
+
+    pub fn flow_notifier(&mut self) -> Arc<FlowNotifier> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.flow_notifier(),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.flow_notifier(),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.flow_notifier(),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.flow_notifier(),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.flow_notifier(),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.flow_notifier(),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.flow_notifier(),
+            &mut SmolStackType::ShutDown => Arc::new(FlowNotifier::new()),
+        }
+    }
+
+    pub fn clock(&mut self) -> Clock {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.clock(),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.clock(),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.clock(),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.clock(),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.clock(),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.clock(),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.clock(),
+            &mut SmolStackType::ShutDown => Clock::System,
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: Clock) {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.set_clock(clock),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.set_clock(clock),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.set_clock(clock),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.set_clock(clock),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.set_clock(clock),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.set_clock(clock),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.set_clock(clock),
+            &mut SmolStackType::ShutDown => {}
+        }
+    }
+
+    pub fn advance_clock(&mut self, duration: SmolDuration) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.advance_clock(duration),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.advance_clock(duration),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.advance_clock(duration),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.advance_clock(duration),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.advance_clock(duration),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.advance_clock(duration),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.advance_clock(duration),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+        }
+    }
+
+    pub fn poll_delay(&mut self) -> Option<SmolDuration> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.poll_delay(),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.poll_delay(),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.poll_delay(),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.poll_delay(),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.poll_delay(),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.poll_delay(),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.poll_delay(),
+            &mut SmolStackType::ShutDown => None,
+        }
+    }
+
     pub fn get_smol_socket(&mut self, socket_handle_key: usize) -> Option<&mut SmolSocket> {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
@@ -193,6 +580,19 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
             &mut SmolStackType::Tap(ref mut smol_stack) => {
                 smol_stack.get_smol_socket(socket_handle_key)
             }
+            &mut SmolStackType::Callback(ref mut smol_stack) => {
+                smol_stack.get_smol_socket(socket_handle_key)
+            }
+            &mut SmolStackType::Fd(ref mut smol_stack) => {
+                smol_stack.get_smol_socket(socket_handle_key)
+            }
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => {
+                smol_stack.get_smol_socket(socket_handle_key)
+            }
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => {
+                smol_stack.get_smol_socket(socket_handle_key)
+            }
+            &mut SmolStackType::ShutDown => None,
         }
     }
 
@@ -213,22 +613,45 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
             &mut SmolStackType::Tap(ref mut smol_stack) => {
                 smol_stack.tcp_connect_ipv6(socket_handle_key, address, src_port, dst_port)
             }
+            &mut SmolStackType::Callback(ref mut smol_stack) => {
+                smol_stack.tcp_connect_ipv6(socket_handle_key, address, src_port, dst_port)
+            }
+            &mut SmolStackType::Fd(ref mut smol_stack) => {
+                smol_stack.tcp_connect_ipv6(socket_handle_key, address, src_port, dst_port)
+            }
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => {
+                smol_stack.tcp_connect_ipv6(socket_handle_key, address, src_port, dst_port)
+            }
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => {
+                smol_stack.tcp_connect_ipv6(socket_handle_key, address, src_port, dst_port)
+            }
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
         }
     }
 
-    pub fn add_ipv4_address(&mut self, cidr: CIpv4Cidr) {
+    pub fn add_ipv4_address(&mut self, cidr: CIpv4Cidr) -> u8 {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.add_ipv4_address(cidr),
             &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.add_ipv4_address(cidr),
             &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.add_ipv4_address(cidr),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.add_ipv4_address(cidr),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.add_ipv4_address(cidr),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.add_ipv4_address(cidr),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.add_ipv4_address(cidr),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
         }
     }
 
-    pub fn add_ipv6_address(&mut self, cidr: CIpv6Cidr) {
+    pub fn add_ipv6_address(&mut self, cidr: CIpv6Cidr) -> u8 {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.add_ipv6_address(cidr),
             &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.add_ipv6_address(cidr),
             &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.add_ipv6_address(cidr),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.add_ipv6_address(cidr),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.add_ipv6_address(cidr),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.add_ipv6_address(cidr),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.add_ipv6_address(cidr),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
         }
     }
 
@@ -243,6 +666,19 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
             &mut SmolStackType::Tap(ref mut smol_stack) => {
                 smol_stack.add_default_v4_gateway(address)
             }
+            &mut SmolStackType::Callback(ref mut smol_stack) => {
+                smol_stack.add_default_v4_gateway(address)
+            }
+            &mut SmolStackType::Fd(ref mut smol_stack) => {
+                smol_stack.add_default_v4_gateway(address)
+            }
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => {
+                smol_stack.add_default_v4_gateway(address)
+            }
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => {
+                smol_stack.add_default_v4_gateway(address)
+            }
+            &mut SmolStackType::ShutDown => {}
         }
     }
 
@@ -257,6 +693,147 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
             &mut SmolStackType::Tap(ref mut smol_stack) => {
                 smol_stack.add_default_v6_gateway(address)
             }
+            &mut SmolStackType::Callback(ref mut smol_stack) => {
+                smol_stack.add_default_v6_gateway(address)
+            }
+            &mut SmolStackType::Fd(ref mut smol_stack) => {
+                smol_stack.add_default_v6_gateway(address)
+            }
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => {
+                smol_stack.add_default_v6_gateway(address)
+            }
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => {
+                smol_stack.add_default_v6_gateway(address)
+            }
+            &mut SmolStackType::ShutDown => {}
+        }
+    }
+
+    pub fn add_route(&mut self, cidr: IpCidr, via: IpAddress) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.add_route(cidr, via),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.add_route(cidr, via),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.add_route(cidr, via),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.add_route(cidr, via),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.add_route(cidr, via),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.add_route(cidr, via),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.add_route(cidr, via),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+        }
+    }
+
+    pub fn set_dns_servers(&mut self, dns_servers: Vec<IpAddress>) {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.set_dns_servers(dns_servers),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.set_dns_servers(dns_servers),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.set_dns_servers(dns_servers),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.set_dns_servers(dns_servers),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.set_dns_servers(dns_servers),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.set_dns_servers(dns_servers),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.set_dns_servers(dns_servers),
+            &mut SmolStackType::ShutDown => {},
+        }
+    }
+
+    pub fn dns_servers(&mut self) -> Vec<IpAddress> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.dns_servers().to_vec(),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.dns_servers().to_vec(),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.dns_servers().to_vec(),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.dns_servers().to_vec(),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.dns_servers().to_vec(),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.dns_servers().to_vec(),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.dns_servers().to_vec(),
+            &mut SmolStackType::ShutDown => Vec::new(),
+        }
+    }
+
+    //See SmolStack::connections, empty once shut down
+    pub fn connections(&mut self) -> Vec<Connection> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.connections(),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.connections(),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.connections(),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.connections(),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.connections(),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.connections(),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.connections(),
+            &mut SmolStackType::ShutDown => Vec::new(),
+        }
+    }
+
+    pub fn set_socket_buffers(&mut self, socket_buffers: SocketBuffers) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.set_socket_buffers(socket_buffers),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.set_socket_buffers(socket_buffers),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.set_socket_buffers(socket_buffers),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.set_socket_buffers(socket_buffers),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.set_socket_buffers(socket_buffers),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.set_socket_buffers(socket_buffers),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.set_socket_buffers(socket_buffers),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+        }
+    }
+
+    //Only VirtualTun stacks can be captured for now, the others return SMOL_RESULT_UNSUPPORTED
+    pub fn set_capture(&mut self, capture: Option<Arc<PacketCapture>>) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => match smol_stack.device_mut() {
+                Some(device) => {
+                    device.set_capture(capture);
+                    SMOL_RESULT_OK
+                }
+                None => SMOL_RESULT_ERROR,
+            },
+            &mut SmolStackType::Tun(_) => SMOL_RESULT_UNSUPPORTED,
+            &mut SmolStackType::Tap(_) => SMOL_RESULT_UNSUPPORTED,
+            &mut SmolStackType::Callback(_) => SMOL_RESULT_UNSUPPORTED,
+            &mut SmolStackType::Fd(_) => SMOL_RESULT_UNSUPPORTED,
+            &mut SmolStackType::UnixSocket(_) => SMOL_RESULT_UNSUPPORTED,
+            &mut SmolStackType::UdpTunnel(_) => SMOL_RESULT_UNSUPPORTED,
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&mut self) -> Option<Arc<StackMetrics>> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => Some(smol_stack.metrics()),
+            &mut SmolStackType::Tun(ref mut smol_stack) => Some(smol_stack.metrics()),
+            &mut SmolStackType::Tap(ref mut smol_stack) => Some(smol_stack.metrics()),
+            &mut SmolStackType::Callback(ref mut smol_stack) => Some(smol_stack.metrics()),
+            &mut SmolStackType::Fd(ref mut smol_stack) => Some(smol_stack.metrics()),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => Some(smol_stack.metrics()),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => Some(smol_stack.metrics()),
+            &mut SmolStackType::ShutDown => None,
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn render_metrics(&mut self) -> Option<String> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => Some(smol_stack.render_metrics()),
+            &mut SmolStackType::Tun(ref mut smol_stack) => Some(smol_stack.render_metrics()),
+            &mut SmolStackType::Tap(ref mut smol_stack) => Some(smol_stack.render_metrics()),
+            &mut SmolStackType::Callback(ref mut smol_stack) => Some(smol_stack.render_metrics()),
+            &mut SmolStackType::Fd(ref mut smol_stack) => Some(smol_stack.render_metrics()),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => Some(smol_stack.render_metrics()),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => Some(smol_stack.render_metrics()),
+            &mut SmolStackType::ShutDown => None,
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn serve_metrics(&mut self, address: SocketAddr) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.serve_metrics(address),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.serve_metrics(address),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.serve_metrics(address),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.serve_metrics(address),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.serve_metrics(address),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.serve_metrics(address),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.serve_metrics(address),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
         }
     }
 
@@ -265,6 +842,11 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.finalize(),
             &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.finalize(),
             &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.finalize(),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.finalize(),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.finalize(),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.finalize(),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.finalize(),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
         }
     }
 
@@ -273,6 +855,11 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.poll(),
             &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.poll(),
             &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.poll(),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.poll(),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.poll(),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.poll(),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.poll(),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
         }
     }
 
@@ -281,6 +868,11 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.spin(socket_handle),
             &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.spin(socket_handle),
             &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.spin(socket_handle),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.spin(socket_handle),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.spin(socket_handle),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.spin(socket_handle),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.spin(socket_handle),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
         }
     }
 
@@ -289,32 +881,52 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.spin_all(),
             &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.spin_all(),
             &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.spin_all(),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.spin_all(),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.spin_all(),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.spin_all(),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.spin_all(),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
         }
     }
 
-    pub fn phy_wait(&mut self, timestamp: i64) {
+    /*
+        Waits until the stack has to be polled again and returns the
+        WAKE_REASON_* bits of why. `timestamp` is only used by stacks
+        that wait on an fd, the others read their own clock
+    */
+    pub fn phy_wait(&mut self, timestamp: i64) -> u8 {
         match self {
-            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
-                smol_stack.phy_wait_timeout(Duration::from_millis(timestamp as u64))
-            }
-            &mut SmolStackType::Tun(ref mut smol_stack) => phy_wait(
-                smol_stack.fd.unwrap(),
-                smol_stack
-                    .interface
-                    .as_mut()
-                    .unwrap()
-                    .poll_delay(&smol_stack.sockets, Instant::from_millis(timestamp)),
-            )
-            .expect("wait error"),
-            &mut SmolStackType::Tap(ref mut smol_stack) => phy_wait(
-                smol_stack.fd.unwrap(),
-                smol_stack
-                    .interface
-                    .as_mut()
-                    .unwrap()
-                    .poll_delay(&smol_stack.sockets, Instant::from_millis(timestamp)),
-            )
-            .expect("wait error"),
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.phy_wait(),
+            &mut SmolStackType::Tun(ref mut smol_stack) => {
+                smol_stack.phy_wait_fd(Instant::from_millis(timestamp))
+            }
+            &mut SmolStackType::Tap(ref mut smol_stack) => {
+                smol_stack.phy_wait_fd(Instant::from_millis(timestamp))
+            }
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.phy_wait(),
+            &mut SmolStackType::Fd(ref mut smol_stack) => {
+                smol_stack.phy_wait_fd(Instant::from_millis(timestamp))
+            }
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => {
+                smol_stack.phy_wait_fd(Instant::from_millis(timestamp))
+            }
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => {
+                smol_stack.phy_wait_fd(Instant::from_millis(timestamp))
+            }
+            &mut SmolStackType::ShutDown => WAKE_REASON_SHUT_DOWN,
+        }
+    }
+
+    pub fn wake(&mut self) {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.wake(),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.wake(),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.wake(),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.wake(),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.wake(),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.wake(),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.wake(),
+            &mut SmolStackType::ShutDown => {}
         }
     }
 
@@ -325,7 +937,8 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
     ) -> u8 {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.receive_wait(cbuffer, allocate_function),
-            _ => panic!("receive is only for VirtualTun")
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+            _ => SMOL_RESULT_UNSUPPORTED,
             //&mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
             //&mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
         }
@@ -338,36 +951,152 @@ impl<'a, 'b: 'a, 'c: 'a + 'b> SmolStackType<'a, 'b, 'c> {
     ) -> u8 {
         match self {
             &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.receive_instantly(cbuffer, allocate_function),
-            _ => panic!("receive is only for VirtualTun")
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+            _ => SMOL_RESULT_UNSUPPORTED,
             //&mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
             //&mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
         }
     }
 
-    pub fn send(&mut self, blob: Blob) -> u8 {
+    pub fn receive_instantly_into(&mut self, buffer: &mut [u8], len: &mut usize) -> u8 {
         match self {
-            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.send(blob),
-            _ => panic!("send is only for VirtualTun")
-            //&mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
-            //&mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.receive_instantly_into(buffer, len),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+            _ => SMOL_RESULT_UNSUPPORTED,
         }
     }
-}
 
-#[repr(C)]
-pub struct CIpv4Address {
-    pub address: [u8; 4],
-}
-
-#[repr(C)]
-pub struct CIpAddress {
-    pub is_ipv4: u8,
-    pub ipv4_address: CIpv4Address,
-    pub ipv6_address: CIpv6Address,
-
-}
+    pub fn receive_wait_into(&mut self, buffer: &mut [u8], len: &mut usize) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.receive_wait_into(buffer, len),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+            _ => SMOL_RESULT_UNSUPPORTED,
+        }
+    }
 
-impl Into<Ipv4Address> for CIpv4Address {
+    pub fn receive_batch_into(&mut self, buffers: &mut [&mut [u8]], lens: &mut [usize], received: &mut usize) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                smol_stack.receive_batch_into(buffers, lens, received)
+            }
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+            _ => SMOL_RESULT_UNSUPPORTED,
+        }
+    }
+
+    //See SmolStack::set_ingress_checks. Only VirtualTun stacks are sent packets by C++
+    pub fn set_ingress_checks(&mut self, checks: u8) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.set_ingress_checks(checks),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+            _ => SMOL_RESULT_UNSUPPORTED,
+        }
+    }
+
+    pub fn ingress_counters(&mut self) -> Option<&IngressCounters> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => Some(&smol_stack.ingress_checks().counters),
+            _ => None,
+        }
+    }
+
+    //See SmolStack::enable_nat. The NAT needs the packets from outside, so VirtualTun only
+    pub fn enable_nat(&mut self) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => {
+                let receive_budget = Arc::new(AtomicUsize::new(0));
+                let r = smol_stack.enable_nat(receive_budget.clone());
+                if r != SMOL_RESULT_OK {
+                    return r;
+                }
+                match smol_stack.device_mut() {
+                    Some(device) => {
+                        device.set_receive_budget(Some(receive_budget));
+                        SMOL_RESULT_OK
+                    }
+                    None => SMOL_RESULT_ERROR,
+                }
+            }
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+            _ => SMOL_RESULT_UNSUPPORTED,
+        }
+    }
+
+    pub fn set_nat_host_alias(&mut self, host_alias: IpAddress) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.set_nat_host_alias(host_alias),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+            _ => SMOL_RESULT_UNSUPPORTED,
+        }
+    }
+
+    pub fn nat_flows(&mut self) -> Option<(usize, usize)> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.nat_flows(),
+            _ => None,
+        }
+    }
+
+    //The firewall of VirtualTun stacks, see PacketFilter
+    pub fn packet_filter(&mut self) -> Option<Arc<PacketFilter>> {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.packet_filter(),
+            _ => None,
+        }
+    }
+
+    pub fn send_batch(&mut self, packets: &[&[u8]], sent: &mut usize) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.send_batch(packets, sent),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+            _ => SMOL_RESULT_UNSUPPORTED,
+        }
+    }
+
+    /*
+        See SmolStack::shutdown. The stack is dropped, only the
+        ShutDown variant stays
+    */
+    pub fn shutdown(&mut self, graceful: Option<Duration>) -> u8 {
+        let r = match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.shutdown(graceful),
+            &mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.shutdown(graceful),
+            &mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.shutdown(graceful),
+            &mut SmolStackType::Callback(ref mut smol_stack) => smol_stack.shutdown(graceful),
+            &mut SmolStackType::Fd(ref mut smol_stack) => smol_stack.shutdown(graceful),
+            &mut SmolStackType::UnixSocket(ref mut smol_stack) => smol_stack.shutdown(graceful),
+            &mut SmolStackType::UdpTunnel(ref mut smol_stack) => smol_stack.shutdown(graceful),
+            &mut SmolStackType::ShutDown => return SMOL_RESULT_SHUT_DOWN,
+        };
+        *self = SmolStackType::ShutDown;
+        r
+    }
+
+    pub fn send(&mut self, data: &[u8]) -> u8 {
+        match self {
+            &mut SmolStackType::VirtualTun(ref mut smol_stack) => smol_stack.send(data),
+            &mut SmolStackType::ShutDown => SMOL_RESULT_SHUT_DOWN,
+            _ => SMOL_RESULT_UNSUPPORTED,
+            //&mut SmolStackType::Tun(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
+            //&mut SmolStackType::Tap(ref mut smol_stack) => smol_stack.receive(cbuffer, allocate_function),
+        }
+    }
+}
+
+#[repr(C)]
+pub struct CIpv4Address {
+    pub address: [u8; 4],
+}
+
+#[repr(C)]
+pub struct CIpAddress {
+    pub is_ipv4: u8,
+    pub ipv4_address: CIpv4Address,
+    pub ipv6_address: CIpv6Address,
+
+}
+
+impl Into<Ipv4Address> for CIpv4Address {
     fn into(self) -> Ipv4Address {
         Ipv4Address::new(
             self.address[0],
@@ -378,6 +1107,12 @@ impl Into<Ipv4Address> for CIpv4Address {
     }
 }
 
+impl From<Ipv4Address> for CIpv4Address {
+    fn from(address: Ipv4Address) -> CIpv4Address {
+        CIpv4Address { address: address.0 }
+    }
+}
+
 impl Into<IpAddress> for CIpv4Address {
     fn into(self) -> IpAddress {
         IpAddress::v4(
@@ -420,6 +1155,16 @@ impl Into<Ipv6Address> for CIpv6Address {
     }
 }
 
+impl From<Ipv6Address> for CIpv6Address {
+    fn from(address: Ipv6Address) -> CIpv6Address {
+        let mut segments = [0; 8];
+        for (i, segment) in segments.iter_mut().enumerate() {
+            *segment = u16::from_be_bytes([address.0[2 * i], address.0[2 * i + 1]]);
+        }
+        CIpv6Address { address: segments }
+    }
+}
+
 impl Into<IpAddress> for CIpv6Address {
     fn into(self) -> IpAddress {
         IpAddress::v6(
@@ -438,7 +1183,7 @@ impl Into<IpAddress> for CIpv6Address {
 //Warning: keep this synced with CIpEndpointType on interface.h
 static CIpEndpoint_NONE: u8 = 0;
 static CIPENDPOINT_IPV4: u8 = 1;
-static CIPENDPOINT_IPV6: u8 = 0;
+static CIPENDPOINT_IPV6: u8 = 2;
 
 #[repr(C)]
 pub struct CIpEndpoint {
@@ -480,6 +1225,192 @@ impl Into<Option<IpEndpoint>> for CIpEndpoint {
     }
 }
 
+/*
+    Unspecified addresses (unbound, or listening on any address) become
+    CIpEndpoint_NONE, keeping the port
+*/
+impl From<IpEndpoint> for CIpEndpoint {
+    fn from(endpoint: IpEndpoint) -> CIpEndpoint {
+        let mut c_endpoint = CIpEndpoint {
+            endpoint_type: CIpEndpoint_NONE,
+            ipv4: CIpv4Address { address: [0; 4] },
+            ipv6: CIpv6Address { address: [0; 8] },
+            port: endpoint.port,
+        };
+        match endpoint.addr {
+            IpAddress::Ipv4(address) => {
+                c_endpoint.endpoint_type = CIPENDPOINT_IPV4;
+                c_endpoint.ipv4 = address.into();
+            }
+            IpAddress::Ipv6(address) => {
+                c_endpoint.endpoint_type = CIPENDPOINT_IPV6;
+                c_endpoint.ipv6 = address.into();
+            }
+            _ => {}
+        }
+        c_endpoint
+    }
+}
+
+//Warning: keep these synced with the TCP_STATE_* constants on interface.h
+pub const TCP_STATE_CLOSED: u8 = 0;
+pub const TCP_STATE_LISTEN: u8 = 1;
+pub const TCP_STATE_SYN_SENT: u8 = 2;
+pub const TCP_STATE_SYN_RECEIVED: u8 = 3;
+pub const TCP_STATE_ESTABLISHED: u8 = 4;
+pub const TCP_STATE_FIN_WAIT_1: u8 = 5;
+pub const TCP_STATE_FIN_WAIT_2: u8 = 6;
+pub const TCP_STATE_CLOSE_WAIT: u8 = 7;
+pub const TCP_STATE_CLOSING: u8 = 8;
+pub const TCP_STATE_LAST_ACK: u8 = 9;
+pub const TCP_STATE_TIME_WAIT: u8 = 10;
+//Not a TCP socket
+pub const TCP_STATE_NONE: u8 = 255;
+
+fn tcp_state_to_u8(state: Option<TcpState>) -> u8 {
+    match state {
+        Some(TcpState::Closed) => TCP_STATE_CLOSED,
+        Some(TcpState::Listen) => TCP_STATE_LISTEN,
+        Some(TcpState::SynSent) => TCP_STATE_SYN_SENT,
+        Some(TcpState::SynReceived) => TCP_STATE_SYN_RECEIVED,
+        Some(TcpState::Established) => TCP_STATE_ESTABLISHED,
+        Some(TcpState::FinWait1) => TCP_STATE_FIN_WAIT_1,
+        Some(TcpState::FinWait2) => TCP_STATE_FIN_WAIT_2,
+        Some(TcpState::CloseWait) => TCP_STATE_CLOSE_WAIT,
+        Some(TcpState::Closing) => TCP_STATE_CLOSING,
+        Some(TcpState::LastAck) => TCP_STATE_LAST_ACK,
+        Some(TcpState::TimeWait) => TCP_STATE_TIME_WAIT,
+        None => TCP_STATE_NONE,
+    }
+}
+
+/*
+    One row of smol_stack_get_connections, see Connection.
+    `socket_type` is the one given to smol_stack_add_socket
+*/
+#[repr(C)]
+pub struct CConnection {
+    pub key: usize,
+    pub socket_type: u8,
+    pub tcp_state: u8,
+    pub local: CIpEndpoint,
+    pub remote: CIpEndpoint,
+    pub send_queue: usize,
+    pub recv_queue: usize,
+    pub age_millis: u64,
+}
+
+impl From<&Connection> for CConnection {
+    fn from(connection: &Connection) -> CConnection {
+        CConnection {
+            key: connection.key,
+            socket_type: match connection.socket_type {
+                SocketType::TCP => 0,
+                SocketType::UDP => 1,
+                //add_socket creates nothing else
+                _ => 255,
+            },
+            tcp_state: tcp_state_to_u8(connection.tcp_state),
+            local: connection.local.into(),
+            remote: connection.remote.into(),
+            send_queue: connection.send_queue,
+            recv_queue: connection.recv_queue,
+            age_millis: connection.age.total_millis(),
+        }
+    }
+}
+
+//Warning: keep this synced with CIngressCounters on interface.h
+#[repr(C)]
+pub struct CIngressCounters {
+    pub invalid_ip_version: u64,
+    pub invalid_header_length: u64,
+    pub invalid_total_length: u64,
+    pub invalid_checksum: u64,
+    pub not_our_address: u64,
+}
+
+impl From<&IngressCounters> for CIngressCounters {
+    fn from(counters: &IngressCounters) -> CIngressCounters {
+        let load = |counter: &std::sync::atomic::AtomicU64| counter.load(std::sync::atomic::Ordering::Relaxed);
+        CIngressCounters {
+            invalid_ip_version: load(&counters.invalid_ip_version),
+            invalid_header_length: load(&counters.invalid_header_length),
+            invalid_total_length: load(&counters.invalid_total_length),
+            invalid_checksum: load(&counters.invalid_checksum),
+            not_our_address: load(&counters.not_our_address),
+        }
+    }
+}
+
+/*
+    Warning: keep this synced with CFilterRule on interface.h
+    protocol 0 matches any, addresses only count with has_source or
+    has_destination and port ranges with a max of 0 match any port
+*/
+#[repr(C)]
+pub struct CFilterRule {
+    pub direction: u8,
+    pub action: u8,
+    pub protocol: u8,
+    pub has_source: u8,
+    pub source: CIpAddress,
+    pub source_prefix: u8,
+    pub has_destination: u8,
+    pub destination: CIpAddress,
+    pub destination_prefix: u8,
+    pub source_port_min: u16,
+    pub source_port_max: u16,
+    pub destination_port_min: u16,
+    pub destination_port_max: u16,
+}
+
+fn filter_cidr(has_address: u8, address: CIpAddress, prefix: u8) -> Result<Option<IpCidr>, ()> {
+    if has_address == 0 {
+        return Ok(None);
+    }
+    let address: IpAddress = address.into();
+    let max_prefix = match address {
+        IpAddress::Ipv4(_) => 32,
+        _ => 128,
+    };
+    if prefix > max_prefix {
+        return Err(());
+    }
+    Ok(Some(IpCidr::new(address, prefix)))
+}
+
+fn filter_ports(min: u16, max: u16) -> Result<Option<(u16, u16)>, ()> {
+    match (min, max) {
+        (_, 0) => Ok(None),
+        (min, max) if min <= max => Ok(Some((min, max))),
+        _ => Err(()),
+    }
+}
+
+impl CFilterRule {
+    //None if an address prefix or a port range makes no sense
+    fn into_rule(self) -> Option<FilterRule> {
+        Some(FilterRule {
+            direction: self.direction,
+            protocol: match self.protocol {
+                0 => None,
+                protocol => Some(IpProtocol::from(protocol)),
+            },
+            source: filter_cidr(self.has_source, self.source, self.source_prefix).ok()?,
+            destination: filter_cidr(self.has_destination, self.destination, self.destination_prefix).ok()?,
+            source_ports: filter_ports(self.source_port_min, self.source_port_max).ok()?,
+            destination_ports: filter_ports(self.destination_port_min, self.destination_port_max).ok()?,
+            action: self.action,
+        })
+    }
+}
+
+#[repr(C)]
+pub struct CEthernetAddress {
+    pub address: [u8; 6],
+}
+
 #[repr(C)]
 pub struct CIpv4Cidr {
     pub address: CIpv4Address,
@@ -492,36 +1423,430 @@ pub struct CIpv6Cidr {
     pub prefix: u8,
 }
 
+/*
+    C++ may pass a null pointer along with a 0 length for empty buffers,
+    which slice::from_raw_parts doesn't accept. None for a null pointer
+    with a length other than 0
+*/
+unsafe fn slice_from_c<'s>(data: *const u8, len: usize) -> Option<&'s [u8]> {
+    if len == 0 {
+        Some(&[])
+    } else if data.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(data, len))
+    }
+}
+
+unsafe fn slice_from_c_mut<'s>(data: *mut u8, len: usize) -> Option<&'s mut [u8]> {
+    if len == 0 {
+        Some(&mut [])
+    } else if data.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts_mut(data, len))
+    }
+}
+
+//Negative timeouts mean waiting forever
+fn timeout_from_millis(timeout_millis: i64) -> Option<Duration> {
+    if timeout_millis < 0 {
+        None
+    } else {
+        Some(Duration::from_millis(timeout_millis as u64))
+    }
+}
+
+//Returns null if `interface_name` is null
 #[no_mangle]
 pub extern "C" fn smol_stack_smol_stack_new_virtual_tun<'a, 'b: 'a, 'c: 'a + 'b>(
     interface_name: *const c_char,
-) -> Box<SmolStackType<'a, 'b, 'c>> {
+) -> Option<Box<SmolStackType<'a, 'b, 'c>>> {
+    if interface_name.is_null() {
+        return None;
+    }
     let interface_name_c_str: &CStr = unsafe { CStr::from_ptr(interface_name) };
-    let interface_name_slice: &str = interface_name_c_str.to_str().unwrap();
-    let s: String = interface_name_slice.to_owned();
-    SmolStackType::new_virtual_tun(s)
+    let s: String = interface_name_c_str.to_string_lossy().into_owned();
+    Some(SmolStackType::new_virtual_tun(s))
 }
 
+//Returns null if `interface_name` is null or the interface can't be opened
 #[no_mangle]
 pub extern "C" fn smol_stack_smol_stack_new_tun<'a, 'b: 'a, 'c: 'a + 'b>(
     interface_name: *const c_char,
-) -> Box<SmolStackType<'a, 'b, 'c>> {
+) -> Option<Box<SmolStackType<'a, 'b, 'c>>> {
+    if interface_name.is_null() {
+        return None;
+    }
     let interface_name_c_str: &CStr = unsafe { CStr::from_ptr(interface_name) };
-    let interface_name_slice: &str = interface_name_c_str.to_str().unwrap();
-    let s: String = interface_name_slice.to_owned();
-    SmolStackType::new_tun(s)
+    let s: String = interface_name_c_str.to_string_lossy().into_owned();
+    match SmolStackType::open_tun(s.as_str()) {
+        Ok(smol_stack) => Some(smol_stack),
+        Err(err) => {
+            error!("can't open tun {}: {}", s, err);
+            None
+        }
+    }
 }
 
+//Returns null if `interface_name` is null or the interface can't be opened
 #[no_mangle]
 pub extern "C" fn smol_stack_smol_stack_new_tap<'a, 'b: 'a, 'c: 'a + 'b>(
     interface_name: *const c_char,
-) -> Box<SmolStackType<'a, 'b, 'c>> {
+) -> Option<Box<SmolStackType<'a, 'b, 'c>>> {
+    if interface_name.is_null() {
+        return None;
+    }
     let interface_name_c_str: &CStr = unsafe { CStr::from_ptr(interface_name) };
-    let interface_name_slice: &str = interface_name_c_str.to_str().unwrap();
-    let s: String = interface_name_slice.to_owned();
-    SmolStackType::new_tap(s)
+    let s: String = interface_name_c_str.to_string_lossy().into_owned();
+    match SmolStackType::open_tap(s.as_str()) {
+        Ok(smol_stack) => Some(smol_stack),
+        Err(err) => {
+            error!("can't open tap {}: {}", s, err);
+            None
+        }
+    }
+}
+
+/*
+    Stack for a device implemented by C functions, see callback_device.rs.
+    `medium` is one of the MEDIUM_* constants. Ethernet stacks need
+    smol_stack_set_ethernet_address before finalize. Returns null if
+    the medium or the mtu are invalid
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_smol_stack_new_callback<'a, 'b: 'a, 'c: 'a + 'b>(
+    medium: u8,
+    mtu: usize,
+    receive_function: CReceiveFunction,
+    transmit_function: CTransmitFunction,
+    context: *const c_void,
+) -> Option<Box<SmolStackType<'a, 'b, 'c>>> {
+    let medium = medium_from_u8(medium)?;
+    if mtu == 0 {
+        return None;
+    }
+    Some(SmolStackType::new_callback(
+        medium,
+        mtu,
+        receive_function,
+        transmit_function,
+        context,
+    ))
+}
+
+/*
+    Stack whose packets are read from and written to `fd`, one packet per
+    read/write: a TUN/TAP fd opened by the platform, or one end of a
+    datagram socketpair. The fd is made non blocking and is not closed
+    by smol_stack_destroy. Returns null if the medium or the mtu are
+    invalid or the fd can't be used, like stream sockets, which don't
+    keep packet boundaries
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_new_from_fd<'a, 'b: 'a, 'c: 'a + 'b>(
+    fd: c_int,
+    medium: u8,
+    mtu: usize,
+) -> Option<Box<SmolStackType<'a, 'b, 'c>>> {
+    smol_stack_new_from_fd_pair(fd, fd, medium, mtu)
+}
+
+/*
+    Same as smol_stack_new_from_fd, but reading and writing on different
+    fds, like a pair of pipes. Both must keep packet boundaries: pipes
+    must be opened with O_DIRECT and the mtu be at most PIPE_BUF
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_new_from_fd_pair<'a, 'b: 'a, 'c: 'a + 'b>(
+    read_fd: c_int,
+    write_fd: c_int,
+    medium: u8,
+    mtu: usize,
+) -> Option<Box<SmolStackType<'a, 'b, 'c>>> {
+    let medium = medium_from_u8(medium)?;
+    if mtu == 0 || read_fd < 0 || write_fd < 0 {
+        return None;
+    }
+    match SmolStackType::new_from_fd(read_fd, write_fd, medium, mtu) {
+        Ok(smol_stack) => Some(smol_stack),
+        Err(err) => {
+            error!("can't create a stack on fds {} and {}: {}", read_fd, write_fd, err);
+            None
+        }
+    }
 }
 
+/*
+    Stack linked to another process' stack by a SOCK_DGRAM Unix socket,
+    see unix_socket_device.rs. It binds to `path` and sends its packets
+    to `peer_path`, so the other side is created with the two swapped.
+    Returns null if the medium or the mtu are invalid or `path` can't
+    be bound, which includes it being bound by a running process
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_smol_stack_new_unix_socket<'a, 'b: 'a, 'c: 'a + 'b>(
+    path: *const c_char,
+    peer_path: *const c_char,
+    medium: u8,
+    mtu: usize,
+) -> Option<Box<SmolStackType<'a, 'b, 'c>>> {
+    let medium = medium_from_u8(medium)?;
+    if mtu == 0 || path.is_null() || peer_path.is_null() {
+        return None;
+    }
+    let path = unsafe { CStr::from_ptr(path) }.to_string_lossy().into_owned();
+    let peer_path = unsafe { CStr::from_ptr(peer_path) }.to_string_lossy().into_owned();
+    match SmolStackType::new_unix_socket(Path::new(&path), Path::new(&peer_path), medium, mtu) {
+        Ok(smol_stack) => Some(smol_stack),
+        Err(err) => {
+            error!("can't bind {}: {}", path, err);
+            None
+        }
+    }
+}
+
+/*
+    Stack whose packets travel inside UDP datagrams between the host
+    addresses `local` and `peer`, see udp_tunnel_device.rs. The other
+    side is created with the two swapped. Returns null if an endpoint,
+    the medium or the mtu are invalid or `local` can't be bound
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_smol_stack_new_udp_tunnel<'a, 'b: 'a, 'c: 'a + 'b>(
+    local: CIpEndpoint,
+    peer: CIpEndpoint,
+    medium: u8,
+    mtu: usize,
+) -> Option<Box<SmolStackType<'a, 'b, 'c>>> {
+    let medium = medium_from_u8(medium)?;
+    let local: Option<IpEndpoint> = local.into();
+    let peer: Option<IpEndpoint> = peer.into();
+    let local = socket_address(local?)?;
+    let peer = socket_address(peer?)?;
+    if mtu == 0 {
+        return None;
+    }
+    match SmolStackType::new_udp_tunnel(local, peer, medium, mtu) {
+        Ok(smol_stack) => Some(smol_stack),
+        Err(err) => {
+            error!("can't bind {}: {}", local, err);
+            None
+        }
+    }
+}
+
+/*
+    Builds and finalizes a stack from a configuration document, TOML or
+    JSON (a document starting with '{'), see config.rs for its fields.
+    Returns null if the document is invalid or the device can't be
+    opened, with the reason copied to `error` (null terminated and cut to
+    fit `error_capacity` bytes) unless `error` is null
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_new_from_config<'a, 'b: 'a, 'c: 'a + 'b>(
+    document: *const c_char,
+    error: *mut c_char,
+    error_capacity: usize,
+) -> Option<Box<SmolStackType<'a, 'b, 'c>>> {
+    let result = if document.is_null() {
+        Err("the document is null".to_string())
+    } else {
+        match unsafe { CStr::from_ptr(document) }.to_str() {
+            Ok(document) => StackConfig::parse(document)
+                .and_then(|config| config.build())
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(format!("the document is not UTF-8: {}", err)),
+        }
+    };
+    match result {
+        Ok(smol_stack) => Some(smol_stack),
+        Err(message) => {
+            error!("can't create a stack from the configuration: {}", message);
+            if !error.is_null() && error_capacity > 0 {
+                let len = message.len().min(error_capacity - 1);
+                unsafe {
+                    std::ptr::copy_nonoverlapping(message.as_ptr(), error as *mut u8, len);
+                    *error.add(len) = 0;
+                }
+            }
+            None
+        }
+    }
+}
+
+/*
+    Copies the DNS servers the stack was configured with to `addresses`,
+    which holds `capacity` of them, and writes how many there are on
+    `count`. SMOL_RESULT_BUFFER_TOO_SMALL (with nothing copied) if
+    `capacity` is less than that, SMOL_RESULT_INVALID_VALUE if `count`
+    is null
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_get_dns_servers(
+    smol_stack: &mut SmolStackType,
+    addresses: *mut CIpAddress,
+    capacity: usize,
+    count: *mut usize,
+) -> u8 {
+    if let SmolStackType::ShutDown = smol_stack {
+        return SMOL_RESULT_SHUT_DOWN;
+    }
+    if count.is_null() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
+    let dns_servers = smol_stack.dns_servers();
+    unsafe { *count = dns_servers.len() };
+    if dns_servers.len() > capacity {
+        return SMOL_RESULT_BUFFER_TOO_SMALL;
+    }
+    if addresses.is_null() && !dns_servers.is_empty() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
+    for (i, dns_server) in dns_servers.into_iter().enumerate() {
+        write_address(unsafe { addresses.add(i) }, Some(IpEndpoint::new(dns_server, 0)));
+    }
+    SMOL_RESULT_OK
+}
+
+/*
+    Metrics feature only. The stack's metrics in the Prometheus text
+    format, on a buffer allocated with `allocate_function` like
+    smol_stack_virtual_tun_receive_instantly does, owned by C++ after
+*/
+#[cfg(feature = "metrics")]
+#[no_mangle]
+pub extern "C" fn smol_stack_get_metrics(
+    smol_stack: &mut SmolStackType,
+    cbuffer: *mut CBuffer,
+    allocate_function: extern "C" fn(size: usize) -> *mut u8,
+) -> u8 {
+    if cbuffer.is_null() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
+    let text = match smol_stack.render_metrics() {
+        Some(text) => text,
+        None => return SMOL_RESULT_SHUT_DOWN,
+    };
+    let p: *mut u8 = allocate_function(text.len());
+    unsafe {
+        std::ptr::copy(text.as_ptr(), p, text.len());
+        *cbuffer = CBuffer {
+            data: p,
+            len: text.len(),
+        };
+    }
+    SMOL_RESULT_OK
+}
+
+/*
+    Metrics feature only. Serves the metrics for Prometheus at
+    http://`address`/metrics, a host socket, from a thread of its own
+    until the stack is destroyed. Calling it again moves the server.
+    SMOL_RESULT_ERROR if `address` can't be bound
+*/
+#[cfg(feature = "metrics")]
+#[no_mangle]
+pub extern "C" fn smol_stack_serve_metrics(smol_stack: &mut SmolStackType, address: CIpEndpoint) -> u8 {
+    let address: Option<IpEndpoint> = address.into();
+    match address.and_then(socket_address) {
+        Some(address) => smol_stack.serve_metrics(address),
+        None => SMOL_RESULT_INVALID_VALUE,
+    }
+}
+
+/*
+    The connection table, like `ss -tan` for the stack: copies one record
+    per socket, ordered by key, to `connections`, which holds `capacity`
+    of them, and writes how many there are on `count`.
+    SMOL_RESULT_BUFFER_TOO_SMALL (with nothing copied) if `capacity` is
+    less than that, SMOL_RESULT_INVALID_VALUE if `count` is null.
+    Sockets may come and go between a call asking for the count and the
+    next one
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_get_connections(
+    smol_stack: &mut SmolStackType,
+    connections: *mut CConnection,
+    capacity: usize,
+    count: *mut usize,
+) -> u8 {
+    if let SmolStackType::ShutDown = smol_stack {
+        return SMOL_RESULT_SHUT_DOWN;
+    }
+    if count.is_null() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
+    let table = smol_stack.connections();
+    unsafe { *count = table.len() };
+    if table.len() > capacity {
+        return SMOL_RESULT_BUFFER_TOO_SMALL;
+    }
+    if connections.is_null() && !table.is_empty() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
+    for (i, connection) in table.iter().enumerate() {
+        unsafe { *connections.add(i) = connection.into() };
+    }
+    SMOL_RESULT_OK
+}
+
+/*
+    Same table as smol_stack_get_connections, as a JSON array to attach
+    to bug reports, on a buffer allocated with `allocate_function`
+    (not null terminated) that C++ owns after
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_get_connections_json(
+    smol_stack: &mut SmolStackType,
+    cbuffer: *mut CBuffer,
+    allocate_function: extern "C" fn(size: usize) -> *mut u8,
+) -> u8 {
+    if cbuffer.is_null() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
+    if let SmolStackType::ShutDown = smol_stack {
+        return SMOL_RESULT_SHUT_DOWN;
+    }
+    let table: Vec<serde_json::Value> = smol_stack
+        .connections()
+        .iter()
+        .map(|connection| connection.to_json())
+        .collect();
+    let json = serde_json::Value::Array(table).to_string();
+    let p: *mut u8 = allocate_function(json.len());
+    unsafe {
+        std::ptr::copy(json.as_ptr(), p, json.len());
+        *cbuffer = CBuffer {
+            data: p,
+            len: json.len(),
+        };
+    }
+    SMOL_RESULT_OK
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_set_ethernet_address(
+    smol_stack: &mut SmolStackType,
+    address: CEthernetAddress,
+) -> u8 {
+    smol_stack.set_ethernet_address(address)
+}
+
+/*
+    Wakes whoever is in smol_stack_phy_wait, so the stack is polled now.
+    Callback stacks use it to tell that their device has packets
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_wake(smol_stack: &mut SmolStackType) {
+    smol_stack.wake()
+}
+
+/*
+    Takes ownership of `pointer_to_owner`, destructed once the data is
+    sent or on error, except on SMOL_RESULT_WOULD_BLOCK: the queue was
+    full and the owner stays with C++, to send again later
+*/
 #[no_mangle]
 pub extern "C" fn smol_stack_smol_socket_send(
     smol_stack: &mut SmolStackType,
@@ -534,8 +1859,15 @@ pub extern "C" fn smol_stack_smol_socket_send(
 ) -> u8 {
     let smol_socket = smol_stack.get_smol_socket(socket_handle_key);
     //let packet_as_vector = unsafe { Vec::from_raw_parts(data, len, len) };
+    let slice = match unsafe { slice_from_c(data, len) } {
+        Some(slice) => slice,
+        None => {
+            //We own pointer_to_owner from now on, so it's released even on error
+            unsafe { pointer_to_destructor(pointer_to_owner) };
+            return SMOL_RESULT_INVALID_VALUE;
+        }
+    };
     let mut packet_as_vector = Vec::new();
-    let slice = unsafe { slice::from_raw_parts(data, len) };
     packet_as_vector.extend_from_slice(slice);
     let packet = Packet {
         blob: Blob {
@@ -547,10 +1879,7 @@ pub extern "C" fn smol_stack_smol_socket_send(
         endpoint: Into::<Option<IpEndpoint>>::into(endpoint),
     };
     match smol_socket {
-        Some(smol_socket) => {
-            smol_socket.send(packet);
-            0
-        }
+        Some(smol_socket) => smol_socket.send(packet),
         None => 1,
     }
 }
@@ -567,8 +1896,11 @@ pub extern "C" fn smol_stack_smol_socket_send_copy(
     endpoint: CIpEndpoint,
 ) -> u8 {
     let smol_socket = smol_stack.get_smol_socket(socket_handle_key);
+    let slice = match unsafe { slice_from_c(data, len) } {
+        Some(slice) => slice,
+        None => return SMOL_RESULT_INVALID_VALUE,
+    };
     let mut packet_as_vector = Vec::new();
-    let slice = unsafe { slice::from_raw_parts(data, len) };
     packet_as_vector.extend_from_slice(slice);
     let packet = Packet {
         blob: Blob {
@@ -580,10 +1912,7 @@ pub extern "C" fn smol_stack_smol_socket_send_copy(
         endpoint: Into::<Option<IpEndpoint>>::into(endpoint),
     };
     match smol_socket {
-        Some(smol_socket) => {
-            smol_socket.send(packet);
-            0
-        }
+        Some(smol_socket) => smol_socket.send(packet),
         None => 1,
     }
 }
@@ -596,6 +1925,9 @@ pub extern "C" fn smol_stack_smol_socket_receive(
     allocate_function: extern "C" fn(size: usize) -> *mut u8,
     address: *mut CIpAddress
 ) -> u8 {
+    if cbuffer.is_null() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
     let smol_socket = smol_stack.get_smol_socket(socket_handle_key);
     match smol_socket {
         Some(smol_socket) => smol_socket.receive(cbuffer, allocate_function, address),
@@ -603,17 +1935,124 @@ pub extern "C" fn smol_stack_smol_socket_receive(
     }
 }
 
+/*
+    Blocks for at most timeout_millis (forever if negative). Besides the
+    receive results, it returns SMOL_RESULT_TIMED_OUT,
+    SMOL_RESULT_CANCELLED (see smol_stack_smol_socket_cancel_receive)
+    or SMOL_RESULT_CLOSED when the socket is reset or removed meanwhile
+*/
 #[no_mangle]
 pub extern "C" fn smol_stack_smol_socket_receive_wait(
     smol_stack: &mut SmolStackType,
     socket_handle_key: usize,
     cbuffer: *mut CBuffer,
     allocate_function: extern "C" fn(size: usize) -> *mut u8,
-    address: *mut CIpAddress
+    address: *mut CIpAddress,
+    timeout_millis: i64,
+) -> u8 {
+    if cbuffer.is_null() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
+    let smol_socket = smol_stack.get_smol_socket(socket_handle_key);
+    match smol_socket {
+        Some(smol_socket) => smol_socket.receive_wait(
+            cbuffer,
+            allocate_function,
+            address,
+            timeout_from_millis(timeout_millis),
+        ),
+        None => 1,
+    }
+}
+
+/*
+    Receives into a buffer owned by the caller, `len` gets the number of
+    bytes copied. See SmolSocket::receive_into for how leftovers are kept
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_smol_socket_receive_into(
+    smol_stack: &mut SmolStackType,
+    socket_handle_key: usize,
+    buffer: *mut u8,
+    capacity: usize,
+    len: *mut usize,
+    address: *mut CIpAddress,
 ) -> u8 {
     let smol_socket = smol_stack.get_smol_socket(socket_handle_key);
+    let buffer = match unsafe { slice_from_c_mut(buffer, capacity) } {
+        Some(buffer) if !len.is_null() => buffer,
+        _ => return SMOL_RESULT_INVALID_VALUE,
+    };
     match smol_socket {
-        Some(smol_socket) => smol_socket.receive_wait(cbuffer, allocate_function, address),
+        Some(smol_socket) => {
+            let mut endpoint = None;
+            let r = smol_socket.receive_into(buffer, unsafe { &mut *len }, &mut endpoint);
+            if r == SMOL_RESULT_OK {
+                write_address(address, endpoint);
+            }
+            r
+        }
+        None => 1,
+    }
+}
+
+//Same timeout and results as smol_stack_smol_socket_receive_wait
+#[no_mangle]
+pub extern "C" fn smol_stack_smol_socket_receive_wait_into(
+    smol_stack: &mut SmolStackType,
+    socket_handle_key: usize,
+    buffer: *mut u8,
+    capacity: usize,
+    len: *mut usize,
+    address: *mut CIpAddress,
+    timeout_millis: i64,
+) -> u8 {
+    let smol_socket = smol_stack.get_smol_socket(socket_handle_key);
+    let buffer = match unsafe { slice_from_c_mut(buffer, capacity) } {
+        Some(buffer) if !len.is_null() => buffer,
+        _ => return SMOL_RESULT_INVALID_VALUE,
+    };
+    match smol_socket {
+        Some(smol_socket) => {
+            let mut endpoint = None;
+            let r = smol_socket.receive_wait_into(
+                buffer,
+                unsafe { &mut *len },
+                &mut endpoint,
+                timeout_from_millis(timeout_millis),
+            );
+            if r == SMOL_RESULT_OK {
+                write_address(address, endpoint);
+            }
+            r
+        }
+        None => 1,
+    }
+}
+
+//Wakes the threads blocked receiving on this socket with SMOL_RESULT_CANCELLED
+#[no_mangle]
+pub extern "C" fn smol_stack_smol_socket_cancel_receive(
+    smol_stack: &mut SmolStackType,
+    socket_handle_key: usize,
+) -> u8 {
+    match smol_stack.get_smol_socket(socket_handle_key) {
+        Some(smol_socket) => {
+            smol_socket.cancel_receive();
+            SMOL_RESULT_OK
+        }
+        None => SMOL_RESULT_NOT_AVAILABLE,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_smol_socket_shutdown_write(
+    smol_stack: &mut SmolStackType,
+    socket_handle_key: usize,
+) -> u8 {
+    let smol_socket = smol_stack.get_smol_socket(socket_handle_key);
+    match smol_socket {
+        Some(smol_socket) => smol_socket.shutdown_write(),
         None => 1,
     }
 }
@@ -626,21 +2065,146 @@ pub extern "C" fn smol_stack_smol_socket_may_send(
     smol_stack.may_send(socket_handle_key)
 }
 
+#[no_mangle]
+pub extern "C" fn smol_stack_smol_socket_set_option(
+    smol_stack: &mut SmolStackType,
+    socket_handle_key: usize,
+    option: u8,
+    value: u64,
+) -> u8 {
+    match SocketOption::from_u8(option) {
+        Some(option) => smol_stack.set_socket_option(socket_handle_key, option, value),
+        None => SMOL_RESULT_UNSUPPORTED,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_smol_socket_get_option(
+    smol_stack: &mut SmolStackType,
+    socket_handle_key: usize,
+    option: u8,
+    value: *mut u64,
+) -> u8 {
+    if value.is_null() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
+    match SocketOption::from_u8(option) {
+        Some(option) => smol_stack.get_socket_option(socket_handle_key, option, unsafe { &mut *value }),
+        None => SMOL_RESULT_UNSUPPORTED,
+    }
+}
+
+/*
+    The stack picks the key and writes it on `socket_handle_key`.
+    Use it for every other call on this socket
+*/
 #[no_mangle]
 pub extern "C" fn smol_stack_add_socket(
     smol_stack: &mut SmolStackType,
     socket_type: u8,
-    socket_handle: usize,
+    socket_handle_key: *mut usize,
 ) -> u8 {
+    if socket_handle_key.is_null() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
+    let socket_handle_key = unsafe { &mut *socket_handle_key };
     match socket_type {
-        0 => smol_stack.add_socket(SocketType::TCP, socket_handle),
-        1 => smol_stack.add_socket(SocketType::UDP, socket_handle),
-        _ => panic!("wrong type"),
+        0 => smol_stack.add_socket(SocketType::TCP, socket_handle_key),
+        1 => smol_stack.add_socket(SocketType::UDP, socket_handle_key),
+        _ => SMOL_RESULT_UNSUPPORTED,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_remove_socket(
+    smol_stack: &mut SmolStackType,
+    socket_handle_key: usize,
+) -> u8 {
+    smol_stack.remove_socket(socket_handle_key)
+}
+
+/*
+    Limits for one of the QUEUE_* queues (0 means unlimited). Once
+    a limit is hit, the function that would push to that queue returns
+    SMOL_RESULT_WOULD_BLOCK until the matching writable event fires.
+    QUEUE_PACKETS_* never hold more than RING_SLOTS (256) packets
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_set_queue_limits(
+    smol_stack: &mut SmolStackType,
+    queue: u8,
+    max_packets: usize,
+    max_bytes: usize,
+) -> u8 {
+    smol_stack.set_queue_limits(
+        queue,
+        QueueLimits {
+            max_packets: max_packets,
+            max_bytes: max_bytes,
+        },
+    )
+}
+
+/*
+    Called with one of the FLOW_EVENT_* events and the socket key (0 for
+    stack wide events). It runs on whichever thread frees the space or
+    queues the data, so it should be quick
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_set_flow_callback(
+    smol_stack: &mut SmolStackType,
+    flow_function: Option<CFlowFunction>,
+    context: *const c_void,
+) {
+    smol_stack.flow_notifier().set_callback(flow_function, context);
+}
+
+/*
+    Switches the stack to a manual clock starting at start_millis. From
+    then on poll only sees time pass through smol_stack_advance_clock,
+    and phy_wait returns right away instead of sleeping
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_set_manual_clock(smol_stack: &mut SmolStackType, start_millis: i64) {
+    smol_stack.set_clock(Clock::Manual(Instant::from_millis(start_millis)));
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_set_system_clock(smol_stack: &mut SmolStackType) {
+    smol_stack.set_clock(Clock::System);
+}
+
+//SMOL_RESULT_UNSUPPORTED if the stack isn't using a manual clock
+#[no_mangle]
+pub extern "C" fn smol_stack_advance_clock(smol_stack: &mut SmolStackType, millis: u64) -> u8 {
+    smol_stack.advance_clock(SmolDuration::from_millis(millis))
+}
+
+//Current time of the stack's clock, in milliseconds
+#[no_mangle]
+pub extern "C" fn smol_stack_now(smol_stack: &mut SmolStackType) -> i64 {
+    smol_stack.clock().now().total_millis()
+}
+
+/*
+    Milliseconds until the stack needs to be polled again, or -1 if no
+    timer is pending. With a manual clock, advancing by this much and
+    polling runs the next retransmission/timeout
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_poll_delay(smol_stack: &mut SmolStackType) -> i64 {
+    match smol_stack.poll_delay() {
+        Some(delay) => delay.total_millis() as i64,
+        None => -1,
     }
 }
 
+/*
+    Blocks until the stack needs a poll: a timer is due, packets
+    arrived or C++ did something on the stack. Returns WAKE_REASON_* bits
+*/
 #[no_mangle]
-pub extern "C" fn smol_stack_phy_wait(smol_stack: &mut SmolStackType, timestamp: i64) {
+pub extern "C" fn smol_stack_phy_wait(smol_stack: &mut SmolStackType, timestamp: i64) -> u8 {
     smol_stack.phy_wait(timestamp)
 }
 
@@ -655,6 +2219,24 @@ pub extern "C" fn smol_stack_tcp_connect(
     smol_stack.tcp_connect(socket_handle_key, address, src_port, dst_port)
 }
 
+#[no_mangle]
+pub extern "C" fn smol_stack_tcp_listen(
+    smol_stack: &mut SmolStackType,
+    socket_handle_key: usize,
+    port: u16,
+) -> u8 {
+    smol_stack.tcp_listen(socket_handle_key, port)
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_udp_bind(
+    smol_stack: &mut SmolStackType,
+    socket_handle_key: usize,
+    port: u16,
+) -> u8 {
+    smol_stack.udp_bind(socket_handle_key, port)
+}
+
 #[no_mangle]
 pub extern "C" fn smol_stack_tcp_connect_ipv4(
     smol_stack: &mut SmolStackType,
@@ -693,13 +2275,13 @@ pub extern "C" fn smol_stack_spin_all(smol_stack: &mut SmolStackType) -> u8 {
 }
 
 #[no_mangle]
-pub extern "C" fn smol_stack_add_ipv4_address(smol_stack: &mut SmolStackType, cidr: CIpv4Cidr) {
-    smol_stack.add_ipv4_address(cidr);
+pub extern "C" fn smol_stack_add_ipv4_address(smol_stack: &mut SmolStackType, cidr: CIpv4Cidr) -> u8 {
+    smol_stack.add_ipv4_address(cidr)
 }
 
 #[no_mangle]
-pub extern "C" fn smol_stack_add_ipv6_address(smol_stack: &mut SmolStackType, cidr: CIpv6Cidr) {
-    smol_stack.add_ipv6_address(cidr);
+pub extern "C" fn smol_stack_add_ipv6_address(smol_stack: &mut SmolStackType, cidr: CIpv6Cidr) -> u8 {
+    smol_stack.add_ipv6_address(cidr)
 }
 
 #[no_mangle]
@@ -725,6 +2307,27 @@ pub extern "C" fn smol_stack_finalize<'a, 'b: 'a, 'c: 'a + 'b>(
     smol_stack.finalize()
 }
 
+/*
+    Tears the stack down. With graceful_timeout_millis >= 0, TCP
+    connections first send what's queued and close in order, for at most
+    that long (SMOL_RESULT_TIMED_OUT if some didn't make it); with a
+    negative value they're aborted right away. Then every socket is
+    dropped, releasing queued buffers through their destructors, blocked
+    receives return SMOL_RESULT_CLOSED and phy_wait returns
+    WAKE_REASON_SHUT_DOWN. Every later call fails with SMOL_RESULT_SHUT_DOWN, the stack
+    still has to be destroyed. Call it from the thread that polls, or
+    while that thread is blocked in phy_wait, never during other calls
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_shutdown(smol_stack: &mut SmolStackType, graceful_timeout_millis: i64) -> u8 {
+    let graceful = if graceful_timeout_millis < 0 {
+        None
+    } else {
+        Some(Duration::from_millis(graceful_timeout_millis as u64))
+    };
+    smol_stack.shutdown(graceful)
+}
+
 #[no_mangle]
 pub extern "C" fn smol_stack_destroy(_: Option<Box<SmolStackType>>) {}
 
@@ -734,23 +2337,288 @@ pub extern "C" fn smol_stack_virtual_tun_receive_instantly(
     cbuffer: *mut CBuffer,
     allocate_function: extern "C" fn(size: usize) -> *mut u8,
 ) -> u8 {
+    if cbuffer.is_null() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
     smol_stack.receive_instantly(cbuffer, allocate_function)
 }
 
+#[no_mangle]
+pub extern "C" fn smol_stack_virtual_tun_receive_wait(
+    smol_stack: &mut SmolStackType,
+    cbuffer: *mut CBuffer,
+    allocate_function: extern "C" fn(size: usize) -> *mut u8,
+) -> u8 {
+    if cbuffer.is_null() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
+    smol_stack.receive_wait(cbuffer, allocate_function)
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_virtual_tun_receive_instantly_into(
+    smol_stack: &mut SmolStackType,
+    buffer: *mut u8,
+    capacity: usize,
+    len: *mut usize,
+) -> u8 {
+    let buffer = match unsafe { slice_from_c_mut(buffer, capacity) } {
+        Some(buffer) if !len.is_null() => buffer,
+        _ => return SMOL_RESULT_INVALID_VALUE,
+    };
+    smol_stack.receive_instantly_into(buffer, unsafe { &mut *len })
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_virtual_tun_receive_wait_into(
+    smol_stack: &mut SmolStackType,
+    buffer: *mut u8,
+    capacity: usize,
+    len: *mut usize,
+) -> u8 {
+    let buffer = match unsafe { slice_from_c_mut(buffer, capacity) } {
+        Some(buffer) if !len.is_null() => buffer,
+        _ => return SMOL_RESULT_INVALID_VALUE,
+    };
+    smol_stack.receive_wait_into(buffer, unsafe { &mut *len })
+}
+
 #[no_mangle]
 pub extern "C" fn smol_stack_virtual_tun_send(
     smol_stack: &mut SmolStackType,
     data: *mut u8,
     len: usize,
 ) -> u8 {
-    let slice = unsafe { slice::from_raw_parts(data, len) };
-    let mut packet_as_vector = Vec::new();
-    packet_as_vector.extend_from_slice(slice);
-    let blob = Blob {
-        data: packet_as_vector,
-        start: 0,
-        pointer_to_owner: None,
-        pointer_to_destructor: None,
+    let slice = match unsafe { slice_from_c(data, len) } {
+        Some(slice) => slice,
+        None => return SMOL_RESULT_INVALID_VALUE,
     };
-    smol_stack.send(blob)
+    smol_stack.send(slice)
+}
+
+/*
+    Enables the INGRESS_CHECK_* bits on what smol_stack_virtual_tun_send
+    and smol_stack_virtual_tun_send_batch accept (0 disables them, the
+    default). Invalid packets are refused with a SMOL_RESULT_INVALID_*
+    code or SMOL_RESULT_NOT_OUR_ADDRESS and counted. VirtualTun only
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_virtual_tun_set_ingress_checks(smol_stack: &mut SmolStackType, checks: u8) -> u8 {
+    smol_stack.set_ingress_checks(checks)
+}
+
+/*
+    How many packets the ingress checks refused since the stack was
+    created, by reason. SMOL_RESULT_UNSUPPORTED on stacks that aren't
+    VirtualTun, SMOL_RESULT_SHUT_DOWN after smol_stack_shutdown
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_virtual_tun_get_ingress_counters(
+    smol_stack: &mut SmolStackType,
+    counters: *mut CIngressCounters,
+) -> u8 {
+    if let SmolStackType::ShutDown = smol_stack {
+        return SMOL_RESULT_SHUT_DOWN;
+    }
+    if counters.is_null() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
+    match smol_stack.ingress_counters() {
+        Some(ingress_counters) => {
+            unsafe { *counters = ingress_counters.into() };
+            SMOL_RESULT_OK
+        }
+        None => SMOL_RESULT_UNSUPPORTED,
+    }
+}
+
+/*
+    Makes a VirtualTun stack relay the TCP and UDP flows it's sent to
+    other addresses through host sockets, like slirp does for guests.
+    Call it before smol_stack_finalize, it replaces the default gateways
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_nat_enable(smol_stack: &mut SmolStackType) -> u8 {
+    smol_stack.enable_nat()
+}
+
+//Flows to `address` go to the host's own loopback (127.0.0.1 or ::1) instead
+#[no_mangle]
+pub extern "C" fn smol_stack_nat_set_host_alias(smol_stack: &mut SmolStackType, address: CIpAddress) -> u8 {
+    smol_stack.set_nat_host_alias(address.into())
+}
+
+//Open TCP connections and UDP flows of the NAT
+#[no_mangle]
+pub extern "C" fn smol_stack_nat_get_flows(smol_stack: &mut SmolStackType, tcp: *mut usize, udp: *mut usize) -> u8 {
+    if tcp.is_null() || udp.is_null() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
+    match smol_stack.nat_flows() {
+        Some((tcp_flows, udp_flows)) => {
+            unsafe {
+                *tcp = tcp_flows;
+                *udp = udp_flows;
+            }
+            SMOL_RESULT_OK
+        }
+        None => SMOL_RESULT_NOT_AVAILABLE,
+    }
+}
+
+/*
+    Appends a rule to the packet filter of a VirtualTun stack, checked
+    after the ones already there, and writes its id to `id`. Takes effect
+    on the next packet, from any thread
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_filter_add_rule(smol_stack: &mut SmolStackType, rule: CFilterRule, id: *mut u32) -> u8 {
+    if id.is_null() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
+    let filter = match smol_stack.packet_filter() {
+        Some(filter) => filter,
+        None => return SMOL_RESULT_UNSUPPORTED,
+    };
+    match rule.into_rule().and_then(|rule| filter.add_rule(rule)) {
+        Some(rule_id) => {
+            unsafe { *id = rule_id };
+            SMOL_RESULT_OK
+        }
+        None => SMOL_RESULT_INVALID_VALUE,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_filter_remove_rule(smol_stack: &mut SmolStackType, id: u32) -> u8 {
+    match smol_stack.packet_filter() {
+        Some(filter) if filter.remove_rule(id) => SMOL_RESULT_OK,
+        Some(_) => SMOL_RESULT_NOT_AVAILABLE,
+        None => SMOL_RESULT_UNSUPPORTED,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn smol_stack_filter_clear(smol_stack: &mut SmolStackType) -> u8 {
+    match smol_stack.packet_filter() {
+        Some(filter) => {
+            filter.clear_rules();
+            SMOL_RESULT_OK
+        }
+        None => SMOL_RESULT_UNSUPPORTED,
+    }
+}
+
+//What happens to the packets of `direction` no rule matched, FILTER_ACTION_ACCEPT by default
+#[no_mangle]
+pub extern "C" fn smol_stack_filter_set_default(smol_stack: &mut SmolStackType, direction: u8, action: u8) -> u8 {
+    match smol_stack.packet_filter() {
+        Some(filter) if filter.set_default_action(direction, action) => SMOL_RESULT_OK,
+        Some(_) => SMOL_RESULT_INVALID_VALUE,
+        None => SMOL_RESULT_UNSUPPORTED,
+    }
+}
+
+/*
+    Asks `filter_function` about the packets no rule matched, instead of
+    using the default action. It's called on the poller thread and must
+    not call back into the stack, except for the smol_stack_filter_*
+    functions. NULL removes it
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_filter_set_callback(
+    smol_stack: &mut SmolStackType,
+    filter_function: Option<CFilterFunction>,
+    context: *const c_void,
+) -> u8 {
+    match smol_stack.packet_filter() {
+        Some(filter) => {
+            filter.set_callback(filter_function, context);
+            SMOL_RESULT_OK
+        }
+        None => SMOL_RESULT_UNSUPPORTED,
+    }
+}
+
+/*
+    Copies `count` packets, each one a CBuffer that stays owned by the
+    caller, to the stack, waking the poller once. `sent` gets how many
+    were queued; SMOL_RESULT_WOULD_BLOCK means the ring refused the rest
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_virtual_tun_send_batch(
+    smol_stack: &mut SmolStackType,
+    cbuffers: *const CBuffer,
+    count: usize,
+    sent: *mut usize,
+) -> u8 {
+    if (cbuffers.is_null() && count > 0) || sent.is_null() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
+    let cbuffers: &[CBuffer] = if count == 0 {
+        &[]
+    } else {
+        unsafe { slice::from_raw_parts(cbuffers, count) }
+    };
+    let mut packets = Vec::with_capacity(count);
+    for cbuffer in cbuffers.iter() {
+        match unsafe { slice_from_c(cbuffer.data, cbuffer.len) } {
+            Some(slice) => packets.push(slice),
+            None => return SMOL_RESULT_INVALID_VALUE,
+        }
+    }
+    smol_stack.send_batch(&packets, unsafe { &mut *sent })
+}
+
+/*
+    Fills up to `count` caller owned CBuffers with one packet each,
+    without allocations. On input each len is the buffer's
+    capacity, on output the packet's size. `received` gets how many were
+    filled. If the first packet doesn't fit, SMOL_RESULT_BUFFER_TOO_SMALL
+    is returned with the needed size on the first CBuffer's len
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_virtual_tun_receive_batch_into(
+    smol_stack: &mut SmolStackType,
+    cbuffers: *mut CBuffer,
+    count: usize,
+    received: *mut usize,
+) -> u8 {
+    if (cbuffers.is_null() && count > 0) || received.is_null() {
+        return SMOL_RESULT_INVALID_VALUE;
+    }
+    let cbuffers: &mut [CBuffer] = if count == 0 {
+        &mut []
+    } else {
+        unsafe { slice::from_raw_parts_mut(cbuffers, count) }
+    };
+    let mut buffers = Vec::with_capacity(count);
+    for cbuffer in cbuffers.iter() {
+        match unsafe { slice_from_c_mut(cbuffer.data, cbuffer.len) } {
+            Some(buffer) => buffers.push(buffer),
+            None => return SMOL_RESULT_INVALID_VALUE,
+        }
+    }
+    let mut lens: Vec<usize> = cbuffers.iter().map(|cbuffer| cbuffer.len).collect();
+    let received = unsafe { &mut *received };
+    let r = smol_stack.receive_batch_into(&mut buffers, &mut lens, received);
+    //On BUFFER_TOO_SMALL nothing was received but lens[0] has the needed size
+    let written = if r == SMOL_RESULT_BUFFER_TOO_SMALL { 1 } else { *received };
+    for (cbuffer, len) in cbuffers.iter_mut().zip(lens.iter()).take(written) {
+        cbuffer.len = *len;
+    }
+    r
+}
+
+/*
+    Routes this library's logs (and smoltcp's) to the host application.
+    Passing a null function removes the callback. See logging.rs for
+    the level numbering and return codes
+*/
+#[no_mangle]
+pub extern "C" fn smol_stack_set_log_callback(
+    log_function: Option<CLogFunction>,
+    level: u8,
+) -> u8 {
+    logging::set_log_function(log_function, level)
 }
@@ -0,0 +1,86 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Once, RwLock};
+
+/*
+    Logging callback provided by the host application. Receives the
+    level (1 = error, 2 = warn, 3 = info, 4 = debug, 5 = trace, same
+    numbering as log::Level), the target (usually the module path,
+    smoltcp's own messages start with "smoltcp::") and the message.
+    Both strings are null terminated and only valid during the call
+*/
+pub type CLogFunction = extern "C" fn(level: u8, target: *const c_char, message: *const c_char);
+
+static LOG_FUNCTION: RwLock<Option<CLogFunction>> = RwLock::new(None);
+static INSTALL_LOGGER: Once = Once::new();
+static LOGGER_INSTALLED: AtomicBool = AtomicBool::new(false);
+static LOGGER: CLogger = CLogger;
+
+/*
+    log::Log implementation that forwards every record (ours and
+    smoltcp's) to the C callback, if there's one
+*/
+struct CLogger;
+
+impl Log for CLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level() && LOG_FUNCTION.read().unwrap().is_some()
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() > log::max_level() {
+            return;
+        }
+        let log_function = match *LOG_FUNCTION.read().unwrap() {
+            Some(log_function) => log_function,
+            None => return,
+        };
+        //CString::new fails on interior null bytes, so we strip them
+        let target = CString::new(record.target().replace('\0', "")).unwrap();
+        let message = CString::new(format!("{}", record.args()).replace('\0', "")).unwrap();
+        log_function(record.level() as u8, target.as_ptr(), message.as_ptr());
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn level_filter_from_u8(level: u8) -> Option<LevelFilter> {
+    match level {
+        0 => Some(LevelFilter::Off),
+        1 => Some(LevelFilter::Error),
+        2 => Some(LevelFilter::Warn),
+        3 => Some(LevelFilter::Info),
+        4 => Some(LevelFilter::Debug),
+        5 => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+/*
+    Installs (or replaces, or removes if None) the C logging callback.
+    The log facade only accepts one logger per process, so the first
+    call registers CLogger and the next ones just swap the callback.
+    Returns 0 on success, 1 if the level is invalid and 2 if another
+    logger was already registered by someone else (for example by the
+    Rust application embedding us), in which case only the level is applied
+*/
+pub fn set_log_function(log_function: Option<CLogFunction>, level: u8) -> u8 {
+    let level_filter = match level_filter_from_u8(level) {
+        Some(level_filter) => level_filter,
+        None => return 1,
+    };
+    INSTALL_LOGGER.call_once(|| {
+        if log::set_logger(&LOGGER).is_ok() {
+            LOGGER_INSTALLED.store(true, Ordering::SeqCst);
+        }
+    });
+    *LOG_FUNCTION.write().unwrap() = log_function;
+    log::set_max_level(level_filter);
+    if LOGGER_INSTALLED.load(Ordering::SeqCst) {
+        0
+    } else {
+        2
+    }
+}
@@ -0,0 +1,387 @@
+use smoltcp::socket::TcpState;
+use std::fmt::Write;
+use std::io::{self, Read, Write as IoWrite};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+//How often the server thread checks if it must stop, also the longest MetricsServer's drop waits
+const METRICS_STOP_CHECK_MILLIS: libc::c_int = 100;
+
+//Upper bounds of the poll duration histogram buckets, in seconds
+const POLL_DURATION_BUCKETS: [f64; 8] = [0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.1];
+
+//Every TcpState, in the order of the `state` label values
+const TCP_STATES: [(TcpState, &str); 11] = [
+    (TcpState::Closed, "closed"),
+    (TcpState::Listen, "listen"),
+    (TcpState::SynSent, "syn_sent"),
+    (TcpState::SynReceived, "syn_received"),
+    (TcpState::Established, "established"),
+    (TcpState::FinWait1, "fin_wait_1"),
+    (TcpState::FinWait2, "fin_wait_2"),
+    (TcpState::CloseWait, "close_wait"),
+    (TcpState::Closing, "closing"),
+    (TcpState::LastAck, "last_ack"),
+    (TcpState::TimeWait, "time_wait"),
+];
+
+/*
+    `queue` label values, indexed by the QUEUE_* constants. Socket queues
+    are summed over every socket
+*/
+const METRICS_QUEUES: [&str; 4] = [
+    "socket_to_send",
+    "socket_received",
+    "packets_from_inside",
+    "packets_from_outside",
+];
+
+//Traffic of one VirtualTun ring, counted when packets are queued on it
+#[derive(Default)]
+pub struct RingCounters {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    //Refused because the ring was full, or because the packet was bigger than the MTU
+    dropped_full: AtomicU64,
+    dropped_too_big: AtomicU64,
+}
+
+impl RingCounters {
+    pub fn queued(&self, len: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn dropped_full(&self) {
+        self.dropped_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped_too_big(&self) {
+        self.dropped_too_big.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/*
+    What the poller saw last time it looked at the sockets and queues.
+    Counting sockets means walking all of them, so the stack only does
+    it every so often (see SmolStack::snapshot_metrics) instead of on
+    each poll
+*/
+#[derive(Clone, Default)]
+pub struct Gauges {
+    //Indexed like TCP_STATES
+    pub tcp_sockets: [u64; 11],
+    pub udp_sockets: u64,
+    //Indexed by the QUEUE_* constants, (packets, bytes)
+    pub queues: [(u64, u64); 4],
+}
+
+impl Gauges {
+    pub fn count_tcp_socket(&mut self, state: TcpState) {
+        if let Some(i) = TCP_STATES.iter().position(|&(s, _)| s == state) {
+            self.tcp_sockets[i] += 1;
+        }
+    }
+}
+
+/*
+    Counters of one stack, shared by the poller, the threads calling the
+    VirtualTun functions and whoever renders them, all without locking
+    the stack. Rendered in the Prometheus text exposition format
+*/
+#[derive(Default)]
+pub struct StackMetrics {
+    pub from_outside: RingCounters,
+    pub from_inside: RingCounters,
+    polls: AtomicU64,
+    poll_errors: AtomicU64,
+    //Not cumulative, render() adds them up
+    poll_duration_buckets: [AtomicU64; 8],
+    poll_duration_nanos: AtomicU64,
+    gauges: Mutex<Gauges>,
+}
+
+impl StackMetrics {
+    pub fn new() -> StackMetrics {
+        StackMetrics::default()
+    }
+
+    pub fn poll_done(&self, duration: Duration, ok: bool) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.poll_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = POLL_DURATION_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.poll_duration_buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.poll_duration_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_gauges(&self, gauges: Gauges) {
+        *self.gauges.lock().unwrap() = gauges;
+    }
+
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        //Writing to a String never fails
+        let t = &mut text;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let directions = [("from_outside", &self.from_outside), ("from_inside", &self.from_inside)];
+        //Each family is one block, its header and then all of its samples
+        header(t, "smol_packets_total", "counter", "Packets queued on the VirtualTun rings");
+        for &(direction, counters) in directions.iter() {
+            writeln!(t, "smol_packets_total{{direction=\"{}\"}} {}", direction, load(&counters.packets)).unwrap();
+        }
+        header(t, "smol_bytes_total", "counter", "Bytes queued on the VirtualTun rings");
+        for &(direction, counters) in directions.iter() {
+            writeln!(t, "smol_bytes_total{{direction=\"{}\"}} {}", direction, load(&counters.bytes)).unwrap();
+        }
+        header(
+            t,
+            "smol_packets_dropped_total",
+            "counter",
+            "Packets refused by the VirtualTun rings",
+        );
+        for &(direction, counters) in directions.iter() {
+            for &(reason, counter) in [("full", &counters.dropped_full), ("too_big", &counters.dropped_too_big)].iter() {
+                writeln!(
+                    t,
+                    "smol_packets_dropped_total{{direction=\"{}\",reason=\"{}\"}} {}",
+                    direction,
+                    reason,
+                    load(counter)
+                )
+                .unwrap();
+            }
+        }
+        header(t, "smol_polls_total", "counter", "Calls to poll");
+        writeln!(t, "smol_polls_total {}", self.polls.load(Ordering::Relaxed)).unwrap();
+        header(t, "smol_poll_errors_total", "counter", "Calls to poll that returned an error");
+        writeln!(t, "smol_poll_errors_total {}", self.poll_errors.load(Ordering::Relaxed)).unwrap();
+        header(t, "smol_poll_duration_seconds", "histogram", "Time spent in poll");
+        let mut cumulative = 0;
+        for (bound, bucket) in POLL_DURATION_BUCKETS.iter().zip(self.poll_duration_buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            writeln!(t, "smol_poll_duration_seconds_bucket{{le=\"{}\"}} {}", bound, cumulative).unwrap();
+        }
+        let polls = self.polls.load(Ordering::Relaxed);
+        writeln!(t, "smol_poll_duration_seconds_bucket{{le=\"+Inf\"}} {}", polls).unwrap();
+        let nanos = self.poll_duration_nanos.load(Ordering::Relaxed);
+        writeln!(t, "smol_poll_duration_seconds_sum {}", nanos as f64 / 1e9).unwrap();
+        writeln!(t, "smol_poll_duration_seconds_count {}", polls).unwrap();
+        let gauges = self.gauges.lock().unwrap().clone();
+        header(t, "smol_tcp_sockets", "gauge", "TCP sockets by state");
+        for (&(_, state), count) in TCP_STATES.iter().zip(gauges.tcp_sockets.iter()) {
+            writeln!(t, "smol_tcp_sockets{{state=\"{}\"}} {}", state, count).unwrap();
+        }
+        header(t, "smol_udp_sockets", "gauge", "UDP sockets");
+        writeln!(t, "smol_udp_sockets {}", gauges.udp_sockets).unwrap();
+        header(t, "smol_queue_packets", "gauge", "Packets waiting on each queue");
+        for (queue, &(packets, _)) in METRICS_QUEUES.iter().zip(gauges.queues.iter()) {
+            writeln!(t, "smol_queue_packets{{queue=\"{}\"}} {}", queue, packets).unwrap();
+        }
+        header(t, "smol_queue_bytes", "gauge", "Bytes waiting on each queue");
+        for (queue, &(_, bytes)) in METRICS_QUEUES.iter().zip(gauges.queues.iter()) {
+            writeln!(t, "smol_queue_bytes{{queue=\"{}\"}} {}", queue, bytes).unwrap();
+        }
+        text
+    }
+}
+
+fn header(text: &mut String, name: &str, metric_type: &str, help: &str) {
+    writeln!(text, "# HELP {} {}", name, help).unwrap();
+    writeln!(text, "# TYPE {} {}", name, metric_type).unwrap();
+}
+
+/*
+    Serves StackMetrics::render() over HTTP on a host socket, for
+    Prometheus to scrape at /metrics. Requests are answered one at a time
+    on its own thread, which stops when this is dropped
+*/
+pub struct MetricsServer {
+    address: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    //Port 0 picks a free port, see address()
+    pub fn start(metrics: Arc<StackMetrics>, address: SocketAddr) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        //Never blocks in accept, so the thread sees `stop` in time
+        listener.set_nonblocking(true)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Ordering::SeqCst) {
+                let mut pollfd = libc::pollfd {
+                    fd: listener.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                if unsafe { libc::poll(&mut pollfd, 1, METRICS_STOP_CHECK_MILLIS) } <= 0 {
+                    continue;
+                }
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        //Accepted sockets inherit non blocking mode on some systems
+                        let r = stream.set_nonblocking(false).and_then(|_| respond(&metrics, stream));
+                        if let Err(err) = r {
+                            debug!("metrics request failed: {}", err);
+                        }
+                    }
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(err) => debug!("metrics accept failed: {}", err),
+                }
+            }
+        });
+        Ok(MetricsServer {
+            address: address,
+            stop: stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        //The thread sees it within METRICS_STOP_CHECK_MILLIS, or once the request it's answering is done
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn respond(metrics: &StackMetrics, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    //Only the request line matters, the rest of the request is ignored
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) | (Some("GET"), Some("/")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "not found, try /metrics\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::interface::SmolStackType;
+    use super::super::queue::QUEUE_SOCKET_TO_SEND;
+    use super::super::smol_stack::{SMOL_RESULT_INVALID_VALUE, SMOL_RESULT_OK};
+    use super::super::virtual_tun::VIRTUAL_TUN_MTU;
+    use super::*;
+
+    #[test]
+    fn renders_counters_histogram_and_gauges() {
+        let metrics = StackMetrics::new();
+        metrics.from_outside.queued(60);
+        metrics.from_outside.queued(40);
+        metrics.from_inside.dropped_too_big();
+        metrics.poll_done(Duration::from_micros(20), true);
+        metrics.poll_done(Duration::from_secs(1), false);
+        let mut gauges = Gauges::default();
+        gauges.count_tcp_socket(TcpState::Established);
+        gauges.count_tcp_socket(TcpState::Established);
+        gauges.queues[QUEUE_SOCKET_TO_SEND as usize] = (3, 300);
+        metrics.set_gauges(gauges);
+        let text = metrics.render();
+        for line in &[
+            "smol_packets_total{direction=\"from_outside\"} 2",
+            "smol_bytes_total{direction=\"from_outside\"} 100",
+            "smol_packets_dropped_total{direction=\"from_inside\",reason=\"too_big\"} 1",
+            "smol_polls_total 2",
+            "smol_poll_errors_total 1",
+            "smol_poll_duration_seconds_bucket{le=\"0.00001\"} 0",
+            "smol_poll_duration_seconds_bucket{le=\"0.00005\"} 1",
+            "smol_poll_duration_seconds_bucket{le=\"0.1\"} 1",
+            "smol_poll_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "smol_tcp_sockets{state=\"established\"} 2",
+            "smol_tcp_sockets{state=\"listen\"} 0",
+            "smol_queue_packets{queue=\"socket_to_send\"} 3",
+            "smol_queue_bytes{queue=\"socket_to_send\"} 300",
+        ] {
+            assert!(text.lines().any(|l| l == *line), "missing {}", line);
+        }
+        //Every line of a family is in one block, right after its header
+        let mut families: Vec<&str> = Vec::new();
+        for line in text.lines().filter(|l| !l.starts_with('#')) {
+            let name = line.split(|c| c == '{' || c == ' ').next().unwrap();
+            let family = ["_bucket", "_sum", "_count"]
+                .iter()
+                .find(|suffix| name.starts_with("smol_poll_duration_seconds") && name.ends_with(*suffix))
+                .map_or(name, |suffix| &name[..name.len() - suffix.len()]);
+            if families.last() != Some(&family) {
+                assert!(!families.contains(&family), "{} is split", family);
+                families.push(family);
+            }
+        }
+        for family in families {
+            assert!(text.contains(&format!("# TYPE {} ", family)));
+        }
+    }
+
+    #[test]
+    fn virtual_tun_stacks_count_their_packets() {
+        let mut smol_stack = SmolStackType::new_virtual_tun("tun0".to_owned());
+        assert_eq!(smol_stack.finalize(), SMOL_RESULT_OK);
+        assert_eq!(smol_stack.send(&[0x45, 0, 0, 20]), SMOL_RESULT_OK);
+        assert_eq!(smol_stack.send(&[0; VIRTUAL_TUN_MTU + 1]), SMOL_RESULT_INVALID_VALUE);
+        smol_stack.poll();
+        let text = smol_stack.render_metrics().unwrap();
+        for line in &[
+            "smol_packets_total{direction=\"from_outside\"} 1",
+            "smol_bytes_total{direction=\"from_outside\"} 4",
+            "smol_packets_dropped_total{direction=\"from_outside\",reason=\"too_big\"} 1",
+            "smol_polls_total 1",
+            "smol_queue_packets{queue=\"packets_from_outside\"} 0",
+        ] {
+            assert!(text.lines().any(|l| l == *line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn serves_metrics_over_http() {
+        let metrics = Arc::new(StackMetrics::new());
+        metrics.poll_done(Duration::from_millis(2), true);
+        let server = MetricsServer::start(metrics, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n# HELP smol_packets_total"));
+        assert!(response.lines().any(|l| l == "smol_polls_total 1"));
+        let address = server.address();
+        drop(server);
+        //The listener is closed once drop returns
+        assert!(TcpStream::connect(address).is_err());
+    }
+}
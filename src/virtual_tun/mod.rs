@@ -7,6 +7,24 @@ extern crate smoltcp;
 pub mod virtual_tun;
 pub mod interface;
 pub mod smol_stack;
+pub mod logging;
+pub mod queue;
+pub mod ring;
+pub mod signal;
+pub mod pcap;
+pub mod config;
+pub mod ingress;
+pub mod filter;
+pub mod nat;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod clock;
+pub mod callback_device;
+pub mod fd_device;
+pub mod unix_socket_device;
+pub mod udp_tunnel_device;
+#[cfg(test)]
+mod tests;
 
 pub use virtual_tun::VirtualTunInterface;
 pub use smol_stack::SmolStack;
\ No newline at end of file
@@ -0,0 +1,522 @@
+use super::filter::{PacketFilter, FILTER_ACTION_ACCEPT, FILTER_INGRESS};
+use super::ring::PacketRing;
+use super::signal::{Signal, WAKE_REASON_PACKETS};
+use super::smol_stack::SocketBuffers;
+use super::udp_tunnel_device::socket_address;
+use smoltcp::iface::Interface;
+use smoltcp::phy::Device;
+use smoltcp::socket::{SocketHandle, SocketSet, TcpSocket, TcpSocketBuffer, TcpState};
+use smoltcp::socket::{UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
+use smoltcp::time::{Duration as SmolDuration, Instant};
+use smoltcp::wire::{IpAddress, IpCidr, IpEndpoint, IpProtocol, Ipv4Address, Ipv4Packet, Ipv6Address, Ipv6Packet};
+use smoltcp::wire::{TcpPacket, UdpPacket};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, UdpSocket as HostUdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+//Rounds of scanning and polling in one SmolStack::poll, what's left waits for the next one
+const NAT_POLL_ROUNDS: usize = 4;
+//Host sockets don't wake the poller, so it polls at least this often while flows are open
+const NAT_POLL_INTERVAL_MILLIS: u64 = 10;
+//Host connects run on their own thread, given up after this
+const NAT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+//A listener whose SYN never made it to the socket (dropped by smoltcp) goes after this
+const NAT_LISTEN_TIMEOUT_MILLIS: u64 = 10_000;
+//UDP has no end, flows go after this long without datagrams either way
+const NAT_UDP_IDLE_MILLIS: u64 = 60_000;
+//New flows past these are answered by smoltcp as if nothing listened (RST, nothing for UDP)
+const NAT_MAX_TCP_FLOWS: usize = 1024;
+const NAT_MAX_UDP_PORTS: usize = 1024;
+//Guest endpoints over every UDP port, each one holds a host socket. Datagrams of new ones past it are dropped
+const NAT_MAX_UDP_FLOWS: usize = 4096;
+//Biggest datagram read from a host UDP socket
+const NAT_DATAGRAM_BYTES: usize = 65536;
+
+enum Host {
+    //The guest's handshake isn't done yet, nothing was opened on the host
+    Idle,
+    //Result of the connect thread
+    Connecting(Receiver<io::Result<TcpStream>>),
+    Connected(TcpStream),
+    //Closed or failed, the stack side is finishing
+    Gone,
+}
+
+/*
+    One TCP connection of a guest: a smoltcp socket listening on (and
+    then connected from) the address the guest asked for, relayed to a
+    host socket connected to it for real
+*/
+struct TcpFlow {
+    handle: SocketHandle,
+    guest: IpEndpoint,
+    destination: IpEndpoint,
+    host: Host,
+    created: Instant,
+    //The guest sent FIN and the host socket's write side was shut down
+    host_write_closed: bool,
+    //The host sent EOF and the smoltcp socket was closed
+    guest_write_closed: bool,
+}
+
+//Datagrams of one guest endpoint to one destination, over a connected host socket
+struct UdpFlow {
+    host: HostUdpSocket,
+    last_used: Instant,
+}
+
+//smoltcp socket bound to one destination, shared by every guest that sends to it
+struct UdpPort {
+    handle: SocketHandle,
+    destination: IpEndpoint,
+    flows: HashMap<IpEndpoint, UdpFlow>,
+    last_used: Instant,
+}
+
+/*
+    Userspace NAT of a VirtualTun stack, like slirp: TCP and UDP flows
+    from outside to addresses that aren't the stack's are terminated by
+    smoltcp and re-originated from real host sockets, with data relayed
+    both ways. Before each poll the NAT looks at the queued packets and
+    opens sockets for new flows (listening on the exact address and port
+    the guest asked for), and the device only gives smoltcp the packets
+    looked at, so no SYN gets there before its listener. Everything runs
+    on the poller thread
+*/
+pub struct Nat {
+    packets_from_outside: Arc<PacketRing>,
+    //Shared with the device, see VirtualTunInterface::set_receive_budget
+    receive_budget: Arc<AtomicUsize>,
+    has_data: Arc<Signal>,
+    //The stack's firewall, whose ingress rules new flows must pass before they get sockets
+    filter: Arc<PacketFilter>,
+    //Guests reach the host's loopback through this address, like slirp's 10.0.2.2
+    host_alias: Option<IpAddress>,
+    tcp_flows: Vec<TcpFlow>,
+    udp_ports: Vec<UdpPort>,
+    //Where relay_udp reads host datagrams, NAT_DATAGRAM_BYTES long
+    datagram: Vec<u8>,
+}
+
+//What a packet from outside would open: (protocol, source, destination, TCP SYN)
+fn parse_flow(packet: &[u8]) -> Option<(IpProtocol, IpEndpoint, IpEndpoint, bool)> {
+    let (protocol, source, destination, payload): (_, IpAddress, IpAddress, _) = match packet.first()? >> 4 {
+        4 => {
+            let ipv4 = Ipv4Packet::new_checked(packet).ok()?;
+            if ipv4.frag_offset() != 0 {
+                return None;
+            }
+            (ipv4.protocol(), ipv4.src_addr().into(), ipv4.dst_addr().into(), ipv4.payload())
+        }
+        6 => {
+            let ipv6 = Ipv6Packet::new_checked(packet).ok()?;
+            (ipv6.next_header(), ipv6.src_addr().into(), ipv6.dst_addr().into(), ipv6.payload())
+        }
+        _ => return None,
+    };
+    let (source_port, destination_port, syn) = match protocol {
+        IpProtocol::Tcp => {
+            let tcp = TcpPacket::new_checked(payload).ok()?;
+            (tcp.src_port(), tcp.dst_port(), tcp.syn() && !tcp.ack())
+        }
+        IpProtocol::Udp => {
+            let udp = UdpPacket::new_checked(payload).ok()?;
+            (udp.src_port(), udp.dst_port(), false)
+        }
+        _ => return None,
+    };
+    Some((
+        protocol,
+        IpEndpoint::new(source, source_port),
+        IpEndpoint::new(destination, destination_port),
+        syn,
+    ))
+}
+
+//Where the host socket connects to for `destination`
+fn host_address(host_alias: Option<IpAddress>, destination: IpEndpoint) -> Option<SocketAddr> {
+    let addr = match destination.addr {
+        IpAddress::Ipv4(_) if Some(destination.addr) == host_alias => Ipv4Address::new(127, 0, 0, 1).into(),
+        IpAddress::Ipv6(_) if Some(destination.addr) == host_alias => Ipv6Address::LOOPBACK.into(),
+        addr => addr,
+    };
+    socket_address(IpEndpoint::new(addr, destination.port))
+}
+
+/*
+    Whether flows to `destination` may go through the NAT. The host's own
+    loopback and link-local networks are only reachable through the host
+    alias, or any guest could talk to services that trust localhost
+*/
+fn forwardable(host_alias: Option<IpAddress>, destination: IpAddress) -> bool {
+    if Some(destination) == host_alias {
+        return true;
+    }
+    match destination {
+        IpAddress::Ipv4(addr) => addr.is_unicast() && !addr.is_loopback() && !addr.is_link_local(),
+        //IPv4-mapped addresses reach IPv4 on dual-stack hosts
+        IpAddress::Ipv6(addr) if addr.is_ipv4_mapped() => {
+            forwardable(None, IpAddress::Ipv4(Ipv4Address::from_bytes(&addr.as_bytes()[12..])))
+        }
+        IpAddress::Ipv6(addr) => addr.is_unicast() && !addr.is_loopback() && !addr.is_link_local(),
+        _ => false,
+    }
+}
+
+fn would_block<T>(r: &io::Result<T>) -> bool {
+    match r {
+        Err(e) => e.kind() == ErrorKind::WouldBlock,
+        Ok(_) => false,
+    }
+}
+
+impl Nat {
+    pub fn new(
+        packets_from_outside: Arc<PacketRing>,
+        receive_budget: Arc<AtomicUsize>,
+        has_data: Arc<Signal>,
+        filter: Arc<PacketFilter>,
+    ) -> Nat {
+        Nat {
+            packets_from_outside: packets_from_outside,
+            receive_budget: receive_budget,
+            has_data: has_data,
+            filter: filter,
+            host_alias: None,
+            tcp_flows: Vec::new(),
+            udp_ports: Vec::new(),
+            datagram: vec![0; NAT_DATAGRAM_BYTES],
+        }
+    }
+
+    pub fn set_host_alias(&mut self, host_alias: IpAddress) {
+        self.host_alias = Some(host_alias);
+    }
+
+    //Open TCP connections (listeners included) and UDP flows
+    pub fn flows(&self) -> (usize, usize) {
+        let udp = self.udp_ports.iter().map(|port| port.flows.len()).sum();
+        (self.tcp_flows.len(), udp)
+    }
+
+    //The poller can't sleep longer than this, see NAT_POLL_INTERVAL_MILLIS
+    pub fn poll_delay(&self) -> Option<SmolDuration> {
+        if self.tcp_flows.is_empty() && self.udp_ports.is_empty() {
+            None
+        } else {
+            Some(SmolDuration::from_millis(NAT_POLL_INTERVAL_MILLIS))
+        }
+    }
+
+    /*
+        Replaces Interface::poll on stacks with NAT: opens the sockets of
+        new flows, polls, relays to and from the host sockets and polls
+        again if that gave smoltcp something to send
+    */
+    pub fn poll<'a, 'b: 'a, 'c: 'a + 'b, DeviceT>(
+        &mut self,
+        interface: &mut Interface<'a, 'b, 'c, DeviceT>,
+        sockets: &mut SocketSet<'a, 'b, 'c>,
+        timestamp: Instant,
+        buffers: SocketBuffers,
+    ) -> smoltcp::Result<bool>
+    where
+        DeviceT: for<'d> Device<'d>,
+    {
+        let mut r = Ok(false);
+        for _ in 0..NAT_POLL_ROUNDS {
+            let seen = self.open_flows(sockets, interface.ip_addrs(), timestamp, buffers);
+            self.receive_budget.store(seen, Ordering::SeqCst);
+            r = interface.poll(sockets, timestamp);
+            if r.is_err() || self.packets_from_outside.is_empty() {
+                break;
+            }
+        }
+        //Packets that arrive from now on wait for the next scan
+        self.receive_budget.store(0, Ordering::SeqCst);
+        if !self.packets_from_outside.is_empty() {
+            self.has_data.notify(WAKE_REASON_PACKETS);
+        }
+        let relayed = self.relay_tcp(sockets, timestamp) | self.relay_udp(sockets, timestamp);
+        if relayed && r.is_ok() {
+            r = interface.poll(sockets, timestamp);
+        }
+        r
+    }
+
+    //Returns how many queued packets it looked at
+    fn open_flows<'a, 'b: 'a, 'c: 'a + 'b>(
+        &mut self,
+        sockets: &mut SocketSet<'a, 'b, 'c>,
+        addresses: &[IpCidr],
+        timestamp: Instant,
+        buffers: SocketBuffers,
+    ) -> usize {
+        let Nat {
+            ref packets_from_outside,
+            ref filter,
+            host_alias,
+            ref mut tcp_flows,
+            ref mut udp_ports,
+            ..
+        } = *self;
+        packets_from_outside.for_each(|packet| {
+            let (protocol, source, destination, syn) = match parse_flow(packet) {
+                Some(flow) => flow,
+                None => return,
+            };
+            //Our own addresses are for the stack's sockets
+            if destination.port == 0
+                || !forwardable(host_alias, destination.addr)
+                || addresses.iter().any(|cidr| cidr.address() == destination.addr)
+            {
+                return;
+            }
+            match protocol {
+                IpProtocol::Tcp => {
+                    let known = tcp_flows
+                        .iter()
+                        .any(|flow| flow.guest == source && flow.destination == destination);
+                    if !syn || known || tcp_flows.len() >= NAT_MAX_TCP_FLOWS {
+                        return;
+                    }
+                    //Refused SYNs are answered by the device, without a listener left behind
+                    if filter.decide(FILTER_INGRESS, packet) != FILTER_ACTION_ACCEPT {
+                        return;
+                    }
+                    let mut socket = TcpSocket::new(
+                        TcpSocketBuffer::new(vec![0; buffers.tcp_bytes]),
+                        TcpSocketBuffer::new(vec![0; buffers.tcp_bytes]),
+                    );
+                    if socket.listen(destination).is_err() {
+                        return;
+                    }
+                    debug!("nat: {} connecting to {}", source, destination);
+                    tcp_flows.push(TcpFlow {
+                        handle: sockets.add(socket),
+                        guest: source,
+                        destination: destination,
+                        host: Host::Idle,
+                        created: timestamp,
+                        host_write_closed: false,
+                        guest_write_closed: false,
+                    });
+                }
+                _ => {
+                    let known = udp_ports.iter().any(|port| port.destination == destination);
+                    if known || udp_ports.len() >= NAT_MAX_UDP_PORTS {
+                        return;
+                    }
+                    if filter.decide(FILTER_INGRESS, packet) != FILTER_ACTION_ACCEPT {
+                        return;
+                    }
+                    let metadata = || vec![UdpPacketMetadata::EMPTY; buffers.udp_packets];
+                    let mut socket = UdpSocket::new(
+                        UdpSocketBuffer::new(metadata(), vec![0; buffers.udp_bytes]),
+                        UdpSocketBuffer::new(metadata(), vec![0; buffers.udp_bytes]),
+                    );
+                    if socket.bind(destination).is_err() {
+                        return;
+                    }
+                    udp_ports.push(UdpPort {
+                        handle: sockets.add(socket),
+                        destination: destination,
+                        flows: HashMap::new(),
+                        last_used: timestamp,
+                    });
+                }
+            }
+        })
+    }
+
+    //Returns whether anything moved, so smoltcp has something to send
+    fn relay_tcp<'a, 'b: 'a, 'c: 'a + 'b>(&mut self, sockets: &mut SocketSet<'a, 'b, 'c>, timestamp: Instant) -> bool {
+        let host_alias = self.host_alias;
+        let mut relayed = false;
+        let mut finished = Vec::new();
+        for (i, flow) in self.tcp_flows.iter_mut().enumerate() {
+            let mut socket = sockets.get::<TcpSocket>(flow.handle);
+            let state = socket.state();
+            if state == TcpState::Listen {
+                if timestamp - flow.created > SmolDuration::from_millis(NAT_LISTEN_TIMEOUT_MILLIS) {
+                    finished.push(i);
+                }
+                continue;
+            }
+            if state == TcpState::Closed || state == TcpState::TimeWait {
+                //Reset by the guest, or both sides are done
+                finished.push(i);
+                continue;
+            }
+            if let Host::Idle = flow.host {
+                //Whoever accepted the SYN is the guest from now on
+                let guest = socket.remote_endpoint();
+                if guest.port != 0 {
+                    flow.guest = guest;
+                }
+                flow.host = match host_address(host_alias, flow.destination) {
+                    Some(address) => {
+                        let (sender, receiver) = mpsc::channel();
+                        thread::spawn(move || {
+                            let _ = sender.send(TcpStream::connect_timeout(&address, NAT_CONNECT_TIMEOUT));
+                        });
+                        Host::Connecting(receiver)
+                    }
+                    None => {
+                        socket.abort();
+                        Host::Gone
+                    }
+                };
+            }
+            if let Host::Connecting(ref receiver) = flow.host {
+                flow.host = match receiver.try_recv() {
+                    Ok(Ok(stream)) if stream.set_nonblocking(true).is_ok() => Host::Connected(stream),
+                    Err(TryRecvError::Empty) => continue,
+                    _ => {
+                        //The guest sees the host's refusal as a reset
+                        debug!("nat: could not connect to {}", flow.destination);
+                        socket.abort();
+                        relayed = true;
+                        Host::Gone
+                    }
+                };
+            }
+            let failed = match flow.host {
+                Host::Connected(ref mut stream) => {
+                    //Guest to host, until the host socket is full
+                    while socket.can_recv() {
+                        let written = socket.recv(|data| match stream.write(data) {
+                            Ok(n) => (n, Ok(n)),
+                            Err(e) => (0, Err(e)),
+                        });
+                        match written {
+                            Ok(Ok(n)) if n > 0 => relayed = true,
+                            Ok(ref r) if would_block(r) => break,
+                            //The host socket failed, the guest gets a reset
+                            _ => socket.abort(),
+                        }
+                        if !socket.is_active() {
+                            break;
+                        }
+                    }
+                    if !flow.host_write_closed && !socket.may_recv() && !socket.can_recv() && socket.is_active() {
+                        let _ = stream.shutdown(Shutdown::Write);
+                        flow.host_write_closed = true;
+                    }
+                    //Host to guest, until the socket's send buffer is full
+                    while !flow.guest_write_closed && socket.can_send() {
+                        let read = socket.send(|buffer| match stream.read(buffer) {
+                            Ok(n) => (n, Ok(n)),
+                            Err(e) => (0, Err(e)),
+                        });
+                        match read {
+                            Ok(Ok(0)) => {
+                                socket.close();
+                                flow.guest_write_closed = true;
+                            }
+                            Ok(Ok(_)) => (),
+                            Ok(ref r) if would_block(r) => break,
+                            //The host socket failed, the guest gets a reset
+                            _ => socket.abort(),
+                        }
+                        relayed = true;
+                    }
+                    !socket.is_active()
+                }
+                _ => false,
+            };
+            if failed {
+                flow.host = Host::Gone;
+            }
+        }
+        for &i in finished.iter().rev() {
+            let flow = self.tcp_flows.remove(i);
+            //Dropping the stream closes the host side
+            sockets.remove(flow.handle);
+        }
+        relayed
+    }
+
+    fn relay_udp<'a, 'b: 'a, 'c: 'a + 'b>(&mut self, sockets: &mut SocketSet<'a, 'b, 'c>, timestamp: Instant) -> bool {
+        if self.udp_ports.is_empty() {
+            return false;
+        }
+        let host_alias = self.host_alias;
+        let idle = SmolDuration::from_millis(NAT_UDP_IDLE_MILLIS);
+        let mut relayed = false;
+        let (_, mut flows) = self.flows();
+        let buffer = &mut self.datagram;
+        for port in self.udp_ports.iter_mut() {
+            let mut socket = sockets.get::<UdpSocket>(port.handle);
+            let destination = port.destination;
+            while let Ok((data, guest)) = socket.recv() {
+                if !port.flows.contains_key(&guest) {
+                    if flows >= NAT_MAX_UDP_FLOWS {
+                        debug!("nat: too many udp flows, dropping a datagram from {}", guest);
+                        continue;
+                    }
+                    let host = host_address(host_alias, destination).and_then(|address| {
+                        let local: SocketAddr = match address {
+                            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+                            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+                        };
+                        let host = HostUdpSocket::bind(local).ok()?;
+                        host.connect(address).ok()?;
+                        host.set_nonblocking(true).ok()?;
+                        Some(host)
+                    });
+                    match host {
+                        Some(host) => {
+                            port.flows.insert(
+                                guest,
+                                UdpFlow {
+                                    host: host,
+                                    last_used: timestamp,
+                                },
+                            );
+                            flows += 1;
+                        }
+                        None => {
+                            debug!("nat: could not open a socket to {}", destination);
+                            continue;
+                        }
+                    }
+                }
+                let flow = port.flows.get_mut(&guest).unwrap();
+                //Lost if the host socket can't take it, like on any UDP path
+                let _ = flow.host.send(data);
+                flow.last_used = timestamp;
+                port.last_used = timestamp;
+            }
+            for (guest, flow) in port.flows.iter_mut() {
+                while socket.can_send() {
+                    let len = match flow.host.recv(buffer) {
+                        Ok(len) => len,
+                        Err(_) => break,
+                    };
+                    if socket.send_slice(&buffer[..len], *guest).is_ok() {
+                        relayed = true;
+                    }
+                    flow.last_used = timestamp;
+                    port.last_used = timestamp;
+                }
+            }
+            let open = port.flows.len();
+            port.flows.retain(|_, flow| timestamp - flow.last_used < idle);
+            flows -= open - port.flows.len();
+        }
+        self.udp_ports.retain(|port| {
+            let kept = !port.flows.is_empty() || timestamp - port.last_used < idle;
+            if !kept {
+                sockets.remove(port.handle);
+            }
+            kept
+        });
+        relayed
+    }
+}
@@ -0,0 +1,85 @@
+use smoltcp::phy::Medium;
+use smoltcp::time::Instant;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+//LINKTYPE_* values from the pcap format
+const LINK_TYPE_ETHERNET: u32 = 1;
+const LINK_TYPE_RAW: u32 = 101;
+//Packets are written whole, up to the largest IP packet
+const SNAPSHOT_LENGTH: u32 = 65535;
+
+/*
+    Writes the packets a device receives and sends to a pcap file, which
+    tcpdump and Wireshark open. Every packet goes to the file as soon as
+    it's seen, without buffering, so it can be read while the stack runs
+    and is complete if the process dies. Write errors are logged and the
+    packet is skipped, the stack itself is never affected
+*/
+pub struct PacketCapture {
+    file: Mutex<File>,
+}
+
+impl PacketCapture {
+    //Creates (or truncates) the file at `path` and writes the pcap header
+    pub fn create(path: &Path, medium: Medium) -> io::Result<PacketCapture> {
+        let mut file = File::create(path)?;
+        let link_type = match medium {
+            Medium::Ethernet => LINK_TYPE_ETHERNET,
+            _ => LINK_TYPE_RAW,
+        };
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        //Time zone offset and timestamp accuracy, always 0
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&SNAPSHOT_LENGTH.to_le_bytes());
+        header.extend_from_slice(&link_type.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(PacketCapture {
+            file: Mutex::new(file),
+        })
+    }
+
+    //`timestamp` is the one smoltcp gives to the device tokens, the stack's clock
+    pub fn packet(&self, timestamp: Instant, packet: &[u8]) {
+        let millis = timestamp.total_millis();
+        let captured = packet.len().min(SNAPSHOT_LENGTH as usize);
+        let mut record = Vec::with_capacity(16 + captured);
+        record.extend_from_slice(&((millis / 1000) as u32).to_le_bytes());
+        record.extend_from_slice(&(((millis % 1000) * 1000) as u32).to_le_bytes());
+        record.extend_from_slice(&(captured as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet[..captured]);
+        if let Err(err) = self.file.lock().unwrap().write_all(&record) {
+            debug!("could not write packet to the capture: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn writes_header_and_records() {
+        let path = std::env::temp_dir().join(format!("smol_capture_{}.pcap", std::process::id()));
+        let capture = PacketCapture::create(&path, Medium::Ip).unwrap();
+        capture.packet(Instant::from_millis(2_345), &[0x45, 0, 0, 20]);
+        drop(capture);
+        let contents = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(contents.len(), 24 + 16 + 4);
+        assert_eq!(&contents[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(&contents[20..24], &LINK_TYPE_RAW.to_le_bytes());
+        assert_eq!(&contents[24..28], &2u32.to_le_bytes());
+        assert_eq!(&contents[28..32], &345_000u32.to_le_bytes());
+        assert_eq!(&contents[32..40], &[4, 0, 0, 0, 4, 0, 0, 0]);
+        assert_eq!(&contents[40..], &[0x45, 0, 0, 20]);
+    }
+}
@@ -0,0 +1,294 @@
+use super::smol_stack::{Blob, Packet};
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::sync::Mutex;
+
+//Warning: keep these synced with the QUEUE_* constants on interface.h
+pub const QUEUE_SOCKET_TO_SEND: u8 = 0;
+pub const QUEUE_SOCKET_RECEIVED: u8 = 1;
+pub const QUEUE_PACKETS_FROM_INSIDE: u8 = 2;
+pub const QUEUE_PACKETS_FROM_OUTSIDE: u8 = 3;
+
+//Warning: keep these synced with the FLOW_EVENT_* constants on interface.h
+//`send` on the socket can be called again
+pub const FLOW_EVENT_SOCKET_WRITABLE: u8 = 0;
+//The socket has data to receive
+pub const FLOW_EVENT_SOCKET_READABLE: u8 = 1;
+//smol_stack_virtual_tun_send can be called again (socket key is 0)
+pub const FLOW_EVENT_STACK_WRITABLE: u8 = 2;
+//The stack has packets to be received by C++ (socket key is 0)
+pub const FLOW_EVENT_STACK_READABLE: u8 = 3;
+
+pub trait QueueItem {
+    fn byte_len(&self) -> usize;
+}
+
+impl QueueItem for Vec<u8> {
+    fn byte_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl QueueItem for Blob {
+    fn byte_len(&self) -> usize {
+        self.data.len() - self.start
+    }
+}
+
+impl QueueItem for Packet {
+    fn byte_len(&self) -> usize {
+        self.blob.byte_len()
+    }
+}
+
+//0 means unlimited, for both fields
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueueLimits {
+    pub max_packets: usize,
+    pub max_bytes: usize,
+}
+
+impl QueueLimits {
+    pub fn unlimited() -> QueueLimits {
+        QueueLimits {
+            max_packets: 0,
+            max_bytes: 0,
+        }
+    }
+}
+
+/*
+    VecDeque that keeps track of how many bytes it holds and refuses
+    new items once one of its limits is reached. It remembers that it
+    refused something, so whoever frees space can tell the producer
+    it can continue (see take_unblocked)
+*/
+pub struct PacketQueue<T> {
+    items: VecDeque<T>,
+    bytes: usize,
+    limits: QueueLimits,
+    blocked: bool,
+}
+
+impl<T: QueueItem> PacketQueue<T> {
+    pub fn new(limits: QueueLimits) -> PacketQueue<T> {
+        PacketQueue {
+            items: VecDeque::new(),
+            bytes: 0,
+            limits: limits,
+            blocked: false,
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: QueueLimits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> QueueLimits {
+        self.limits
+    }
+
+    /*
+        An item bigger than max_bytes is still accepted when the queue
+        is empty, otherwise it could never be queued at all
+    */
+    fn has_room_for(&self, len: usize) -> bool {
+        if self.limits.max_packets != 0 && self.items.len() >= self.limits.max_packets {
+            return false;
+        }
+        if self.limits.max_bytes != 0
+            && !self.items.is_empty()
+            && self.bytes + len > self.limits.max_bytes
+        {
+            return false;
+        }
+        true
+    }
+
+    pub fn push_back(&mut self, item: T) -> Result<(), T> {
+        let len = item.byte_len();
+        if !self.has_room_for(len) {
+            self.blocked = true;
+            return Err(item);
+        }
+        self.bytes += len;
+        self.items.push_back(item);
+        Ok(())
+    }
+
+    //Puts back an item that was just popped, ignoring limits
+    pub fn push_front(&mut self, item: T) {
+        self.bytes += item.byte_len();
+        self.items.push_front(item);
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        let item = self.items.pop_front();
+        if let Some(item) = &item {
+            self.bytes -= item.byte_len();
+        }
+        item
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.items.front()
+    }
+
+    fn room(&self) -> usize {
+        if self.limits.max_packets != 0 && self.items.len() >= self.limits.max_packets {
+            0
+        } else if self.limits.max_bytes == 0 {
+            usize::MAX
+        } else {
+            self.limits.max_bytes.saturating_sub(self.bytes)
+        }
+    }
+
+    /*
+        How many bytes can still be pushed. Marks the queue as blocked
+        when it's 0, so the consumer knows it must wake the producer
+    */
+    pub fn available_bytes(&mut self) -> usize {
+        let available = self.room();
+        if available == 0 {
+            self.blocked = true;
+        }
+        available
+    }
+
+    //Same as available_bytes() > 0, for producers that push whole packets
+    pub fn check_room(&mut self) -> bool {
+        self.available_bytes() > 0
+    }
+
+    /*
+        Whether an item of `len` bytes would be accepted by push_back,
+        marking the queue as blocked when not, like a refused push
+    */
+    pub fn check_room_for(&mut self, len: usize) -> bool {
+        if self.has_room_for(len) {
+            return true;
+        }
+        self.blocked = true;
+        false
+    }
+
+    /*
+        Returns true once after a refused push, as soon as there's room
+        again. Consumers call this after popping to know if they should
+        wake the producer up
+    */
+    pub fn take_unblocked(&mut self) -> bool {
+        if self.blocked && self.room() > 0 {
+            self.blocked = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+pub type CFlowFunction = extern "C" fn(context: *const c_void, socket_handle_key: usize, event: u8);
+
+struct FlowCallback {
+    function: CFlowFunction,
+    context: *const c_void,
+}
+
+/*
+    Holds the optional C++ callback that's told when a full queue has
+    room again or an empty one got data. Shared by SmolStack and
+    VirtualTunInterface, since both sides of the queues live there
+*/
+pub struct FlowNotifier {
+    callback: Mutex<Option<FlowCallback>>,
+}
+
+//The context pointer belongs to C++, which must make it usable from any thread
+unsafe impl Send for FlowNotifier {}
+unsafe impl Sync for FlowNotifier {}
+
+impl FlowNotifier {
+    pub fn new() -> FlowNotifier {
+        FlowNotifier {
+            callback: Mutex::new(None),
+        }
+    }
+
+    pub fn set_callback(&self, function: Option<CFlowFunction>, context: *const c_void) {
+        *self.callback.lock().unwrap() = function.map(|function| FlowCallback {
+            function: function,
+            context: context,
+        });
+    }
+
+    /*
+        Never call this while holding a queue lock: C++ might call
+        send/receive from inside the callback
+    */
+    pub fn notify(&self, socket_handle_key: usize, event: u8) {
+        let callback = match &*self.callback.lock().unwrap() {
+            Some(callback) => (callback.function, callback.context),
+            None => return,
+        };
+        (callback.0)(callback.1, socket_handle_key, event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_over_limits_and_unblocks_once() {
+        let mut queue = PacketQueue::new(QueueLimits {
+            max_packets: 2,
+            max_bytes: 10,
+        });
+        assert!(queue.push_back(vec![0; 6]).is_ok());
+        assert!(queue.push_back(vec![0; 6]).is_err());
+        assert!(queue.push_back(vec![0; 4]).is_ok());
+        assert!(queue.push_back(vec![0; 1]).is_err());
+        assert_eq!(queue.bytes(), 10);
+        assert!(!queue.take_unblocked());
+        queue.pop_front();
+        assert!(queue.take_unblocked());
+        assert!(!queue.take_unblocked());
+    }
+
+    #[test]
+    fn accepts_oversized_item_when_empty() {
+        let mut queue = PacketQueue::new(QueueLimits {
+            max_packets: 0,
+            max_bytes: 4,
+        });
+        assert!(queue.push_back(vec![0; 8]).is_ok());
+        assert_eq!(queue.available_bytes(), 0);
+        assert!(queue.push_back(vec![0; 1]).is_err());
+    }
+
+    #[test]
+    fn check_room_for_blocks_like_a_refused_push() {
+        let mut queue = PacketQueue::new(QueueLimits {
+            max_packets: 0,
+            max_bytes: 10,
+        });
+        assert!(queue.push_back(vec![0; 6]).is_ok());
+        assert!(queue.check_room_for(4));
+        assert!(!queue.check_room_for(5));
+        queue.pop_front();
+        assert!(queue.take_unblocked());
+    }
+}
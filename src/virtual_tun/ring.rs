@@ -0,0 +1,398 @@
+use super::queue::QueueLimits;
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+//Slots of each VirtualTun ring, the most packets it can ever hold
+pub const RING_SLOTS: usize = 256;
+
+struct Slot {
+    len: usize,
+    data: Box<[u8]>,
+}
+
+/*
+    Bounded single producer, single consumer packet queue without locks,
+    used between C++ and the poller thread on VirtualTun stacks. Its slots
+    are allocated once, `slot_size` bytes each (the MTU), and reused, so
+    queuing a packet is a copy into a slot and nothing else.
+    `head` is only written by the consumer and `tail` by the producer, both
+    only grow (slot i is i % slots.len()). Each side must be used by one
+    thread at a time. The poller thread owns its sides, the C++ entry
+    points claim theirs (see claim_producer) and refuse to run while
+    another thread holds them, instead of corrupting the ring.
+    It keeps PacketQueue's limits and its blocked flag for flow events
+*/
+pub struct PacketRing {
+    slots: Box<[UnsafeCell<Slot>]>,
+    slot_size: usize,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    bytes: AtomicUsize,
+    max_packets: AtomicUsize,
+    max_bytes: AtomicUsize,
+    blocked: AtomicBool,
+    producer_in_use: AtomicBool,
+    consumer_in_use: AtomicBool,
+}
+
+//Holds one side of a PacketRing for the current thread, released on drop
+pub struct SideGuard<'r> {
+    in_use: &'r AtomicBool,
+}
+
+impl<'r> SideGuard<'r> {
+    fn claim(in_use: &'r AtomicBool) -> Option<SideGuard<'r>> {
+        match in_use.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => Some(SideGuard { in_use: in_use }),
+            Err(_) => None,
+        }
+    }
+}
+
+impl<'r> Drop for SideGuard<'r> {
+    fn drop(&mut self) {
+        self.in_use.store(false, Ordering::Release);
+    }
+}
+
+//Slots are only touched by the side that owns them at the moment, see head/tail
+unsafe impl Send for PacketRing {}
+unsafe impl Sync for PacketRing {}
+
+#[derive(Debug, PartialEq)]
+pub enum PushError {
+    //Limits reached, try again once the consumer pops
+    Full,
+    //Bigger than a slot, it can never be queued
+    TooBig,
+}
+
+impl PacketRing {
+    pub fn new(slots: usize, slot_size: usize, limits: QueueLimits) -> PacketRing {
+        let slots: Vec<UnsafeCell<Slot>> = (0..slots)
+            .map(|_| {
+                UnsafeCell::new(Slot {
+                    len: 0,
+                    data: vec![0; slot_size].into_boxed_slice(),
+                })
+            })
+            .collect();
+        let ring = PacketRing {
+            slots: slots.into_boxed_slice(),
+            slot_size: slot_size,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            max_packets: AtomicUsize::new(0),
+            max_bytes: AtomicUsize::new(0),
+            blocked: AtomicBool::new(false),
+            producer_in_use: AtomicBool::new(false),
+            consumer_in_use: AtomicBool::new(false),
+        };
+        ring.set_limits(limits);
+        ring
+    }
+
+    //max_packets is capped by the number of slots, 0 means all of them
+    pub fn set_limits(&self, limits: QueueLimits) {
+        let max_packets = if limits.max_packets == 0 || limits.max_packets > self.slots.len() {
+            self.slots.len()
+        } else {
+            limits.max_packets
+        };
+        self.max_packets.store(max_packets, Ordering::SeqCst);
+        self.max_bytes.store(limits.max_bytes, Ordering::SeqCst);
+    }
+
+    //None while another thread holds the producer side
+    pub fn claim_producer(&self) -> Option<SideGuard> {
+        SideGuard::claim(&self.producer_in_use)
+    }
+
+    //None while another thread holds the consumer side
+    pub fn claim_consumer(&self) -> Option<SideGuard> {
+        SideGuard::claim(&self.consumer_in_use)
+    }
+
+    pub fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+
+    /*
+        Any thread, only a snapshot from the ones not owning a side. head
+        is loaded first: both only grow and tail is never behind head, so
+        a tail loaded later can't be behind it either. Both sides may move
+        several slots between the loads, making it look fuller than it
+        can be, hence the clamp
+    */
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        std::cmp::min(self.tail.load(Ordering::Acquire) - head, self.slots.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Acquire)
+    }
+
+    //Same rules as PacketQueue: a packet bigger than max_bytes goes in if the ring is empty
+    fn has_room_for(&self, len: usize) -> bool {
+        let queued = self.len();
+        if queued >= self.max_packets.load(Ordering::SeqCst) {
+            return false;
+        }
+        let max_bytes = self.max_bytes.load(Ordering::SeqCst);
+        !(max_bytes != 0 && queued != 0 && self.bytes() + len > max_bytes)
+    }
+
+    fn room(&self) -> bool {
+        self.has_room_for(1)
+    }
+
+    /*
+        Marks the ring as blocked when there's no room for `len` bytes. The
+        room is checked again after marking it, otherwise a pop between the
+        two would never see the flag and nobody would wake the producer
+    */
+    fn reserve(&self, len: usize) -> bool {
+        loop {
+            if self.has_room_for(len) {
+                return true;
+            }
+            self.blocked.store(true, Ordering::SeqCst);
+            if !self.has_room_for(len) {
+                return false;
+            }
+            self.blocked.store(false, Ordering::SeqCst);
+        }
+    }
+
+    //Producer only. Same as check_room on PacketQueue
+    pub fn check_room(&self) -> bool {
+        self.reserve(1)
+    }
+
+    /*
+        Producer only. Lets `f` write the packet directly on the next free
+        slot, given with `len` bytes. The packet is only queued if `f`
+        returns Ok
+    */
+    pub fn push_with<R, E, F>(&self, len: usize, f: F) -> Result<Result<R, E>, PushError>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R, E>,
+    {
+        if len > self.slot_size {
+            return Err(PushError::TooBig);
+        }
+        if !self.reserve(len) {
+            return Err(PushError::Full);
+        }
+        let tail = self.tail.load(Ordering::Relaxed);
+        let slot = unsafe { &mut *self.slots[tail % self.slots.len()].get() };
+        slot.len = len;
+        let r = f(&mut slot.data[..len]);
+        if r.is_ok() {
+            self.bytes.fetch_add(len, Ordering::AcqRel);
+            self.tail.store(tail + 1, Ordering::Release);
+        }
+        Ok(r)
+    }
+
+    //Producer only
+    pub fn push(&self, data: &[u8]) -> Result<(), PushError> {
+        self.push_with(data.len(), |slot| {
+            slot.copy_from_slice(data);
+            Ok::<(), ()>(())
+        })
+        .map(|_| ())
+    }
+
+    //Consumer only. Size of the next packet
+    pub fn front_len(&self) -> Option<usize> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        Some(unsafe { &*self.slots[head % self.slots.len()].get() }.len)
+    }
+
+    //Consumer only. Shows the next packet to `f` and leaves it queued
+    pub fn front_with<R, F>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let slot = unsafe { &*self.slots[head % self.slots.len()].get() };
+        Some(f(&slot.data[..slot.len]))
+    }
+
+    //Consumer only. Shows every queued packet to `f`, oldest first, and returns how many there were
+    pub fn for_each<F>(&self, mut f: F) -> usize
+    where
+        F: FnMut(&[u8]),
+    {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        for i in head..tail {
+            let slot = unsafe { &*self.slots[i % self.slots.len()].get() };
+            f(&slot.data[..slot.len]);
+        }
+        tail - head
+    }
+
+    //Consumer only. Gives the next packet to `f` (which may change it in place) and frees its slot
+    pub fn pop_with<R, F>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let slot = unsafe { &mut *self.slots[head % self.slots.len()].get() };
+        let len = slot.len;
+        let r = f(&mut slot.data[..len]);
+        self.bytes.fetch_sub(len, Ordering::AcqRel);
+        self.head.store(head + 1, Ordering::Release);
+        Some(r)
+    }
+
+    //Consumer only. Copies the next packet to `buffer`, if it fits
+    pub fn pop_into(&self, buffer: &mut [u8]) -> Option<usize> {
+        match self.front_len() {
+            Some(len) if len <= buffer.len() => self.pop_with(|s| {
+                buffer[..s.len()].copy_from_slice(s);
+                s.len()
+            }),
+            _ => None,
+        }
+    }
+
+    /*
+        Consumer only. True once after a refused push, as soon as there's
+        room again, so the producer can be told to continue
+    */
+    pub fn take_unblocked(&self) -> bool {
+        self.blocked.load(Ordering::SeqCst) && self.room() && self.blocked.swap(false, Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn refuses_over_limits_and_unblocks_once() {
+        let ring = PacketRing::new(
+            4,
+            16,
+            QueueLimits {
+                max_packets: 2,
+                max_bytes: 10,
+            },
+        );
+        assert_eq!(ring.push(&[0; 6]), Ok(()));
+        assert_eq!(ring.push(&[0; 6]), Err(PushError::Full));
+        assert_eq!(ring.push(&[0; 4]), Ok(()));
+        assert_eq!(ring.push(&[0; 1]), Err(PushError::Full));
+        assert_eq!(ring.push(&[0; 17]), Err(PushError::TooBig));
+        assert_eq!(ring.bytes(), 10);
+        assert!(!ring.take_unblocked());
+        assert_eq!(ring.pop_with(|s| s.len()), Some(6));
+        assert!(ring.take_unblocked());
+        assert!(!ring.take_unblocked());
+    }
+
+    #[test]
+    fn slots_are_reused_in_order() {
+        let ring = PacketRing::new(3, 8, QueueLimits::unlimited());
+        let mut buffer = [0; 8];
+        for i in 0..10u8 {
+            assert_eq!(ring.push(&[i; 5]), Ok(()));
+            assert_eq!(ring.push(&[i + 100; 3]), Ok(()));
+            assert_eq!(ring.pop_into(&mut buffer[..4]), None);
+            assert_eq!(ring.pop_into(&mut buffer), Some(5));
+            assert_eq!(&buffer[..5], &[i; 5]);
+            assert_eq!(ring.pop_into(&mut buffer), Some(3));
+            assert_eq!(&buffer[..3], &[i + 100; 3]);
+        }
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn sides_are_claimed_by_one_caller_at_a_time() {
+        let ring = PacketRing::new(2, 8, QueueLimits::unlimited());
+        let producer = ring.claim_producer();
+        assert!(producer.is_some());
+        assert!(ring.claim_producer().is_none());
+        //The other side is independent
+        assert!(ring.claim_consumer().is_some());
+        drop(producer);
+        assert!(ring.claim_producer().is_some());
+    }
+
+    #[test]
+    fn producer_and_consumer_on_different_threads() {
+        let ring = Arc::new(PacketRing::new(8, 4, QueueLimits::unlimited()));
+        let producer_ring = ring.clone();
+        let producer = thread::spawn(move || {
+            for i in 0..10_000u32 {
+                while producer_ring.push(&i.to_le_bytes()).is_err() {
+                    thread::yield_now();
+                }
+            }
+        });
+        let mut expected = 0u32;
+        let mut buffer = [0; 4];
+        while expected < 10_000 {
+            if ring.pop_into(&mut buffer) == Some(4) {
+                assert_eq!(u32::from_le_bytes(buffer), expected);
+                expected += 1;
+            } else {
+                thread::yield_now();
+            }
+        }
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn len_from_a_third_thread_stays_in_bounds() {
+        let ring = Arc::new(PacketRing::new(4, 4, QueueLimits::unlimited()));
+        let done = Arc::new(AtomicBool::new(false));
+        let observer = {
+            let ring = ring.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    assert!(ring.len() <= 4);
+                }
+            })
+        };
+        let producer_ring = ring.clone();
+        let producer = thread::spawn(move || {
+            for i in 0..100_000u32 {
+                while producer_ring.push(&i.to_le_bytes()).is_err() {
+                    thread::yield_now();
+                }
+            }
+        });
+        let mut buffer = [0; 4];
+        let mut popped = 0;
+        while popped < 100_000 {
+            if ring.pop_into(&mut buffer).is_some() {
+                popped += 1;
+            }
+        }
+        producer.join().unwrap();
+        done.store(true, Ordering::SeqCst);
+        observer.join().unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+//Warning: keep these synced with the WAKE_REASON_* constants on interface.h
+//Reasons are bits, a wait can return several of them at once
+//The wait timed out: a smoltcp timer (retransmission, delayed ACK...) is due
+pub const WAKE_REASON_TIMEOUT: u8 = 1;
+//Packets arrived from outside (VirtualTun send or the device's fd)
+pub const WAKE_REASON_PACKETS: u8 = 2;
+//C++ gave a socket something to do: data to send, connect, listen, options...
+pub const WAKE_REASON_SOCKETS: u8 = 4;
+//C++ took packets or socket data, freeing room the poller may be waiting for
+pub const WAKE_REASON_ROOM: u8 = 8;
+//wake() was called
+pub const WAKE_REASON_WAKE: u8 = 16;
+//The stack was shut down, stop polling it
+pub const WAKE_REASON_SHUT_DOWN: u8 = 32;
+
+/*
+    Replaces the bare (Mutex<()>, Condvar) pairs. A Condvar forgets a
+    notify sent while nobody waits, so a packet queued right before the
+    poller went to sleep would only be seen on its next timeout. Here
+    notify() leaves its reason pending until a wait takes it, so waiting
+    after a notify returns at once.
+    Stacks that wait in poll() on their device's fd can't wait on the
+    Condvar at the same time, so theirs also has a self-pipe: notify
+    writes to it and wake_fd, its read end, is polled along the device
+*/
+pub struct Signal {
+    pending: Mutex<u8>,
+    condvar: Condvar,
+    //Read and write ends, both non blocking
+    wake_pipe: Option<(RawFd, RawFd)>,
+}
+
+fn set_nonblocking_cloexec(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+impl Signal {
+    pub fn new() -> Signal {
+        Signal {
+            pending: Mutex::new(0),
+            condvar: Condvar::new(),
+            wake_pipe: None,
+        }
+    }
+
+    //Same as new, with a wake_fd for stacks that wait on an fd
+    pub fn with_wake_fd() -> io::Result<Signal> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut signal = Signal::new();
+        signal.wake_pipe = Some((fds[0], fds[1]));
+        //Dropping the signal closes both ends, even on error
+        set_nonblocking_cloexec(fds[0])?;
+        set_nonblocking_cloexec(fds[1])?;
+        Ok(signal)
+    }
+
+    //Readable while there are pending reasons, None without with_wake_fd
+    pub fn wake_fd(&self) -> Option<RawFd> {
+        self.wake_pipe.map(|(read_fd, _)| read_fd)
+    }
+
+    pub fn notify(&self, reason: u8) {
+        *self.pending.lock().unwrap() |= reason;
+        self.condvar.notify_all();
+        if let Some((_, write_fd)) = self.wake_pipe {
+            //A full pipe is already readable, nothing is lost if this fails
+            unsafe { libc::write(write_fd, [0u8].as_ptr() as *const libc::c_void, 1) };
+        }
+    }
+
+    /*
+        Empties the pipe before the reasons are taken: a notify coming
+        in between leaves a byte behind, so the next poll returns at once
+        instead of missing it
+    */
+    fn drain_wake_fd(&self) {
+        if let Some((read_fd, _)) = self.wake_pipe {
+            let mut buffer = [0u8; 64];
+            while unsafe { libc::read(read_fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) } > 0 {}
+        }
+    }
+
+    //Takes the pending reasons without waiting, 0 if there are none
+    pub fn take(&self) -> u8 {
+        self.drain_wake_fd();
+        std::mem::replace(&mut *self.pending.lock().unwrap(), 0)
+    }
+
+    /*
+        Waits until notified or until `timeout` passes (forever if None),
+        returning (and clearing) the reasons, WAKE_REASON_TIMEOUT if
+        nothing came
+    */
+    pub fn wait(&self, timeout: Option<Duration>) -> u8 {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut pending = self.pending.lock().unwrap();
+        while *pending == 0 {
+            pending = match deadline {
+                None => self.condvar.wait(pending).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return WAKE_REASON_TIMEOUT;
+                    }
+                    self.condvar.wait_timeout(pending, deadline - now).unwrap().0
+                }
+            };
+        }
+        self.drain_wake_fd();
+        std::mem::replace(&mut *pending, 0)
+    }
+
+    /*
+        Waits until `ready` gives something, calling it again after every
+        notify. Unlike wait(), nothing is taken, so several threads can
+        wait on the same Signal for their own condition. None if `timeout`
+        passes first (forever if None). `ready` runs with the Signal
+        locked, it must not notify it
+    */
+    pub fn wait_until<R, F>(&self, timeout: Option<Duration>, mut ready: F) -> Option<R>
+    where
+        F: FnMut() -> Option<R>,
+    {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            if let Some(r) = ready() {
+                return Some(r);
+            }
+            pending = match deadline {
+                None => self.condvar.wait(pending).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    self.condvar.wait_timeout(pending, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+}
+
+impl Drop for Signal {
+    fn drop(&mut self) {
+        if let Some((read_fd, write_fd)) = self.wake_pipe {
+            unsafe {
+                libc::close(read_fd);
+                libc::close(write_fd);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn notify_before_wait_is_not_lost() {
+        let signal = Signal::new();
+        signal.notify(WAKE_REASON_PACKETS);
+        signal.notify(WAKE_REASON_SOCKETS);
+        assert_eq!(
+            signal.wait(Some(Duration::from_secs(10))),
+            WAKE_REASON_PACKETS | WAKE_REASON_SOCKETS
+        );
+        assert_eq!(signal.wait(Some(Duration::from_millis(1))), WAKE_REASON_TIMEOUT);
+    }
+
+    #[test]
+    fn notify_from_another_thread_wakes_the_waiter() {
+        let signal = Arc::new(Signal::new());
+        let notifier = signal.clone();
+        let thread = thread::spawn(move || notifier.notify(WAKE_REASON_WAKE));
+        assert_eq!(signal.wait(None), WAKE_REASON_WAKE);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn wake_fd_is_readable_until_the_reasons_are_taken() {
+        let signal = Signal::with_wake_fd().unwrap();
+        let readable = |signal: &Signal| {
+            let mut pollfd = libc::pollfd {
+                fd: signal.wake_fd().unwrap(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            unsafe { libc::poll(&mut pollfd, 1, 0) == 1 }
+        };
+        assert!(!readable(&signal));
+        signal.notify(WAKE_REASON_SOCKETS);
+        signal.notify(WAKE_REASON_WAKE);
+        assert!(readable(&signal));
+        assert_eq!(signal.take(), WAKE_REASON_SOCKETS | WAKE_REASON_WAKE);
+        assert!(!readable(&signal));
+    }
+
+    #[test]
+    fn wait_until_wakes_every_waiter() {
+        let signal = Arc::new(Signal::new());
+        let ready = Arc::new(AtomicBool::new(false));
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let signal = signal.clone();
+                let ready = ready.clone();
+                thread::spawn(move || {
+                    signal.wait_until(None, || {
+                        if ready.load(Ordering::SeqCst) {
+                            Some(())
+                        } else {
+                            None
+                        }
+                    })
+                })
+            })
+            .collect();
+        ready.store(true, Ordering::SeqCst);
+        signal.notify(WAKE_REASON_WAKE);
+        for waiter in waiters {
+            assert_eq!(waiter.join().unwrap(), Some(()));
+        }
+        assert_eq!(signal.wait_until(Some(Duration::from_millis(1)), || None::<()>), None);
+    }
+}
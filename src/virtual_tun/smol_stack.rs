@@ -1,20 +1,35 @@
 //use smoltcp_openvpn_bridge::virtual_tun::VirtualTunInterface;
-use super::interface::{CBuffer, CIpAddress, CIpv4Address, CIpv4Cidr, CIpv6Address, CIpv6Cidr};
+use super::clock::Clock;
+use super::filter::PacketFilter;
+use super::interface::{CBuffer, CEthernetAddress, CIpAddress, CIpv4Address, CIpv4Cidr};
+use super::ingress::IngressChecks;
+use super::interface::{CIpv6Address, CIpv6Cidr};
+use super::nat::Nat;
+#[cfg(feature = "metrics")]
+use super::metrics::{Gauges, MetricsServer, StackMetrics};
+use super::queue::{FlowNotifier, PacketQueue, QueueItem, QueueLimits};
+use super::queue::{FLOW_EVENT_SOCKET_READABLE, FLOW_EVENT_SOCKET_WRITABLE};
+use super::queue::{QUEUE_PACKETS_FROM_INSIDE, QUEUE_PACKETS_FROM_OUTSIDE};
+use super::queue::{QUEUE_SOCKET_RECEIVED, QUEUE_SOCKET_TO_SEND};
+use super::ring::{PacketRing, PushError};
+use super::signal::Signal;
+use super::signal::{WAKE_REASON_PACKETS, WAKE_REASON_ROOM, WAKE_REASON_SOCKETS};
+use super::signal::{WAKE_REASON_SHUT_DOWN, WAKE_REASON_TIMEOUT, WAKE_REASON_WAKE};
 use super::virtual_tun::VirtualTunInterface as TunDevice;
-use smoltcp::iface::{Interface, InterfaceBuilder, Routes};
-use smoltcp::phy::wait as phy_wait;
-use smoltcp::phy::{self, Device};
+use smoltcp::iface::{Interface, InterfaceBuilder, NeighborCache, Route, Routes};
+use smoltcp::phy::{self, Device, Medium};
 use std::os::unix::io::AsRawFd;
 use std::time::Duration;
 
 use smoltcp::socket::{
     AnySocket, RawSocket, RawSocketBuffer, Socket, SocketHandle, SocketRef, SocketSet, TcpSocket,
-    TcpSocketBuffer, UdpSocket, UdpSocketBuffer,
+    TcpSocketBuffer, TcpState, UdpPacketMetadata, UdpSocket, UdpSocketBuffer,
 };
 use smoltcp::storage::PacketMetadata;
+use smoltcp::time::Duration as SmolDuration;
 use smoltcp::time::Instant;
 use smoltcp::wire::{
-    IpAddress, IpCidr, IpEndpoint, IpProtocol, IpVersion, Ipv4Address, Ipv6Address,
+    EthernetAddress, IpAddress, IpCidr, IpEndpoint, IpProtocol, IpVersion, Ipv4Address, Ipv6Address,
 };
 use std::cell::RefCell;
 use std::collections::BTreeMap;
@@ -24,10 +39,43 @@ use std::ffi::c_void;
 use std::ptr;
 use std::rc::Rc;
 use std::slice;
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::vec::Vec;
 
-#[derive(PartialEq, Clone)]
+//Warning: keep these synced with the SMOL_RESULT_* constants on interface.h
+pub const SMOL_RESULT_OK: u8 = 0;
+//No such socket, or nothing to receive yet
+pub const SMOL_RESULT_NOT_AVAILABLE: u8 = 1;
+pub const SMOL_RESULT_ERROR: u8 = 2;
+//Operation or option not supported by this socket type
+pub const SMOL_RESULT_UNSUPPORTED: u8 = 3;
+pub const SMOL_RESULT_INVALID_VALUE: u8 = 4;
+//The peer closed its write side and every byte it sent was already received
+pub const SMOL_RESULT_END_OF_STREAM: u8 = 5;
+//A queue limit was reached, retry after the corresponding writable event
+pub const SMOL_RESULT_WOULD_BLOCK: u8 = 6;
+//The caller's buffer can't hold the next datagram/packet, which stays queued
+pub const SMOL_RESULT_BUFFER_TOO_SMALL: u8 = 7;
+//A blocking receive waited as long as it was told to
+pub const SMOL_RESULT_TIMED_OUT: u8 = 8;
+//A blocking receive was interrupted by cancel_receive
+pub const SMOL_RESULT_CANCELLED: u8 = 9;
+//The socket was reset, timed out or removed, and everything received was already read
+pub const SMOL_RESULT_CLOSED: u8 = 10;
+//The stack was shut down, nothing can be done with it besides destroying it
+pub const SMOL_RESULT_SHUT_DOWN: u8 = 11;
+//Packets refused by the ingress checks of VirtualTun stacks, see ingress.rs
+pub const SMOL_RESULT_INVALID_IP_VERSION: u8 = 12;
+//IP header (or TCP/UDP/ICMP header) shorter than it should be or than it says
+pub const SMOL_RESULT_INVALID_HEADER_LENGTH: u8 = 13;
+//The IP total/payload length doesn't match the packet's size
+pub const SMOL_RESULT_INVALID_TOTAL_LENGTH: u8 = 14;
+pub const SMOL_RESULT_INVALID_CHECKSUM: u8 = 15;
+//Not to one of the stack's addresses, nor multicast or broadcast
+pub const SMOL_RESULT_NOT_OUR_ADDRESS: u8 = 16;
+
+#[derive(PartialEq, Clone, Debug)]
 pub enum SocketType {
     RAW_IPV4,
     RAW_IPV6,
@@ -36,6 +84,62 @@ pub enum SocketType {
     UDP,
 }
 
+/*
+    Tunable smoltcp socket options. Time based options are in
+    milliseconds and 0 disables them.
+    Warning: keep this synced with the SOCKET_OPTION_* constants on interface.h
+*/
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum SocketOption {
+    //TCP only. Interval between keep-alive probes on an idle connection
+    KeepAlive,
+    //TCP only. Aborts the connection if the peer is silent for this long
+    Timeout,
+    //TCP only. 1 enables Nagle's algorithm, 0 disables it
+    Nagle,
+    //TCP only. How long to wait before sending a standalone ACK
+    AckDelay,
+    //TCP and UDP. 1-255, or 0 to go back to smoltcp's default
+    HopLimit,
+}
+
+impl SocketOption {
+    pub fn from_u8(option: u8) -> Option<SocketOption> {
+        match option {
+            0 => Some(SocketOption::KeepAlive),
+            1 => Some(SocketOption::Timeout),
+            2 => Some(SocketOption::Nagle),
+            3 => Some(SocketOption::AckDelay),
+            4 => Some(SocketOption::HopLimit),
+            _ => None,
+        }
+    }
+}
+
+fn duration_from_millis(value: u64) -> Option<SmolDuration> {
+    if value == 0 {
+        None
+    } else {
+        Some(SmolDuration::from_millis(value))
+    }
+}
+
+fn millis_from_duration(duration: Option<SmolDuration>) -> u64 {
+    match duration {
+        Some(duration) => duration.total_millis(),
+        None => 0,
+    }
+}
+
+//None if the value is out of range, Some(None) means default hop limit
+fn hop_limit_from_u64(value: u64) -> Option<Option<u8>> {
+    match value {
+        0 => Some(None),
+        1..=255 => Some(Some(value as u8)),
+        _ => None,
+    }
+}
+
 pub struct Blob {
     pub data: Vec<u8>,
     pub start: usize,
@@ -54,6 +158,54 @@ pub struct Packet {
     pub endpoint: Option<IpEndpoint>,
 }
 
+impl Packet {
+    //Packet whose data is owned by Rust, like the ones received from sockets
+    pub fn from_vec(data: Vec<u8>, endpoint: Option<IpEndpoint>) -> Packet {
+        Packet {
+            blob: Blob {
+                data: data,
+                start: 0,
+                pointer_to_owner: None,
+                pointer_to_destructor: None,
+            },
+            endpoint: endpoint,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.blob.data.as_slice()[self.blob.start..]
+    }
+}
+
+//Writes where a received packet came from, if C++ asked for it
+pub fn write_address(address: *mut CIpAddress, endpoint: Option<IpEndpoint>) {
+    if address.is_null() {
+        return;
+    }
+    let c_address = match endpoint.map(|endpoint| endpoint.addr) {
+        Some(IpAddress::Ipv4(ipv4_address)) => CIpAddress {
+            is_ipv4: 1,
+            ipv4_address: ipv4_address.into(),
+            ipv6_address: CIpv6Address { address: [0; 8] },
+        },
+        Some(IpAddress::Ipv6(ipv6_address)) => CIpAddress {
+            is_ipv4: 0,
+            ipv4_address: CIpv4Address { address: [0; 4] },
+            ipv6_address: ipv6_address.into(),
+        },
+        _ => return,
+    };
+    unsafe { *address = c_address };
+}
+
+impl Blob {
+    //Forgets the owner without destructing it, for when C++ keeps it
+    pub fn give_back_owner(&mut self) {
+        self.pointer_to_owner = None;
+        self.pointer_to_destructor = None;
+    }
+}
+
 impl<'a> Drop for Blob {
     fn drop(&mut self) {
         let f = self.pointer_to_destructor;
@@ -67,45 +219,242 @@ impl<'a> Drop for Blob {
     }
 }
 
+/*
+    The parts of a SmolSocket that receiving needs, all shared. Blocking
+    receives wait on their own copy, never touching the SmolSocket, as
+    remove_socket may drop it meanwhile (waking them with
+    SMOL_RESULT_CLOSED)
+*/
+struct SocketReceiver {
+    socket_type: SocketType,
+    received: Arc<Mutex<PacketQueue<Packet>>>,
+    receive_finished: Arc<AtomicBool>,
+    receive_closed: Arc<AtomicBool>,
+    receive_cancels: Arc<AtomicUsize>,
+    smol_socket_has_data: Arc<Signal>,
+    has_data: Option<Arc<Signal>>,
+}
+
+impl SocketReceiver {
+    /*
+        Called after popping from `received`. If spin stopped reading the
+        socket because `received` was full, wake the poller so it continues
+    */
+    fn received_popped(&self) {
+        if self.received.lock().unwrap().take_unblocked() {
+            self.has_data.as_ref().unwrap().notify(WAKE_REASON_ROOM);
+        }
+    }
+
+    /*
+        What to return when `received` is empty. Flags are read before
+        popping: spin sets them only after pushing the last data, so if
+        one was set before we found nothing, nothing else will come
+    */
+    fn nothing_received(finished: bool, closed: bool) -> u8 {
+        if finished {
+            SMOL_RESULT_END_OF_STREAM
+        } else if closed {
+            SMOL_RESULT_CLOSED
+        } else {
+            SMOL_RESULT_NOT_AVAILABLE
+        }
+    }
+
+    fn receive(
+        &self,
+        cbuffer: *mut CBuffer,
+        allocate_function: extern "C" fn(size: usize) -> *mut u8,
+        address: *mut CIpAddress,
+    ) -> u8 {
+        let finished = self.receive_finished.load(Ordering::SeqCst);
+        let closed = self.receive_closed.load(Ordering::SeqCst);
+        let s = self.received.lock().unwrap().pop_front();
+        match s {
+            Some(packet) => {
+                self.received_popped();
+                let s = packet.as_slice();
+                let p: *mut u8 = allocate_function(s.len());
+                unsafe { ptr::copy(s.as_ptr(), p, s.len()) };
+                unsafe {
+                    *cbuffer = CBuffer {
+                        data: p,
+                        len: s.len(),
+                    };
+                }
+                write_address(address, packet.endpoint);
+                SMOL_RESULT_OK
+            }
+            None => SocketReceiver::nothing_received(finished, closed),
+        }
+    }
+
+    //See SmolSocket::receive_into
+    fn receive_into(
+        &self,
+        buffer: &mut [u8],
+        len: &mut usize,
+        endpoint: &mut Option<IpEndpoint>,
+    ) -> u8 {
+        if buffer.is_empty() {
+            return SMOL_RESULT_INVALID_VALUE;
+        }
+        let finished = self.receive_finished.load(Ordering::SeqCst);
+        let closed = self.receive_closed.load(Ordering::SeqCst);
+        {
+            let mut received = self.received.lock().unwrap();
+            if received.is_empty() {
+                return SocketReceiver::nothing_received(finished, closed);
+            }
+            if self.socket_type == SocketType::TCP {
+                let mut copied = 0;
+                *endpoint = received.front().unwrap().endpoint;
+                while copied < buffer.len() {
+                    let mut packet = match received.pop_front() {
+                        Some(packet) => packet,
+                        None => break,
+                    };
+                    let s = packet.as_slice();
+                    let n = std::cmp::min(s.len(), buffer.len() - copied);
+                    buffer[copied..copied + n].copy_from_slice(&s[..n]);
+                    copied += n;
+                    if n < s.len() {
+                        //Leftover bytes go back to the front for the next read
+                        packet.blob.start += n;
+                        received.push_front(packet);
+                    }
+                }
+                *len = copied;
+            } else {
+                let needed = received.front().unwrap().byte_len();
+                if needed > buffer.len() {
+                    *len = needed;
+                    return SMOL_RESULT_BUFFER_TOO_SMALL;
+                }
+                let packet = received.pop_front().unwrap();
+                let s = packet.as_slice();
+                buffer[..s.len()].copy_from_slice(s);
+                *len = s.len();
+                *endpoint = packet.endpoint;
+            }
+        }
+        self.received_popped();
+        SMOL_RESULT_OK
+    }
+
+    /*
+        Calls `receive` again every time spin wakes us, until it returns
+        something other than SMOL_RESULT_NOT_AVAILABLE (data, end of stream,
+        SMOL_RESULT_CLOSED...). Gives up with SMOL_RESULT_TIMED_OUT after
+        `timeout` (None waits forever) or SMOL_RESULT_CANCELLED if
+        cancel_receive is called meanwhile
+    */
+    fn wait<F>(&self, timeout: Option<Duration>, mut receive: F) -> u8
+    where
+        F: FnMut() -> u8,
+    {
+        let cancels = self.receive_cancels.load(Ordering::SeqCst);
+        self.smol_socket_has_data
+            .wait_until(timeout, || {
+                if self.receive_cancels.load(Ordering::SeqCst) != cancels {
+                    return Some(SMOL_RESULT_CANCELLED);
+                }
+                match receive() {
+                    SMOL_RESULT_NOT_AVAILABLE => None,
+                    r => Some(r),
+                }
+            })
+            .unwrap_or(SMOL_RESULT_TIMED_OUT)
+    }
+}
+
 pub struct SmolSocket {
     pub socket_type: SocketType,
     //Socket number inside SmolStack
     pub socket_handle: SocketHandle,
-    pub to_send: Arc<Mutex<VecDeque<Packet>>>,
+    pub to_send: Arc<Mutex<PacketQueue<Packet>>>,
     //If we couldn't send entire packet at once, hold it here for next send
     current_to_send: Option<Packet>,
-    pub received: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    //What was received, with the endpoint it came from
+    pub received: Arc<Mutex<PacketQueue<Packet>>>,
     /*
-        Same has_data condition variable used by SmolStack
+        Same has_data Signal used by SmolStack
         Used so EVERY time something is written to sockets
         the poller loop is unlocked
     */
-    has_data: Option<Arc<(Mutex<()>, Condvar)>>,
+    has_data: Option<Arc<Signal>>,
     /*
         Specific for SmolSocket, used to unlock receive_wait, which
         is unlocked by SmolStack when new data is written to this 
         SmolSocket
     */
-    smol_socket_has_data: Arc<(Mutex<()>, Condvar)>,
+    smol_socket_has_data: Arc<Signal>,
     //The endpoint that this socket is connected to (TCP case)
     endpoint: Option<IpAddress>,
+    //Set by shutdown_write, FIN is sent once `to_send` and `current_to_send` drain
+    shutdown_write: bool,
+    //FIN was handed to smoltcp (TcpSocket::close was called)
+    write_closed: bool,
+    //The TCP socket reached a state where it can send or receive at least once
+    connected_once: bool,
+    /*
+        connect or listen was called on the TCP socket, so getting back to
+        Closed without connecting means it was refused or timed out
+    */
+    opened: bool,
+    /*
+        Set by SmolStack::spin when the peer won't send anything else
+        and all its data is already in `received`. Checked by receive
+        and receive_wait to report end of stream instead of blocking
+    */
+    receive_finished: Arc<AtomicBool>,
+    /*
+        Set when the socket won't receive anything else without the peer
+        ending the stream in order: reset, timed out, or the SmolSocket
+        was dropped. Receives return SMOL_RESULT_CLOSED once `received`
+        is drained
+    */
+    receive_closed: Arc<AtomicBool>,
+    //Bumped by cancel_receive, see SocketReceiver::wait
+    receive_cancels: Arc<AtomicUsize>,
+    //On the stack's clock, for the connection table
+    created: Instant,
+}
+
+//Removed, or the whole stack destroyed: nobody will fill `received` again
+impl Drop for SmolSocket {
+    fn drop(&mut self) {
+        self.receive_closed.store(true, Ordering::SeqCst);
+        self.smol_socket_has_data.notify(WAKE_REASON_WAKE);
+    }
 }
 
 impl<'a> SmolSocket {
     pub fn new(
         socket_handle: SocketHandle,
         socket_type: SocketType,
-        has_data: Option<Arc<(Mutex<()>, Condvar)>>,
+        has_data: Option<Arc<Signal>>,
+        to_send_limits: QueueLimits,
+        received_limits: QueueLimits,
+        created: Instant,
     ) -> SmolSocket {
         SmolSocket {
             socket_type: socket_type,
             socket_handle: socket_handle,
-            to_send: Arc::new(Mutex::new(VecDeque::new())),
+            to_send: Arc::new(Mutex::new(PacketQueue::new(to_send_limits))),
             current_to_send: None,
-            received: Arc::new(Mutex::new(VecDeque::new())),
+            received: Arc::new(Mutex::new(PacketQueue::new(received_limits))),
             has_data: has_data,
-            smol_socket_has_data: Arc::new((Mutex::new(()), Condvar::new())),
+            smol_socket_has_data: Arc::new(Signal::new()),
             endpoint: None,
+            shutdown_write: false,
+            write_closed: false,
+            connected_once: false,
+            opened: false,
+            receive_finished: Arc::new(AtomicBool::new(false)),
+            receive_closed: Arc::new(AtomicBool::new(false)),
+            receive_cancels: Arc::new(AtomicUsize::new(0)),
+            created: created,
         }
     }
 
@@ -113,16 +462,36 @@ impl<'a> SmolSocket {
         if packet.endpoint.is_none()
             && (self.socket_type == SocketType::UDP || self.socket_type == SocketType::ICMP)
         {
-            panic!("this socket type needs an endpoint to send to");
+            //This socket type needs an endpoint to send to
+            return SMOL_RESULT_INVALID_VALUE;
+        }
+        if self.shutdown_write {
+            return SMOL_RESULT_ERROR;
         }
         //println!("packet being sent on SmolSocket!");
-        self.to_send.lock().unwrap().push_back(packet);
-        let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
+        //If the queue is full C++ keeps the owner, to send it again later
+        if let Err(mut packet) = self.to_send.lock().unwrap().push_back(packet) {
+            packet.blob.give_back_owner();
+            return SMOL_RESULT_WOULD_BLOCK;
+        }
         //Unlock the poller thread because new data is available
-        has_data_condition_variable.notify_all();
+        self.has_data.as_ref().unwrap().notify(WAKE_REASON_SOCKETS);
         0
     }
 
+    //Shared parts receiving works on, see SocketReceiver
+    fn receiver(&self) -> SocketReceiver {
+        SocketReceiver {
+            socket_type: self.socket_type.clone(),
+            received: self.received.clone(),
+            receive_finished: self.receive_finished.clone(),
+            receive_closed: self.receive_closed.clone(),
+            receive_cancels: self.receive_cancels.clone(),
+            smol_socket_has_data: self.smol_socket_has_data.clone(),
+            has_data: self.has_data.clone(),
+        }
+    }
+
     //TODO: figure out a better way than copying. Inneficient receive
     pub fn receive(
         &mut self,
@@ -130,82 +499,94 @@ impl<'a> SmolSocket {
         allocate_function: extern "C" fn(size: usize) -> *mut u8,
         address: *mut CIpAddress,
     ) -> u8 {
-        let s;
-        {
-            //Create a scope so we hold the queue for the least ammount needed
-            //TODO: do I really need to create a scope?
-            s = self.received.lock().unwrap().pop_front()
-        }
-        match s {
-            Some(s) => {
-                let p: *mut u8 = allocate_function(s.len());
-                unsafe { ptr::copy(s.as_ptr(), p, s.len()) };
-                unsafe {
-                    *cbuffer = CBuffer {
-                        data: p,
-                        len: s.len(),
-                    };
-                }
-                //TODO:!!!!! fill CIpAddress here
-                0
-            }
-            None => 1,
-        }
+        self.receiver().receive(cbuffer, allocate_function, address)
     }
 
-    //TODO: figure out a better way than copying. Inneficient receive
+    /*
+        Same as receive but blocks until there's something to receive,
+        see SocketReceiver::wait for when it gives up
+    */
     pub fn receive_wait(
         &mut self,
         cbuffer: *mut CBuffer,
         allocate_function: extern "C" fn(size: usize) -> *mut u8,
         address: *mut CIpAddress,
+        timeout: Option<Duration>,
     ) -> u8 {
-        let mut s;
-        loop {
-            {
-                s = self.received.lock().unwrap().pop_front()
-            }
-            match &s {
-                //If we have a packet, we dont need to wait, so break
-                Some(_) => {
-                    break;
-                }
-                None => {}
-            }
-            let (mutex, has_data_condition_variable) = &*self.smol_socket_has_data.as_ref().clone();
-            has_data_condition_variable.wait(mutex.lock().unwrap());
-            //let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
-            //has_data_condition_variable.wait(mutex.lock().unwrap());
+        let receiver = self.receiver();
+        receiver.wait(timeout, || receiver.receive(cbuffer, allocate_function, address))
+    }
+
+    /*
+        Copies what was received into `buffer` instead of allocating on
+        C++ side, and sets `len` to how many bytes were copied.
+        TCP is a stream, so the buffer is filled with as much as is queued
+        and what doesn't fit stays for the next read. Datagrams are never
+        split: if the next one doesn't fit, nothing is copied, `len` gets
+        the size it needs and SMOL_RESULT_BUFFER_TOO_SMALL is returned.
+        `endpoint` gets where the data came from
+    */
+    pub fn receive_into(
+        &mut self,
+        buffer: &mut [u8],
+        len: &mut usize,
+        endpoint: &mut Option<IpEndpoint>,
+    ) -> u8 {
+        self.receiver().receive_into(buffer, len, endpoint)
+    }
+
+    //Same as receive_into but blocks, like receive_wait
+    pub fn receive_wait_into(
+        &mut self,
+        buffer: &mut [u8],
+        len: &mut usize,
+        endpoint: &mut Option<IpEndpoint>,
+        timeout: Option<Duration>,
+    ) -> u8 {
+        let receiver = self.receiver();
+        receiver.wait(timeout, || receiver.receive_into(buffer, len, endpoint))
+    }
+
+    /*
+        Wakes every thread blocked on receive_wait/receive_wait_into of
+        this socket, they return SMOL_RESULT_CANCELLED. Waits started
+        afterwards block as usual
+    */
+    pub fn cancel_receive(&self) {
+        self.receive_cancels.fetch_add(1, Ordering::SeqCst);
+        self.smol_socket_has_data.notify(WAKE_REASON_WAKE);
+    }
+
+    /*
+        Half-close: everything already queued is still sent, then FIN.
+        The socket keeps receiving until the peer closes too.
+        TCP only, and send fails after this is called
+    */
+    pub fn shutdown_write(&mut self) -> u8 {
+        if self.socket_type != SocketType::TCP {
+            return SMOL_RESULT_UNSUPPORTED;
         }
-        use std::io::{self, Write};
-        match s {
-            Some(s) => {
-                let p: *mut u8 = allocate_function(s.len());
-                if s[0]== 36 {
-                    //print!("$");
-                    //io::stdout().flush().unwrap();
-                } else {
-                    //println!("{}", s[0]);
-                }
-                //println!("bufferlen {}, len+1: {}",  s.len(),  s.len()+1);
-                unsafe { ptr::copy(s.as_ptr(), p, s.len()) };
-                unsafe {
-                    *cbuffer = CBuffer {
-                        data: p,
-                        len: s.len(),
-                    };
-                }
-                /*
-                let (mutex, has_data_condition_variable) =
-                    &*self.has_data.as_ref().unwrap().clone();
-                //Unlock the poller thread because new data is available
-                has_data_condition_variable.notify_all();
-                */
-                //TODO:!!!!! fill CIpAddress here
+        self.shutdown_write = true;
+        //Unlock the poller thread so spin flushes and closes
+        self.has_data.as_ref().unwrap().notify(WAKE_REASON_SOCKETS);
+        SMOL_RESULT_OK
+    }
 
-                0
+    /*
+        Drops everything still queued, oldest first: the partly sent
+        packet, `to_send`, then `received`. Buffers owned by C++ go back
+        through their destructors as they are dropped
+    */
+    fn release_queues(&mut self) {
+        self.current_to_send.take();
+        for queue in &[&self.to_send, &self.received] {
+            loop {
+                //Dropped outside of the lock, the destructor calls C++
+                let packet = queue.lock().unwrap().pop_front();
+                if packet.is_none() {
+                    break;
+                }
             }
-            None => 1,
         }
     }
 
@@ -226,6 +607,92 @@ impl<'a> SmolSocket {
     }
 }
 
+/*
+    Socket handle keys given to C++ are generational: the low half of the
+    key is a slot index and the high half is how many times that slot was
+    used. Removing a socket bumps its slot's generation, so an old key to
+    it is rejected instead of pointing to the next socket in the same slot.
+    Generations start at 1, so 0 is never a valid key
+*/
+const KEY_INDEX_BITS: u32 = (std::mem::size_of::<usize>() * 4) as u32;
+const KEY_INDEX_MASK: usize = (1 << KEY_INDEX_BITS) - 1;
+const KEY_MAX_GENERATION: usize = KEY_INDEX_MASK;
+
+fn socket_handle_key(index: usize, generation: usize) -> usize {
+    (generation << KEY_INDEX_BITS) | index
+}
+
+/*
+    One socket of the stack as `ss -tan` would show it, see
+    SmolStack::connections. Unbound or unconnected endpoints have an
+    unspecified address and port 0, listening sockets an unspecified
+    address and their port
+*/
+#[derive(Clone, Debug)]
+pub struct Connection {
+    pub key: usize,
+    pub socket_type: SocketType,
+    pub local: IpEndpoint,
+    pub remote: IpEndpoint,
+    //None for anything but TCP
+    pub tcp_state: Option<TcpState>,
+    //What C++ gave us and the peer didn't acknowledge yet, queued or in the smoltcp socket
+    pub send_queue: usize,
+    //What the peer sent and C++ didn't receive yet, in the smoltcp socket or queued
+    pub recv_queue: usize,
+    //Since the socket was added, on the stack's clock
+    pub age: SmolDuration,
+}
+
+impl Connection {
+    pub fn to_json(&self) -> serde_json::Value {
+        let socket_type = match self.socket_type {
+            SocketType::TCP => "tcp",
+            SocketType::UDP => "udp",
+            SocketType::ICMP => "icmp",
+            SocketType::RAW_IPV4 => "raw_ipv4",
+            SocketType::RAW_IPV6 => "raw_ipv6",
+        };
+        serde_json::json!({
+            "key": self.key,
+            "type": socket_type,
+            "local": self.local.to_string(),
+            "remote": self.remote.to_string(),
+            "state": self.tcp_state.map(|state| state.to_string()),
+            "send_queue": self.send_queue,
+            "recv_queue": self.recv_queue,
+            "age_millis": self.age.total_millis(),
+        })
+    }
+}
+
+//Default size of the smoltcp buffers of each TCP socket, in bytes
+const TCP_BUFFER_BYTES: usize = 65000;
+//Default size of the smoltcp buffers of each UDP socket, in datagrams and in bytes
+const UDP_BUFFER_PACKETS: usize = 64;
+const UDP_BUFFER_BYTES: usize = 65535;
+
+/*
+    Sizes of the smoltcp buffers given to every new socket, the same
+    for receiving and for sending. Sockets already added keep theirs
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SocketBuffers {
+    pub tcp_bytes: usize,
+    pub udp_packets: usize,
+    pub udp_bytes: usize,
+}
+
+impl Default for SocketBuffers {
+    fn default() -> SocketBuffers {
+        SocketBuffers {
+            tcp_bytes: TCP_BUFFER_BYTES,
+            udp_packets: UDP_BUFFER_PACKETS,
+            udp_bytes: UDP_BUFFER_BYTES,
+        }
+    }
+}
+
 pub struct SmolStack<'a, 'b: 'a, 'c: 'a + 'b, DeviceT>
 where
     DeviceT: for<'d> Device<'d>,
@@ -235,21 +702,53 @@ where
         for the socket. 'a is the lifetime of the socket itself
     */
     pub sockets: SocketSet<'a, 'b, 'c>,
-    current_key: usize,
+    //Current generation of each key slot, see socket_handle_key
+    key_generations: Vec<usize>,
+    free_key_slots: Vec<usize>,
     pub fd: Option<i32>,
     smol_sockets: HashMap<usize, SmolSocket>,
     pub device: Option<DeviceT>,
     ip_addrs: Option<std::vec::Vec<IpCidr>>,
     default_v4_gw: Option<Ipv4Address>,
     default_v6_gw: Option<Ipv6Address>,
+    //Other routes, (destination, gateway), added on finalize
+    routes: Vec<(IpCidr, IpAddress)>,
+    //Not used by smoltcp, kept for the application that resolves names
+    dns_servers: Vec<IpAddress>,
+    //Only for devices with Medium::Ethernet, which can't be finalized without it
+    ethernet_address: Option<EthernetAddress>,
     pub interface: Option<Interface<'a, 'b, 'c, DeviceT>>,
     //For TunInterface only. Couldn't think of a way to
     //create a specialized SmolStack for this case only
-    packets_from_inside: Option<Arc<Mutex<VecDeque<Vec<u8>>>>>,
-    packets_from_outside: Option<Arc<Mutex<VecDeque<Blob>>>>,
-    has_data: Option<Arc<(Mutex<()>, Condvar)>>,
+    packets_from_inside: Option<Arc<PacketRing>>,
+    packets_from_outside: Option<Arc<PacketRing>>,
+    has_data: Option<Arc<Signal>>,
+    //The firewall the device checks packets against, VirtualTun only
+    packet_filter: Option<Arc<PacketFilter>>,
+    //Limits given to the queues of every new SmolSocket
+    socket_to_send_limits: QueueLimits,
+    socket_received_limits: QueueLimits,
+    socket_buffers: SocketBuffers,
+    flow_notifier: Arc<FlowNotifier>,
+    //Time source for poll, see Clock
+    clock: Clock,
+    //What send checks before queueing, VirtualTun only
+    ingress: IngressChecks,
+    //Relays flows to addresses that aren't ours through host sockets, VirtualTun only
+    nat: Option<Nat>,
+    #[cfg(feature = "metrics")]
+    metrics: Arc<StackMetrics>,
+    //When poll last refreshed the gauges of `metrics`
+    #[cfg(feature = "metrics")]
+    metrics_refreshed: std::time::Instant,
+    #[cfg(feature = "metrics")]
+    metrics_server: Option<MetricsServer>,
 }
 
+//How often poll walks the sockets to refresh the metrics gauges
+#[cfg(feature = "metrics")]
+const METRICS_REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
 impl<'a, 'b: 'a, 'c: 'a + 'b, DeviceT> SmolStack<'a, 'b, 'c, DeviceT>
 where
     DeviceT: for<'d> Device<'d>,
@@ -257,26 +756,247 @@ where
     pub fn new(
         device: DeviceT,
         fd: Option<i32>,
-        packets_from_inside: Option<Arc<Mutex<VecDeque<Vec<u8>>>>>,
-        packets_from_outside: Option<Arc<Mutex<VecDeque<Blob>>>>,
-        has_data: Option<Arc<(Mutex<()>, Condvar)>>,
+        packets_from_inside: Option<Arc<PacketRing>>,
+        packets_from_outside: Option<Arc<PacketRing>>,
+        has_data: Option<Arc<Signal>>,
+        packet_filter: Option<Arc<PacketFilter>>,
+        flow_notifier: Arc<FlowNotifier>,
     ) -> SmolStack<'a, 'b, 'c, DeviceT> {
         let socket_set = SocketSet::new(vec![]);
         let ip_addrs = std::vec::Vec::new();
         SmolStack {
             sockets: socket_set,
-            current_key: 0,
+            key_generations: Vec::new(),
+            free_key_slots: Vec::new(),
             fd: fd,
             smol_sockets: HashMap::new(),
             device: Some(device),
             ip_addrs: Some(ip_addrs),
             default_v4_gw: None,
             default_v6_gw: None,
+            routes: Vec::new(),
+            dns_servers: Vec::new(),
+            ethernet_address: None,
             interface: None,
             packets_from_inside: packets_from_inside,
             packets_from_outside: packets_from_outside,
             has_data: has_data,
+            packet_filter: packet_filter,
+            socket_to_send_limits: QueueLimits::unlimited(),
+            socket_received_limits: QueueLimits::unlimited(),
+            socket_buffers: SocketBuffers::default(),
+            flow_notifier: flow_notifier,
+            clock: Clock::System,
+            ingress: IngressChecks::default(),
+            nat: None,
+            #[cfg(feature = "metrics")]
+            metrics: Arc::new(StackMetrics::new()),
+            #[cfg(feature = "metrics")]
+            metrics_refreshed: std::time::Instant::now(),
+            #[cfg(feature = "metrics")]
+            metrics_server: None,
+        }
+    }
+
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    //SMOL_RESULT_UNSUPPORTED if the stack uses the system clock
+    pub fn advance_clock(&mut self, duration: SmolDuration) -> u8 {
+        if self.clock.advance(duration) {
+            SMOL_RESULT_OK
+        } else {
+            SMOL_RESULT_UNSUPPORTED
+        }
+    }
+
+    /*
+        How long until poll has something to do (retransmissions, delayed
+        ACKs, keep-alives...), measured on the stack's clock. None if
+        there's no timer pending or the interface isn't finalized
+    */
+    pub fn poll_delay(&mut self) -> Option<SmolDuration> {
+        let timestamp = self.clock.now();
+        let sockets = &self.sockets;
+        let delay = self
+            .interface
+            .as_mut()
+            .and_then(|interface| interface.poll_delay(sockets, timestamp));
+        match (delay, self.nat.as_ref().and_then(|nat| nat.poll_delay())) {
+            (Some(delay), Some(nat_delay)) => Some(std::cmp::min(delay, nat_delay)),
+            (delay, nat_delay) => delay.or(nat_delay),
+        }
+    }
+
+    /*
+        Delay for waiting on the fd of Tun/Tap stacks. With a manual
+        clock, time doesn't pass while we wait, so we only check
+        the fd for readiness
+    */
+    pub fn fd_wait_delay(&mut self, timestamp: Instant) -> Option<SmolDuration> {
+        if self.clock.is_manual() {
+            return Some(SmolDuration::from_millis(0));
+        }
+        let sockets = &self.sockets;
+        self.interface
+            .as_mut()
+            .and_then(|interface| interface.poll_delay(sockets, timestamp))
+    }
+
+    /*
+        Limits for one of the QUEUE_* queues. Socket queue limits apply
+        to every socket, existing or created later. Packet queues only
+        exist on VirtualTun stacks.
+    */
+    pub fn set_queue_limits(&mut self, queue: u8, limits: QueueLimits) -> u8 {
+        match queue {
+            QUEUE_SOCKET_TO_SEND => {
+                self.socket_to_send_limits = limits;
+                for smol_socket in self.smol_sockets.values_mut() {
+                    smol_socket.to_send.lock().unwrap().set_limits(limits);
+                }
+            }
+            QUEUE_SOCKET_RECEIVED => {
+                self.socket_received_limits = limits;
+                for smol_socket in self.smol_sockets.values_mut() {
+                    smol_socket.received.lock().unwrap().set_limits(limits);
+                }
+            }
+            QUEUE_PACKETS_FROM_INSIDE => match &self.packets_from_inside {
+                Some(packets_from_inside) => packets_from_inside.set_limits(limits),
+                None => return SMOL_RESULT_UNSUPPORTED,
+            },
+            QUEUE_PACKETS_FROM_OUTSIDE => match &self.packets_from_outside {
+                Some(packets_from_outside) => packets_from_outside.set_limits(limits),
+                None => return SMOL_RESULT_UNSUPPORTED,
+            },
+            _ => return SMOL_RESULT_INVALID_VALUE,
+        }
+        //Raising a limit may unblock the poller
+        self.has_data.as_ref().unwrap().notify(WAKE_REASON_ROOM);
+        SMOL_RESULT_OK
+    }
+
+    //INGRESS_CHECK_* bits, SMOL_RESULT_INVALID_VALUE for unknown ones
+    pub fn set_ingress_checks(&mut self, checks: u8) -> u8 {
+        if self.ingress.set_checks(checks) {
+            SMOL_RESULT_OK
+        } else {
+            SMOL_RESULT_INVALID_VALUE
+        }
+    }
+
+    pub fn ingress_checks(&self) -> &IngressChecks {
+        &self.ingress
+    }
+
+    //Before finalize, the ones added so far
+    /*
+        Turns the stack into a NAT gateway for whoever sends it packets,
+        see Nat. Must be called before finalize, which then makes smoltcp
+        take packets for any address and routes them all through the
+        stack's own addresses, replacing the default gateways. The
+        INGRESS_CHECK_DESTINATION check refuses what the NAT is for, so
+        don't enable both. `receive_budget` must also be given to the device
+    */
+    pub fn enable_nat(&mut self, receive_budget: Arc<AtomicUsize>) -> u8 {
+        if self.interface.is_some() {
+            return SMOL_RESULT_ERROR;
+        }
+        let (packets_from_outside, has_data, packet_filter) = match (
+            self.packets_from_outside.as_ref(),
+            self.has_data.as_ref(),
+            self.packet_filter.as_ref(),
+        ) {
+            (Some(packets_from_outside), Some(has_data), Some(packet_filter)) => {
+                (packets_from_outside.clone(), has_data.clone(), packet_filter.clone())
+            }
+            _ => return SMOL_RESULT_UNSUPPORTED,
+        };
+        self.nat = Some(Nat::new(packets_from_outside, receive_budget, has_data, packet_filter));
+        SMOL_RESULT_OK
+    }
+
+    //Flows to `host_alias` go to the host's loopback instead
+    pub fn set_nat_host_alias(&mut self, host_alias: IpAddress) -> u8 {
+        match self.nat.as_mut() {
+            Some(nat) => {
+                nat.set_host_alias(host_alias);
+                SMOL_RESULT_OK
+            }
+            None => SMOL_RESULT_NOT_AVAILABLE,
+        }
+    }
+
+    //Open (TCP, UDP) flows of the NAT, None if it isn't enabled
+    pub fn nat_flows(&self) -> Option<(usize, usize)> {
+        self.nat.as_ref().map(|nat| nat.flows())
+    }
+
+    fn ip_addrs(&self) -> &[IpCidr] {
+        match (self.interface.as_ref(), self.ip_addrs.as_ref()) {
+            (Some(interface), _) => interface.ip_addrs(),
+            (None, Some(ip_addrs)) => ip_addrs,
+            (None, None) => &[],
+        }
+    }
+
+    pub fn flow_notifier(&self) -> Arc<FlowNotifier> {
+        self.flow_notifier.clone()
+    }
+
+    pub fn packet_filter(&self) -> Option<Arc<PacketFilter>> {
+        self.packet_filter.clone()
+    }
+
+    /*
+        Every socket of the stack, ordered by key, with its endpoints,
+        state and how much is waiting in each direction. For finding out
+        why a connection is stuck, not for polling often: it walks and
+        locks every socket
+    */
+    pub fn connections(&mut self) -> Vec<Connection> {
+        let now = self.clock.now();
+        let mut connections = Vec::with_capacity(self.smol_sockets.len());
+        for (&key, smol_socket) in self.smol_sockets.iter() {
+            let current_to_send = smol_socket
+                .current_to_send
+                .as_ref()
+                .map(|packet| packet.byte_len())
+                .unwrap_or(0);
+            let mut connection = Connection {
+                key: key,
+                socket_type: smol_socket.socket_type.clone(),
+                local: IpEndpoint::default(),
+                remote: IpEndpoint::default(),
+                tcp_state: None,
+                send_queue: smol_socket.to_send.lock().unwrap().bytes() + current_to_send,
+                recv_queue: smol_socket.received.lock().unwrap().bytes(),
+                age: now - smol_socket.created,
+            };
+            match smol_socket.socket_type {
+                SocketType::TCP => {
+                    let socket = self.sockets.get::<TcpSocket>(smol_socket.socket_handle);
+                    connection.local = socket.local_endpoint();
+                    connection.remote = socket.remote_endpoint();
+                    connection.tcp_state = Some(socket.state());
+                    connection.send_queue += socket.send_queue();
+                    connection.recv_queue += socket.recv_queue();
+                }
+                SocketType::UDP => {
+                    connection.local = self.sockets.get::<UdpSocket>(smol_socket.socket_handle).endpoint();
+                }
+                _ => {}
+            }
+            connections.push(connection);
         }
+        connections.sort_by_key(|connection| connection.key);
+        connections
     }
 
     pub fn get_smol_socket(&mut self, smol_socket_handle: usize) -> Option<&mut SmolSocket> {
@@ -284,29 +1004,79 @@ where
         smol_socket
     }
 
-    pub fn new_socket_handle_key(&mut self) -> usize {
-        //TODO: panic when usize is about to overflow
-        self.current_key += 1;
-        self.current_key
+    //None when every slot was used up to its last generation
+    fn new_socket_handle_key(&mut self) -> Option<usize> {
+        match self.free_key_slots.pop() {
+            Some(index) => Some(socket_handle_key(index, self.key_generations[index])),
+            None => {
+                let index = self.key_generations.len();
+                if index > KEY_INDEX_MASK {
+                    return None;
+                }
+                self.key_generations.push(1);
+                Some(socket_handle_key(index, 1))
+            }
+        }
+    }
+
+    fn release_socket_handle_key(&mut self, smol_socket_handle: usize) {
+        let index = smol_socket_handle & KEY_INDEX_MASK;
+        if self.key_generations[index] < KEY_MAX_GENERATION {
+            self.key_generations[index] += 1;
+            self.free_key_slots.push(index);
+        }
+        //else the slot is retired, reusing it would make old keys valid again
     }
 
-    pub fn add_socket(&mut self, socket_type: SocketType, smol_socket_handle: usize) -> u8 {
+    /*
+        Creates a socket and writes the key that identifies it on
+        `smol_socket_handle`. Keys are only ever created here
+    */
+    pub fn add_socket(&mut self, socket_type: SocketType, smol_socket_handle: &mut usize) -> u8 {
+        if socket_type != SocketType::TCP && socket_type != SocketType::UDP {
+            return SMOL_RESULT_UNSUPPORTED;
+        }
+        *smol_socket_handle = match self.new_socket_handle_key() {
+            Some(key) => key,
+            None => return SMOL_RESULT_ERROR,
+        };
+        let smol_socket_handle = *smol_socket_handle;
         match socket_type {
             SocketType::TCP => {
-                let rx_buffer = TcpSocketBuffer::new(vec![0; 65000]);
-                let tx_buffer = TcpSocketBuffer::new(vec![0; 65000]);
+                let rx_buffer = TcpSocketBuffer::new(vec![0; self.socket_buffers.tcp_bytes]);
+                let tx_buffer = TcpSocketBuffer::new(vec![0; self.socket_buffers.tcp_bytes]);
                 let socket = TcpSocket::new(rx_buffer, tx_buffer);
                 let handle = self.sockets.add(socket);
-                let smol_socket = SmolSocket::new(handle, SocketType::TCP, self.has_data.clone());
+                let smol_socket = SmolSocket::new(
+                    handle,
+                    SocketType::TCP,
+                    self.has_data.clone(),
+                    self.socket_to_send_limits,
+                    self.socket_received_limits,
+                    self.clock.now(),
+                );
                 self.smol_sockets.insert(smol_socket_handle, smol_socket);
                 0
             }
             SocketType::UDP => {
-                let rx_buffer = UdpSocketBuffer::new(Vec::new(), vec![0; 1024]);
-                let tx_buffer = UdpSocketBuffer::new(Vec::new(), vec![0; 1024]);
+                let rx_buffer = UdpSocketBuffer::new(
+                    vec![UdpPacketMetadata::EMPTY; self.socket_buffers.udp_packets],
+                    vec![0; self.socket_buffers.udp_bytes],
+                );
+                let tx_buffer = UdpSocketBuffer::new(
+                    vec![UdpPacketMetadata::EMPTY; self.socket_buffers.udp_packets],
+                    vec![0; self.socket_buffers.udp_bytes],
+                );
                 let socket = UdpSocket::new(rx_buffer, tx_buffer);
                 let handle = self.sockets.add(socket);
-                let smol_socket = SmolSocket::new(handle, SocketType::UDP, self.has_data.clone());
+                let smol_socket = SmolSocket::new(
+                    handle,
+                    SocketType::UDP,
+                    self.has_data.clone(),
+                    self.socket_to_send_limits,
+                    self.socket_received_limits,
+                    self.clock.now(),
+                );
                 self.smol_sockets.insert(smol_socket_handle, smol_socket);
                 0
             }
@@ -327,12 +1097,24 @@ where
                 self.sockets.add(socket);
             }
             */
-            _ => {
-                panic! {"wrong choice for socket type"}
-            }
+            _ => SMOL_RESULT_UNSUPPORTED,
         }
     }
 
+    /*
+        Removes the socket from the stack, dropping whatever was still
+        queued on it. Its key is never valid again
+    */
+    pub fn remove_socket(&mut self, smol_socket_handle: usize) -> u8 {
+        let smol_socket = match self.smol_sockets.remove(&smol_socket_handle) {
+            Some(smol_socket) => smol_socket,
+            None => return SMOL_RESULT_NOT_AVAILABLE,
+        };
+        self.sockets.remove(smol_socket.socket_handle);
+        self.release_socket_handle_key(smol_socket_handle);
+        SMOL_RESULT_OK
+    }
+
     pub fn tcp_connect(
         &mut self,
         smol_socket_handle: usize,
@@ -343,38 +1125,190 @@ where
         let smol_socket_ = self.smol_sockets.get_mut(&smol_socket_handle);
         match smol_socket_ {
             Some(smol_socket) => {
+                if smol_socket.socket_type != SocketType::TCP {
+                    return SMOL_RESULT_UNSUPPORTED;
+                }
                 let socket_handle = smol_socket.socket_handle;
                 let mut socket = self.sockets.get::<TcpSocket>(socket_handle);
                 let endpoint_ = Into::<IpAddress>::into(address);
                 let endpoint: IpAddress = endpoint_.into();
-                println!("smol stack going to connect to {} with dst_port {} and src_port {}", endpoint, dst_port, src_port);
+                debug!("smol stack going to connect to {} with dst_port {} and src_port {}", endpoint, dst_port, src_port);
                 let r = socket.connect((endpoint_, dst_port), src_port);
                 smol_socket.endpoint = Some(endpoint);
-                let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
+                smol_socket.opened = r.is_ok();
                 //Unlock the poller thread because new data is available
-                has_data_condition_variable.notify_all();
+                self.has_data.as_ref().unwrap().notify(WAKE_REASON_SOCKETS);
                 match r {
                     Ok(_) => {
                         //println!("connection ok");
                         0
                     }
                     _ => {
-                        println!("connection error");
+                        error!("connection error: {:?}", r);
                         2
                     }
                 }
             }
-            None => {
-                panic!("NO smol socket");
-                1
-            }
+            None => SMOL_RESULT_NOT_AVAILABLE,
         }
     }
 
-    pub fn may_send(&mut self, smol_socket_handle: usize) -> u8 {
-        let smol_socket = self.smol_sockets.get_mut(&smol_socket_handle);
-        let socket_handle = smol_socket.as_ref().unwrap().socket_handle.clone();
-        let socket_type = &smol_socket.unwrap().socket_type;
+    /*
+        Puts the TCP socket in listening state on `port`. A smoltcp socket
+        holds a single connection, so C++ adds one listening socket for
+        each connection it wants to accept
+    */
+    pub fn tcp_listen(&mut self, smol_socket_handle: usize, port: u16) -> u8 {
+        let smol_socket = match self.smol_sockets.get_mut(&smol_socket_handle) {
+            Some(smol_socket) if smol_socket.socket_type == SocketType::TCP => smol_socket,
+            Some(_) => return SMOL_RESULT_UNSUPPORTED,
+            None => return SMOL_RESULT_NOT_AVAILABLE,
+        };
+        let r = self.sockets.get::<TcpSocket>(smol_socket.socket_handle).listen(port);
+        smol_socket.opened = r.is_ok();
+        self.has_data.as_ref().unwrap().notify(WAKE_REASON_SOCKETS);
+        match r {
+            Ok(_) => SMOL_RESULT_OK,
+            Err(e) => {
+                error!("listen error: {}", e);
+                SMOL_RESULT_ERROR
+            }
+        }
+    }
+
+    //Binds the UDP socket to a local port, needed before sending or receiving
+    pub fn udp_bind(&mut self, smol_socket_handle: usize, port: u16) -> u8 {
+        let socket_handle = match self.smol_sockets.get(&smol_socket_handle) {
+            Some(smol_socket) if smol_socket.socket_type == SocketType::UDP => {
+                smol_socket.socket_handle
+            }
+            Some(_) => return SMOL_RESULT_UNSUPPORTED,
+            None => return SMOL_RESULT_NOT_AVAILABLE,
+        };
+        match self.sockets.get::<UdpSocket>(socket_handle).bind(port) {
+            Ok(_) => SMOL_RESULT_OK,
+            Err(e) => {
+                error!("bind error: {}", e);
+                SMOL_RESULT_ERROR
+            }
+        }
+    }
+
+    /*
+        Changes an option of the smoltcp socket behind smol_socket_handle.
+        Also works on sockets that are already connected: the new value is
+        used from the next poll on, so we wake the poller to recompute its
+        timers. Returns SMOL_RESULT_UNSUPPORTED if the socket type doesn't
+        have this option and SMOL_RESULT_INVALID_VALUE if it's out of range
+    */
+    pub fn set_socket_option(
+        &mut self,
+        smol_socket_handle: usize,
+        option: SocketOption,
+        value: u64,
+    ) -> u8 {
+        let (socket_handle, socket_type) = match self.smol_sockets.get(&smol_socket_handle) {
+            Some(smol_socket) => (smol_socket.socket_handle, smol_socket.socket_type.clone()),
+            None => return SMOL_RESULT_NOT_AVAILABLE,
+        };
+        let r = match socket_type {
+            SocketType::TCP => {
+                let mut socket = self.sockets.get::<TcpSocket>(socket_handle);
+                match option {
+                    SocketOption::KeepAlive => {
+                        socket.set_keep_alive(duration_from_millis(value));
+                        SMOL_RESULT_OK
+                    }
+                    SocketOption::Timeout => {
+                        socket.set_timeout(duration_from_millis(value));
+                        SMOL_RESULT_OK
+                    }
+                    SocketOption::Nagle => match value {
+                        0 | 1 => {
+                            socket.set_nagle_enabled(value == 1);
+                            SMOL_RESULT_OK
+                        }
+                        _ => SMOL_RESULT_INVALID_VALUE,
+                    },
+                    SocketOption::AckDelay => {
+                        socket.set_ack_delay(duration_from_millis(value));
+                        SMOL_RESULT_OK
+                    }
+                    SocketOption::HopLimit => match hop_limit_from_u64(value) {
+                        Some(hop_limit) => {
+                            socket.set_hop_limit(hop_limit);
+                            SMOL_RESULT_OK
+                        }
+                        None => SMOL_RESULT_INVALID_VALUE,
+                    },
+                }
+            }
+            SocketType::UDP => {
+                let mut socket = self.sockets.get::<UdpSocket>(socket_handle);
+                match option {
+                    SocketOption::HopLimit => match hop_limit_from_u64(value) {
+                        Some(hop_limit) => {
+                            socket.set_hop_limit(hop_limit);
+                            SMOL_RESULT_OK
+                        }
+                        None => SMOL_RESULT_INVALID_VALUE,
+                    },
+                    _ => SMOL_RESULT_UNSUPPORTED,
+                }
+            }
+            _ => SMOL_RESULT_UNSUPPORTED,
+        };
+        if r == SMOL_RESULT_OK {
+            //Unlock the poller thread so it picks the new timers up
+            self.has_data.as_ref().unwrap().notify(WAKE_REASON_SOCKETS);
+        }
+        r
+    }
+
+    //Reads an option set by set_socket_option, same units and return codes
+    pub fn get_socket_option(
+        &mut self,
+        smol_socket_handle: usize,
+        option: SocketOption,
+        value: &mut u64,
+    ) -> u8 {
+        let (socket_handle, socket_type) = match self.smol_sockets.get(&smol_socket_handle) {
+            Some(smol_socket) => (smol_socket.socket_handle, smol_socket.socket_type.clone()),
+            None => return SMOL_RESULT_NOT_AVAILABLE,
+        };
+        match socket_type {
+            SocketType::TCP => {
+                let socket = self.sockets.get::<TcpSocket>(socket_handle);
+                *value = match option {
+                    SocketOption::KeepAlive => millis_from_duration(socket.keep_alive()),
+                    SocketOption::Timeout => millis_from_duration(socket.timeout()),
+                    SocketOption::Nagle => socket.nagle_enabled() as u64,
+                    SocketOption::AckDelay => millis_from_duration(socket.ack_delay()),
+                    SocketOption::HopLimit => socket.hop_limit().unwrap_or(0) as u64,
+                };
+                SMOL_RESULT_OK
+            }
+            SocketType::UDP => {
+                let socket = self.sockets.get::<UdpSocket>(socket_handle);
+                match option {
+                    SocketOption::HopLimit => {
+                        *value = socket.hop_limit().unwrap_or(0) as u64;
+                        SMOL_RESULT_OK
+                    }
+                    _ => SMOL_RESULT_UNSUPPORTED,
+                }
+            }
+            _ => SMOL_RESULT_UNSUPPORTED,
+        }
+    }
+
+    pub fn may_send(&mut self, smol_socket_handle: usize) -> u8 {
+        let smol_socket = match self.smol_sockets.get_mut(&smol_socket_handle) {
+            Some(smol_socket) => smol_socket,
+            None => return SMOL_RESULT_NOT_AVAILABLE,
+        };
+        let socket_handle = smol_socket.socket_handle.clone();
+        let socket_type = &smol_socket.socket_type;
 
         match socket_type {
             SocketType::TCP => {
@@ -387,7 +1321,11 @@ where
             },
             SocketType::UDP => {
                 let socket = self.sockets.get::<UdpSocket>(socket_handle.clone());
-                panic!("not implemented yet");
+                if socket.can_send() {
+                    0
+                } else {
+                    1
+                }
             }
             _ => {
                 panic!("not implemented yet");
@@ -406,30 +1344,30 @@ where
         let smol_socket_ = self.smol_sockets.get_mut(&smol_socket_handle);
         match smol_socket_ {
             Some(smol_socket) => {
+                if smol_socket.socket_type != SocketType::TCP {
+                    return SMOL_RESULT_UNSUPPORTED;
+                }
                 let socket_handle = smol_socket.socket_handle;
                 let mut socket = self.sockets.get::<TcpSocket>(socket_handle);
                 let endpoint_ = Into::<IpAddress>::into(address);
                 let endpoint: IpAddress = endpoint_.into();
                 let r = socket.connect((endpoint_, dst_port), src_port);
                 smol_socket.endpoint = Some(endpoint);
-                let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
+                smol_socket.opened = r.is_ok();
                 //Unlock the poller thread because new data is available
-                has_data_condition_variable.notify_all();
+                self.has_data.as_ref().unwrap().notify(WAKE_REASON_SOCKETS);
                 match r {
                     Ok(_) => {
                         //println!("connection ok");
                         0
                     }
                     _ => {
-                        println!("connection error");
+                        error!("connection error: {:?}", r);
                         2
                     }
                 }
             }
-            None => {
-                panic!("NO smol socket");
-                1
-            }
+            None => SMOL_RESULT_NOT_AVAILABLE,
         }
     }
 
@@ -441,15 +1379,18 @@ where
         src_port: u16,
         dst_port: u16,
     ) -> u8 {
-        let smol_socket_ = self.smol_sockets.get(&smol_socket_handle);
+        let smol_socket_ = self.smol_sockets.get_mut(&smol_socket_handle);
         match smol_socket_ {
             Some(smol_socket) => {
+                if smol_socket.socket_type != SocketType::TCP {
+                    return SMOL_RESULT_UNSUPPORTED;
+                }
                 let socket_handle = smol_socket.socket_handle;
                 let mut socket = self.sockets.get::<TcpSocket>(socket_handle);
                 let r = socket.connect((Into::<Ipv6Address>::into(address), dst_port), src_port);
-                let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
+                smol_socket.opened = r.is_ok();
                 //Unlock the poller thread because new data is available
-                has_data_condition_variable.notify_all();
+                self.has_data.as_ref().unwrap().notify(WAKE_REASON_SOCKETS);
                 match r {
                     Ok(_) => 0,
                     _ => 2,
@@ -459,18 +1400,31 @@ where
         }
     }
 
-    pub fn add_ipv4_address(&mut self, cidr: CIpv4Cidr) {
-        self.ip_addrs.as_mut().unwrap().push(IpCidr::new(
-            Into::<IpAddress>::into(cidr.address),
-            cidr.prefix,
-        ));
+    //Addresses can only be added before finalize
+    pub fn add_ipv4_address(&mut self, cidr: CIpv4Cidr) -> u8 {
+        if cidr.prefix > 32 {
+            return SMOL_RESULT_INVALID_VALUE;
+        }
+        match self.ip_addrs.as_mut() {
+            Some(ip_addrs) => {
+                ip_addrs.push(IpCidr::new(Into::<IpAddress>::into(cidr.address), cidr.prefix));
+                SMOL_RESULT_OK
+            }
+            None => SMOL_RESULT_ERROR,
+        }
     }
 
-    pub fn add_ipv6_address(&mut self, cidr: CIpv6Cidr) {
-        self.ip_addrs.as_mut().unwrap().push(IpCidr::new(
-            Into::<IpAddress>::into(cidr.address),
-            cidr.prefix,
-        ));
+    pub fn add_ipv6_address(&mut self, cidr: CIpv6Cidr) -> u8 {
+        if cidr.prefix > 128 {
+            return SMOL_RESULT_INVALID_VALUE;
+        }
+        match self.ip_addrs.as_mut() {
+            Some(ip_addrs) => {
+                ip_addrs.push(IpCidr::new(Into::<IpAddress>::into(cidr.address), cidr.prefix));
+                SMOL_RESULT_OK
+            }
+            None => SMOL_RESULT_ERROR,
+        }
     }
 
     pub fn add_default_v4_gateway(&mut self, address: CIpv4Address) {
@@ -481,40 +1435,230 @@ where
         self.default_v6_gw = Some(Into::<Ipv6Address>::into(address));
     }
 
+    /*
+        Route to `cidr` through the gateway `via`, both of the same IP
+        version. Like addresses, routes can only be added before finalize
+    */
+    pub fn add_route(&mut self, cidr: IpCidr, via: IpAddress) -> u8 {
+        match (cidr, via) {
+            (IpCidr::Ipv4(_), IpAddress::Ipv4(_)) | (IpCidr::Ipv6(_), IpAddress::Ipv6(_)) => {}
+            _ => return SMOL_RESULT_INVALID_VALUE,
+        }
+        if self.interface.is_some() {
+            return SMOL_RESULT_ERROR;
+        }
+        self.routes.push((cidr, via));
+        SMOL_RESULT_OK
+    }
+
+    pub fn set_dns_servers(&mut self, dns_servers: Vec<IpAddress>) {
+        self.dns_servers = dns_servers;
+    }
+
+    pub fn dns_servers(&self) -> &[IpAddress] {
+        &self.dns_servers
+    }
+
+    pub fn set_socket_buffers(&mut self, socket_buffers: SocketBuffers) -> u8 {
+        if socket_buffers.tcp_bytes == 0 || socket_buffers.udp_packets == 0 || socket_buffers.udp_bytes == 0 {
+            return SMOL_RESULT_INVALID_VALUE;
+        }
+        self.socket_buffers = socket_buffers;
+        SMOL_RESULT_OK
+    }
+
+    //The device, wherever it is: on the stack before finalize, on the interface after
+    pub fn device_mut(&mut self) -> Option<&mut DeviceT> {
+        match self.interface.as_mut() {
+            Some(interface) => Some(interface.device_mut()),
+            None => self.device.as_mut(),
+        }
+    }
+
+    pub fn set_ethernet_address(&mut self, address: CEthernetAddress) -> u8 {
+        let ethernet_address = EthernetAddress(address.address);
+        if !ethernet_address.is_unicast() {
+            return SMOL_RESULT_INVALID_VALUE;
+        }
+        self.ethernet_address = Some(ethernet_address);
+        SMOL_RESULT_OK
+    }
+
+    /*
+        Builds the smoltcp interface. Default gateways are optional, and
+        calling this twice returns SMOL_RESULT_ERROR
+    */
     pub fn finalize(&mut self) -> u8 {
+        let medium = match self.device.as_ref() {
+            Some(device) => device.medium(),
+            None => return SMOL_RESULT_ERROR,
+        };
+        if medium == Medium::Ethernet && self.ethernet_address.is_none() {
+            error!("an ethernet device needs an ethernet address before finalize");
+            return SMOL_RESULT_ERROR;
+        }
+        let (device, ip_addrs) = match (self.device.take(), self.ip_addrs.take()) {
+            (Some(device), Some(ip_addrs)) => (device, ip_addrs),
+            _ => return SMOL_RESULT_ERROR,
+        };
         let routes_storage = BTreeMap::new();
         let mut routes = Routes::new(routes_storage);
-        //TODO: return C error if something is wrong, no unwrap
-        routes
-            .add_default_ipv4_route(self.default_v4_gw.unwrap())
-            .unwrap();
-        routes
-            .add_default_ipv6_route(self.default_v6_gw.unwrap())
-            .unwrap();
-        let interface = InterfaceBuilder::new(self.device.take().unwrap())
-            .ip_addrs(self.ip_addrs.take().unwrap())
+        if let Some(default_v4_gw) = self.default_v4_gw {
+            if let Err(e) = routes.add_default_ipv4_route(default_v4_gw) {
+                error!("could not add default ipv4 route: {}", e);
+            }
+        }
+        if let Some(default_v6_gw) = self.default_v6_gw {
+            if let Err(e) = routes.add_default_ipv6_route(default_v6_gw) {
+                error!("could not add default ipv6 route: {}", e);
+            }
+        }
+        for &(cidr, via_router) in self.routes.iter() {
+            let mut added = false;
+            routes.update(|storage| {
+                let route = Route {
+                    via_router: via_router,
+                    preferred_until: None,
+                    expires_at: None,
+                };
+                added = storage.insert(cidr, route).is_ok();
+            });
+            if !added {
+                error!("could not add route to {} via {}", cidr, via_router);
+            }
+        }
+        if self.nat.is_some() {
+            //smoltcp only takes packets for other addresses if they are routed through one of ours
+            let own_v4 = ip_addrs.iter().find_map(|cidr| match cidr.address() {
+                IpAddress::Ipv4(address) => Some(address),
+                _ => None,
+            });
+            let own_v6 = ip_addrs.iter().find_map(|cidr| match cidr.address() {
+                IpAddress::Ipv6(address) => Some(address),
+                _ => None,
+            });
+            if let Some(Err(e)) = own_v4.map(|address| routes.add_default_ipv4_route(address)) {
+                error!("could not route ipv4 through the nat: {}", e);
+            }
+            if let Some(Err(e)) = own_v6.map(|address| routes.add_default_ipv6_route(address)) {
+                error!("could not route ipv6 through the nat: {}", e);
+            }
+        }
+        let mut interface_builder = InterfaceBuilder::new(device)
+            .ip_addrs(ip_addrs)
             .routes(routes)
-            .finalize();
+            .any_ip(self.nat.is_some());
+        if medium == Medium::Ethernet {
+            interface_builder = interface_builder
+                .ethernet_addr(self.ethernet_address.unwrap())
+                .neighbor_cache(NeighborCache::new(BTreeMap::new()));
+        }
+        let interface = interface_builder.finalize();
         self.interface = Some(interface);
         0
     }
 
     pub fn poll(&mut self) -> u8 {
-        let timestamp = Instant::now();
-        match self
-            .interface
-            .as_mut()
-            .unwrap()
-            .poll(&mut self.sockets, timestamp)
+        let timestamp = self.clock.now();
+        let interface = match self.interface.as_mut() {
+            Some(interface) => interface,
+            //Not finalized yet
+            None => return SMOL_RESULT_NOT_AVAILABLE,
+        };
+        #[cfg(feature = "metrics")]
+        let started = std::time::Instant::now();
+        let r = match self.nat.as_mut() {
+            Some(nat) => nat.poll(interface, &mut self.sockets, timestamp, self.socket_buffers),
+            None => interface.poll(&mut self.sockets, timestamp),
+        };
+        #[cfg(feature = "metrics")]
         {
+            self.metrics.poll_done(started.elapsed(), r.is_ok());
+            if self.metrics_refreshed.elapsed() >= METRICS_REFRESH_INTERVAL {
+                self.snapshot_metrics();
+            }
+        }
+        match r {
             Ok(_) => 0,
             Err(e) => {
-                //debug!("poll error: {}",e);
+                debug!("poll error: {}", e);
                 1
             }
         }
     }
 
+    //Counters shared with the device and everything that renders them
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> Arc<StackMetrics> {
+        self.metrics.clone()
+    }
+
+    //Counts sockets by state and what's waiting on every queue, see Gauges
+    #[cfg(feature = "metrics")]
+    pub fn snapshot_metrics(&mut self) {
+        let mut gauges = Gauges::default();
+        for smol_socket in self.smol_sockets.values() {
+            match smol_socket.socket_type {
+                SocketType::TCP => {
+                    let socket = self.sockets.get::<TcpSocket>(smol_socket.socket_handle);
+                    gauges.count_tcp_socket(socket.state());
+                }
+                SocketType::UDP => gauges.udp_sockets += 1,
+                _ => {}
+            }
+            for &(queue, packets) in [
+                (QUEUE_SOCKET_TO_SEND, &smol_socket.to_send),
+                (QUEUE_SOCKET_RECEIVED, &smol_socket.received),
+            ]
+            .iter()
+            {
+                let packets = packets.lock().unwrap();
+                gauges.queues[queue as usize].0 += packets.len() as u64;
+                gauges.queues[queue as usize].1 += packets.bytes() as u64;
+            }
+        }
+        for &(queue, ring) in [
+            (QUEUE_PACKETS_FROM_INSIDE, &self.packets_from_inside),
+            (QUEUE_PACKETS_FROM_OUTSIDE, &self.packets_from_outside),
+        ]
+        .iter()
+        {
+            if let Some(ring) = ring {
+                gauges.queues[queue as usize] = (ring.len() as u64, ring.bytes() as u64);
+            }
+        }
+        self.metrics.set_gauges(gauges);
+        self.metrics_refreshed = std::time::Instant::now();
+    }
+
+    //The metrics in the Prometheus text format, with fresh gauges
+    #[cfg(feature = "metrics")]
+    pub fn render_metrics(&mut self) -> String {
+        self.snapshot_metrics();
+        self.metrics.render()
+    }
+
+    /*
+        Serves the metrics over HTTP on `address`, a host socket, replacing
+        the previous server if there was one. Gauges are as fresh as the
+        last poll
+    */
+    #[cfg(feature = "metrics")]
+    pub fn serve_metrics(&mut self, address: std::net::SocketAddr) -> u8 {
+        //Frees the port in case the new server wants the same one
+        self.metrics_server = None;
+        match MetricsServer::start(self.metrics.clone(), address) {
+            Ok(server) => {
+                self.metrics_server = Some(server);
+                SMOL_RESULT_OK
+            }
+            Err(err) => {
+                error!("could not serve metrics on {}: {}", address, err);
+                SMOL_RESULT_ERROR
+            }
+        }
+    }
+
     pub fn spin_all(&mut self) -> u8 {
         //TODO: maybe store self.smol_sockets in a smart pointer
         //so we don't do this copy every time
@@ -533,84 +1677,182 @@ where
         pointed by smol_socket_handle
     */
     pub fn spin(&mut self, smol_socket_handle: usize) -> u8 {
-        let smol_socket = self.smol_sockets.get_mut(&smol_socket_handle).unwrap();
+        let smol_socket = match self.smol_sockets.get_mut(&smol_socket_handle) {
+            Some(smol_socket) => smol_socket,
+            None => return SMOL_RESULT_NOT_AVAILABLE,
+        };
         match smol_socket.socket_type {
             SocketType::TCP => {
                 let mut socket = self.sockets.get::<TcpSocket>(smol_socket.socket_handle);
-                let mut put_back = false;
+                let mut writable = false;
+                let mut readable = false;
+                if socket.may_send() || socket.may_recv() {
+                    smol_socket.connected_once = true;
+                }
                 if socket.may_send() {
-                    //Returns None if there are no packets
-                    let mut packet = smol_socket.get_latest_packet();
-                    match &mut packet {
-                        Some(ref mut packet) => {
-                            //Sends from the start (which might be more than 0 if we didn't send
-                            //an entire packet in the last call)
-                            let bytes_sent = socket
-                                .send_slice(&packet.blob.data.as_slice()[packet.blob.start..]);
-                            match bytes_sent {
-                                Ok(bytes_sent) => {
-                                    /*
-                                        Sent less than entire packet, so we must put this packet
-                                        in `smol_socket.current_to_send` so it's returned the next time
-                                        so we can continue sending it
-                                    */
-                                    if bytes_sent < packet.blob.data.len() {
-                                        let remaining_bytes = packet.blob.data.len() - bytes_sent;
-                                        //start from remaining in the next call
-                                        packet.blob.start = remaining_bytes;
-                                        put_back = true;
-                                    } else {
-                                        //Sent the entire packet, nothing needs to be done
-                                        //0
-                                    }
-                                }
-                                Err(e) => {
-                                    println!("bytes not sent, ERROR {}, putting packet back", e);
-                                    //1
+                    //Sends as many queued packets as the socket buffer accepts
+                    //Returns None when there are no packets left
+                    while let Some(mut packet) = smol_socket.get_latest_packet() {
+                        //Sends from the start (which might be more than 0 if we didn't send
+                        //an entire packet in the last call)
+                        let bytes_sent =
+                            socket.send_slice(&packet.blob.data.as_slice()[packet.blob.start..]);
+                        match bytes_sent {
+                            Ok(bytes_sent) => {
+                                packet.blob.start += bytes_sent;
+                                /*
+                                    Sent less than entire packet, so we must put this packet
+                                    in `smol_socket.current_to_send` so it's returned the next time
+                                    so we can continue sending it from `start`
+                                */
+                                if packet.blob.start < packet.blob.data.len() {
+                                    smol_socket.current_to_send = Some(packet);
+                                    break;
                                 }
                             }
+                            Err(e) => {
+                                error!("bytes not sent, ERROR {}, putting packet back", e);
+                                smol_socket.current_to_send = Some(packet);
+                                break;
+                            }
                         }
-                        None => {}
                     }
-                    //Outside of match because it matches as reference so we cannot move
-                    if put_back {
-                        println!("ATTENTION: putting the packet back");
-                        use std::process;
-                        //TODO: take off exit when things are better reviewed
-                        process::exit(1);
-                        smol_socket.current_to_send = packet;
+                    //Everything queued before shutdown_write is in the socket now, send FIN
+                    if smol_socket.shutdown_write
+                        && !smol_socket.write_closed
+                        && smol_socket.current_to_send.is_none()
+                        && smol_socket.to_send.lock().unwrap().is_empty()
+                    {
+                        debug!("send queue drained, closing write side of socket");
+                        socket.close();
+                        smol_socket.write_closed = true;
+                    }
+                    //We popped from a full `to_send`, C++ can send again
+                    if smol_socket.to_send.lock().unwrap().take_unblocked() {
+                        writable = true;
                     }
-                } else {
-                    //println!("socket cannot send");
-                    //1
                 }
-                if socket.can_recv() {
+                /*
+                    Only takes from the socket what fits in `received`. What stays
+                    in the socket's rx buffer shrinks the TCP window, so the
+                    peer slows down until C++ catches up
+                */
+                let available = smol_socket.received.lock().unwrap().available_bytes();
+                if socket.can_recv() && available > 0 {
+                    let remote_endpoint = socket.remote_endpoint();
                     socket
                         .recv(|data| {
-                            let len = data.len();
+                            let len = std::cmp::min(data.len(), available);
                             {
                                 let mut s = vec![0; len];
-                                s.copy_from_slice(data);
-                                smol_socket.received.lock().unwrap().push_back(s);
+                                s.copy_from_slice(&data[..len]);
+                                let mut received = smol_socket.received.lock().unwrap();
+                                readable = received.is_empty();
+                                //available_bytes() guaranteed there's room
+                                let _ = received.push_back(Packet::from_vec(s, Some(remote_endpoint)));
                             }
-                            let has_data = smol_socket.smol_socket_has_data.as_ref();
-                            let (_, smol_socket_has_data_condition_variable) = &*has_data.clone();
-                            smol_socket_has_data_condition_variable.notify_all();
-                            /*
-                            let has_data = smol_socket.has_data.as_ref().unwrap();
-                            let (_, has_data_condition_variable) = &*has_data.clone();
-                            has_data_condition_variable.notify_all();
-                            */
+                            smol_socket.smol_socket_has_data.notify(WAKE_REASON_PACKETS);
                             (len, ())
                         })
                         .unwrap();
-                //0
+                }
+                /*
+                    The peer sent FIN (or the connection is gone) and we already moved
+                    everything it sent to `received`, so receivers get end of stream
+                    once they drain it. Only these states come after the peer's FIN,
+                    anything else means a reset or a timeout and receivers get
+                    SMOL_RESULT_CLOSED instead. A connect refused or timed out
+                    before connecting ends the same way
+                */
+                let ended = if smol_socket.connected_once {
+                    !socket.may_recv() && !socket.can_recv()
                 } else {
-                    //2
+                    smol_socket.opened && socket.state() == TcpState::Closed
+                };
+                if ended
+                    && !smol_socket.receive_finished.load(Ordering::SeqCst)
+                    && !smol_socket.receive_closed.load(Ordering::SeqCst)
+                {
+                    match socket.state() {
+                        TcpState::CloseWait | TcpState::LastAck | TcpState::Closing | TcpState::TimeWait => {
+                            smol_socket.receive_finished.store(true, Ordering::SeqCst)
+                        }
+                        _ => smol_socket.receive_closed.store(true, Ordering::SeqCst),
+                    }
+                    smol_socket.smol_socket_has_data.notify(WAKE_REASON_PACKETS);
+                }
+                //Outside of the queue locks, C++ might call us back from the callback
+                if writable {
+                    self.flow_notifier
+                        .notify(smol_socket_handle, FLOW_EVENT_SOCKET_WRITABLE);
+                }
+                if readable {
+                    self.flow_notifier
+                        .notify(smol_socket_handle, FLOW_EVENT_SOCKET_READABLE);
+                }
+                0
+            }
+            SocketType::UDP => {
+                let mut socket = self.sockets.get::<UdpSocket>(smol_socket.socket_handle);
+                let mut writable = false;
+                let mut readable = false;
+                //Datagrams are never split, each one waits until it fits whole
+                while let Some(packet) = smol_socket.get_latest_packet() {
+                    //SmolSocket::send only accepts UDP packets with an endpoint
+                    let endpoint = packet.endpoint.unwrap();
+                    match socket.send_slice(packet.as_slice(), endpoint) {
+                        Ok(_) => {}
+                        Err(smoltcp::Error::Exhausted) => {
+                            smol_socket.current_to_send = Some(packet);
+                            break;
+                        }
+                        Err(e) => {
+                            error!("dropping datagram to {}: {}", endpoint, e);
+                        }
+                    }
+                }
+                if smol_socket.to_send.lock().unwrap().take_unblocked() {
+                    writable = true;
+                }
+                while socket.can_recv() {
+                    let mut received = smol_socket.received.lock().unwrap();
+                    //A datagram that doesn't fit stays in smoltcp until C++ makes room, like TCP data
+                    let len = match socket.peek() {
+                        Ok((data, _)) => data.len(),
+                        Err(_) => break,
+                    };
+                    if !received.check_room_for(len) {
+                        break;
+                    }
+                    match socket.recv() {
+                        Ok((data, endpoint)) => {
+                            let was_empty = received.is_empty();
+                            //check_room_for guaranteed there's room
+                            let _ = received.push_back(Packet::from_vec(data.to_vec(), Some(endpoint)));
+                            if was_empty {
+                                readable = true;
+                            }
+                        }
+                        Err(e) => {
+                            error!("udp receive error: {}", e);
+                            break;
+                        }
+                    }
+                }
+                if readable {
+                    smol_socket.smol_socket_has_data.notify(WAKE_REASON_PACKETS);
+                }
+                //Outside of the queue locks, C++ might call us back from the callback
+                if writable {
+                    self.flow_notifier
+                        .notify(smol_socket_handle, FLOW_EVENT_SOCKET_WRITABLE);
+                }
+                if readable {
+                    self.flow_notifier
+                        .notify(smol_socket_handle, FLOW_EVENT_SOCKET_READABLE);
                 }
                 0
             }
-            SocketType::UDP => panic!("not implemented yet"),
             //TODO
             SocketType::ICMP => panic!("not implemented yet"),
             SocketType::RAW_IPV4 => panic!("not implemented yet"),
@@ -618,124 +1860,531 @@ where
         }
     }
 
-    //VirtualTun only
-    //Send a packet to the stack (Ethernet/IP)
-    //not to confuse with TCP/UDP/etc packets
-    pub fn send(&mut self, blob: Blob) -> u8 {
-        //println!("stack received blob with size {}", blob.data.len());
-        let packets_from_outside = &*self.packets_from_outside.as_ref().unwrap().clone();
-        packets_from_outside.lock().unwrap().push_back(blob);
-        let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
+    /*
+        VirtualTun only. Sends a packet (IP) to the stack, not to confuse
+        with TCP/UDP/etc packets. It's copied to a slot of
+        packets_from_outside. Returns SMOL_RESULT_WOULD_BLOCK if the ring
+        is full or another thread is sending at the same time, and
+        SMOL_RESULT_INVALID_VALUE if it's bigger than the MTU.
+        With ingress checks enabled, invalid packets are refused with
+        the code of what's wrong, see IngressChecks::validate
+    */
+    pub fn send(&mut self, data: &[u8]) -> u8 {
+        if let Err(r) = self.ingress.validate(data, self.ip_addrs()) {
+            return r;
+        }
+        let packets_from_outside = self.packets_from_outside.as_ref().unwrap();
+        let _producer = match packets_from_outside.claim_producer() {
+            Some(producer) => producer,
+            None => return SMOL_RESULT_WOULD_BLOCK,
+        };
+        let pushed = packets_from_outside.push(data);
+        #[cfg(feature = "metrics")]
+        self.count_from_outside(&pushed, data.len());
+        match pushed {
+            Ok(()) => {}
+            Err(PushError::Full) => return SMOL_RESULT_WOULD_BLOCK,
+            Err(PushError::TooBig) => return SMOL_RESULT_INVALID_VALUE,
+        }
         //Unlock the poller thread because new data is available
-        has_data_condition_variable.notify_all();
-        0
+        self.has_data.as_ref().unwrap().notify(WAKE_REASON_PACKETS);
+        SMOL_RESULT_OK
+    }
+
+    #[cfg(feature = "metrics")]
+    fn count_from_outside(&self, pushed: &Result<(), PushError>, len: usize) {
+        match pushed {
+            Ok(()) => self.metrics.from_outside.queued(len),
+            Err(PushError::Full) => self.metrics.from_outside.dropped_full(),
+            Err(PushError::TooBig) => self.metrics.from_outside.dropped_too_big(),
+        }
     }
 
     /*
-        TODO: figure out a better way than copying. Inneficient receive
+        VirtualTun only. Receives a packet (IP) from the stack on `cbuffer`,
+        allocated on C++ with `allocate_function`, which then owns it.
+        Returns 0 in case of sucess
+        Returns 1 if there's no packet to receive
+        Returns SMOL_RESULT_WOULD_BLOCK if another thread is receiving
     */
-    //VirtualTun only
-    //Receive a packet from the stack (Ethernet/IP)
-    //not to confuse with TCP/UDP/etc packets
-    //TODO: Rename to receive_wait()?
+    pub fn receive_instantly(
+        &mut self,
+        cbuffer: *mut CBuffer,
+        allocate_function: extern "C" fn(size: usize) -> *mut u8,
+    ) -> u8 {
+        let packets_from_inside = self.packets_from_inside.as_ref().unwrap();
+        let _consumer = match packets_from_inside.claim_consumer() {
+            Some(consumer) => consumer,
+            None => return SMOL_RESULT_WOULD_BLOCK,
+        };
+        let received = packets_from_inside.pop_with(|s| {
+            //Allocates a raw pointer on C++ side
+            let p: *mut u8 = allocate_function(s.len());
+            //Fills the pointer
+            unsafe { ptr::copy(s.as_ptr(), p, s.len()) };
+            //Sends the pointer back to C++, which has the responsibility
+            //to delete it
+            unsafe {
+                *cbuffer = CBuffer {
+                    data: p,
+                    len: s.len(),
+                };
+            }
+        });
+        match received {
+            Some(()) => {
+                //Unlock the poller thread, it might be waiting for room in packets_from_inside
+                self.has_data.as_ref().unwrap().notify(WAKE_REASON_ROOM);
+                SMOL_RESULT_OK
+            }
+            None => SMOL_RESULT_NOT_AVAILABLE,
+        }
+    }
+
+    //VirtualTun only. Doesn't wait (yet), same as receive_instantly
     pub fn receive_wait(
         &mut self,
         cbuffer: *mut CBuffer,
         allocate_function: extern "C" fn(size: usize) -> *mut u8,
     ) -> u8 {
-        let s;
+        self.receive_instantly(cbuffer, allocate_function)
+    }
 
-        //let has_data = &*self.has_data.as_ref().unwrap().clone();
-        let packets_from_inside = &*self.packets_from_inside.as_ref().unwrap().clone();
-        {
-            //Create a scope so we hold the queue for the least ammount needed
-            //TODO: do I really need to create a scope?
+    /*
+        Copies the next packet from the stack into `buffer`, with no C++
+        allocation. If it doesn't fit, it stays queued, `len` gets the
+        size needed and SMOL_RESULT_BUFFER_TOO_SMALL is returned.
+        Returns SMOL_RESULT_WOULD_BLOCK if another thread is receiving
+    */
+    //VirtualTun only
+    pub fn receive_instantly_into(&mut self, buffer: &mut [u8], len: &mut usize) -> u8 {
+        let packets_from_inside = self.packets_from_inside.as_ref().unwrap();
+        let _consumer = match packets_from_inside.claim_consumer() {
+            Some(consumer) => consumer,
+            None => return SMOL_RESULT_WOULD_BLOCK,
+        };
+        let needed = match packets_from_inside.front_len() {
+            Some(needed) => needed,
+            None => return SMOL_RESULT_NOT_AVAILABLE,
+        };
+        if needed > buffer.len() {
+            *len = needed;
+            return SMOL_RESULT_BUFFER_TOO_SMALL;
+        }
+        *len = match packets_from_inside.pop_into(buffer) {
+            Some(len) => len,
+            //Can't happen while we hold the consumer side, front_len's packet is still there
+            None => return SMOL_RESULT_ERROR,
+        };
+        //Unlock the poller thread, it might be waiting for room in packets_from_inside
+        self.has_data.as_ref().unwrap().notify(WAKE_REASON_ROOM);
+        SMOL_RESULT_OK
+    }
+
+    //VirtualTun only. Counterpart of receive_wait
+    pub fn receive_wait_into(&mut self, buffer: &mut [u8], len: &mut usize) -> u8 {
+        self.receive_instantly_into(buffer, len)
+    }
 
-            //TODO: handle Mutex poisoning error
-            //condition_variable.wait_while(packets_from_inside.lock().unwrap(), |p| p.len() > 0);
-            s = packets_from_inside.lock().unwrap().pop_front();
+    /*
+        VirtualTun only. Queues every packet on packets_from_outside and
+        wakes the poller once. Stops at the first one that can't be queued
+        (that one and the rest are not sent), `sent` tells how many were
+        taken. Returns SMOL_RESULT_WOULD_BLOCK if the ring filled up or
+        another thread is sending, or the ingress check code of an
+        invalid packet
+    */
+    pub fn send_batch(&mut self, packets: &[&[u8]], sent: &mut usize) -> u8 {
+        let packets_from_outside = self.packets_from_outside.as_ref().unwrap();
+        *sent = 0;
+        let _producer = match packets_from_outside.claim_producer() {
+            Some(producer) => producer,
+            None => return SMOL_RESULT_WOULD_BLOCK,
+        };
+        let mut r = SMOL_RESULT_OK;
+        for packet in packets {
+            if let Err(invalid) = self.ingress.validate(packet, self.ip_addrs()) {
+                r = invalid;
+                break;
+            }
+            let pushed = packets_from_outside.push(packet);
+            #[cfg(feature = "metrics")]
+            self.count_from_outside(&pushed, packet.len());
+            match pushed {
+                Ok(()) => *sent += 1,
+                Err(PushError::Full) => {
+                    r = SMOL_RESULT_WOULD_BLOCK;
+                    break;
+                }
+                Err(PushError::TooBig) => {
+                    r = SMOL_RESULT_INVALID_VALUE;
+                    break;
+                }
+            }
         }
-        match s {
-            Some(s) => {
-                //Allocates a raw pointer on C++ side
-                let p: *mut u8 = allocate_function(s.len());
-                //Fills the pointer
-                unsafe { ptr::copy(s.as_ptr(), p, s.len()) };
-                //Sends the pointer back to C++, which has the responsibility
-                //to delete it
-                //TODO: remove +1
+        if *sent > 0 {
+            self.has_data.as_ref().unwrap().notify(WAKE_REASON_PACKETS);
+        }
+        r
+    }
 
-                unsafe {
-                    *cbuffer = CBuffer {
-                        data: p,
-                        len: s.len(),
-                    };
+    /*
+        VirtualTun only. Copies up to buffers.len() packets from
+        packets_from_inside, one per buffer, writing each size to `lens`
+        and how many were copied to `received`. Stops early at a packet
+        bigger than its buffer, which stays queued.
+        Returns SMOL_RESULT_NOT_AVAILABLE if there was nothing and
+        SMOL_RESULT_BUFFER_TOO_SMALL (with the needed size on lens[0]) if
+        the first packet doesn't fit, SMOL_RESULT_WOULD_BLOCK if another
+        thread is receiving
+    */
+    pub fn receive_batch_into(&mut self, buffers: &mut [&mut [u8]], lens: &mut [usize], received: &mut usize) -> u8 {
+        let packets_from_inside = self.packets_from_inside.as_ref().unwrap();
+        *received = 0;
+        let _consumer = match packets_from_inside.claim_consumer() {
+            Some(consumer) => consumer,
+            None => return SMOL_RESULT_WOULD_BLOCK,
+        };
+        for (buffer, len) in buffers.iter_mut().zip(lens.iter_mut()) {
+            let needed = match packets_from_inside.front_len() {
+                Some(needed) => needed,
+                None => break,
+            };
+            if needed > buffer.len() {
+                if *received == 0 {
+                    *len = needed;
+                    return SMOL_RESULT_BUFFER_TOO_SMALL;
                 }
-                let (mutex, has_data_condition_variable) =
-                    &*self.has_data.as_ref().unwrap().clone();
-                //Unlock the poller thread because new data is available
-                has_data_condition_variable.notify_all();
-                //0 means everything went well
-                0
+                break;
             }
-            None => 1,
+            *len = match packets_from_inside.pop_into(buffer) {
+                Some(len) => len,
+                None => break,
+            };
+            *received += 1;
         }
+        if *received == 0 {
+            return SMOL_RESULT_NOT_AVAILABLE;
+        }
+        //Unlock the poller thread, it might be waiting for room in packets_from_inside
+        self.has_data.as_ref().unwrap().notify(WAKE_REASON_ROOM);
+        SMOL_RESULT_OK
     }
 
     /*
-        Returns 0 in case of sucess
-        Returns 1 if there's no packet to receive
+        Waits until poll has something to do: the next timer is due (see
+        poll_delay), packets arrived, C++ gave the sockets work or took
+        what the poller was waiting to have room for. Returns the
+        WAKE_REASON_* bits of why it woke. For stacks without an fd
+        (VirtualTun, Callback)
     */
-    pub fn receive_instantly(
-        &mut self,
-        cbuffer: *mut CBuffer,
-        allocate_function: extern "C" fn(size: usize) -> *mut u8,
-    ) -> u8 {
-        let s;
-        //We ignore the condvar because we want to return immediately
-        let packets_from_inside = &*self.packets_from_inside.as_ref().unwrap().clone();
-        {
-            //Create a scope so we hold the queue for the least ammount needed
-            //TODO: do I really need to create a scope?
-            s = packets_from_inside.lock().unwrap().pop_front();
-            //println!("packets_from_inside pop_front, now has {} elements", packets_from_inside.lock().unwrap().len())
+    pub fn phy_wait(&mut self) -> u8 {
+        let has_data = self.has_data.as_ref().unwrap().clone();
+        //A manual clock only moves when told to, so there's nothing to wait for
+        if self.clock.is_manual() {
+            return match has_data.take() {
+                0 => WAKE_REASON_TIMEOUT,
+                reasons => reasons,
+            };
         }
-        match s {
-            Some(s) => {
-                //Allocates a raw pointer on C++ side
-                let p: *mut u8 = allocate_function(s.len());
-                //Fills the pointer
-                unsafe { ptr::copy(s.as_ptr(), p, s.len()) };
-                //Sends the pointer back to C++, which has the responsibility
-                //to delete it
-                unsafe {
-                    *cbuffer = CBuffer {
-                        data: p,
-                        len: s.len(),
-                    };
-                }
-                let (mutex, has_data_condition_variable) =
-                    &*self.has_data.as_ref().unwrap().clone();
-                //Unlock the poller thread because new data is available
-                has_data_condition_variable.notify_all();
-                0
+        let delay = self
+            .poll_delay()
+            .map(|delay| Duration::from_millis(delay.total_millis()));
+        has_data.wait(delay)
+    }
+
+    /*
+        Same as phy_wait for stacks that read packets from `fd`, which is
+        polled along the wake_fd of has_data, so notifications (sends,
+        wake, shutdown...) end the wait like packets and timers do
+    */
+    pub fn phy_wait_fd(&mut self, timestamp: Instant) -> u8 {
+        let timeout = match self.fd_wait_delay(timestamp) {
+            Some(delay) => delay.total_millis() as libc::c_int,
+            None => -1,
+        };
+        let has_data = self.has_data.as_ref().unwrap().clone();
+        let mut pollfds = [
+            libc::pollfd {
+                fd: self.fd.unwrap(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                //Negative fds are ignored by poll
+                fd: has_data.wake_fd().unwrap_or(-1),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        let r = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout) };
+        let mut reasons = has_data.take();
+        if r < 0 {
+            error!("wait error: {}", std::io::Error::last_os_error());
+        } else if pollfds[0].revents != 0 {
+            reasons |= WAKE_REASON_PACKETS;
+        }
+        if reasons == 0 {
+            WAKE_REASON_TIMEOUT
+        } else {
+            reasons
+        }
+    }
+
+    pub fn wake(&mut self) {
+        self.has_data.as_ref().unwrap().notify(WAKE_REASON_WAKE);
+    }
+
+    /*
+        Closes every connection and drops every socket, in the order
+        their keys were given, see smol_stack_shutdown. With `graceful`,
+        TCP connections first send what's queued and FIN, for at most that
+        long. Returns SMOL_RESULT_TIMED_OUT if some didn't finish in time,
+        those are aborted like everything else
+    */
+    pub fn shutdown(&mut self, graceful: Option<Duration>) -> u8 {
+        let mut r = SMOL_RESULT_OK;
+        if let Some(timeout) = graceful {
+            if !self.close_gracefully(timeout) {
+                r = SMOL_RESULT_TIMED_OUT;
             }
-            None => 1,
         }
+        let mut smol_socket_handles: Vec<usize> = self.smol_sockets.keys().cloned().collect();
+        smol_socket_handles.sort();
+        let mut socket_handles = Vec::new();
+        for smol_socket_handle in smol_socket_handles {
+            let mut smol_socket = self.smol_sockets.remove(&smol_socket_handle).unwrap();
+            if smol_socket.socket_type == SocketType::TCP {
+                //RST for peers still connected
+                self.sockets.get::<TcpSocket>(smol_socket.socket_handle).abort();
+            }
+            smol_socket.release_queues();
+            socket_handles.push(smol_socket.socket_handle);
+            //Its blocked receivers wake with SMOL_RESULT_CLOSED
+            drop(smol_socket);
+        }
+        //Sends the RSTs
+        self.poll();
+        for socket_handle in socket_handles {
+            self.sockets.remove(socket_handle);
+        }
+        self.has_data.as_ref().unwrap().notify(WAKE_REASON_SHUT_DOWN);
+        r
     }
 
     /*
-        Waits until either data was sent or received, that is,
-        either packets_from_outside or packets_from_inside
-        have changed
+        Starts closing every TCP connection and polls until all of them
+        are done or `timeout` passes. True if they finished in time
     */
-    pub fn phy_wait(&mut self) {
-        let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
-        has_data_condition_variable.wait(mutex.lock().unwrap());
+    fn close_gracefully(&mut self, timeout: Duration) -> bool {
+        let deadline = std::time::Instant::now() + timeout;
+        for smol_socket in self.smol_sockets.values_mut() {
+            if smol_socket.socket_type != SocketType::TCP {
+                continue;
+            }
+            let mut socket = self.sockets.get::<TcpSocket>(smol_socket.socket_handle);
+            if socket.may_send() {
+                //spin sends FIN once what's queued is out
+                smol_socket.shutdown_write = true;
+            } else {
+                //Listening or still connecting, nothing to flush
+                socket.close();
+            }
+        }
+        let has_data = self.has_data.as_ref().unwrap().clone();
+        loop {
+            self.spin_all();
+            self.poll();
+            self.spin_all();
+            if self.tcp_closed() {
+                return true;
+            }
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            //Packets moved by C++ wake us too, VirtualTun stacks need them to
+            let mut wait = deadline - now;
+            if !self.clock.is_manual() {
+                if let Some(delay) = self.poll_delay() {
+                    wait = std::cmp::min(wait, Duration::from_millis(delay.total_millis()));
+                }
+            }
+            has_data.wait(Some(wait));
+        }
+    }
+
+    //Every TCP socket is closed or only waits for the peer, with all we sent acknowledged
+    fn tcp_closed(&mut self) -> bool {
+        let sockets = &mut self.sockets;
+        self.smol_sockets
+            .values()
+            .filter(|smol_socket| smol_socket.socket_type == SocketType::TCP)
+            .all(|smol_socket| match sockets.get::<TcpSocket>(smol_socket.socket_handle).state() {
+                TcpState::Closed | TcpState::FinWait2 | TcpState::TimeWait => true,
+                _ => false,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::interface::SmolStackType;
+    use super::*;
+    use std::thread;
+
+    //Lets another thread use a socket the way C++ does, through a pointer
+    struct SocketPointer(*const SmolSocket);
+    unsafe impl Send for SocketPointer {}
+
+    #[test]
+    fn stale_socket_handle_keys_are_rejected() {
+        let mut smol_stack = SmolStackType::new_virtual_tun("tun0".to_owned());
+        let mut first = 0;
+        assert_eq!(smol_stack.add_socket(SocketType::TCP, &mut first), SMOL_RESULT_OK);
+        assert_ne!(first, 0);
+        assert_eq!(smol_stack.remove_socket(first), SMOL_RESULT_OK);
+        let mut second = 0;
+        assert_eq!(smol_stack.add_socket(SocketType::TCP, &mut second), SMOL_RESULT_OK);
+        //Same slot, next generation
+        assert_eq!(first & KEY_INDEX_MASK, second & KEY_INDEX_MASK);
+        assert_ne!(first, second);
+        assert!(smol_stack.get_smol_socket(first).is_none());
+        assert!(smol_stack.get_smol_socket(second).is_some());
+        assert_eq!(smol_stack.remove_socket(first), SMOL_RESULT_NOT_AVAILABLE);
+    }
+
+    #[test]
+    fn connection_table_lists_every_socket() {
+        let mut smol_stack = SmolStackType::new_virtual_tun("tun0".to_owned());
+        smol_stack.set_clock(Clock::Manual(Instant::from_millis(1000)));
+        let (mut tcp, mut udp) = (0, 0);
+        assert_eq!(smol_stack.add_socket(SocketType::TCP, &mut tcp), SMOL_RESULT_OK);
+        assert_eq!(smol_stack.add_socket(SocketType::UDP, &mut udp), SMOL_RESULT_OK);
+        assert_eq!(smol_stack.tcp_listen(tcp, 80), SMOL_RESULT_OK);
+        assert_eq!(smol_stack.udp_bind(udp, 53), SMOL_RESULT_OK);
+        smol_stack.advance_clock(SmolDuration::from_millis(1500));
+        let connections = smol_stack.connections();
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[0].key, tcp);
+        assert_eq!(connections[0].tcp_state, Some(TcpState::Listen));
+        assert_eq!(connections[0].local.port, 80);
+        assert_eq!(connections[0].age, SmolDuration::from_millis(1500));
+        assert_eq!(connections[1].socket_type, SocketType::UDP);
+        assert_eq!(connections[1].tcp_state, None);
+        assert_eq!(connections[1].local.port, 53);
+        let json = connections[0].to_json();
+        assert_eq!(json["type"], "tcp");
+        assert_eq!(json["state"], "LISTEN");
+        assert_eq!(json["age_millis"], 1500);
+    }
+
+    #[test]
+    fn manual_clock_only_moves_when_advanced() {
+        let mut smol_stack = SmolStackType::new_virtual_tun("tun0".to_owned());
+        assert_eq!(
+            smol_stack.advance_clock(SmolDuration::from_millis(10)),
+            SMOL_RESULT_UNSUPPORTED
+        );
+        smol_stack.set_clock(Clock::Manual(Instant::from_millis(1000)));
+        assert_eq!(smol_stack.clock().now(), Instant::from_millis(1000));
+        assert_eq!(
+            smol_stack.advance_clock(SmolDuration::from_millis(250)),
+            SMOL_RESULT_OK
+        );
+        assert_eq!(smol_stack.clock().now(), Instant::from_millis(1250));
+    }
+    unsafe extern "C" fn count_destructions(owner: *const c_void) -> u8 {
+        (*(owner as *const AtomicUsize)).fetch_add(1, Ordering::SeqCst);
+        0
+    }
+
+    #[test]
+    fn owners_of_refused_sends_are_given_back() {
+        let mut smol_stack = SmolStackType::new_virtual_tun("tun0".to_owned());
+        let limits = QueueLimits {
+            max_packets: 1,
+            max_bytes: 0,
+        };
+        assert_eq!(smol_stack.set_queue_limits(QUEUE_SOCKET_TO_SEND, limits), SMOL_RESULT_OK);
+        let mut key = 0;
+        assert_eq!(smol_stack.add_socket(SocketType::TCP, &mut key), SMOL_RESULT_OK);
+        let destructions = AtomicUsize::new(0);
+        let owned_packet = || Packet {
+            blob: Blob {
+                data: vec![1, 2, 3],
+                start: 0,
+                pointer_to_owner: Some(&destructions as *const AtomicUsize as *const c_void),
+                pointer_to_destructor: Some(count_destructions),
+            },
+            endpoint: None,
+        };
+        let smol_socket = smol_stack.get_smol_socket(key).unwrap();
+        assert_eq!(smol_socket.send(owned_packet()), SMOL_RESULT_OK);
+        assert_eq!(smol_socket.send(owned_packet()), SMOL_RESULT_WOULD_BLOCK);
+        assert_eq!(destructions.load(Ordering::SeqCst), 0);
+        //The queued one is released with the socket
+        assert_eq!(smol_stack.remove_socket(key), SMOL_RESULT_OK);
+        assert_eq!(destructions.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn blocking_receive_times_out() {
+        let mut smol_stack = SmolStackType::new_virtual_tun("tun0".to_owned());
+        let mut key = 0;
+        assert_eq!(smol_stack.add_socket(SocketType::UDP, &mut key), SMOL_RESULT_OK);
+        let smol_socket = smol_stack.get_smol_socket(key).unwrap();
+        let mut buffer = [0; 16];
+        let mut len = 0;
+        let mut endpoint = None;
+        assert_eq!(
+            smol_socket.receive_wait_into(&mut buffer, &mut len, &mut endpoint, Some(Duration::from_millis(10))),
+            SMOL_RESULT_TIMED_OUT
+        );
+    }
+
+    #[test]
+    fn cancel_wakes_blocked_receive() {
+        let mut smol_stack = SmolStackType::new_virtual_tun("tun0".to_owned());
+        let mut key = 0;
+        assert_eq!(smol_stack.add_socket(SocketType::UDP, &mut key), SMOL_RESULT_OK);
+        let smol_socket = smol_stack.get_smol_socket(key).unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+        let canceller = {
+            let smol_socket = SocketPointer(&*smol_socket as *const SmolSocket);
+            let done = done.clone();
+            //Cancels until it's seen, a cancel before the wait starts doesn't count
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    unsafe { &*smol_socket.0 }.cancel_receive();
+                    thread::sleep(Duration::from_millis(5));
+                }
+            })
+        };
+        let mut buffer = [0; 16];
+        let mut len = 0;
+        let mut endpoint = None;
+        let r = smol_socket.receive_wait_into(&mut buffer, &mut len, &mut endpoint, Some(Duration::from_secs(10)));
+        done.store(true, Ordering::SeqCst);
+        canceller.join().unwrap();
+        assert_eq!(r, SMOL_RESULT_CANCELLED);
     }
 
-    pub fn phy_wait_timeout(&mut self, duration: Duration) {
-        let (mutex, has_data_condition_variable) = &*self.has_data.as_ref().unwrap().clone();
-        has_data_condition_variable.wait_timeout(mutex.lock().unwrap(), duration);
+    #[test]
+    fn removing_the_socket_closes_its_receivers() {
+        let mut smol_stack = SmolStackType::new_virtual_tun("tun0".to_owned());
+        let mut key = 0;
+        assert_eq!(smol_stack.add_socket(SocketType::UDP, &mut key), SMOL_RESULT_OK);
+        let receiver = smol_stack.get_smol_socket(key).unwrap().receiver();
+        assert_eq!(smol_stack.remove_socket(key), SMOL_RESULT_OK);
+        let mut buffer = [0; 16];
+        let mut len = 0;
+        let mut endpoint = None;
+        assert_eq!(
+            receiver.wait(Some(Duration::from_secs(10)), || {
+                receiver.receive_into(&mut buffer, &mut len, &mut endpoint)
+            }),
+            SMOL_RESULT_CLOSED
+        );
     }
 }
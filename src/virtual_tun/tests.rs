@@ -0,0 +1,966 @@
+/*
+    Two VirtualTun stacks wired back to back: every packet one of them
+    puts on packets_from_inside is given to the other one through
+    packets_from_outside, as if they were two hosts on the same link.
+    Both use a manual clock, so these tests need no root, no kernel TUN
+    devices and no sleeps, and behave the same on every run. The NAT
+    tests are the exception: they relay to real sockets on localhost.
+    At the end, the device conformance checks every other device type
+    must pass, on a real link and with the real clock
+*/
+use super::clock::Clock;
+use super::filter::{FilterRule, FILTER_ACTION_REJECT, FILTER_EGRESS, FILTER_INGRESS};
+use super::ingress::INGRESS_CHECK_ALL;
+use super::interface::{CIpAddress, CIpv4Address, CIpv4Cidr, CIpv6Address, SmolStackType};
+use super::interface::smol_stack_virtual_tun_get_ingress_counters;
+use super::queue::{QueueLimits, QUEUE_SOCKET_RECEIVED};
+use super::signal::{WAKE_REASON_PACKETS, WAKE_REASON_ROOM, WAKE_REASON_SOCKETS};
+use super::signal::{WAKE_REASON_SHUT_DOWN, WAKE_REASON_TIMEOUT, WAKE_REASON_WAKE};
+use super::smol_stack::{Blob, Packet, SocketType};
+use super::smol_stack::{SMOL_RESULT_CLOSED, SMOL_RESULT_END_OF_STREAM, SMOL_RESULT_NOT_AVAILABLE, SMOL_RESULT_OK};
+use super::smol_stack::{SMOL_RESULT_SHUT_DOWN, SMOL_RESULT_TIMED_OUT};
+use super::smol_stack::{SMOL_RESULT_INVALID_CHECKSUM, SMOL_RESULT_NOT_OUR_ADDRESS};
+use smoltcp::socket::TcpState;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{IpAddress, IpEndpoint, IpProtocol, Ipv4Address};
+use super::callback_device::{CReceiveFunction, CTransmitFunction};
+use smoltcp::phy::Medium;
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+type Stack = Box<SmolStackType<'static, 'static, 'static>>;
+
+const ADDRESS_A: [u8; 4] = [192, 168, 69, 1];
+const ADDRESS_B: [u8; 4] = [192, 168, 69, 2];
+const GATEWAY: [u8; 4] = [192, 168, 69, 100];
+//pump() gives up after this many rounds, so a stuck connection fails instead of hanging
+const MAX_ROUNDS: usize = 100_000;
+
+fn c_ip_address(address: [u8; 4]) -> CIpAddress {
+    CIpAddress {
+        is_ipv4: 1,
+        ipv4_address: CIpv4Address { address: address },
+        ipv6_address: CIpv6Address { address: [0; 8] },
+    }
+}
+
+fn endpoint(address: [u8; 4], port: u16) -> IpEndpoint {
+    IpEndpoint::new(
+        IpAddress::v4(address[0], address[1], address[2], address[3]),
+        port,
+    )
+}
+
+//Bytes that differ from one offset to the next, so reordering or loss shows up
+fn pattern(seed: usize, len: usize) -> Vec<u8> {
+    (0..len).map(|i| ((seed + i) % 251) as u8).collect()
+}
+
+//Everything but finalize, for tests that need more before it
+fn unfinalized_stack(address: [u8; 4]) -> Stack {
+    let mut smol_stack = SmolStackType::new_virtual_tun("tun0".to_owned());
+    smol_stack.set_clock(Clock::Manual(Instant::from_millis(0)));
+    let cidr = CIpv4Cidr {
+        address: CIpv4Address { address: address },
+        prefix: 24,
+    };
+    assert_eq!(smol_stack.add_ipv4_address(cidr), SMOL_RESULT_OK);
+    //Optional for finalize, but every host here has them
+    smol_stack.add_default_v4_gateway(CIpv4Address { address: GATEWAY });
+    smol_stack.add_default_v6_gateway(CIpv6Address {
+        address: [0xfe80, 0, 0, 0, 0, 0, 0, 1],
+    });
+    smol_stack
+}
+
+fn new_stack(address: [u8; 4]) -> Stack {
+    let mut smol_stack = unfinalized_stack(address);
+    assert_eq!(smol_stack.finalize(), SMOL_RESULT_OK);
+    smol_stack
+}
+
+fn add_socket(smol_stack: &mut Stack, socket_type: SocketType) -> usize {
+    let mut socket_handle_key = 0;
+    assert_eq!(
+        smol_stack.add_socket(socket_type, &mut socket_handle_key),
+        SMOL_RESULT_OK
+    );
+    socket_handle_key
+}
+
+fn send(smol_stack: &mut Stack, socket_handle_key: usize, data: Vec<u8>, endpoint: Option<IpEndpoint>) {
+    let smol_socket = smol_stack.get_smol_socket(socket_handle_key).unwrap();
+    assert_eq!(smol_socket.send(Packet::from_vec(data, endpoint)), SMOL_RESULT_OK);
+}
+
+/*
+    Appends everything queued on the socket to `data` and returns the
+    result of the last receive (SMOL_RESULT_NOT_AVAILABLE or
+    SMOL_RESULT_END_OF_STREAM)
+*/
+fn receive_all(smol_stack: &mut Stack, socket_handle_key: usize, data: &mut Vec<u8>) -> u8 {
+    let smol_socket = smol_stack.get_smol_socket(socket_handle_key).unwrap();
+    let mut buffer = vec![0; 4096];
+    loop {
+        let mut len = 0;
+        let mut endpoint = None;
+        let r = smol_socket.receive_into(&mut buffer, &mut len, &mut endpoint);
+        if r != SMOL_RESULT_OK {
+            return r;
+        }
+        data.extend_from_slice(&buffer[..len]);
+    }
+}
+
+struct BackToBack {
+    a: Stack,
+    b: Stack,
+}
+
+impl BackToBack {
+    fn new() -> BackToBack {
+        BackToBack {
+            a: new_stack(ADDRESS_A),
+            b: new_stack(ADDRESS_B),
+        }
+    }
+
+    //Moves every packet `from` produced to `to`, returns how many were moved
+    fn forward(from: &mut Stack, to: &mut Stack) -> usize {
+        let mut buffer = vec![0; 65536];
+        let mut moved = 0;
+        loop {
+            let mut len = 0;
+            if from.receive_instantly_into(&mut buffer, &mut len) != SMOL_RESULT_OK {
+                return moved;
+            }
+            assert_eq!(to.send(&buffer[..len]), SMOL_RESULT_OK);
+            moved += 1;
+        }
+    }
+
+    //Queued socket data goes to smoltcp, then smoltcp's output goes back to the sockets
+    fn turn(smol_stack: &mut Stack) {
+        smol_stack.spin_all();
+        smol_stack.poll();
+        smol_stack.spin_all();
+    }
+
+    //One round on both stacks, returns how many packets crossed the link
+    fn step(&mut self) -> usize {
+        BackToBack::turn(&mut self.a);
+        BackToBack::turn(&mut self.b);
+        BackToBack::forward(&mut self.a, &mut self.b) + BackToBack::forward(&mut self.b, &mut self.a)
+    }
+
+    fn advance(&mut self, duration: Duration) {
+        assert_eq!(self.a.advance_clock(duration), SMOL_RESULT_OK);
+        assert_eq!(self.b.advance_clock(duration), SMOL_RESULT_OK);
+    }
+
+    /*
+        Steps until no packet crosses and no timer is pending. When the
+        link is idle, the clocks jump to the next timer (delayed ACKs,
+        retransmissions, TIME-WAIT), at least 1ms so we always move on
+    */
+    fn pump(&mut self) {
+        for _ in 0..MAX_ROUNDS {
+            if self.step() > 0 {
+                continue;
+            }
+            let delay = match (self.a.poll_delay(), self.b.poll_delay()) {
+                (Some(a), Some(b)) => std::cmp::min(a, b),
+                (Some(delay), None) | (None, Some(delay)) => delay,
+                (None, None) => return,
+            };
+            self.advance(std::cmp::max(delay, Duration::from_millis(1)));
+        }
+        panic!("stacks still busy after {} rounds", MAX_ROUNDS);
+    }
+
+    /*
+        `a` behind `b` acting as its NAT gateway, reaching the host's
+        loopback through GATEWAY
+    */
+    fn nat() -> BackToBack {
+        let mut b = unfinalized_stack(ADDRESS_B);
+        assert_eq!(b.enable_nat(), SMOL_RESULT_OK);
+        assert_eq!(b.finalize(), SMOL_RESULT_OK);
+        assert_eq!(b.set_nat_host_alias(IpAddress::Ipv4(Ipv4Address(GATEWAY))), SMOL_RESULT_OK);
+        BackToBack {
+            a: new_stack(ADDRESS_A),
+            b: b,
+        }
+    }
+
+    /*
+        Steps until `done`, for links with host sockets behind them, which
+        answer on their own time. Fails after a few seconds
+    */
+    fn step_until<F>(&mut self, mut done: F)
+    where
+        F: FnMut(&mut BackToBack) -> bool,
+    {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while !done(self) {
+            assert!(std::time::Instant::now() < deadline, "the host never answered");
+            self.step();
+            self.advance(Duration::from_millis(1));
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    //Client socket on `a` connected to a server socket listening on `b`
+    fn tcp_pair(&mut self) -> (usize, usize) {
+        let server = add_socket(&mut self.b, SocketType::TCP);
+        assert_eq!(self.b.tcp_listen(server, 80), SMOL_RESULT_OK);
+        let client = add_socket(&mut self.a, SocketType::TCP);
+        assert_eq!(
+            self.a.tcp_connect(client, c_ip_address(ADDRESS_B), 49152, 80),
+            SMOL_RESULT_OK
+        );
+        self.pump();
+        (client, server)
+    }
+}
+
+#[test]
+fn tcp_connect_establishes_both_sides() {
+    let mut link = BackToBack::new();
+    let (client, server) = link.tcp_pair();
+    assert_eq!(link.a.may_send(client), SMOL_RESULT_OK);
+    assert_eq!(link.b.may_send(server), SMOL_RESULT_OK);
+    let mut data = Vec::new();
+    assert_eq!(receive_all(&mut link.b, server, &mut data), SMOL_RESULT_NOT_AVAILABLE);
+    assert!(data.is_empty());
+}
+
+#[test]
+fn tcp_bulk_transfer_in_both_directions() {
+    let mut link = BackToBack::new();
+    let (client, server) = link.tcp_pair();
+    let mut upload = Vec::new();
+    let mut download = Vec::new();
+    for i in 0..256 {
+        let chunk = pattern(i, 1000);
+        upload.extend_from_slice(&chunk);
+        send(&mut link.a, client, chunk, None);
+        let chunk = pattern(i + 7, 700);
+        download.extend_from_slice(&chunk);
+        send(&mut link.b, server, chunk, None);
+    }
+    link.pump();
+    let mut received = Vec::new();
+    receive_all(&mut link.b, server, &mut received);
+    assert!(received == upload);
+    let mut received = Vec::new();
+    receive_all(&mut link.a, client, &mut received);
+    assert!(received == download);
+}
+
+#[test]
+fn tcp_partial_sends_continue_where_they_stopped() {
+    let mut link = BackToBack::new();
+    let (client, server) = link.tcp_pair();
+    //Each packet is bigger than the socket's tx buffer, so it takes several spins to go
+    let mut expected = Vec::new();
+    for i in 0..3 {
+        let chunk = pattern(i * 31, 100_000);
+        expected.extend_from_slice(&chunk);
+        send(&mut link.a, client, chunk, None);
+    }
+    link.pump();
+    let mut received = Vec::new();
+    receive_all(&mut link.b, server, &mut received);
+    assert_eq!(received.len(), expected.len());
+    assert!(received == expected);
+}
+
+#[test]
+fn tcp_close_ends_the_stream() {
+    let mut link = BackToBack::new();
+    let (client, server) = link.tcp_pair();
+    send(&mut link.a, client, b"request".to_vec(), None);
+    assert_eq!(
+        link.a.get_smol_socket(client).unwrap().shutdown_write(),
+        SMOL_RESULT_OK
+    );
+    link.pump();
+    let mut received = Vec::new();
+    assert_eq!(receive_all(&mut link.b, server, &mut received), SMOL_RESULT_END_OF_STREAM);
+    assert_eq!(received, b"request".to_vec());
+    //Half-closed: the server can still answer
+    send(&mut link.b, server, b"response".to_vec(), None);
+    assert_eq!(
+        link.b.get_smol_socket(server).unwrap().shutdown_write(),
+        SMOL_RESULT_OK
+    );
+    link.pump();
+    let mut received = Vec::new();
+    assert_eq!(receive_all(&mut link.a, client, &mut received), SMOL_RESULT_END_OF_STREAM);
+    assert_eq!(received, b"response".to_vec());
+}
+
+#[test]
+fn ingress_checks_pass_real_traffic_and_refuse_corrupted_packets() {
+    let mut link = BackToBack::new();
+    assert_eq!(link.a.set_ingress_checks(INGRESS_CHECK_ALL), SMOL_RESULT_OK);
+    assert_eq!(link.b.set_ingress_checks(INGRESS_CHECK_ALL), SMOL_RESULT_OK);
+    let (client, server) = link.tcp_pair();
+    send(&mut link.a, client, b"hello".to_vec(), None);
+    BackToBack::turn(&mut link.a);
+    let mut buffer = vec![0; 65536];
+    let mut len = 0;
+    assert_eq!(link.a.receive_instantly_into(&mut buffer, &mut len), SMOL_RESULT_OK);
+    let mut packet = buffer[..len].to_vec();
+    //Goes to B, so A refuses it
+    assert_eq!(link.a.send(&packet), SMOL_RESULT_NOT_OUR_ADDRESS);
+    *packet.last_mut().unwrap() ^= 0xff;
+    assert_eq!(link.b.send(&packet), SMOL_RESULT_INVALID_CHECKSUM);
+    *packet.last_mut().unwrap() ^= 0xff;
+    assert_eq!(link.b.send(&packet), SMOL_RESULT_OK);
+    link.pump();
+    let mut received = Vec::new();
+    receive_all(&mut link.b, server, &mut received);
+    assert_eq!(received, b"hello".to_vec());
+    let counters = link.b.ingress_counters().unwrap();
+    assert_eq!(counters.invalid_checksum.load(Ordering::Relaxed), 1);
+    assert_eq!(counters.not_our_address.load(Ordering::Relaxed), 0);
+}
+
+fn tcp_state(smol_stack: &mut Stack, socket_handle_key: usize) -> TcpState {
+    let connections = smol_stack.connections();
+    let connection = connections.iter().find(|connection| connection.key == socket_handle_key);
+    connection.unwrap().tcp_state.unwrap()
+}
+
+//Rejects TCP to port 80 in `direction`
+fn reject_port_80(smol_stack: &mut Stack, direction: u8) {
+    let rule = FilterRule {
+        direction: direction,
+        protocol: Some(IpProtocol::Tcp),
+        source: None,
+        destination: None,
+        source_ports: None,
+        destination_ports: Some((80, 80)),
+        action: FILTER_ACTION_REJECT,
+    };
+    assert!(smol_stack.packet_filter().unwrap().add_rule(rule).is_some());
+}
+
+#[test]
+fn filter_rejects_incoming_connections_with_a_reset() {
+    let mut link = BackToBack::new();
+    reject_port_80(&mut link.b, FILTER_INGRESS);
+    let (client, _) = link.tcp_pair();
+    //A drop would leave it retransmitting the SYN
+    assert_eq!(tcp_state(&mut link.a, client), TcpState::Closed);
+    assert_eq!(receive_all(&mut link.a, client, &mut Vec::new()), SMOL_RESULT_CLOSED);
+    assert_eq!(link.b.packet_filter().unwrap().refused(FILTER_INGRESS), 1);
+}
+
+#[test]
+fn filter_rejects_outgoing_connections_before_they_leave() {
+    let mut link = BackToBack::new();
+    reject_port_80(&mut link.a, FILTER_EGRESS);
+    let client = add_socket(&mut link.a, SocketType::TCP);
+    assert_eq!(
+        link.a.tcp_connect(client, c_ip_address(ADDRESS_B), 49152, 80),
+        SMOL_RESULT_OK
+    );
+    //The SYN never crosses, the RST comes from a's own device
+    assert_eq!(link.step(), 0);
+    assert_eq!(tcp_state(&mut link.a, client), TcpState::Closed);
+    assert_eq!(link.a.packet_filter().unwrap().refused(FILTER_EGRESS), 1);
+    assert_eq!(receive_all(&mut link.a, client, &mut Vec::new()), SMOL_RESULT_CLOSED);
+}
+
+#[test]
+fn udp_exchange_reports_the_sender() {
+    let mut link = BackToBack::new();
+    let client = add_socket(&mut link.a, SocketType::UDP);
+    assert_eq!(link.a.udp_bind(client, 5000), SMOL_RESULT_OK);
+    let server = add_socket(&mut link.b, SocketType::UDP);
+    assert_eq!(link.b.udp_bind(server, 6000), SMOL_RESULT_OK);
+
+    send(&mut link.a, client, b"ping".to_vec(), Some(endpoint(ADDRESS_B, 6000)));
+    link.pump();
+    let mut buffer = vec![0; 1500];
+    let mut len = 0;
+    let mut from = None;
+    let smol_socket = link.b.get_smol_socket(server).unwrap();
+    assert_eq!(smol_socket.receive_into(&mut buffer, &mut len, &mut from), SMOL_RESULT_OK);
+    assert_eq!(&buffer[..len], b"ping");
+    assert_eq!(from, Some(endpoint(ADDRESS_A, 5000)));
+
+    //Answer to whoever sent it
+    send(&mut link.b, server, b"pong".to_vec(), from);
+    link.pump();
+    let smol_socket = link.a.get_smol_socket(client).unwrap();
+    assert_eq!(smol_socket.receive_into(&mut buffer, &mut len, &mut from), SMOL_RESULT_OK);
+    assert_eq!(&buffer[..len], b"pong");
+    assert_eq!(from, Some(endpoint(ADDRESS_B, 6000)));
+    assert_eq!(
+        smol_socket.receive_into(&mut buffer, &mut len, &mut from),
+        SMOL_RESULT_NOT_AVAILABLE
+    );
+}
+
+#[test]
+fn nat_relays_tcp_to_host_sockets() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    //Echoes one message and closes
+    let host = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut message = [0; 9];
+        stream.read_exact(&mut message).unwrap();
+        stream.write_all(&message).unwrap();
+    });
+    let mut link = BackToBack::nat();
+    let client = add_socket(&mut link.a, SocketType::TCP);
+    assert_eq!(
+        link.a.tcp_connect(client, c_ip_address(GATEWAY), 49152, port),
+        SMOL_RESULT_OK
+    );
+    send(&mut link.a, client, b"hello nat".to_vec(), None);
+    let mut received = Vec::new();
+    link.step_until(|link| receive_all(&mut link.a, client, &mut received) == SMOL_RESULT_END_OF_STREAM);
+    host.join().unwrap();
+    assert_eq!(received, b"hello nat".to_vec());
+    assert_eq!(link.b.nat_flows(), Some((1, 0)));
+}
+
+#[test]
+fn nat_relays_udp_and_answers_from_the_destination() {
+    let host = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = host.local_addr().unwrap().port();
+    let echo = std::thread::spawn(move || {
+        let mut datagram = [0; 64];
+        let (len, from) = host.recv_from(&mut datagram).unwrap();
+        host.send_to(&datagram[..len], from).unwrap();
+    });
+    let mut link = BackToBack::nat();
+    let client = add_socket(&mut link.a, SocketType::UDP);
+    assert_eq!(link.a.udp_bind(client, 5000), SMOL_RESULT_OK);
+    send(&mut link.a, client, b"ping".to_vec(), Some(endpoint(GATEWAY, port)));
+    let mut buffer = vec![0; 1500];
+    let mut len = 0;
+    let mut from = None;
+    link.step_until(|link| {
+        let smol_socket = link.a.get_smol_socket(client).unwrap();
+        smol_socket.receive_into(&mut buffer, &mut len, &mut from) == SMOL_RESULT_OK
+    });
+    echo.join().unwrap();
+    assert_eq!(&buffer[..len], b"ping");
+    assert_eq!(from, Some(endpoint(GATEWAY, port)));
+    assert_eq!(link.b.nat_flows(), Some((0, 1)));
+}
+
+#[test]
+fn nat_refuses_the_host_loopback_unless_through_the_alias() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut link = BackToBack::nat();
+    let client = add_socket(&mut link.a, SocketType::TCP);
+    assert_eq!(
+        link.a.tcp_connect(client, c_ip_address([127, 0, 0, 1]), 49152, port),
+        SMOL_RESULT_OK
+    );
+    //Nothing listens for it, so smoltcp answers with a RST
+    link.pump();
+    assert_eq!(tcp_state(&mut link.a, client), TcpState::Closed);
+    assert_eq!(receive_all(&mut link.a, client, &mut Vec::new()), SMOL_RESULT_CLOSED);
+    assert_eq!(link.b.nat_flows(), Some((0, 0)));
+    assert!(listener.accept().is_err());
+}
+
+#[test]
+fn nat_opens_no_flow_for_what_the_filter_refuses() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut link = BackToBack::nat();
+    let rule = FilterRule {
+        direction: FILTER_INGRESS,
+        protocol: Some(IpProtocol::Tcp),
+        source: None,
+        destination: None,
+        source_ports: None,
+        destination_ports: Some((port, port)),
+        action: FILTER_ACTION_REJECT,
+    };
+    assert!(link.b.packet_filter().unwrap().add_rule(rule).is_some());
+    let client = add_socket(&mut link.a, SocketType::TCP);
+    assert_eq!(
+        link.a.tcp_connect(client, c_ip_address(GATEWAY), 49152, port),
+        SMOL_RESULT_OK
+    );
+    link.pump();
+    assert_eq!(tcp_state(&mut link.a, client), TcpState::Closed);
+    assert_eq!(link.b.nat_flows(), Some((0, 0)));
+    assert_eq!(link.b.packet_filter().unwrap().refused(FILTER_INGRESS), 1);
+    assert!(listener.accept().is_err());
+}
+
+#[test]
+fn large_payload_through_a_small_receive_queue() {
+    let mut link = BackToBack::new();
+    //The server reads slowly, so the client has to wait for its window to open
+    let limits = QueueLimits {
+        max_packets: 0,
+        max_bytes: 16 * 1024,
+    };
+    assert_eq!(
+        link.b.set_queue_limits(QUEUE_SOCKET_RECEIVED, limits),
+        SMOL_RESULT_OK
+    );
+    let (client, server) = link.tcp_pair();
+    let expected = pattern(3, 4 * 1024 * 1024);
+    for chunk in expected.chunks(64 * 1024) {
+        send(&mut link.a, client, chunk.to_vec(), None);
+    }
+    let mut received = Vec::new();
+    while received.len() < expected.len() {
+        let before = received.len();
+        link.pump();
+        receive_all(&mut link.b, server, &mut received);
+        assert!(received.len() > before, "transfer stalled at {} bytes", before);
+    }
+    assert!(received == expected);
+}
+
+#[test]
+fn datagrams_bigger_than_the_room_left_wait_in_the_socket() {
+    let mut link = BackToBack::new();
+    let limits = QueueLimits {
+        max_packets: 0,
+        max_bytes: 10,
+    };
+    assert_eq!(link.b.set_queue_limits(QUEUE_SOCKET_RECEIVED, limits), SMOL_RESULT_OK);
+    let client = add_socket(&mut link.a, SocketType::UDP);
+    assert_eq!(link.a.udp_bind(client, 5000), SMOL_RESULT_OK);
+    let server = add_socket(&mut link.b, SocketType::UDP);
+    assert_eq!(link.b.udp_bind(server, 6000), SMOL_RESULT_OK);
+    send(&mut link.a, client, pattern(0, 6), Some(endpoint(ADDRESS_B, 6000)));
+    send(&mut link.a, client, pattern(1, 6), Some(endpoint(ADDRESS_B, 6000)));
+    link.pump();
+    let mut buffer = vec![0; 1500];
+    let mut len = 0;
+    let mut from = None;
+    for seed in 0..2 {
+        let smol_socket = link.b.get_smol_socket(server).unwrap();
+        assert_eq!(smol_socket.receive_into(&mut buffer, &mut len, &mut from), SMOL_RESULT_OK);
+        assert_eq!(&buffer[..len], &pattern(seed, 6)[..]);
+        //The second one only fits now
+        link.pump();
+    }
+}
+
+#[test]
+fn batches_move_several_packets_per_call() {
+    let mut link = BackToBack::new();
+    let client = add_socket(&mut link.a, SocketType::UDP);
+    assert_eq!(link.a.udp_bind(client, 5000), SMOL_RESULT_OK);
+    let server = add_socket(&mut link.b, SocketType::UDP);
+    assert_eq!(link.b.udp_bind(server, 6000), SMOL_RESULT_OK);
+    for i in 0..5 {
+        send(&mut link.a, client, pattern(i, 100), Some(endpoint(ADDRESS_B, 6000)));
+    }
+    BackToBack::turn(&mut link.a);
+
+    let mut storage = vec![vec![0u8; 1500]; 8];
+    let mut buffers: Vec<&mut [u8]> = storage.iter_mut().map(|b| &mut b[..]).collect();
+    let mut lens = vec![0; 8];
+    let mut received = 0;
+    assert_eq!(
+        link.a.receive_batch_into(&mut buffers, &mut lens, &mut received),
+        SMOL_RESULT_OK
+    );
+    assert_eq!(received, 5);
+    assert_eq!(
+        link.a.receive_batch_into(&mut buffers, &mut lens, &mut received),
+        SMOL_RESULT_NOT_AVAILABLE
+    );
+
+    let packets: Vec<&[u8]> = storage
+        .iter()
+        .zip(lens.iter())
+        .take(5)
+        .map(|(buffer, len)| &buffer[..*len])
+        .collect();
+    let mut sent = 0;
+    assert_eq!(link.b.send_batch(&packets, &mut sent), SMOL_RESULT_OK);
+    assert_eq!(sent, 5);
+    BackToBack::turn(&mut link.b);
+
+    let smol_socket = link.b.get_smol_socket(server).unwrap();
+    let mut buffer = vec![0; 1500];
+    for i in 0..5 {
+        let mut len = 0;
+        let mut from = None;
+        assert_eq!(smol_socket.receive_into(&mut buffer, &mut len, &mut from), SMOL_RESULT_OK);
+        assert_eq!(&buffer[..len], &pattern(i, 100)[..]);
+    }
+}
+
+#[test]
+fn phy_wait_reports_why_it_woke() {
+    let mut link = BackToBack::new();
+    //Manual clocks don't sleep, nothing is pending yet
+    assert_eq!(link.b.phy_wait(0), WAKE_REASON_TIMEOUT);
+    let client = add_socket(&mut link.a, SocketType::UDP);
+    assert_eq!(link.a.udp_bind(client, 5000), SMOL_RESULT_OK);
+    send(&mut link.a, client, pattern(0, 100), Some(endpoint(ADDRESS_B, 6000)));
+    //Notified before anyone waited, still reported
+    assert_eq!(link.a.phy_wait(0), WAKE_REASON_SOCKETS);
+    BackToBack::turn(&mut link.a);
+    //Polling doesn't wake the poller itself
+    assert_eq!(link.a.phy_wait(0), WAKE_REASON_TIMEOUT);
+    assert_eq!(BackToBack::forward(&mut link.a, &mut link.b), 1);
+    assert_eq!(link.a.phy_wait(0), WAKE_REASON_ROOM);
+    assert_eq!(link.b.phy_wait(0), WAKE_REASON_PACKETS);
+    link.b.wake();
+    link.b.wake();
+    assert_eq!(link.b.phy_wait(0), WAKE_REASON_WAKE);
+    assert_eq!(link.b.phy_wait(0), WAKE_REASON_TIMEOUT);
+}
+
+#[test]
+fn refused_connect_reports_closed() {
+    let mut link = BackToBack::new();
+    //Nobody listens on b, which answers the SYN with a RST
+    let client = add_socket(&mut link.a, SocketType::TCP);
+    assert_eq!(
+        link.a.tcp_connect(client, c_ip_address(ADDRESS_B), 49152, 80),
+        SMOL_RESULT_OK
+    );
+    link.pump();
+    let mut data = Vec::new();
+    assert_eq!(receive_all(&mut link.a, client, &mut data), SMOL_RESULT_CLOSED);
+    assert!(data.is_empty());
+}
+
+#[test]
+fn listening_socket_keeps_waiting() {
+    let mut link = BackToBack::new();
+    let server = add_socket(&mut link.b, SocketType::TCP);
+    assert_eq!(link.b.tcp_listen(server, 80), SMOL_RESULT_OK);
+    link.pump();
+    assert_eq!(receive_all(&mut link.b, server, &mut Vec::new()), SMOL_RESULT_NOT_AVAILABLE);
+}
+
+#[test]
+fn reset_connection_reports_closed() {
+    let mut link = BackToBack::new();
+    let (client, server) = link.tcp_pair();
+    //The server forgets the connection, so it answers the next segment with RST
+    assert_eq!(link.b.remove_socket(server), SMOL_RESULT_OK);
+    send(&mut link.a, client, pattern(0, 100), None);
+    link.pump();
+    let mut data = Vec::new();
+    assert_eq!(receive_all(&mut link.a, client, &mut data), SMOL_RESULT_CLOSED);
+    assert!(data.is_empty());
+}
+
+//Owners (1 to 9) released by release_owner, one decimal digit each, in order
+static RELEASED_OWNERS: AtomicUsize = AtomicUsize::new(0);
+
+//Stands for the C++ destructor of a SmolOwner, the owner pointer is just a number here
+unsafe extern "C" fn release_owner(owner: *const c_void) -> u8 {
+    let released = RELEASED_OWNERS.load(Ordering::SeqCst);
+    RELEASED_OWNERS.store(released * 10 + owner as usize, Ordering::SeqCst);
+    0
+}
+
+#[test]
+fn shutdown_releases_buffers_in_order_and_fails_later_calls() {
+    let mut smol_stack = new_stack(ADDRESS_A);
+    let socket = add_socket(&mut smol_stack, SocketType::UDP);
+    assert_eq!(smol_stack.udp_bind(socket, 5000), SMOL_RESULT_OK);
+    let smol_socket = smol_stack.get_smol_socket(socket).unwrap();
+    for owner in 1..=3 {
+        let packet = Packet {
+            blob: Blob {
+                data: pattern(owner, 10),
+                start: 0,
+                pointer_to_owner: Some(owner as *const c_void),
+                pointer_to_destructor: Some(release_owner),
+            },
+            endpoint: Some(endpoint(ADDRESS_B, 6000)),
+        };
+        assert_eq!(smol_socket.send(packet), SMOL_RESULT_OK);
+    }
+    assert_eq!(smol_stack.shutdown(None), SMOL_RESULT_OK);
+    assert_eq!(RELEASED_OWNERS.load(Ordering::SeqCst), 123);
+    assert!(smol_stack.get_smol_socket(socket).is_none());
+    let mut other = 0;
+    assert_eq!(smol_stack.add_socket(SocketType::UDP, &mut other), SMOL_RESULT_SHUT_DOWN);
+    assert_eq!(smol_stack.poll(), SMOL_RESULT_SHUT_DOWN);
+    assert_eq!(smol_stack.phy_wait(0), WAKE_REASON_SHUT_DOWN);
+    assert_eq!(
+        smol_stack_virtual_tun_get_ingress_counters(&mut smol_stack, std::ptr::null_mut()),
+        SMOL_RESULT_SHUT_DOWN
+    );
+    assert_eq!(smol_stack.shutdown(None), SMOL_RESULT_SHUT_DOWN);
+}
+
+#[test]
+fn graceful_shutdown_waits_for_connections_up_to_the_deadline() {
+    //Only a listening socket, nothing to flush
+    let mut smol_stack = new_stack(ADDRESS_B);
+    let server = add_socket(&mut smol_stack, SocketType::TCP);
+    assert_eq!(smol_stack.tcp_listen(server, 80), SMOL_RESULT_OK);
+    assert_eq!(
+        smol_stack.shutdown(Some(std::time::Duration::from_secs(10))),
+        SMOL_RESULT_OK
+    );
+
+    //Nobody moves packets between the stacks now, so the FIN is never acknowledged
+    let mut link = BackToBack::new();
+    link.tcp_pair();
+    assert_eq!(
+        link.a.shutdown(Some(std::time::Duration::from_millis(10))),
+        SMOL_RESULT_TIMED_OUT
+    );
+}
+
+//A socket path no other test process uses
+pub(super) fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("smol_stack_{}_{}", std::process::id(), name))
+}
+
+//Adds `address` to a stack on a real device and finalizes it
+fn device_stack(mut smol_stack: Stack, address: [u8; 4]) -> Stack {
+    let cidr = CIpv4Cidr {
+        address: CIpv4Address { address: address },
+        prefix: 24,
+    };
+    assert_eq!(smol_stack.add_ipv4_address(cidr), SMOL_RESULT_OK);
+    assert_eq!(smol_stack.finalize(), SMOL_RESULT_OK);
+    smol_stack
+}
+
+/*
+    Sends `data` from `from`'s socket to `to`'s and returns who `to` says
+    sent it. Real devices don't always deliver synchronously, so `to` is
+    polled until the datagram shows up
+*/
+fn deliver(
+    from: &mut Stack,
+    from_socket: usize,
+    to: &mut Stack,
+    to_socket: usize,
+    data: &[u8],
+    destination: IpEndpoint,
+) -> Option<IpEndpoint> {
+    send(from, from_socket, data.to_vec(), Some(destination));
+    from.spin_all();
+    from.poll();
+    let mut buffer = vec![0; 2048];
+    let mut len = 0;
+    let mut sender = None;
+    for _ in 0..1000 {
+        to.poll();
+        to.spin_all();
+        let smol_socket = to.get_smol_socket(to_socket).unwrap();
+        if smol_socket.receive_into(&mut buffer, &mut len, &mut sender) == SMOL_RESULT_OK {
+            assert_eq!(&buffer[..len], data);
+            return sender;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    panic!("{} bytes were never delivered", data.len());
+}
+
+/*
+    What every device must do: carry packets both ways, whole, small or
+    close to the MTU (`a` and `b` use at least 1280), so that the sender
+    endpoint can be answered
+*/
+fn check_device_link(a: Stack, b: Stack) {
+    let mut a = device_stack(a, ADDRESS_A);
+    let mut b = device_stack(b, ADDRESS_B);
+    let client = add_socket(&mut a, SocketType::UDP);
+    assert_eq!(a.udp_bind(client, 5000), SMOL_RESULT_OK);
+    let server = add_socket(&mut b, SocketType::UDP);
+    assert_eq!(b.udp_bind(server, 6000), SMOL_RESULT_OK);
+    for &len in [1, 1000].iter() {
+        let data = pattern(len, len);
+        let from = deliver(&mut a, client, &mut b, server, &data, endpoint(ADDRESS_B, 6000));
+        assert_eq!(from, Some(endpoint(ADDRESS_A, 5000)));
+        let from = deliver(&mut b, server, &mut a, client, &data, from.unwrap());
+        assert_eq!(from, Some(endpoint(ADDRESS_B, 6000)));
+    }
+}
+
+//One end of a link between callback stacks: it receives what the other end transmits
+struct CallbackEnd {
+    inbox: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    outbox: Arc<Mutex<VecDeque<Vec<u8>>>>,
+}
+
+extern "C" fn receive_from_link(context: *const c_void, buffer: *mut u8, capacity: usize, len: *mut usize) -> u8 {
+    let end = unsafe { &*(context as *const CallbackEnd) };
+    match end.inbox.lock().unwrap().pop_front() {
+        Some(packet) => {
+            assert!(packet.len() <= capacity);
+            unsafe {
+                std::ptr::copy_nonoverlapping(packet.as_ptr(), buffer, packet.len());
+                *len = packet.len();
+            }
+            SMOL_RESULT_OK
+        }
+        None => SMOL_RESULT_NOT_AVAILABLE,
+    }
+}
+
+extern "C" fn transmit_to_link(context: *const c_void, data: *const u8, len: usize) -> u8 {
+    let end = unsafe { &*(context as *const CallbackEnd) };
+    let packet = unsafe { std::slice::from_raw_parts(data, len) }.to_vec();
+    end.outbox.lock().unwrap().push_back(packet);
+    SMOL_RESULT_OK
+}
+
+#[test]
+fn callback_stacks_pass_the_device_conformance_checks() {
+    let a_to_b = Arc::new(Mutex::new(VecDeque::new()));
+    let b_to_a = Arc::new(Mutex::new(VecDeque::new()));
+    let end_a = CallbackEnd {
+        inbox: b_to_a.clone(),
+        outbox: a_to_b.clone(),
+    };
+    let end_b = CallbackEnd {
+        inbox: a_to_b,
+        outbox: b_to_a,
+    };
+    let new_callback = |end: &CallbackEnd| {
+        SmolStackType::new_callback(
+            Medium::Ip,
+            1500,
+            receive_from_link as CReceiveFunction,
+            transmit_to_link as CTransmitFunction,
+            end as *const CallbackEnd as *const c_void,
+        )
+    };
+    //The ends outlive the stacks, which are dropped by check_device_link
+    check_device_link(new_callback(&end_a), new_callback(&end_b));
+}
+
+#[test]
+fn fd_stacks_pass_the_device_conformance_checks() {
+    let mut fds = [0; 2];
+    assert_eq!(
+        unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_DGRAM, 0, fds.as_mut_ptr()) },
+        0
+    );
+    let a = SmolStackType::new_from_fd(fds[0], fds[0], Medium::Ip, 1500).unwrap();
+    let b = SmolStackType::new_from_fd(fds[1], fds[1], Medium::Ip, 1500).unwrap();
+    check_device_link(a, b);
+    unsafe {
+        libc::close(fds[0]);
+        libc::close(fds[1]);
+    }
+}
+
+#[test]
+fn pipe_stacks_pass_the_device_conformance_checks() {
+    //Packet mode pipes, one for each direction
+    let (mut a_to_b, mut b_to_a) = ([0; 2], [0; 2]);
+    assert_eq!(unsafe { libc::pipe2(a_to_b.as_mut_ptr(), libc::O_DIRECT) }, 0);
+    assert_eq!(unsafe { libc::pipe2(b_to_a.as_mut_ptr(), libc::O_DIRECT) }, 0);
+    let a = SmolStackType::new_from_fd(b_to_a[0], a_to_b[1], Medium::Ip, 1500).unwrap();
+    let b = SmolStackType::new_from_fd(a_to_b[0], b_to_a[1], Medium::Ip, 1500).unwrap();
+    check_device_link(a, b);
+    unsafe {
+        libc::close(a_to_b[0]);
+        libc::close(a_to_b[1]);
+        libc::close(b_to_a[0]);
+        libc::close(b_to_a[1]);
+    }
+}
+
+#[test]
+fn unix_socket_stacks_pass_the_device_conformance_checks() {
+    let (path_a, path_b) = (socket_path("conformance_a"), socket_path("conformance_b"));
+    let a = SmolStackType::new_unix_socket(&path_a, &path_b, Medium::Ip, 1500).unwrap();
+    let b = SmolStackType::new_unix_socket(&path_b, &path_a, Medium::Ip, 1500).unwrap();
+    check_device_link(a, b);
+    assert!(!path_a.exists());
+    assert!(!path_b.exists());
+}
+
+#[test]
+fn unix_socket_packets_wait_for_the_peer() {
+    let (path_a, path_b) = (socket_path("early_a"), socket_path("early_b"));
+    let a = SmolStackType::new_unix_socket(&path_a, &path_b, Medium::Ip, 1500).unwrap();
+    let mut a = device_stack(a, ADDRESS_A);
+    let client = add_socket(&mut a, SocketType::UDP);
+    assert_eq!(a.udp_bind(client, 5000), SMOL_RESULT_OK);
+    //b isn't there yet, so this one waits in a's socket
+    send(&mut a, client, b"early".to_vec(), Some(endpoint(ADDRESS_B, 6000)));
+    a.spin_all();
+    a.poll();
+
+    let b = SmolStackType::new_unix_socket(&path_b, &path_a, Medium::Ip, 1500).unwrap();
+    let mut b = device_stack(b, ADDRESS_B);
+    let server = add_socket(&mut b, SocketType::UDP);
+    assert_eq!(b.udp_bind(server, 6000), SMOL_RESULT_OK);
+    send(&mut a, client, b"later".to_vec(), Some(endpoint(ADDRESS_B, 6000)));
+    a.spin_all();
+    a.poll();
+    b.poll();
+    b.spin_all();
+    let mut buffer = vec![0; 1500];
+    let mut len = 0;
+    let mut from = None;
+    let smol_socket = b.get_smol_socket(server).unwrap();
+    for &expected in [&b"early"[..], &b"later"[..]].iter() {
+        assert_eq!(smol_socket.receive_into(&mut buffer, &mut len, &mut from), SMOL_RESULT_OK);
+        assert_eq!(&buffer[..len], expected);
+        assert_eq!(from, Some(endpoint(ADDRESS_A, 5000)));
+    }
+}
+
+#[test]
+fn udp_tunnel_stacks_pass_the_device_conformance_checks() {
+    //Bound to port 0 first, so each one knows the other's port without racing for a free one
+    let socket_a = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let socket_b = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let (address_a, address_b) = (socket_a.local_addr().unwrap(), socket_b.local_addr().unwrap());
+    let a = SmolStackType::new_udp_tunnel_from_socket(socket_a, address_b, Medium::Ip, 1400).unwrap();
+    let b = SmolStackType::new_udp_tunnel_from_socket(socket_b, address_a, Medium::Ip, 1400).unwrap();
+    check_device_link(a, b);
+}
+
+#[test]
+fn shutdown_wakes_a_thread_waiting_on_the_fd() {
+    let mut fds = [0; 2];
+    assert_eq!(
+        unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_DGRAM, 0, fds.as_mut_ptr()) },
+        0
+    );
+    let smol_stack = SmolStackType::new_from_fd(fds[0], fds[0], Medium::Ip, 1500).unwrap();
+    let mut smol_stack = device_stack(smol_stack, ADDRESS_A);
+    //Like C++ does: the poller thread and this one share the stack, shutdown is allowed meanwhile
+    let pointer = &mut *smol_stack as *mut SmolStackType<'static, 'static, 'static> as usize;
+    let poller = std::thread::spawn(move || {
+        let smol_stack = unsafe { &mut *(pointer as *mut SmolStackType<'static, 'static, 'static>) };
+        //No socket, no timer: only a notification can end this wait
+        smol_stack.phy_wait(0)
+    });
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    assert_eq!(smol_stack.shutdown(None), SMOL_RESULT_OK);
+    assert_ne!(poller.join().unwrap() & WAKE_REASON_SHUT_DOWN, 0);
+    drop(smol_stack);
+    unsafe {
+        libc::close(fds[0]);
+        libc::close(fds[1]);
+    }
+}
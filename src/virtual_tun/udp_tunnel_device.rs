@@ -0,0 +1,148 @@
+use super::fd_device::received_packet;
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use smoltcp::wire::{IpAddress, IpEndpoint};
+use smoltcp::{Error, Result};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+//Host address of a smoltcp endpoint, None for unspecified addresses
+pub fn socket_address(endpoint: IpEndpoint) -> Option<SocketAddr> {
+    match endpoint.addr {
+        IpAddress::Ipv4(address) => Some(SocketAddr::new(Ipv4Addr::from(address.0).into(), endpoint.port)),
+        IpAddress::Ipv6(address) => Some(SocketAddr::new(Ipv6Addr::from(address.0).into(), endpoint.port)),
+        _ => None,
+    }
+}
+
+/*
+    Point to point tunnel over the host's network: every packet the stack
+    emits goes, as is, in one datagram from a real UDP socket bound to
+    `local` to `peer`, and datagrams from `peer` are the packets received.
+    The socket is connected to `peer`, so the kernel drops datagrams from
+    anyone else. A packet whose datagram can't be sent (full socket,
+    unreachable peer) is dropped and reported to smoltcp as Exhausted,
+    like the other devices do, so its socket may send it again on a later
+    poll. There's no encryption nor authentication, it's meant for
+    staging environments and tests, not as a VPN
+*/
+pub struct UdpTunnelDevice {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    medium: Medium,
+    mtu: usize,
+}
+
+impl UdpTunnelDevice {
+    pub fn new(local: SocketAddr, peer: SocketAddr, medium: Medium, mtu: usize) -> io::Result<UdpTunnelDevice> {
+        UdpTunnelDevice::from_socket(UdpSocket::bind(local)?, peer, medium, mtu)
+    }
+
+    //Same as new, on a socket that's already bound, like one bound to port 0
+    pub fn from_socket(socket: UdpSocket, peer: SocketAddr, medium: Medium, mtu: usize) -> io::Result<UdpTunnelDevice> {
+        socket.connect(peer)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpTunnelDevice {
+            socket: socket,
+            peer: peer,
+            medium: medium,
+            mtu: mtu,
+        })
+    }
+
+    //The fd phy_wait waits on
+    pub fn fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+
+    pub fn local_address(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl<'d> Device<'d> for UdpTunnelDevice {
+    type RxToken = RxToken;
+    type TxToken = TxToken<'d>;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut d = DeviceCapabilities::default();
+        d.max_transmission_unit = self.mtu;
+        d
+    }
+
+    fn receive(&'d mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let mut buffer = vec![0; self.mtu];
+        loop {
+            let result = self.socket.recv(&mut buffer[..]);
+            match result {
+                //ICMP port unreachable for something we sent earlier, the peer isn't up
+                Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => continue,
+                result => {
+                    let buffer = received_packet(buffer, result, &self.peer)?;
+                    return Some((RxToken { buffer: buffer }, TxToken { device: self }));
+                }
+            }
+        }
+    }
+
+    fn transmit(&'d mut self) -> Option<Self::TxToken> {
+        Some(TxToken { device: self })
+    }
+
+    fn medium(&self) -> Medium {
+        self.medium
+    }
+}
+
+#[doc(hidden)]
+pub struct RxToken {
+    buffer: Vec<u8>,
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        f(&mut self.buffer[..])
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken<'d> {
+    device: &'d UdpTunnelDevice,
+}
+
+impl<'d> phy::TxToken for TxToken<'d> {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let mut buffer = vec![0; len];
+        let result = f(&mut buffer)?;
+        match self.device.socket.send(&buffer[..]) {
+            Ok(_) => Ok(result),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Err(Error::Exhausted),
+            //Unreachable peer or a real failure, nothing was sent
+            Err(err) => {
+                debug!("send to {} failed: {}", self.device.peer, err);
+                Err(Error::Exhausted)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoints_convert_to_host_addresses() {
+        let endpoint = IpEndpoint::new(IpAddress::v4(10, 0, 0, 1), 1194);
+        assert_eq!(socket_address(endpoint), Some(SocketAddr::from(([10, 0, 0, 1], 1194))));
+        let endpoint = IpEndpoint::new(IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 1), 1194);
+        assert_eq!(socket_address(endpoint), Some("[::1]:1194".parse().unwrap()));
+        assert_eq!(socket_address(IpEndpoint::new(IpAddress::Unspecified, 1)), None);
+    }
+}
@@ -0,0 +1,181 @@
+use super::fd_device::received_packet;
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use smoltcp::{Error, Result};
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/*
+    Device that exchanges packets with another process through a
+    SOCK_DGRAM Unix socket, one datagram per packet. It binds to `path`
+    and sends everything to `peer_path`, where the other process' device
+    is bound, so two stacks configured with each other's paths behave as
+    if connected by a cable. The peer doesn't need to exist yet: until it
+    binds, sends fail and are counted in send_errors, and smoltcp keeps
+    the packets in their sockets to try again on the next poll.
+    The socket file is created on creation and removed on drop
+*/
+pub struct UnixSocketDevice {
+    socket: UnixDatagram,
+    path: PathBuf,
+    peer_path: PathBuf,
+    medium: Medium,
+    mtu: usize,
+    send_errors: AtomicU64,
+}
+
+/*
+    A socket file left by a process that died would make bind fail, so
+    it's removed, but only if nobody answers on it: a live one belongs
+    to another stack and taking it over would cut that stack off
+*/
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.file_type().is_socket() => {}
+        _ => return Ok(()),
+    }
+    match UnixDatagram::unbound()?.connect(path) {
+        Ok(()) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is used by a running process", path.display()),
+        )),
+        Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(err) => Err(err),
+    }
+}
+
+impl UnixSocketDevice {
+    pub fn new(path: &Path, peer_path: &Path, medium: Medium, mtu: usize) -> io::Result<UnixSocketDevice> {
+        remove_stale_socket(path)?;
+        let socket = UnixDatagram::bind(path)?;
+        socket.set_nonblocking(true)?;
+        Ok(UnixSocketDevice {
+            socket: socket,
+            path: path.to_owned(),
+            peer_path: peer_path.to_owned(),
+            medium: medium,
+            mtu: mtu,
+            send_errors: AtomicU64::new(0),
+        })
+    }
+
+    //Packets that couldn't be sent for reasons other than a full peer queue, like a missing peer
+    pub fn send_errors(&self) -> u64 {
+        self.send_errors.load(Ordering::Relaxed)
+    }
+
+    //The fd phy_wait waits on
+    pub fn fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl Drop for UnixSocketDevice {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl<'d> Device<'d> for UnixSocketDevice {
+    type RxToken = RxToken;
+    type TxToken = TxToken<'d>;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut d = DeviceCapabilities::default();
+        d.max_transmission_unit = self.mtu;
+        d
+    }
+
+    fn receive(&'d mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let mut buffer = vec![0; self.mtu];
+        let result = self.socket.recv(&mut buffer[..]);
+        let buffer = received_packet(buffer, result, &self.path.display())?;
+        Some((RxToken { buffer: buffer }, TxToken { device: self }))
+    }
+
+    fn transmit(&'d mut self) -> Option<Self::TxToken> {
+        Some(TxToken { device: self })
+    }
+
+    fn medium(&self) -> Medium {
+        self.medium
+    }
+}
+
+#[doc(hidden)]
+pub struct RxToken {
+    buffer: Vec<u8>,
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        f(&mut self.buffer[..])
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken<'d> {
+    device: &'d UnixSocketDevice,
+}
+
+impl<'d> phy::TxToken for TxToken<'d> {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let mut buffer = vec![0; len];
+        let result = f(&mut buffer)?;
+        match self.device.socket.send_to(&buffer[..], &self.device.peer_path) {
+            Ok(_) => Ok(result),
+            //The peer's queue is full, TCP retransmits what we drop here
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Err(Error::Exhausted),
+            //Nobody on the other end (yet) or a real failure, nothing was sent
+            Err(err) => {
+                debug!("send to {} failed: {}", self.device.peer_path.display(), err);
+                self.device.send_errors.fetch_add(1, Ordering::Relaxed);
+                Err(Error::Exhausted)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::socket_path;
+    use super::*;
+
+    #[test]
+    fn live_sockets_are_not_taken_over() {
+        let path = socket_path("taken");
+        let peer_path = socket_path("taken_peer");
+        let device = UnixSocketDevice::new(&path, &peer_path, Medium::Ip, 1500).unwrap();
+        assert_eq!(
+            UnixSocketDevice::new(&path, &peer_path, Medium::Ip, 1500).err().map(|err| err.kind()),
+            Some(io::ErrorKind::AddrInUse)
+        );
+        //A socket file nobody is bound to any more is reused
+        let stale = UnixDatagram::bind(&peer_path).unwrap();
+        drop(stale);
+        let peer = UnixSocketDevice::new(&peer_path, &path, Medium::Ip, 1500).unwrap();
+        drop(peer);
+        drop(device);
+    }
+
+    #[test]
+    fn sends_without_a_peer_are_counted() {
+        let path = socket_path("lonely");
+        let mut device = UnixSocketDevice::new(&path, &socket_path("nobody"), Medium::Ip, 1500).unwrap();
+        let token = device.transmit().unwrap();
+        let result = phy::TxToken::consume(token, Instant::from_millis(0), 20, |_| Ok(()));
+        assert_eq!(result, Err(Error::Exhausted));
+        assert_eq!(device.send_errors(), 1);
+    }
+}
@@ -1,81 +1,155 @@
 #![allow(unsafe_code)]
 #![allow(unused)]
 
-use super::smol_stack::Blob;
+use super::filter::{reject_reply, PacketFilter, FILTER_ACTION_ACCEPT, FILTER_ACTION_REJECT};
+use super::filter::{FILTER_EGRESS, FILTER_INGRESS};
+#[cfg(feature = "metrics")]
+use super::metrics::StackMetrics;
+use super::pcap::PacketCapture;
+use super::queue::FlowNotifier;
+use super::queue::{FLOW_EVENT_STACK_READABLE, FLOW_EVENT_STACK_WRITABLE};
+use super::ring::{PacketRing, PushError};
+use super::signal::Signal;
 use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
 use smoltcp::time::Instant;
 use smoltcp::{Error, Result};
 use std::cell::RefCell;
 use std::collections::VecDeque;
-use std::io;
-use std::rc::Rc;
-use std::sync::{Arc, Condvar, Mutex};
-use std::vec::Vec;
-use std::time::Duration;
-use std::isize;
-use std::ops::Deref;
-use std::slice;
-
-static ERR_WOULD_BLOCK: u32 = 1;
-
-#[derive(Clone)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+//Also the size of each slot on the packet rings
+pub const VIRTUAL_TUN_MTU: usize = 1500;
+
+/*
+    Device whose packets come from and go to C++ through two PacketRings:
+    packets_from_outside (C++ produces, we consume) and packets_from_inside
+    (we produce, C++ consumes). Packets are read and written in place on
+    the ring slots, with no allocations.
+    Both directions go through `filter`, refused packets never reach the
+    other side
+*/
 pub struct VirtualTunInterface {
     mtu: usize,
-    has_data: Arc<(Mutex<()>, Condvar)>,
-    packets_from_inside: Arc<Mutex<VecDeque<Vec<u8>>>>,
-    packets_from_outside: Arc<Mutex<VecDeque<Blob>>>,
-}
-
-fn copy_slice(dst: &mut [u8], src: &[u8]) -> usize {
-    let mut c = 0;
-    for (d, s) in dst.iter_mut().zip(src.iter()) {
-        *d = *s;
-        c += 1;
-    }
-    c 
+    has_data: Arc<Signal>,
+    packets_from_inside: Arc<PacketRing>,
+    packets_from_outside: Arc<PacketRing>,
+    flow_notifier: Arc<FlowNotifier>,
+    //Every packet received and sent is also written here, if set
+    capture: Option<Arc<PacketCapture>>,
+    filter: Arc<PacketFilter>,
+    //Replies to packets the stack sent and the filter rejected, received before packets_from_outside
+    rejected_replies: RefCell<VecDeque<Vec<u8>>>,
+    //When set, how many more packets from outside smoltcp may have, see Nat
+    receive_budget: Option<Arc<AtomicUsize>>,
+    //Counts what smoltcp sends, on stacks built with the metrics feature
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<StackMetrics>>,
 }
 
-impl<'a> VirtualTunInterface {
+impl VirtualTunInterface {
     pub fn new(
         _name: &str,
-        packets_from_inside: Arc<Mutex<VecDeque<Vec<u8>>>>,
-        packets_from_outside: Arc<Mutex<VecDeque<Blob>>>,
-        has_data: Arc<(Mutex<()>, Condvar)>
+        packets_from_inside: Arc<PacketRing>,
+        packets_from_outside: Arc<PacketRing>,
+        has_data: Arc<Signal>,
+        flow_notifier: Arc<FlowNotifier>,
+        filter: Arc<PacketFilter>,
     ) -> Result<VirtualTunInterface> {
-        let mtu = 1500; //??
         Ok(VirtualTunInterface {
-            mtu: mtu,
+            mtu: packets_from_inside.slot_size(),
             has_data: has_data,
             packets_from_outside: packets_from_outside,
             packets_from_inside: packets_from_inside,
+            flow_notifier: flow_notifier,
+            capture: None,
+            filter: filter,
+            rejected_replies: RefCell::new(VecDeque::new()),
+            receive_budget: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
         })
     }
-    //TODO: this cant block, I guess?? Or it can..
-    fn recv(&mut self, buffer: &mut [u8]) -> core::result::Result<usize, u32> {
-        let packets_from_outside = &*self.packets_from_outside.clone();
-        let p;
-        {
-            p = packets_from_outside.lock().unwrap().pop_front();
+
+    pub fn set_capture(&mut self, capture: Option<Arc<PacketCapture>>) {
+        self.capture = capture;
+    }
+
+    pub fn set_receive_budget(&mut self, receive_budget: Option<Arc<AtomicUsize>>) {
+        self.receive_budget = receive_budget;
+    }
+
+    fn has_budget(&self) -> bool {
+        self.receive_budget
+            .as_ref()
+            .map_or(true, |budget| budget.load(Ordering::SeqCst) > 0)
+    }
+
+    //Only the poller changes the budget once set, so there's no race between checking and spending
+    fn spend_budget(&self) {
+        if let Some(budget) = self.receive_budget.as_ref() {
+            budget.fetch_sub(1, Ordering::SeqCst);
         }
-        match p {
-            Some(packet) => {
-                copy_slice(buffer, packet.data.as_slice());
-                let (mutex, has_data_condition_variable) = &*self.has_data.clone();
-                has_data_condition_variable.notify_one();
-                Ok(packet.data.len())
+    }
+
+    /*
+        Pops the packets at the front of packets_from_outside the filter
+        refuses, until one is accepted or none is left. Runs on the poller,
+        the only consumer of packets_from_outside and producer of
+        packets_from_inside, where rejections are answered
+    */
+    fn drop_refused_ingress(&self) {
+        let mut popped = false;
+        while self.has_budget() {
+            let action = self
+                .packets_from_outside
+                .front_with(|packet| self.filter.check(FILTER_INGRESS, packet));
+            match action {
+                None | Some(FILTER_ACTION_ACCEPT) => break,
+                Some(action) => {
+                    self.spend_budget();
+                    let reply = self.packets_from_outside.pop_with(|packet| {
+                        if action == FILTER_ACTION_REJECT {
+                            reject_reply(packet)
+                        } else {
+                            None
+                        }
+                    });
+                    if let Some(Some(reply)) = reply {
+                        self.queue_reply(&reply);
+                    }
+                    popped = true;
+                }
+            }
+        }
+        if popped && self.packets_from_outside.take_unblocked() {
+            self.flow_notifier.notify(0, FLOW_EVENT_STACK_WRITABLE);
+        }
+    }
+
+    //Sends C++ the answer to one of its packets, as if it came from the stack
+    fn queue_reply(&self, reply: &[u8]) {
+        let was_empty = self.packets_from_inside.is_empty();
+        match self.packets_from_inside.push(reply) {
+            Ok(()) => {
+                if was_empty {
+                    self.flow_notifier.notify(0, FLOW_EVENT_STACK_READABLE);
+                }
             }
-            /*
-                Simply returns ERR_WOULD_BLOCK. Device::receive(&mut self) is prepared
-                to assume that it'd block so it does nothing in this case (returns None)
-            */
-            None => Err(ERR_WOULD_BLOCK),
+            //It's only a courtesy, the packet was refused anyway
+            Err(_) => debug!("packets_from_inside is full, dropping reject reply"),
         }
     }
+
+    #[cfg(feature = "metrics")]
+    pub fn set_metrics(&mut self, metrics: Option<Arc<StackMetrics>>) {
+        self.metrics = metrics;
+    }
 }
 
 impl<'d> Device<'d> for VirtualTunInterface {
-    type RxToken = RxToken;
-    type TxToken = TxToken;
+    type RxToken = RxToken<'d>;
+    type TxToken = TxToken<'d>;
 
     fn capabilities(&self) -> DeviceCapabilities {
         let mut d = DeviceCapabilities::default();
@@ -84,29 +158,35 @@ impl<'d> Device<'d> for VirtualTunInterface {
     }
 
     fn receive(&'d mut self) -> Option<(Self::RxToken, Self::TxToken)> {
-        let mut buffer = vec![0; self.mtu];
-        match self.recv(&mut buffer[..]) {
-            Ok(size) => {
-                buffer.resize(size, 0);
-                let rx = RxToken {
-                    lower: Rc::new(RefCell::new(self.clone())),
-                    buffer,
-                };
-                let tx = TxToken {
-                    lower: Rc::new(RefCell::new(self.clone())),
-                };
-                Some((rx, tx))
-            }
+        let rejected_reply = self.rejected_replies.get_mut().pop_front();
+        if rejected_reply.is_none() {
+            self.drop_refused_ingress();
             //Simulates a tun/tap device that returns EWOULDBLOCK
-            Err(err) if err == ERR_WOULD_BLOCK => None,
-            Err(err) => panic!("{}", err),
+            if self.packets_from_outside.is_empty() || !self.has_budget() {
+                return None;
+            }
+            self.spend_budget();
         }
+        let lower: &'d VirtualTunInterface = self;
+        Some((
+            RxToken {
+                lower: lower,
+                rejected_reply: rejected_reply,
+            },
+            TxToken { lower: lower },
+        ))
     }
 
     fn transmit(&'d mut self) -> Option<Self::TxToken> {
-        Some(TxToken {
-            lower: Rc::new(RefCell::new(self.clone())),
-        })
+        /*
+            No room for what smoltcp wants to send, so we tell it we can't
+            transmit now. Data stays in the sockets until C++ receives
+            the packets it has to
+        */
+        if !self.packets_from_inside.check_room() {
+            return None;
+        }
+        Some(TxToken { lower: self })
     }
 
     fn medium(&self) -> Medium {
@@ -115,46 +195,114 @@ impl<'d> Device<'d> for VirtualTunInterface {
 }
 
 #[doc(hidden)]
-pub struct RxToken {
-    lower: Rc<RefCell<VirtualTunInterface>>,
-    buffer: Vec<u8>,
+pub struct RxToken<'d> {
+    lower: &'d VirtualTunInterface,
+    //Given instead of the next packet from outside, see rejected_replies
+    rejected_reply: Option<Vec<u8>>,
 }
 
-impl phy::RxToken for RxToken {
-    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> Result<R>
+impl<'d> phy::RxToken for RxToken<'d> {
+    fn consume<R, F>(self, timestamp: Instant, f: F) -> Result<R>
     where
         F: FnOnce(&mut [u8]) -> Result<R>,
     {
-        let mut lower = self.lower.as_ref().borrow_mut();
-        let r = f(&mut self.buffer[..]);
-        let (mutex, has_data_condition_variable) = &*lower.has_data.clone();
-        has_data_condition_variable.notify_one();
+        let capture = self.lower.capture.as_ref();
+        if let Some(mut packet) = self.rejected_reply {
+            if let Some(capture) = capture {
+                capture.packet(timestamp, &packet);
+            }
+            return f(&mut packet);
+        }
+        //receive() saw a packet and we are the only consumer, so it's still there
+        let r = self
+            .lower
+            .packets_from_outside
+            .pop_with(|packet| {
+                if let Some(capture) = capture {
+                    capture.packet(timestamp, packet);
+                }
+                f(packet)
+            })
+            .unwrap_or(Err(Error::Exhausted));
+        //C++ got SMOL_RESULT_WOULD_BLOCK before, tell it there's room now
+        if self.lower.packets_from_outside.take_unblocked() {
+            self.lower.flow_notifier.notify(0, FLOW_EVENT_STACK_WRITABLE);
+        }
         r
     }
 }
 
+//Why TxToken::consume didn't commit the ring slot smoltcp wrote
+enum NotQueued<R> {
+    Failed(Error),
+    //Sent as far as smoltcp knows, with the reply to give it if rejected
+    Refused(R, Option<Vec<u8>>),
+}
+
 #[doc(hidden)]
-pub struct TxToken {
-    lower: Rc<RefCell<VirtualTunInterface>>,
+pub struct TxToken<'d> {
+    lower: &'d VirtualTunInterface,
 }
 
-impl<'a> phy::TxToken for TxToken {
-    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> Result<R>
+impl<'d> phy::TxToken for TxToken<'d> {
+    fn consume<R, F>(self, timestamp: Instant, len: usize, f: F) -> Result<R>
     where
         F: FnOnce(&mut [u8]) -> Result<R>,
     {
-        let mut lower = self.lower.as_ref().borrow_mut();
-        let mut buffer = vec![0; len];
-        let result = f(&mut buffer);
-        
-        let packets_from_inside = &*lower.packets_from_inside.clone();
-        {
-            packets_from_inside.lock().unwrap().push_back(buffer);
+        let capture = self.lower.capture.as_ref();
+        #[cfg(feature = "metrics")]
+        let metrics = self.lower.metrics.as_ref();
+        let packets_from_inside = &self.lower.packets_from_inside;
+        let was_empty = packets_from_inside.is_empty();
+        let filter = &self.lower.filter;
+        let push = packets_from_inside.push_with(len, |packet| {
+            let r = f(packet).map_err(NotQueued::Failed)?;
+            match filter.check(FILTER_EGRESS, packet) {
+                FILTER_ACTION_ACCEPT => (),
+                FILTER_ACTION_REJECT => return Err(NotQueued::Refused(r, reject_reply(packet))),
+                _ => return Err(NotQueued::Refused(r, None)),
+            }
+            if let Some(capture) = capture {
+                capture.packet(timestamp, packet);
+            }
+            Ok(r)
+        });
+        let result = match push {
+            Ok(Ok(result)) => result,
+            Ok(Err(NotQueued::Failed(e))) => return Err(e),
+            Ok(Err(NotQueued::Refused(result, reply))) => {
+                if let Some(reply) = reply {
+                    self.lower.rejected_replies.borrow_mut().push_back(reply);
+                }
+                return Ok(result);
+            }
+            //Only happens for replies given by receive(), transmit() checks for room.
+            //TCP retransmits what we drop here
+            Err(PushError::Full) => {
+                debug!("packets_from_inside is full, dropping packet");
+                #[cfg(feature = "metrics")]
+                if let Some(metrics) = metrics {
+                    metrics.from_inside.dropped_full();
+                }
+                return Err(Error::Exhausted);
+            }
+            Err(PushError::TooBig) => {
+                error!("packet of {} bytes is bigger than the MTU", len);
+                #[cfg(feature = "metrics")]
+                if let Some(metrics) = metrics {
+                    metrics.from_inside.dropped_too_big();
+                }
+                return Err(Error::Exhausted);
+            }
+        };
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = metrics {
+            metrics.from_inside.queued(len);
+        }
+        //No has_data notify here: we run on the poller, it would only wake itself
+        if was_empty {
+            self.lower.flow_notifier.notify(0, FLOW_EVENT_STACK_READABLE);
         }
-        
-        //TODO: I think this is not necessary?
-        let (mutex, has_data_condition_variable) = &*lower.has_data.clone();
-        has_data_condition_variable.notify_one();
-        result
+        Ok(result)
     }
 }